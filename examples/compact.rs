@@ -18,10 +18,15 @@ fn run(config: Config) -> Result<()> {
         );
     }
 
-    let metrics = Metrics::new(config.monitoring_addr);
+    let metrics = Metrics::new(config.monitoring_addr, config.monitoring_auth_token.clone());
     metrics.start();
 
-    let store = DbStore::open(&config.db_path, /*low_memory=*/ true, &metrics);
+    let store = DbStore::open(
+        &config.db_path,
+        /*low_memory=*/ true,
+        /*live_wal=*/ true,
+        &metrics,
+    );
     store.compact();
     Ok(())
 }
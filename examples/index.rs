@@ -1,4 +1,5 @@
 /// Benchmark regular indexing flow (using JSONRPC), don't persist the resulting index.
+/// Reports the indexing throughput, e.g. to compare numbers of fetch threads.
 extern crate electrscash;
 extern crate error_chain;
 
@@ -6,32 +7,64 @@ extern crate error_chain;
 extern crate log;
 
 use electrscash::{
-    cache::BlockTxIDsCache, config::Config, daemon::Daemon, errors::*, fake::FakeStore,
-    index::Index, metrics::Metrics, signal::Waiter,
+    cache::{BlockTxIDsCache, TransactionCache},
+    config::Config,
+    daemon::Daemon,
+    errors::*,
+    fake::FakeStore,
+    index::Index,
+    metrics::Metrics,
+    rndcache::Watermarks,
+    signal::Waiter,
 };
 use error_chain::ChainedError;
 use std::sync::Arc;
+use std::time::Instant;
 
 fn run() -> Result<()> {
     let signal = Waiter::start();
     let config = Config::from_args();
-    let metrics = Metrics::new(config.monitoring_addr);
+    let metrics = Metrics::new(config.monitoring_addr, config.monitoring_auth_token.clone());
     metrics.start();
-    let cache = Arc::new(BlockTxIDsCache::new(0, &metrics));
+    let cache = Arc::new(BlockTxIDsCache::new(0, Watermarks::default(), &metrics));
 
     let daemon = Daemon::new(
         &config.daemon_dir,
         &config.blocks_dir,
-        config.daemon_rpc_addr,
+        config.daemon_rpc_addrs(),
         config.cookie_getter(),
         config.network_type,
         signal.clone(),
+        config.daemon_retry_limit,
+        config.daemon_rpc_timeout,
         cache,
         &metrics,
     )?;
     let fake_store = FakeStore {};
-    let index = Index::load(&fake_store, &daemon, &metrics, config.index_batch_size, 0)?;
+    let index = Index::load(
+        &fake_store,
+        &daemon,
+        &metrics,
+        config.index_batch_size,
+        config.index_fetch_threads,
+        config.index_checkpoint_interval,
+        0,
+        config.index_options(),
+        Arc::new(TransactionCache::new(0, Watermarks::default(), &metrics)),
+        config.header_window,
+        config.index_start_height,
+    )?;
+    let start = Instant::now();
     index.update(&fake_store, &signal)?;
+    let elapsed = start.elapsed().as_secs_f64();
+    let blocks = index.best_header().map_or(0, |tip| tip.height() + 1);
+    info!(
+        "indexed {} blocks in {:.1}s ({:.1} blocks/s) with {} fetch threads",
+        blocks,
+        elapsed,
+        blocks as f64 / elapsed,
+        config.index_fetch_threads
+    );
     Ok(())
 }
 
@@ -0,0 +1,129 @@
+/// Benchmark loading the transactions of the latest blocks as a non-verbose
+/// blockchain.transaction.get does, with and without looking up their
+/// blockhash first. Compare runs against bitcoind with and without -txindex.
+/// The DB must exist, and not be in use by a running server.
+extern crate electrscash;
+
+#[macro_use]
+extern crate log;
+
+extern crate error_chain;
+
+use electrscash::{
+    app::App,
+    cache::{AddressCache, BlockTxIDsCache, TransactionCache},
+    config::Config,
+    daemon::Daemon,
+    errors::*,
+    index::Index,
+    metrics::Metrics,
+    query::{executor::QueryExecutor, Query, QueryOptions},
+    rndcache::Watermarks,
+    signal::Waiter,
+    store::DbStore,
+};
+use error_chain::ChainedError;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const BLOCKS: usize = 10;
+
+fn run(config: Config) -> Result<()> {
+    if !config.db_path.exists() {
+        panic!(
+            "DB {:?} must exist when running this benchmark!",
+            config.db_path
+        );
+    }
+
+    let signal = Waiter::start();
+    let metrics = Metrics::new(config.monitoring_addr, config.monitoring_auth_token.clone());
+    metrics.start();
+
+    let daemon = Daemon::new(
+        &config.daemon_dir,
+        &config.blocks_dir,
+        config.daemon_rpc_addrs(),
+        config.cookie_getter(),
+        config.network_type,
+        signal,
+        config.daemon_retry_limit,
+        config.daemon_rpc_timeout,
+        Arc::new(BlockTxIDsCache::new(0, Watermarks::default(), &metrics)),
+        &metrics,
+    )?;
+    let store = DbStore::open(
+        &config.db_path,
+        config.low_memory,
+        /*live_wal=*/ true,
+        &metrics,
+    );
+    // nothing is cached, so every transaction is loaded from bitcoind
+    let tx_cache = Arc::new(TransactionCache::new(0, Watermarks::default(), &metrics));
+    let index = Index::load(
+        &store,
+        &daemon,
+        &metrics,
+        config.index_batch_size,
+        config.index_fetch_threads,
+        config.index_checkpoint_interval,
+        config.cashaccount_activation_height,
+        config.index_options(),
+        Arc::clone(&tx_cache),
+        config.header_window,
+        config.index_start_height,
+    )?;
+    let app = App::new(store, index, Arc::new(daemon), &config.server_banner)?;
+    let query = Query::new(
+        app.clone(),
+        &metrics,
+        tx_cache,
+        QueryOptions {
+            txheight_cache_size: 0,
+            merkle_cache_size: 0,
+            ..config.query_options()
+        },
+        QueryExecutor::new(1, 0, &metrics)?,
+        AddressCache::new(0, Watermarks::default(), &metrics),
+    )?;
+
+    let tip = query.get_best_header()?;
+    let mut txids = vec![];
+    for height in (tip.height() + 1).saturating_sub(BLOCKS)..=tip.height() {
+        let header = query
+            .header()
+            .at_height(height)
+            .chain_err(|| format!("missing header at height {}", height))?;
+        txids.extend(app.daemon().getblocktxids(header.hash())?);
+    }
+    info!(
+        "loading {} txs of the latest {} blocks",
+        txids.len(),
+        BLOCKS
+    );
+
+    let mut with_lookup = Duration::default();
+    let mut without_lookup = Duration::default();
+    for txid in &txids {
+        let start = Instant::now();
+        query.tx().get(txid, None, None)?;
+        with_lookup += start.elapsed();
+
+        let start = Instant::now();
+        query.tx().get_raw(txid)?;
+        without_lookup += start.elapsed();
+    }
+    let average = |total: Duration| total / txids.len().max(1) as u32;
+    info!(
+        "blockhash looked up first: {:?} per tx, only when needed: {:?} per tx",
+        average(with_lookup),
+        average(without_lookup)
+    );
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run(Config::from_args()) {
+        error!("{}", e.display_chain());
+    }
+}
@@ -42,10 +42,15 @@ fn run(config: Config) {
     }
 
     let config = Config::from_args();
-    let metrics = Metrics::new(config.monitoring_addr);
+    let metrics = Metrics::new(config.monitoring_addr, config.monitoring_auth_token.clone());
     metrics.start();
 
-    let store = DbStore::open(&config.db_path, /*low_memory=*/ false, &metrics);
+    let store = DbStore::open(
+        &config.db_path,
+        /*low_memory=*/ false,
+        /*live_wal=*/ true,
+        &metrics,
+    );
     max_collision(store, b"T");
 }
 
@@ -1,14 +1,15 @@
 use bitcoincash::hash_types::BlockHash;
 use std::sync::{Arc, Mutex};
 
+use crate::banner::{BannerTemplate, BannerVars};
 use crate::util::HeaderEntry;
-use crate::{config::Config, daemon, errors::*, index, signal::Waiter, store};
+use crate::{daemon, errors::*, index, signal::Waiter, store};
 
 pub struct App {
     store: store::DbStore,
     index: index::Index,
     daemon: daemon::Daemon,
-    banner: String,
+    banner: BannerTemplate,
     tip: Mutex<BlockHash>,
 }
 
@@ -17,18 +18,18 @@ impl App {
         store: store::DbStore,
         index: index::Index,
         daemon: Arc<daemon::Daemon>,
-        config: &Config,
+        server_banner: &str,
     ) -> Result<Arc<App>> {
         Ok(Arc::new(App {
             store,
             index,
             daemon: daemon.reconnect()?,
-            banner: config.server_banner.clone(),
+            banner: BannerTemplate::parse(server_banner),
             tip: Mutex::new(BlockHash::default()),
         }))
     }
 
-    fn write_store(&self) -> &impl store::WriteStore {
+    fn write_store(&self) -> &(impl store::WriteStore + store::ReadStore) {
         &self.store
     }
     // TODO: use index for queries.
@@ -41,6 +42,9 @@ impl App {
     pub fn daemon(&self) -> &daemon::Daemon {
         &self.daemon
     }
+    pub fn store_compactor(&self) -> impl FnOnce() + Send + 'static {
+        self.store.compactor()
+    }
 
     pub fn update(&self, signal: &Waiter) -> Result<(Vec<HeaderEntry>, Option<HeaderEntry>)> {
         let mut tip = self.tip.lock().expect("failed to lock tip");
@@ -54,10 +58,10 @@ impl App {
         }
     }
 
-    pub fn get_banner(&self) -> Result<String> {
+    pub fn get_banner(&self, vars: &BannerVars) -> Result<String> {
         Ok(format!(
             "{}\n{}",
-            self.banner,
+            self.banner.render(vars),
             self.daemon.get_subversion()?
         ))
     }
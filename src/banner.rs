@@ -0,0 +1,143 @@
+/// Live server state substituted into the banner.
+pub struct BannerVars {
+    pub height: usize,
+    pub version: String,
+    pub mempool_count: usize,
+    pub peers: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BannerVar {
+    Height,
+    Version,
+    MempoolCount,
+    Peers,
+}
+
+impl BannerVar {
+    fn from_name(name: &str) -> Option<BannerVar> {
+        match name {
+            "height" => Some(BannerVar::Height),
+            "version" => Some(BannerVar::Version),
+            "mempool_count" => Some(BannerVar::MempoolCount),
+            "peers" => Some(BannerVar::Peers),
+            _ => None,
+        }
+    }
+
+    fn render(self, vars: &BannerVars) -> String {
+        match self {
+            BannerVar::Height => vars.height.to_string(),
+            BannerVar::Version => vars.version.clone(),
+            BannerVar::MempoolCount => vars.mempool_count.to_string(),
+            BannerVar::Peers => vars.peers.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Var(BannerVar),
+}
+
+/// Server banner with `{name}` variables, such as `{height}`, substituted
+/// when rendered. The template is parsed once, unknown variables are kept
+/// as-is.
+#[derive(Debug)]
+pub struct BannerTemplate {
+    parts: Vec<Part>,
+}
+
+impl BannerTemplate {
+    pub fn parse(template: &str) -> BannerTemplate {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            literal.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let var = rest
+                .find('}')
+                .map(|end| (&rest[1..end], end))
+                .and_then(|(name, end)| BannerVar::from_name(name).map(|var| (var, end)));
+            match var {
+                Some((var, end)) => {
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Var(var));
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    if let Some(end) = rest.find('}') {
+                        warn!("unknown banner variable {}", &rest[..=end]);
+                    }
+                    literal.push('{');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        BannerTemplate { parts }
+    }
+
+    pub fn render(&self, vars: &BannerVars) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.clone(),
+                Part::Var(var) => var.render(vars),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> BannerVars {
+        BannerVars {
+            height: 700_000,
+            version: "1.2.3".to_string(),
+            mempool_count: 42,
+            peers: 7,
+        }
+    }
+
+    #[test]
+    fn test_render_banner() {
+        let template = BannerTemplate::parse(
+            "ElectrsCash {version} at height {height}: {mempool_count} mempool txs, {peers} peers",
+        );
+        assert_eq!(
+            template.render(&vars()),
+            "ElectrsCash 1.2.3 at height 700000: 42 mempool txs, 7 peers"
+        );
+    }
+
+    #[test]
+    fn test_render_banner_literal() {
+        for literal in [
+            "",
+            "Welcome!",
+            "{unknown} {height",
+            "{}{",
+            "}{{peers}}",
+            "{ height }",
+        ] {
+            let expected = literal.replace("{peers}", "7");
+            assert_eq!(BannerTemplate::parse(literal).render(&vars()), expected);
+        }
+        // variables are rendered again on each request
+        let template = BannerTemplate::parse("{height}{height}");
+        let mut vars = vars();
+        assert_eq!(template.render(&vars), "700000700000");
+        vars.height += 1;
+        assert_eq!(template.render(&vars), "700001700001");
+    }
+}
@@ -11,63 +11,102 @@ use std::sync::Arc;
 use electrscash::{
     app::App,
     bulk,
-    cache::{BlockTxIDsCache, TransactionCache},
+    cache::{AddressCache, BlockTxIDsCache, TransactionCache},
     config::Config,
-    daemon::Daemon,
-    doslimit::{ConnectionLimits, GlobalLimits},
+    daemon::{start_zmq_listeners, zmq_endpoints, Daemon},
+    doslimit::GlobalLimits,
     errors::*,
-    index::Index,
+    index::{index_options_marker, options_need_reindex, start_height_marker, Index},
     metrics::Metrics,
-    query::Query,
-    rpc::Rpc,
+    query::{executor::QueryExecutor, Query},
+    rpc::{relayfee::RelayFee, warnings::DaemonWarnings, Rpc, SocketOptions},
     signal::Waiter,
-    store::{full_compaction, is_compatible_version, is_fully_compacted, DbStore},
+    store::{
+        full_compaction, is_fully_compacted, needs_reindex, DbStore, TipCompaction, WriteStore,
+    },
 };
 
 fn run_server(config: &Config) -> Result<()> {
     let signal = Waiter::start();
-    let metrics = Arc::new(Metrics::new(config.monitoring_addr));
+    let metrics = Arc::new(Metrics::new(
+        config.monitoring_addr,
+        config.monitoring_auth_token.clone(),
+    ));
     metrics.start();
     let blocktxids_cache = Arc::new(BlockTxIDsCache::new(
         config.blocktxids_cache_size as u64,
+        config.cache_watermarks,
         &*metrics,
     ));
 
     let daemon = Arc::new(Daemon::new(
         &config.daemon_dir,
         &config.blocks_dir,
-        config.daemon_rpc_addr,
+        config.daemon_rpc_addrs(),
         config.cookie_getter(),
         config.network_type,
         signal.clone(),
+        config.daemon_retry_limit,
+        config.daemon_rpc_timeout,
         blocktxids_cache,
         &*metrics,
     )?);
+    let index_options = config.index_options();
     // Perform initial indexing.
     let compatible = {
-        let store = DbStore::open(&config.db_path, config.low_memory, &*metrics);
-        is_compatible_version(&store)
+        let store = DbStore::open(
+            &config.db_path,
+            config.low_memory,
+            config.live_index_wal,
+            &*metrics,
+        );
+        let reindex_incompatible = config.reindex_incompatible_db;
+        let reindex = needs_reindex(&store, config.index_start_height, reindex_incompatible)?
+            || options_need_reindex(&store, &index_options, reindex_incompatible)?;
+        !reindex
     };
 
     if !compatible {
-        info!("Incompatible database. Running full reindex.");
         DbStore::destroy(&config.db_path);
     }
-    let store = DbStore::open(&config.db_path, config.low_memory, &*metrics);
+    let store = DbStore::open(
+        &config.db_path,
+        config.low_memory,
+        config.live_index_wal,
+        &*metrics,
+    );
+    store.write(
+        vec![
+            start_height_marker(config.index_start_height),
+            index_options_marker(&index_options),
+        ],
+        true,
+    )?;
+    let tx_cache = Arc::new(TransactionCache::new(
+        config.tx_cache_size as u64,
+        config.cache_watermarks,
+        &*metrics,
+    ));
     let index = Index::load(
         &store,
         &daemon,
         &*metrics,
         config.index_batch_size,
+        config.index_fetch_threads,
+        config.index_checkpoint_interval,
         config.cashaccount_activation_height,
+        index_options,
+        Arc::clone(&tx_cache),
+        config.header_window,
+        config.index_start_height,
     )?;
-    let store = if is_fully_compacted(&store) {
+    let store = if is_fully_compacted(&store)? {
         store // initial import and full compaction are over
     } else if config.jsonrpc_import {
         // slower: uses JSONRPC for fetching blocks
-        index.reload(&store); // load headers
+        index.reload(&store)?; // load headers
         index.update(&store, &signal)?;
-        full_compaction(store)
+        full_compaction(store)?
     } else {
         // faster, but uses more memory
         let store = bulk::index_blk_files(
@@ -77,63 +116,140 @@ fn run_server(config: &Config) -> Result<()> {
             &signal,
             store,
             config.cashaccount_activation_height,
+            index_options,
+            config.index_start_height,
         )?;
-        let store = full_compaction(store);
-        index.reload(&store); // make sure the block header index is up-to-date
+        let store = full_compaction(store)?;
+        index.reload(&store)?; // make sure the block header index is up-to-date
         store
     }
     .enable_compaction(); // enable auto compactions before starting incremental index updates.
 
-    let app = App::new(store, index, daemon, config)?;
-    let tx_cache = TransactionCache::new(config.tx_cache_size as u64, &*metrics);
-    let query = Query::new(app.clone(), &*metrics, tx_cache, config.network_type)?;
-    let relayfee = query.get_relayfee()?;
-    let connection_limits = ConnectionLimits::new(
-        config.rpc_timeout,
-        config.scripthash_subscription_limit,
-        config.scripthash_alias_bytes_limit,
-    );
+    let app = App::new(store, index, daemon, &config.server_banner)?;
+    let query = Query::new(
+        app.clone(),
+        &*metrics,
+        tx_cache,
+        config.query_options(),
+        QueryExecutor::new(config.query_threads, config.query_queue_size, &metrics)?,
+        AddressCache::new(
+            config.address_cache_size as u64,
+            config.cache_watermarks,
+            &metrics,
+        ),
+    )?;
+    if config.blocktxids_warmup_blocks > 0 {
+        match query.warmup_blocktxids_cache(config.blocktxids_warmup_blocks) {
+            Ok(fetched) => info!("prefetched txids of {} recent blocks", fetched),
+            Err(e) => warn!("failed to prefetch block txids: {}", e.display_chain()),
+        }
+    }
+    let relayfee = Arc::new(RelayFee::new(
+        query.get_relayfee()?,
+        config.relayfee_refresh_interval,
+        config.min_relay_fee_override.unwrap_or(0.0),
+    ));
+    let warnings = Arc::new(DaemonWarnings::new(
+        query.get_daemon_warnings()?,
+        config.daemon_warnings_refresh_interval,
+    ));
+    let connection_limits = config.connection_limits();
     let global_limits = Arc::new(GlobalLimits::new(
         config.rpc_max_connections,
         config.rpc_max_connections_shared_prefix,
+        config.rpc_connections_ipv4_prefix_len,
+        config.rpc_connections_ipv6_prefix_len,
+        config.rpc_max_concurrent_requests_per_ip,
+        config.scripthash_subscription_limit_total,
+        config.rpc_exempt_loopback,
         &*metrics,
     ));
 
-    let mut server: Option<Rpc> = None; // Electrum RPC server
+    let socket_options = SocketOptions {
+        listen_backlog: config.rpc_listen_backlog,
+        keepalive: config.rpc_tcp_keepalive,
+        read_timeout: config.rpc_read_timeout,
+        read_buffer_size: config.rpc_read_buffer_size,
+    };
 
     let rpc_addr = config.electrum_rpc_addr;
     let ws_addr = config.electrum_ws_addr;
+    let ws_allowed_origins = config.electrum_ws_allowed_origins.clone();
+    let ws_max_frame_size = config.electrum_ws_max_frame_size as u64;
+    let proxied_peers = global_limits.proxied_peers();
     electrscash::util::spawn_thread("ws", move || {
-        electrscash::wstcp::start_ws_proxy(ws_addr, rpc_addr)
+        electrscash::wstcp::start_ws_proxy(
+            ws_addr,
+            rpc_addr,
+            ws_allowed_origins,
+            proxied_peers,
+            ws_max_frame_size,
+        )
     });
 
+    let zmq_listeners = start_zmq_listeners(
+        zmq_endpoints(
+            config.daemon_zmq_hashblock_addr,
+            config.daemon_zmq_hashtx_addr,
+        ),
+        &signal,
+    );
+
+    let tip_compaction = TipCompaction::new(
+        config.db_compact_at_tip,
+        config.db_compact_interval,
+        &metrics,
+    );
+
+    // Electrum RPC server. It is started before catching up with bitcoind,
+    // as status queries fail with a "syncing" error meanwhile.
+    let server = Rpc::start(
+        config.electrum_rpc_addr,
+        query.clone(),
+        metrics.clone(),
+        relayfee.clone(),
+        warnings.clone(),
+        connection_limits,
+        global_limits.clone(),
+        config.rpc_buffer_size,
+        config.rpc_max_dropped_notifications,
+        config.rpc_detect_ssl,
+        config.rpc_method_hints,
+        config.rpc_compression_threshold,
+        config.rpc_max_peer_threads,
+        socket_options,
+        config.electrum_rpc_unix_socket.clone(),
+        config.rpc_session_grace,
+        config.rpc_max_sessions_per_prefix,
+    );
+    let mut caught_up = false;
     loop {
         let (headers_changed, new_tip) = app.update(&signal)?;
+        tip_compaction.maybe_start(app.store_compactor());
         let txs_changed = query.update_mempool()?;
+        if let Err(e) = relayfee.maybe_refresh(|| query.get_relayfee()) {
+            warn!("{}", e.display_chain());
+        }
+        if let Err(e) = warnings.maybe_refresh(|| query.get_daemon_warnings()) {
+            warn!("{}", e.display_chain());
+        }
 
-        server = match server {
-            Some(rpc) => {
-                rpc.notify_scripthash_subscriptions(&headers_changed, txs_changed);
-                if let Some(header) = new_tip {
-                    rpc.notify_subscriptions_chaintip(header);
-                }
-                Some(rpc)
-            }
-            None => Some(Rpc::start(
-                config.electrum_rpc_addr,
-                query.clone(),
-                metrics.clone(),
-                relayfee,
-                connection_limits,
-                global_limits.clone(),
-                config.rpc_buffer_size,
-            )),
-        };
+        // Nothing could subscribe to a status before catching up, so the
+        // blocks indexed meanwhile are not looked at.
+        let headers_changed = if caught_up { &headers_changed[..] } else { &[] };
+        server.notify_scripthash_subscriptions(headers_changed, txs_changed);
+        if let Some(header) = new_tip {
+            server.notify_subscriptions_chaintip(header);
+        }
+        caught_up = true;
         if let Err(err) = signal.wait(config.wait_duration) {
             info!("stopping server: {}", err);
             break;
         }
     }
+    for listener in zmq_listeners {
+        listener.join().expect("ZMQ listener panicked");
+    }
     Ok(())
 }
 
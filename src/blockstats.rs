@@ -0,0 +1,324 @@
+use crate::errors::*;
+use crate::scripthash::{full_hash, FullHash};
+use crate::store::{ReadStore, Row};
+use crate::util::Bytes;
+use bitcoincash::blockdata::block::Block;
+use bitcoincash::blockdata::transaction::{OutPoint, Transaction};
+use bitcoincash::consensus::encode::VarInt;
+use bitcoincash::hash_types::BlockHash;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Block header size, plus the transaction count
+fn header_size(tx_count: usize) -> u64 {
+    80 + VarInt(tx_count as u64).len() as u64
+}
+
+/// Size statistics of an indexed block, stored when `index_block_stats` is
+/// enabled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlockStats {
+    pub size: u64,
+    pub weight: u64,
+    pub tx_count: u64,
+}
+
+impl BlockStats {
+    /// Stats of `block` without its transactions, see `add_tx`.
+    pub fn header_only(block: &Block) -> BlockStats {
+        let size = header_size(block.txdata.len());
+        BlockStats {
+            size,
+            weight: 4 * size,
+            tx_count: 0,
+        }
+    }
+
+    pub fn add_tx(&mut self, txn: &Transaction) {
+        self.size += txn.get_size() as u64;
+        self.weight += txn.get_weight() as u64;
+        self.tx_count += 1;
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "size": self.size,
+            "weight": self.weight,
+            "tx_count": self.tx_count,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct BlockStatsKey {
+    code: u8,
+    hash: FullHash,
+}
+
+/// Index row with the stats of a block, keyed by block hash.
+pub struct BlockStatsRow {
+    key: BlockStatsKey,
+    stats: BlockStats,
+}
+
+impl BlockStatsRow {
+    pub fn new(blockhash: &BlockHash, stats: BlockStats) -> BlockStatsRow {
+        BlockStatsRow {
+            key: BlockStatsKey {
+                code: b'S',
+                hash: full_hash(&blockhash[..]),
+            },
+            stats,
+        }
+    }
+
+    fn key(blockhash: &BlockHash) -> Bytes {
+        bincode::serialize(&BlockStatsKey {
+            code: b'S',
+            hash: full_hash(&blockhash[..]),
+        })
+        .unwrap()
+    }
+
+    pub fn to_row(&self) -> Row {
+        Row {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: bincode::serialize(&self.stats).unwrap(),
+        }
+    }
+}
+
+/// Stats of an indexed block, or None if the block was indexed without them.
+pub fn read_block_stats(
+    store: &dyn ReadStore,
+    blockhash: &BlockHash,
+) -> Result<Option<BlockStats>> {
+    let value = store.get(&BlockStatsRow::key(blockhash))?;
+    Ok(value.map(|value| bincode::deserialize(&value).expect("failed to parse BlockStats")))
+}
+
+/// Fee statistics of the non-coinbase transactions of an indexed block,
+/// stored when `index_block_fees` is enabled. Fee rates are in satoshis per
+/// 1000 bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlockFeeStats {
+    pub total_fee: u64,
+    pub total_size: u64,
+    pub min_feerate: u64,
+    pub median_feerate: u64,
+    pub max_feerate: u64,
+}
+
+impl BlockFeeStats {
+    /// Fee stats of `block`. Outputs spent within the block are taken from
+    /// it, the value of other spent outputs is looked up with `prevout_value`.
+    pub fn from_block<F>(block: &Block, mut prevout_value: F) -> Result<BlockFeeStats>
+    where
+        F: FnMut(&OutPoint) -> Result<u64>,
+    {
+        let block_outputs: HashMap<OutPoint, u64> = block
+            .txdata
+            .iter()
+            .flat_map(|tx| {
+                let txid = tx.txid();
+                tx.output
+                    .iter()
+                    .enumerate()
+                    .map(move |(vout, output)| (OutPoint::new(txid, vout as u32), output.value))
+            })
+            .collect();
+        let mut stats = BlockFeeStats::default();
+        let mut feerates = vec![];
+        for tx in block.txdata.iter().filter(|tx| !tx.is_coin_base()) {
+            let mut input_value = 0u64;
+            for txin in &tx.input {
+                let prevout = &txin.previous_output;
+                input_value += match block_outputs.get(prevout) {
+                    Some(value) => *value,
+                    None => prevout_value(prevout)?,
+                };
+            }
+            let output_value: u64 = tx.output.iter().map(|output| output.value).sum();
+            let fee = input_value
+                .checked_sub(output_value)
+                .chain_err(|| format!("tx {} spends more than its inputs", tx.txid()))?;
+            let size = tx.get_size() as u64;
+            stats.total_fee += fee;
+            stats.total_size += size;
+            feerates.push(fee * 1000 / size);
+        }
+        feerates.sort_unstable();
+        if let (Some(min), Some(max)) = (feerates.first(), feerates.last()) {
+            stats.min_feerate = *min;
+            stats.median_feerate = feerates[feerates.len() / 2];
+            stats.max_feerate = *max;
+        }
+        Ok(stats)
+    }
+
+    /// Totals in satoshis and bytes, fee rates in satoshis per byte
+    pub fn to_json(&self) -> Value {
+        let feerate = |feerate: u64| feerate as f64 / 1000.0;
+        let average = if self.total_size == 0 {
+            0.0
+        } else {
+            self.total_fee as f64 / self.total_size as f64
+        };
+        json!({
+            "total_fee": self.total_fee,
+            "total_size": self.total_size,
+            "average_feerate": average,
+            "min_feerate": feerate(self.min_feerate),
+            "median_feerate": feerate(self.median_feerate),
+            "max_feerate": feerate(self.max_feerate),
+        })
+    }
+}
+
+/// Index row with the fee stats of a block, keyed by block hash.
+pub struct BlockFeeStatsRow {
+    key: BlockStatsKey,
+    stats: BlockFeeStats,
+}
+
+impl BlockFeeStatsRow {
+    pub fn new(blockhash: &BlockHash, stats: BlockFeeStats) -> BlockFeeStatsRow {
+        BlockFeeStatsRow {
+            key: BlockStatsKey {
+                code: b'E',
+                hash: full_hash(&blockhash[..]),
+            },
+            stats,
+        }
+    }
+
+    fn key(blockhash: &BlockHash) -> Bytes {
+        bincode::serialize(&BlockStatsKey {
+            code: b'E',
+            hash: full_hash(&blockhash[..]),
+        })
+        .unwrap()
+    }
+
+    pub fn to_row(&self) -> Row {
+        Row {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: bincode::serialize(&self.stats).unwrap(),
+        }
+    }
+}
+
+/// Fee stats of an indexed block, or None if the block was indexed without
+/// them.
+pub fn read_block_fee_stats(
+    store: &dyn ReadStore,
+    blockhash: &BlockHash,
+) -> Result<Option<BlockFeeStats>> {
+    let value = store.get(&BlockFeeStatsRow::key(blockhash))?;
+    Ok(value.map(|value| bincode::deserialize(&value).expect("failed to parse BlockFeeStats")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::{FailingStore, MemStore};
+    use crate::store::WriteStore;
+    use bitcoincash::blockdata::block::BlockHeader;
+    use bitcoincash::blockdata::script::Script;
+    use bitcoincash::blockdata::transaction::{TxIn, TxOut};
+    use bitcoincash::hash_types::{TxMerkleNode, Txid};
+    use bitcoincash::hashes::Hash;
+
+    fn tx(inputs: Vec<OutPoint>, values: &[u64]) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: inputs
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    script_sig: Script::new(),
+                    sequence: 0xffffffff,
+                    witness: vec![],
+                })
+                .collect(),
+            output: values
+                .iter()
+                .map(|&value| TxOut {
+                    value,
+                    script_pubkey: Script::new(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_block_fee_stats() {
+        let external = OutPoint::new(Txid::from_slice(&[1; 32]).unwrap(), 3);
+        let coinbase = tx(vec![OutPoint::null()], &[625_001_500]);
+        // pays 1000 sat, spending an output of an earlier block
+        let first = tx(vec![external], &[6000, 3000]);
+        // pays 500 sat, spending an output of `first`
+        let second = tx(vec![OutPoint::new(first.txid(), 0)], &[5500]);
+        let block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: BlockHash::default(),
+                merkle_root: TxMerkleNode::default(),
+                time: 0,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata: vec![coinbase, first.clone(), second.clone()],
+        };
+
+        let mut lookups = vec![];
+        let stats = BlockFeeStats::from_block(&block, |prevout| {
+            lookups.push(*prevout);
+            Ok(10_000)
+        })
+        .unwrap();
+        assert_eq!(lookups, vec![external]);
+
+        let (first_size, second_size) = (first.get_size() as u64, second.get_size() as u64);
+        let first_rate = 1000 * 1000 / first_size;
+        let second_rate = 500 * 1000 / second_size;
+        assert!(second_rate < first_rate);
+        assert_eq!(
+            stats,
+            BlockFeeStats {
+                total_fee: 1500,
+                total_size: first_size + second_size,
+                min_feerate: second_rate,
+                median_feerate: first_rate,
+                max_feerate: first_rate,
+            }
+        );
+
+        // stored stats are read back by block hash
+        let store = MemStore::default();
+        let blockhash = block.block_hash();
+        assert_eq!(read_block_fee_stats(&store, &blockhash).unwrap(), None);
+        store
+            .write(
+                vec![BlockFeeStatsRow::new(&blockhash, stats).to_row()],
+                false,
+            )
+            .unwrap();
+        assert_eq!(
+            read_block_fee_stats(&store, &blockhash).unwrap(),
+            Some(stats)
+        );
+        assert_eq!(read_block_stats(&store, &blockhash).unwrap(), None);
+
+        // a failing store is reported as an error
+        assert!(read_block_fee_stats(&FailingStore, &blockhash).is_err());
+
+        // spending more than the inputs is an error
+        let block = Block {
+            txdata: vec![tx(vec![external], &[20_000])],
+            ..block
+        };
+        assert!(BlockFeeStats::from_block(&block, |_| Ok(10_000)).is_err());
+    }
+}
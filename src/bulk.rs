@@ -14,7 +14,9 @@ use std::thread;
 use crate::cashaccount::CashAccountParser;
 use crate::daemon::Daemon;
 use crate::errors::*;
-use crate::index::{index_block, last_indexed_block, read_indexed_blockhashes};
+use crate::index::{
+    block_header_row, index_block, last_indexed_block, read_indexed_blockhashes, IndexOptions,
+};
 use crate::metrics::Metrics;
 use crate::signal::Waiter;
 use crate::store::{DbStore, Row, WriteStore};
@@ -25,6 +27,8 @@ struct Parser {
     current_headers: HeaderList,
     indexed_blockhashes: Mutex<HashSet<BlockHash>>,
     cashaccount_activation_height: u32,
+    index_options: IndexOptions,
+    index_start_height: usize,
     // metrics
     duration: prometheus::HistogramVec,
     block_count: prometheus::IntCounterVec,
@@ -37,12 +41,16 @@ impl Parser {
         metrics: &Metrics,
         indexed_blockhashes: HashSet<BlockHash>,
         cashaccount_activation_height: u32,
+        index_options: IndexOptions,
+        index_start_height: usize,
     ) -> Result<Arc<Parser>> {
         Ok(Arc::new(Parser {
             magic: daemon.disk_magic(),
             current_headers: load_headers(daemon)?,
             indexed_blockhashes: Mutex::new(indexed_blockhashes),
             cashaccount_activation_height,
+            index_options,
+            index_start_height,
             duration: metrics.histogram_vec(
                 prometheus::HistogramOpts::new(
                     "electrscash_parse_duration",
@@ -103,7 +111,17 @@ impl Parser {
                     .expect("indexed_blockhashes")
                     .insert(blockhash)
                 {
-                    rows.extend(index_block(&block, header.height(), &cashaccount));
+                    if header.height() < self.index_start_height {
+                        rows.push(block_header_row(&block.header));
+                        self.block_count.with_label_values(&["header_only"]).inc();
+                        continue;
+                    }
+                    rows.extend(index_block(
+                        &block,
+                        header.height(),
+                        &cashaccount,
+                        self.index_options,
+                    ));
                     self.block_count.with_label_values(&["indexed"]).inc();
                 } else {
                     self.block_count.with_label_values(&["duplicate"]).inc();
@@ -228,6 +246,7 @@ fn start_indexer(
     })
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn index_blk_files(
     daemon: &Daemon,
     index_threads: usize,
@@ -235,6 +254,8 @@ pub fn index_blk_files(
     signal: &Waiter,
     store: DbStore,
     cashaccount_activation_height: u32,
+    index_options: IndexOptions,
+    index_start_height: usize,
 ) -> Result<DbStore> {
     set_open_files_limit(2048); // twice the default `ulimit -n` value
     let blk_files = daemon.list_blk_files()?;
@@ -246,6 +267,8 @@ pub fn index_blk_files(
         metrics,
         indexed_blockhashes,
         cashaccount_activation_height,
+        index_options,
+        index_start_height,
     )?;
     let (blobs, reader) = start_reader(blk_files, parser.clone());
     let rows_chan = SyncChannel::new(0);
@@ -256,7 +279,7 @@ pub fn index_blk_files(
 
     for (rows, path) in rows_chan.into_receiver() {
         trace!("indexed {:?}: {} rows", path, rows.len());
-        store.write(rows, false);
+        store.write(rows, false)?;
         signal
             .poll()
             .chain_err(|| "stopping bulk indexing due to signal")?;
@@ -271,7 +294,7 @@ pub fn index_blk_files(
             .expect("indexer panicked")
             .expect("indexing failed")
     });
-    store.write(vec![parser.last_indexed_row()], true);
+    store.write(vec![parser.last_indexed_row()], true)?;
     Ok(store)
 }
 
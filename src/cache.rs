@@ -1,6 +1,7 @@
 use crate::errors::*;
 use crate::metrics::Metrics;
-use crate::rndcache::RndCache;
+use crate::rndcache::{RndCache, Watermarks};
+use crate::scripthash::FullHash;
 
 use bitcoincash::blockdata::transaction::Transaction;
 use bitcoincash::consensus::encode::deserialize;
@@ -12,7 +13,7 @@ pub struct BlockTxIDsCache {
 }
 
 impl BlockTxIDsCache {
-    pub fn new(bytes_capacity: u64, metrics: &Metrics) -> BlockTxIDsCache {
+    pub fn new(bytes_capacity: u64, watermarks: Watermarks, metrics: &Metrics) -> BlockTxIDsCache {
         let lookups = metrics.counter_int_vec(
             prometheus::Opts::new(
                 "electrscash_cache_blocktxids_lookups",
@@ -23,7 +24,7 @@ impl BlockTxIDsCache {
         let churn = metrics.counter_int_vec(
             prometheus::Opts::new(
                 "electrscash_cache_blocktxids_churn",
-                "# of insertions and evictions from the blocktxids cache",
+                "# of insertions, evictions and too large entries rejected from the blocktxids cache",
             ),
             &["type"],
         );
@@ -36,7 +37,14 @@ impl BlockTxIDsCache {
             "# of entries in the blockstxid cache",
         ));
         BlockTxIDsCache {
-            map: Mutex::new(RndCache::new(bytes_capacity, lookups, churn, size, entries)),
+            map: Mutex::new(RndCache::new(
+                bytes_capacity,
+                watermarks,
+                lookups,
+                churn,
+                size,
+                entries,
+            )),
         }
     }
 
@@ -49,15 +57,25 @@ impl BlockTxIDsCache {
         }
 
         let txids = load_txids_func()?;
-        let mut cache_copy = txids.clone();
-        cache_copy.shrink_to_fit();
-        let size = cache_copy.capacity();
-        self.map
-            .lock()
-            .unwrap()
-            .put(*blockhash, cache_copy, size as u64);
+        self.put(blockhash, txids.clone());
         Ok(txids)
     }
+
+    pub fn put(&self, blockhash: &BlockHash, mut txids: Vec<Txid>) {
+        txids.shrink_to_fit();
+        let size = txids.capacity();
+        self.map.lock().unwrap().put(*blockhash, txids, size as u64);
+    }
+
+    pub fn contains(&self, blockhash: &BlockHash) -> bool {
+        self.map.lock().unwrap().get(blockhash).is_some()
+    }
+
+    /// Bytes used and bytes capacity of the cache
+    pub fn usage(&self) -> (u64, u64) {
+        let map = self.map.lock().unwrap();
+        (map.usage(), map.capacity())
+    }
 }
 
 pub struct TransactionCache {
@@ -66,7 +84,7 @@ pub struct TransactionCache {
 }
 
 impl TransactionCache {
-    pub fn new(bytes_capacity: u64, metrics: &Metrics) -> TransactionCache {
+    pub fn new(bytes_capacity: u64, watermarks: Watermarks, metrics: &Metrics) -> TransactionCache {
         let lookups = metrics.counter_int_vec(
             prometheus::Opts::new(
                 "electrscash_cache_tx_lookups",
@@ -77,7 +95,7 @@ impl TransactionCache {
         let churn = metrics.counter_int_vec(
             prometheus::Opts::new(
                 "electrscash_cache_tx_churn",
-                "# of insertions and evictions from the transaction cache",
+                "# of insertions, evictions and too large entries rejected from the transaction cache",
             ),
             &["type"],
         );
@@ -90,7 +108,14 @@ impl TransactionCache {
             "# of entries in the transaction cache",
         ));
         TransactionCache {
-            map: RwLock::new(RndCache::new(bytes_capacity, lookups, churn, size, entries)),
+            map: RwLock::new(RndCache::new(
+                bytes_capacity,
+                watermarks,
+                lookups,
+                churn,
+                size,
+                entries,
+            )),
         }
     }
 
@@ -105,6 +130,11 @@ impl TransactionCache {
         None
     }
 
+    /// Serialized transaction, without parsing it.
+    pub fn get_raw(&self, txid: &Txid) -> Option<Vec<u8>> {
+        self.map.read().unwrap().get(txid).cloned()
+    }
+
     pub fn put(&self, txid: &Txid, mut serialized_tx: Vec<u8>) {
         serialized_tx.shrink_to_fit();
         let size = serialized_tx.capacity();
@@ -113,4 +143,108 @@ impl TransactionCache {
             .unwrap()
             .put(*txid, serialized_tx, size as u64);
     }
+
+    /// Bytes used and bytes capacity of the cache
+    pub fn usage(&self) -> (u64, u64) {
+        let map = self.map.read().unwrap();
+        (map.usage(), map.capacity())
+    }
+}
+
+/// Scripthashes of addresses, as wallets polling the `blockchain.address.*`
+/// methods convert the same addresses over and over.
+pub struct AddressCache {
+    map: Mutex<RndCache<String, FullHash>>,
+}
+
+impl AddressCache {
+    pub fn new(bytes_capacity: u64, watermarks: Watermarks, metrics: &Metrics) -> AddressCache {
+        let lookups = metrics.counter_int_vec(
+            prometheus::Opts::new(
+                "electrscash_cache_address_lookups",
+                "# of cache lookups in the address cache",
+            ),
+            &["type"],
+        );
+        let churn = metrics.counter_int_vec(
+            prometheus::Opts::new(
+                "electrscash_cache_address_churn",
+                "# of insertions, evictions and too large entries rejected from the address cache",
+            ),
+            &["type"],
+        );
+        let size = metrics.gauge_int(prometheus::Opts::new(
+            "electrscash_cache_address_size",
+            "Size of the address cache [bytes]",
+        ));
+        let entries = metrics.gauge_int(prometheus::Opts::new(
+            "electrscash_cache_address_entries",
+            "# of entries in the address cache",
+        ));
+        AddressCache {
+            map: Mutex::new(RndCache::new(
+                bytes_capacity,
+                watermarks,
+                lookups,
+                churn,
+                size,
+                entries,
+            )),
+        }
+    }
+
+    /// Scripthash of `addr`, converted by `convert_func` if not cached.
+    /// Failed conversions are not cached.
+    pub fn get_or_else<F>(&self, addr: &str, convert_func: F) -> Result<FullHash>
+    where
+        F: FnOnce(&str) -> Result<FullHash>,
+    {
+        if let Some(scripthash) = self.map.lock().unwrap().get(addr) {
+            return Ok(*scripthash);
+        }
+
+        let scripthash = convert_func(addr)?;
+        let size = addr.len() + std::mem::size_of::<FullHash>();
+        self.map
+            .lock()
+            .unwrap()
+            .put(addr.to_string(), scripthash, size as u64);
+        Ok(scripthash)
+    }
+
+    /// Bytes used and bytes capacity of the cache
+    pub fn usage(&self) -> (u64, u64) {
+        let map = self.map.lock().unwrap();
+        (map.usage(), map.capacity())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scripthash::addr_to_scripthash;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_address_cache() {
+        let cache = AddressCache::new(1 << 20, Watermarks::default(), &Metrics::dummy());
+        let conversions = Cell::new(0);
+        let convert = |addr: &str| {
+            conversions.set(conversions.get() + 1);
+            addr_to_scripthash(addr)
+        };
+        let addr = "bitcoincash:qp3wjpa3tjlj042z2wv7hahsldgwhwy0rq9sywjpyy";
+        let expected = addr_to_scripthash(addr).unwrap();
+        for _ in 0..3 {
+            assert_eq!(cache.get_or_else(addr, convert).unwrap(), expected);
+        }
+        assert_eq!(conversions.get(), 1);
+
+        // invalid addresses fail every time
+        for _ in 0..2 {
+            assert!(cache.get_or_else("garbage", convert).is_err());
+        }
+        assert_eq!(conversions.get(), 3);
+        assert!(cache.usage().0 > 0);
+    }
 }
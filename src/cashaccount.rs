@@ -1,3 +1,4 @@
+use crate::errors::*;
 use crate::mempool::MEMPOOL_HEIGHT;
 use crate::scripthash::FullHash;
 use crate::store::ReadStore;
@@ -13,18 +14,21 @@ use cashaccount_sys::{
 };
 use sha2::{Digest, Sha256};
 use std::ffi::CStr;
+use std::ops::RangeInclusive;
 
-fn compute_accountname_hash(accountname: &[u8], blockheight: u32) -> FullHash {
+fn compute_accountname_hash(accountname: &[u8]) -> FullHash {
     let mut sha2 = Sha256::new();
     sha2.update(accountname);
-    sha2.update(&blockheight.to_be_bytes());
     sha2.finalize().into()
 }
 
+/// Registrations are keyed by account name, followed by the registration
+/// height (big endian, so that rows of a name are ordered by height).
 #[derive(Serialize, Deserialize)]
 pub struct TxCashAccountKey {
     code: u8,
     accout_hash_prefix: HashPrefix,
+    blockheight: [u8; 4],
 }
 
 #[derive(Serialize, Deserialize)]
@@ -38,10 +42,8 @@ impl TxCashAccountRow {
         TxCashAccountRow {
             key: TxCashAccountKey {
                 code: b'C',
-                accout_hash_prefix: hash_prefix(&compute_accountname_hash(
-                    accountname,
-                    blockheight,
-                )),
+                accout_hash_prefix: hash_prefix(&compute_accountname_hash(accountname)),
+                blockheight: blockheight.to_be_bytes(),
             },
             txid_prefix: hash_prefix(&txid[..]),
         }
@@ -50,11 +52,16 @@ impl TxCashAccountRow {
     pub fn filter(accountname: &[u8], blockheight: u32) -> Bytes {
         bincode::serialize(&TxCashAccountKey {
             code: b'C',
-            accout_hash_prefix: hash_prefix(&compute_accountname_hash(accountname, blockheight)),
+            accout_hash_prefix: hash_prefix(&compute_accountname_hash(accountname)),
+            blockheight: blockheight.to_be_bytes(),
         })
         .unwrap()
     }
 
+    pub fn blockheight(&self) -> u32 {
+        u32::from_be_bytes(self.key.blockheight)
+    }
+
     pub fn to_row(&self) -> Row {
         Row {
             key: bincode::serialize(&self).unwrap(),
@@ -78,6 +85,36 @@ pub fn txids_by_cashaccount(store: &dyn ReadStore, name: &str, height: u32) -> V
         .collect()
 }
 
+/// Registrations of `name` within `heights`, as (height, txid prefix) pairs
+/// ordered by height. Fails if there are more than `limit` registrations.
+/// Only the rows within `heights` are scanned, and at most `limit` + 1 of them.
+pub fn txids_by_cashaccount_range(
+    store: &dyn ReadStore,
+    name: &str,
+    heights: RangeInclusive<u32>,
+    limit: usize,
+) -> Result<Vec<(u32, HashPrefix)>> {
+    let name_lower = name.to_ascii_lowercase();
+    let start = TxCashAccountRow::filter(name_lower.as_bytes(), *heights.start());
+    let last = TxCashAccountRow::filter(name_lower.as_bytes(), *heights.end());
+    let registrations: Vec<(u32, HashPrefix)> = store
+        .scan_range(&start, &last, limit.saturating_add(1))
+        .iter()
+        .map(TxCashAccountRow::from_row)
+        .map(|row| (row.blockheight(), row.txid_prefix))
+        .collect();
+    if registrations.len() > limit {
+        bail!(ErrorKind::RpcError(
+            RpcErrorCode::Other,
+            format!(
+                "too many registrations of {} (max {}), narrow the height range",
+                name, limit
+            )
+        ));
+    }
+    Ok(registrations)
+}
+
 fn parse_cashaccount(account: *mut CashAccount, txn: &Transaction) -> bool {
     let mut opreturn_found = false;
     let mut cashaccount_found = false;
@@ -167,6 +204,72 @@ impl Drop for CashAccountParser {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::fake::MemStore;
+    use crate::store::WriteStore;
+    use bitcoincash::consensus::encode::deserialize;
+    use bitcoincash::hashes::Hash;
+
+    const ACTIVATION_HEIGHT: u32 = 563620;
+
+    /// Registration of dagur#216
+    fn dagur_registration() -> Transaction {
+        deserialize(&hex::decode("0100000001bca903bbc429218234857628b382e8aa8e3bfa74c5b59628ad053284e50bf6ac010000006b4830450221009bbd0a96ef5ef33e09c4fce7fafd2add714ebe05d87a9cb6c826b863d0e99225022039d77b8bd9c8067636e64d6f1aeeeeb8b816bbc875afd04cef9eb299df83b7d64121037a291b1a7f21b03b2a5120434b7a06b61944e0edc1337c76d737d0b5fa1c871fffffffff020000000000000000226a040101010105646167757215018c092ec2cbd842e89432c7c53b54db3a958c83a575f00d00000000001976a914dfdd3e914d73fee85ad40cd71430327f0404c15488ac00000000").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_txids_by_cashaccount_range() {
+        let tx = dagur_registration();
+        let height = ACTIVATION_HEIGHT + 216;
+        let parser = CashAccountParser::new(Some(ACTIVATION_HEIGHT));
+        assert!(parser.has_cashaccount(&tx, "Dagur"));
+
+        let store = MemStore::default();
+        store
+            .write(parser.index_cashaccount(&tx, height), false)
+            .unwrap();
+        // Another registration of the same name, and of another name
+        let other = Txid::from_slice(&[1; 32]).unwrap();
+        store
+            .write(
+                vec![
+                    TxCashAccountRow::new(&other, b"dagur", height + 1000).to_row(),
+                    TxCashAccountRow::new(&other, b"someone", height).to_row(),
+                ],
+                false,
+            )
+            .unwrap();
+
+        let txid_prefix = hash_prefix(&tx.txid()[..]);
+        assert_eq!(
+            txids_by_cashaccount(&store, "dagur", height),
+            vec![txid_prefix]
+        );
+
+        // Found without knowing the exact height
+        assert_eq!(
+            txids_by_cashaccount_range(&store, "DAGUR", ACTIVATION_HEIGHT..=height + 10, 10)
+                .unwrap(),
+            vec![(height, txid_prefix)]
+        );
+        assert_eq!(
+            txids_by_cashaccount_range(&store, "dagur", 0..=u32::MAX, 10).unwrap(),
+            vec![
+                (height, txid_prefix),
+                (height + 1000, hash_prefix(&other[..]))
+            ]
+        );
+        assert!(
+            txids_by_cashaccount_range(&store, "dagur", height + 1..=height + 999, 10)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(txids_by_cashaccount_range(&store, "dagur", 0..=u32::MAX, 1).is_err());
+        // The limit applies to the registrations within the range only
+        assert_eq!(
+            txids_by_cashaccount_range(&store, "dagur", height + 1000..=u32::MAX, 1).unwrap(),
+            vec![(height + 1000, hash_prefix(&other[..]))]
+        );
+    }
 
     #[test]
     fn test_is_valid_cashaccount_height() {
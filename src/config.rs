@@ -12,7 +12,12 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use crate::daemon::CookieGetter;
+use crate::doslimit::ConnectionLimits;
 use crate::errors::*;
+use crate::index::{IndexOptions, OutputFilter};
+use crate::query::QueryOptions;
+use crate::rndcache::Watermarks;
+use crate::wstcp::util::AllowedOrigins;
 
 // by default, serve on all IPv4 interfaces
 const DEFAULT_BIND_ADDRESS: [u8; 4] = [0, 0, 0, 0];
@@ -132,25 +137,94 @@ pub struct Config {
     pub daemon_dir: PathBuf,
     pub blocks_dir: PathBuf,
     pub daemon_rpc_addr: SocketAddr,
+    pub daemon_rpc_fallback_addrs: Vec<SocketAddr>,
+    pub daemon_zmq_hashblock_addr: Option<SocketAddr>,
+    pub daemon_zmq_hashtx_addr: Option<SocketAddr>,
+    pub daemon_retry_limit: usize,
+    pub daemon_rpc_timeout: Option<Duration>,
     pub electrum_rpc_addr: SocketAddr,
+    pub electrum_rpc_unix_socket: Option<PathBuf>,
     pub electrum_ws_addr: SocketAddr,
+    pub electrum_ws_allowed_origins: AllowedOrigins,
+    pub electrum_ws_max_frame_size: usize,
     pub monitoring_addr: SocketAddr,
+    pub monitoring_auth_token: Option<String>,
     pub jsonrpc_import: bool,
     pub wait_duration: Duration,
+    pub relayfee_refresh_interval: Duration,
+    pub daemon_warnings_refresh_interval: Duration,
+    pub min_relay_fee_override: Option<f64>,
     pub index_batch_size: usize,
+    pub index_fetch_threads: usize,
+    pub index_checkpoint_interval: usize,
     pub bulk_index_threads: usize,
     pub tx_cache_size: usize,
     pub server_banner: String,
     pub blocktxids_cache_size: usize,
+    pub blocktxids_warmup_blocks: usize,
+    pub cache_watermarks: Watermarks,
+    pub txheight_cache_size: usize,
+    pub address_cache_size: usize,
+    pub merkle_cache_size: usize,
+    pub txid_limit: usize,
+    pub query_threads: usize,
+    pub query_queue_size: usize,
     pub cookie_getter: Arc<dyn CookieGetter>,
     pub rpc_timeout: u16,
     pub low_memory: bool,
+    pub reindex_incompatible_db: bool,
+    pub db_compact_at_tip: bool,
+    pub db_compact_interval: Option<Duration>,
+    pub header_window: Option<usize>,
+    pub live_index_wal: bool,
     pub cashaccount_activation_height: u32,
+    pub index_opreturn: bool,
+    pub index_block_stats: bool,
+    pub index_first_use: bool,
+    pub index_block_fees: bool,
+    pub index_max_script_size: usize,
+    pub index_hash_oversized_scripts: bool,
+    pub index_min_output_value: u64,
+    pub index_start_height: usize,
     pub rpc_buffer_size: usize,
+    pub rpc_max_dropped_notifications: usize,
+    pub rpc_detect_ssl: bool,
+    pub rpc_method_hints: bool,
+    pub rpc_compression_threshold: usize,
+    pub rpc_listen_backlog: i32,
+    pub rpc_read_buffer_size: usize,
+    pub rpc_tcp_keepalive: Option<Duration>,
+    pub rpc_read_timeout: Option<Duration>,
+    pub rpc_idle_timeout: Option<Duration>,
+    pub rpc_session_grace: Option<Duration>,
+    pub rpc_max_sessions_per_prefix: usize,
     pub scripthash_subscription_limit: u32,
     pub scripthash_alias_bytes_limit: u32,
+    pub scripthash_subscription_bytes_limit: u32,
+    pub rpc_max_batch_size: u32,
+    pub rpc_max_tx_size: u32,
+    pub rpc_max_headers: u32,
+    pub rpc_max_tx_info_items: u32,
+    pub rpc_max_tx_info_prevouts: u32,
+    pub rpc_max_prevout_txs: u32,
+    pub rpc_max_utxo_proofs: u32,
+    pub rpc_max_cashaccount_registrations: u32,
+    pub rpc_max_opreturn_txs: u32,
+    pub rpc_max_cp_height: usize,
+    pub rpc_max_first_use_txs: usize,
+    pub rpc_max_mempool_txids: usize,
+    pub history_block_order_max_blocks: usize,
+    pub rpc_history_page_size: usize,
+    pub mempool_histogram_threshold_vbytes: u64,
+    pub mempool_histogram_max_age: Option<Duration>,
     pub rpc_max_connections: u32,
+    pub rpc_max_peer_threads: usize,
     pub rpc_max_connections_shared_prefix: u32,
+    pub rpc_max_concurrent_requests_per_ip: u32,
+    pub scripthash_subscription_limit_total: u32,
+    pub rpc_exempt_loopback: bool,
+    pub rpc_connections_ipv4_prefix_len: u8,
+    pub rpc_connections_ipv6_prefix_len: u8,
 }
 
 /// Returns default daemon directory
@@ -207,6 +281,15 @@ fn select_auth(auth: Option<String>, cookie: Option<String>) -> Option<String> {
     }
 }
 
+/// Duration in seconds, where 0 means disabled.
+fn optional_secs(secs: u64) -> Option<Duration> {
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    }
+}
+
 impl Config {
     /// Parses args, env vars, config files and post-processes them
     pub fn from_args() -> Config {
@@ -270,10 +353,35 @@ impl Config {
             (DEFAULT_SERVER_ADDRESS, default_daemon_port).into(),
             ResolvAddr::resolve_or_exit,
         );
+        let daemon_rpc_fallback_addrs: Vec<SocketAddr> = config
+            .daemon_rpc_fallback_addrs
+            .as_deref()
+            .unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| ResolvAddr(addr.to_owned()).resolve_or_exit())
+            .collect();
+        let daemon_zmq_hashblock_addr = config
+            .daemon_zmq_hashblock_addr
+            .map(ResolvAddr::resolve_or_exit);
+        let daemon_zmq_hashtx_addr = config
+            .daemon_zmq_hashtx_addr
+            .map(ResolvAddr::resolve_or_exit);
         let electrum_rpc_addr: SocketAddr = config.electrum_rpc_addr.map_or(
             (DEFAULT_BIND_ADDRESS, default_electrum_port).into(),
             ResolvAddr::resolve_or_exit,
         );
+        if let Some(fee) = config.min_relay_fee_override {
+            if !(fee.is_finite() && fee >= 0.0) {
+                eprintln!("Error: min_relay_fee_override must be a non-negative number");
+                std::process::exit(1);
+            }
+        }
+        if cfg!(not(unix)) && config.electrum_rpc_unix_socket.is_some() {
+            eprintln!("Error: electrum_rpc_unix_socket is only supported on Unix");
+            std::process::exit(1);
+        }
         let electrum_ws_addr: SocketAddr = config.electrum_ws_addr.map_or(
             (DEFAULT_BIND_ADDRESS, default_ws_port).into(),
             ResolvAddr::resolve_or_exit,
@@ -296,6 +404,18 @@ impl Config {
             .blocks_dir
             .unwrap_or_else(|| default_blocks_dir(daemon_dir));
 
+        if config.rpc_connections_ipv4_prefix_len > 32
+            || config.rpc_connections_ipv6_prefix_len > 128
+        {
+            eprintln!("Error: rpc_connections_ipv4_prefix_len must be at most 32 and rpc_connections_ipv6_prefix_len at most 128");
+            std::process::exit(1);
+        }
+        let cache_watermarks =
+            Watermarks::new(config.cache_high_watermark, config.cache_low_watermark)
+                .unwrap_or_else(|e| {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1)
+                });
         let auth = select_auth(config.auth, config.cookie);
         let cookie_getter = create_cookie_getter(auth, config.cookie_file, daemon_dir);
 
@@ -320,6 +440,10 @@ impl Config {
         if config.bulk_index_threads == 0 {
             config.bulk_index_threads = num_cpus::get();
         }
+        if config.index_checkpoint_interval == 0 {
+            eprintln!("Error: index_checkpoint_interval must be at least 1");
+            std::process::exit(1);
+        }
         const MB: f32 = (1 << 20) as f32;
         let config = Config {
             log,
@@ -328,33 +452,167 @@ impl Config {
             daemon_dir: config.daemon_dir,
             blocks_dir,
             daemon_rpc_addr,
+            daemon_rpc_fallback_addrs,
+            daemon_zmq_hashblock_addr,
+            daemon_zmq_hashtx_addr,
+            daemon_retry_limit: config.daemon_retry_limit,
+            daemon_rpc_timeout: optional_secs(config.daemon_rpc_timeout_secs),
             electrum_rpc_addr,
+            electrum_rpc_unix_socket: config.electrum_rpc_unix_socket,
             electrum_ws_addr,
+            electrum_ws_allowed_origins: AllowedOrigins::parse(&config.electrum_ws_allowed_origins),
+            electrum_ws_max_frame_size: config.electrum_ws_max_frame_size,
             monitoring_addr,
+            monitoring_auth_token: config.monitoring_auth_token,
             jsonrpc_import: config.jsonrpc_import,
             wait_duration: Duration::from_secs(config.wait_duration_secs),
+            relayfee_refresh_interval: Duration::from_secs(config.relayfee_refresh_secs),
+            daemon_warnings_refresh_interval: Duration::from_secs(
+                config.daemon_warnings_refresh_secs,
+            ),
+            min_relay_fee_override: config.min_relay_fee_override,
             index_batch_size: config.index_batch_size,
+            index_fetch_threads: config.index_fetch_threads,
+            index_checkpoint_interval: config.index_checkpoint_interval,
             bulk_index_threads: config.bulk_index_threads,
             tx_cache_size: (config.tx_cache_size_mb * MB) as usize,
             blocktxids_cache_size: (config.blocktxids_cache_size_mb * MB) as usize,
+            blocktxids_warmup_blocks: config.blocktxids_warmup_blocks,
+            cache_watermarks,
+            txheight_cache_size: config.txheight_cache_size,
+            address_cache_size: (config.address_cache_size_mb * MB) as usize,
+            merkle_cache_size: config.merkle_cache_size,
+            txid_limit: config.txid_limit,
+            query_threads: config.query_threads,
+            query_queue_size: config.query_queue_size,
             server_banner: config.server_banner,
             cookie_getter,
             rpc_timeout: config.rpc_timeout as u16,
             low_memory: config.low_memory,
+            reindex_incompatible_db: config.reindex_incompatible_db,
+            db_compact_at_tip: config.db_compact_at_tip,
+            db_compact_interval: optional_secs(config.db_compact_interval_secs),
+            header_window: if config.low_memory && config.low_memory_header_window > 0 {
+                Some(config.low_memory_header_window)
+            } else {
+                None
+            },
+            live_index_wal: config.live_index_wal,
             cashaccount_activation_height: config.cashaccount_activation_height as u32,
+            index_opreturn: config.index_opreturn,
+            index_block_stats: config.index_block_stats,
+            index_first_use: config.index_first_use,
+            index_block_fees: config.index_block_fees,
+            index_max_script_size: config.index_max_script_size,
+            index_hash_oversized_scripts: config.index_hash_oversized_scripts,
+            index_min_output_value: config.index_min_output_value,
+            index_start_height: config.index_start_height,
             rpc_buffer_size: config.rpc_buffer_size,
+            rpc_max_dropped_notifications: config.rpc_max_dropped_notifications,
+            rpc_detect_ssl: config.rpc_detect_ssl,
+            rpc_method_hints: config.rpc_method_hints,
+            rpc_compression_threshold: config.rpc_compression_threshold,
+            rpc_listen_backlog: config.rpc_listen_backlog.min(i32::MAX as u32) as i32,
+            rpc_read_buffer_size: config.rpc_read_buffer_size.max(1),
+            rpc_tcp_keepalive: optional_secs(config.rpc_tcp_keepalive_secs),
+            rpc_read_timeout: optional_secs(config.rpc_read_timeout_secs),
+            rpc_idle_timeout: optional_secs(config.rpc_idle_timeout_secs),
+            rpc_session_grace: optional_secs(config.rpc_session_grace_secs),
+            rpc_max_sessions_per_prefix: config.rpc_max_sessions_per_prefix,
             scripthash_subscription_limit: config.scripthash_subscription_limit,
             scripthash_alias_bytes_limit: config.scripthash_alias_bytes_limit,
+            scripthash_subscription_bytes_limit: config.scripthash_subscription_bytes_limit,
+            rpc_max_batch_size: config.rpc_max_batch_size,
+            rpc_max_tx_size: config.rpc_max_tx_size,
+            rpc_max_headers: config.rpc_max_headers,
+            rpc_max_tx_info_items: config.rpc_max_tx_info_items,
+            rpc_max_tx_info_prevouts: config.rpc_max_tx_info_prevouts,
+            rpc_max_prevout_txs: config.rpc_max_prevout_txs,
+            rpc_max_utxo_proofs: config.rpc_max_utxo_proofs,
+            rpc_max_cashaccount_registrations: config.rpc_max_cashaccount_registrations,
+            rpc_max_opreturn_txs: config.rpc_max_opreturn_txs,
+            rpc_max_cp_height: config.rpc_max_cp_height,
+            rpc_max_first_use_txs: config.rpc_max_first_use_txs,
+            rpc_max_mempool_txids: config.rpc_max_mempool_txids.max(1),
+            history_block_order_max_blocks: config.history_block_order_max_blocks,
+            rpc_history_page_size: config.rpc_history_page_size.max(1),
+            mempool_histogram_threshold_vbytes: config.mempool_histogram_threshold_vbytes,
+            mempool_histogram_max_age: optional_secs(config.mempool_histogram_max_age_secs),
             rpc_max_connections: config.rpc_max_connections,
+            rpc_max_peer_threads: config.rpc_max_peer_threads,
             rpc_max_connections_shared_prefix: config.rpc_max_connections_shared_prefix,
+            rpc_max_concurrent_requests_per_ip: config.rpc_max_concurrent_requests_per_ip,
+            scripthash_subscription_limit_total: config.scripthash_subscription_limit_total,
+            rpc_exempt_loopback: config.rpc_exempt_loopback,
+            rpc_connections_ipv4_prefix_len: config.rpc_connections_ipv4_prefix_len,
+            rpc_connections_ipv6_prefix_len: config.rpc_connections_ipv6_prefix_len,
         };
         eprintln!("{:?}", config);
         config
     }
 
+    /// Limits of each RPC connection
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            rpc_timeout: self.rpc_timeout,
+            max_subscriptions: self.scripthash_subscription_limit,
+            max_alias_bytes: self.scripthash_alias_bytes_limit,
+            max_batch_size: self.rpc_max_batch_size,
+            max_subscription_bytes: self.scripthash_subscription_bytes_limit,
+            max_tx_size: self.rpc_max_tx_size,
+            idle_timeout: self.rpc_idle_timeout,
+            max_headers: self.rpc_max_headers,
+            max_tx_info_items: self.rpc_max_tx_info_items,
+            max_tx_info_prevouts: self.rpc_max_tx_info_prevouts,
+            max_prevout_txs: self.rpc_max_prevout_txs,
+            max_utxo_proofs: self.rpc_max_utxo_proofs,
+            max_cashaccount_registrations: self.rpc_max_cashaccount_registrations,
+            max_opreturn_txs: self.rpc_max_opreturn_txs,
+        }
+    }
+
+    /// Options changing which rows are indexed
+    pub fn index_options(&self) -> IndexOptions {
+        IndexOptions {
+            opreturn: self.index_opreturn,
+            first_use: self.index_first_use,
+            output_filter: OutputFilter {
+                max_script_size: self.index_max_script_size,
+                hash_oversized: self.index_hash_oversized_scripts,
+                min_value: self.index_min_output_value,
+            },
+            block_stats: self.index_block_stats,
+            block_fees: self.index_block_fees,
+        }
+    }
+
+    /// Options of the queries behind the RPC methods
+    pub fn query_options(&self) -> QueryOptions {
+        QueryOptions {
+            txheight_cache_size: self.txheight_cache_size,
+            txid_limit: self.txid_limit,
+            network: self.network_type,
+            max_cp_height: self.rpc_max_cp_height,
+            mempool_histogram_threshold: self.mempool_histogram_threshold_vbytes,
+            mempool_histogram_max_age: self.mempool_histogram_max_age,
+            max_first_use_txs: self.rpc_max_first_use_txs,
+            max_mempool_txids: self.rpc_max_mempool_txids,
+            history_block_order_max_blocks: self.history_block_order_max_blocks,
+            history_page_size: self.rpc_history_page_size,
+            merkle_cache_size: self.merkle_cache_size,
+        }
+    }
+
     pub fn cookie_getter(&self) -> Arc<dyn CookieGetter> {
         Arc::clone(&self.cookie_getter)
     }
+
+    /// Daemon JSONRPC addresses, the primary one first.
+    pub fn daemon_rpc_addrs(&self) -> Vec<SocketAddr> {
+        std::iter::once(self.daemon_rpc_addr)
+            .chain(self.daemon_rpc_fallback_addrs.iter().cloned())
+            .collect()
+    }
 }
 
 // CookieGetter + Debug isn't implemented in Rust, so we have to skip cookie_getter
@@ -379,23 +637,91 @@ debug_struct! { Config,
     daemon_dir,
     blocks_dir,
     daemon_rpc_addr,
+    daemon_rpc_fallback_addrs,
+    daemon_zmq_hashblock_addr,
+    daemon_zmq_hashtx_addr,
+    daemon_retry_limit,
+    daemon_rpc_timeout,
     electrum_rpc_addr,
+    electrum_rpc_unix_socket,
     electrum_ws_addr,
+    electrum_ws_allowed_origins,
+    electrum_ws_max_frame_size,
     monitoring_addr,
     jsonrpc_import,
+    relayfee_refresh_interval,
+    daemon_warnings_refresh_interval,
+    min_relay_fee_override,
     index_batch_size,
+    index_fetch_threads,
+    index_checkpoint_interval,
     bulk_index_threads,
     tx_cache_size,
     server_banner,
     blocktxids_cache_size,
+    blocktxids_warmup_blocks,
+    cache_watermarks,
+    txheight_cache_size,
+    address_cache_size,
+    merkle_cache_size,
+    txid_limit,
+    query_threads,
+    query_queue_size,
     rpc_timeout,
     low_memory,
+    reindex_incompatible_db,
+    db_compact_at_tip,
+    db_compact_interval,
+    header_window,
+    live_index_wal,
     cashaccount_activation_height,
+    index_opreturn,
+    index_block_stats,
+    index_first_use,
+    index_block_fees,
+    index_max_script_size,
+    index_hash_oversized_scripts,
+    index_min_output_value,
+    index_start_height,
     rpc_buffer_size,
+    rpc_max_dropped_notifications,
+    rpc_detect_ssl,
+    rpc_method_hints,
+    rpc_compression_threshold,
+    rpc_listen_backlog,
+    rpc_read_buffer_size,
+    rpc_tcp_keepalive,
+    rpc_read_timeout,
+    rpc_idle_timeout,
+    rpc_session_grace,
+    rpc_max_sessions_per_prefix,
     scripthash_subscription_limit,
     scripthash_alias_bytes_limit,
+    scripthash_subscription_bytes_limit,
+    rpc_max_batch_size,
+    rpc_max_tx_size,
+    rpc_max_headers,
+    rpc_max_tx_info_items,
+    rpc_max_tx_info_prevouts,
+    rpc_max_prevout_txs,
+    rpc_max_utxo_proofs,
+    rpc_max_cashaccount_registrations,
+    rpc_max_opreturn_txs,
+    rpc_max_cp_height,
+    rpc_max_first_use_txs,
+    rpc_max_mempool_txids,
+    history_block_order_max_blocks,
+    rpc_history_page_size,
+    mempool_histogram_threshold_vbytes,
+    mempool_histogram_max_age,
     rpc_max_connections,
+    rpc_max_peer_threads,
     rpc_max_connections_shared_prefix,
+    rpc_max_concurrent_requests_per_ip,
+    scripthash_subscription_limit_total,
+    rpc_exempt_loopback,
+    rpc_connections_ipv4_prefix_len,
+    rpc_connections_ipv6_prefix_len,
 }
 
 struct StaticCookie {
@@ -5,20 +5,23 @@ use bitcoincash::hash_types::{BlockHash, Txid};
 use bitcoincash::hashes::hex::{FromHex, ToHex};
 use bitcoincash::hashes::Hash;
 use bitcoincash::network::constants::Network;
+use error_chain::ChainedError;
 use serde_json::{from_str, from_value, Map, Value};
 use std::collections::{HashMap, HashSet};
-use std::io::{BufRead, BufReader, Lines, Write};
+use std::io::{self, BufRead, BufReader, Lines, Write};
 use std::net::{SocketAddr, TcpStream};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::cache::BlockTxIDsCache;
 use crate::errors::*;
 use crate::metrics::Metrics;
 use crate::signal::Waiter;
-use crate::util::HeaderList;
+use crate::util::{spawn_thread, HeaderList};
+use crate::zmq::Subscriber;
 
 fn parse_hash<T: Hash>(value: &Value) -> Result<T> {
     T::from_hex(
@@ -43,6 +46,17 @@ fn block_from_value(value: Value) -> Result<Block> {
     deserialize(&block_bytes).chain_err(|| format!("failed to parse block {}", block_hex))
 }
 
+fn blocktxids_from_value(value: Value) -> Result<Vec<Txid>> {
+    value
+        .get("tx")
+        .chain_err(|| "block missing txids")?
+        .as_array()
+        .chain_err(|| "invalid block txids")?
+        .iter()
+        .map(parse_hash)
+        .collect::<Result<Vec<Txid>>>()
+}
+
 fn tx_from_value(value: Value) -> Result<Transaction> {
     let tx_hex = value.as_str().chain_err(|| "non-string tx")?;
     let tx_bytes = hex::decode(tx_hex).chain_err(|| "non-hex tx")?;
@@ -57,14 +71,33 @@ fn parse_error_code(err: &Value) -> Option<i64> {
     err.as_object()?.get("code")?.as_i64()
 }
 
+fn error_message(err: &Value) -> String {
+    err.get("message")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+/// Such as "Block not available (pruned data)" or "Block not found on disk"
+fn is_pruned_message(err: &Value) -> bool {
+    let msg = error_message(err);
+    msg.contains("pruned") || msg.contains("not available") || msg.contains("not found on disk")
+}
+
 fn check_error_code(reply_obj: &Map<String, Value>, method: &str) -> Result<()> {
     if let Some(err) = reply_obj.get("error") {
         if let Some(code) = parse_error_code(err) {
             match code {
                 // RPC_IN_WARMUP -> retry by later reconnection
                 -28 => bail!(ErrorKind::Connection(err.to_string())),
-                // RPC_INVALID_ADDRESS_OR_KEY
+                // RPC_INVALID_ADDRESS_OR_KEY, also used for unknown txids and
+                // blockhashes. Kept as invalid params, which clients of
+                // blockchain.transaction.get already expect.
                 -5 => bail!(rpc_invalid_params(err.to_string())),
+                // RPC_MISC_ERROR, also used for blocks missing on disk
+                -1 if is_pruned_message(err) => {
+                    bail!(ErrorKind::RpcError(RpcErrorCode::Pruned, err.to_string()))
+                }
                 _ => bail!("{} RPC error: {}", method, err),
             }
         }
@@ -104,6 +137,24 @@ struct BlockchainInfo {
     bestblockhash: String,
     pruned: bool,
     initialblockdownload: bool,
+    /// A string in older daemons, a list of strings in newer ones
+    #[serde(default)]
+    warnings: Value,
+}
+
+/// Non-empty warnings of getblockchaininfo
+fn parse_warnings(warnings: &Value) -> Vec<String> {
+    let warnings = match warnings {
+        Value::String(warning) => vec![warning.as_str()],
+        Value::Array(warnings) => warnings.iter().filter_map(Value::as_str).collect(),
+        _ => vec![],
+    };
+    warnings
+        .into_iter()
+        .map(str::trim)
+        .filter(|warning| !warning.is_empty())
+        .map(str::to_owned)
+        .collect()
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -145,34 +196,204 @@ pub trait CookieGetter: Send + Sync {
     fn get(&self) -> Result<Vec<u8>>;
 }
 
+/// How long a failed daemon endpoint is skipped. Afterwards it is presumed
+/// healthy again, and connections fail back to it if it is preferred.
+const DAEMON_FAILBACK_DELAY: Duration = Duration::from_secs(60);
+
+/// Daemon JSONRPC endpoints, shared by all connections, in order of
+/// preference. Connections are made to the active endpoint, which fails
+/// over to the next healthy one on connection errors and timeouts.
+struct Backends {
+    addrs: Vec<SocketAddr>,
+    active: AtomicUsize,
+    /// When each endpoint last failed, if it did
+    failed_at: Mutex<Vec<Option<Instant>>>,
+
+    // monitoring
+    active_gauge: prometheus::IntGauge,
+    failovers: prometheus::IntCounter,
+    failbacks: prometheus::IntCounter,
+}
+
+fn is_healthy(failed_at: &Option<Instant>) -> bool {
+    failed_at.map_or(true, |at| at.elapsed() >= DAEMON_FAILBACK_DELAY)
+}
+
+impl Backends {
+    fn new(addrs: Vec<SocketAddr>, metrics: &Metrics) -> Backends {
+        assert!(!addrs.is_empty(), "no daemon address");
+        let backends = Backends {
+            failed_at: Mutex::new(vec![None; addrs.len()]),
+            addrs,
+            active: AtomicUsize::new(0),
+            active_gauge: metrics.gauge_int(prometheus::Opts::new(
+                "electrscash_daemon_active_backend",
+                "Index of the bitcoind endpoint in use (0 for the primary)",
+            )),
+            failovers: metrics.counter_int(prometheus::Opts::new(
+                "electrscash_daemon_failovers_total",
+                "# of switches to another bitcoind endpoint",
+            )),
+            failbacks: metrics.counter_int(prometheus::Opts::new(
+                "electrscash_daemon_failbacks_total",
+                "# of switches back to a preferred bitcoind endpoint",
+            )),
+        };
+        backends.active_gauge.set(0);
+        backends
+    }
+
+    fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    /// Index and address of the active endpoint
+    fn active(&self) -> (usize, SocketAddr) {
+        let index = self.active.load(Ordering::SeqCst);
+        (index, self.addrs[index])
+    }
+
+    fn mark_failed(&self, failed: usize) {
+        self.failed_at.lock().unwrap()[failed] = Some(Instant::now());
+    }
+
+    /// Switch from the `failed` endpoint to the next healthy one (or the
+    /// next one, if none is), unless another connection already did.
+    /// Returns the index of the active endpoint.
+    fn failover(&self, failed: usize) -> usize {
+        self.mark_failed(failed);
+        if self.addrs.len() == 1 {
+            return 0;
+        }
+        let next = {
+            let failed_at = self.failed_at.lock().unwrap();
+            (1..self.addrs.len())
+                .map(|offset| (failed + offset) % self.addrs.len())
+                .find(|&index| is_healthy(&failed_at[index]))
+                .unwrap_or((failed + 1) % self.addrs.len())
+        };
+        match self
+            .active
+            .compare_exchange(failed, next, Ordering::SeqCst, Ordering::SeqCst)
+        {
+            Ok(_) => {
+                warn!(
+                    "failing over from daemon at {} to {}",
+                    self.addrs[failed], self.addrs[next]
+                );
+                self.failovers.inc();
+                self.active_gauge.set(next as i64);
+                next
+            }
+            Err(active) => active,
+        }
+    }
+
+    /// The first endpoint preferred over `backend` that is healthy, if any.
+    fn preferred(&self, backend: usize) -> Option<usize> {
+        if backend == 0 {
+            return None;
+        }
+        let failed_at = self.failed_at.lock().unwrap();
+        (0..backend).find(|&index| is_healthy(&failed_at[index]))
+    }
+
+    /// Switch back to the endpoint `to`, unless another connection already
+    /// switched to it (or to an even more preferred one).
+    fn failback(&self, to: usize) {
+        let from = self.active.fetch_min(to, Ordering::SeqCst);
+        if from > to {
+            info!(
+                "failing back from daemon at {} to {}",
+                self.addrs[from], self.addrs[to]
+            );
+            self.failbacks.inc();
+            self.active_gauge.set(to as i64);
+        }
+    }
+
+    /// Presume all endpoints healthy, as if they failed long ago.
+    #[cfg(test)]
+    fn forget_failures(&self) {
+        for failed_at in self.failed_at.lock().unwrap().iter_mut() {
+            *failed_at = None;
+        }
+    }
+}
+
 struct Connection {
     tx: TcpStream,
     rx: Lines<BufReader<TcpStream>>,
     cookie_getter: Arc<dyn CookieGetter>,
-    addr: SocketAddr,
+    backends: Arc<Backends>,
+    backend: usize, // index of the connected endpoint
+    timeout: Option<Duration>,
     signal: Waiter,
 }
 
-fn tcp_connect(addr: SocketAddr, signal: &Waiter) -> Result<TcpStream> {
+fn connect_once(addr: SocketAddr, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    match timeout {
+        Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+        None => TcpStream::connect(addr),
+    }
+}
+
+/// Connect to the active endpoint, failing over until one is reachable.
+/// Waits between rounds over all endpoints.
+fn tcp_connect(
+    backends: &Backends,
+    timeout: Option<Duration>,
+    signal: &Waiter,
+) -> Result<(usize, TcpStream)> {
     loop {
-        match TcpStream::connect(addr) {
-            Ok(conn) => return Ok(conn),
+        let (backend, addr) = backends.active();
+        match connect_once(addr, timeout) {
+            Ok(conn) => return Ok((backend, conn)),
             Err(err) => {
                 warn!("failed to connect daemon at {}: {}", addr, err);
-                signal.wait(Duration::from_secs(3))?;
+                if backends.failover(backend) == 0 {
+                    signal.wait(Duration::from_secs(3))?;
+                }
                 continue;
             }
         }
     }
 }
 
+/// Socket errors are timeouts if the daemon didn't respond within the
+/// configured timeout, connection errors otherwise.
+fn socket_error(err: io::Error, msg: &str) -> Error {
+    let kind = match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+            ErrorKind::DaemonTimeout(msg.to_owned())
+        }
+        _ => ErrorKind::Connection(msg.to_owned()),
+    };
+    Error::with_chain(err, kind)
+}
+
 impl Connection {
     fn new(
-        addr: SocketAddr,
+        backends: Arc<Backends>,
+        cookie_getter: Arc<dyn CookieGetter>,
+        timeout: Option<Duration>,
+        signal: Waiter,
+    ) -> Result<Connection> {
+        let (backend, conn) = tcp_connect(&backends, timeout, &signal)?;
+        Connection::from_stream(conn, backends, backend, cookie_getter, timeout, signal)
+    }
+
+    fn from_stream(
+        conn: TcpStream,
+        backends: Arc<Backends>,
+        backend: usize,
         cookie_getter: Arc<dyn CookieGetter>,
+        timeout: Option<Duration>,
         signal: Waiter,
     ) -> Result<Connection> {
-        let conn = tcp_connect(addr, &signal)?;
+        conn.set_read_timeout(timeout)
+            .and_then(|_| conn.set_write_timeout(timeout))
+            .chain_err(|| format!("failed to set timeout on {:?}", conn))?;
         let reader = BufReader::new(
             conn.try_clone()
                 .chain_err(|| format!("failed to clone {:?}", conn))?,
@@ -181,13 +402,83 @@ impl Connection {
             tx: conn,
             rx: reader.lines(),
             cookie_getter,
-            addr,
+            backends,
+            backend,
+            timeout,
             signal,
         })
     }
 
     fn reconnect(&self) -> Result<Connection> {
-        Connection::new(self.addr, self.cookie_getter.clone(), self.signal.clone())
+        Connection::new(
+            Arc::clone(&self.backends),
+            self.cookie_getter.clone(),
+            self.timeout,
+            self.signal.clone(),
+        )
+    }
+
+    /// Reconnect after a failure of this connection, failing over to the
+    /// next endpoint. Makes a single connection attempt per endpoint.
+    fn try_reconnect(&self) -> Result<Connection> {
+        self.backends.failover(self.backend);
+        let mut attempts = self.backends.len();
+        loop {
+            let (backend, addr) = self.backends.active();
+            match connect_once(addr, self.timeout) {
+                Ok(conn) => {
+                    return Connection::from_stream(
+                        conn,
+                        Arc::clone(&self.backends),
+                        backend,
+                        self.cookie_getter.clone(),
+                        self.timeout,
+                        self.signal.clone(),
+                    )
+                }
+                Err(err) => {
+                    attempts -= 1;
+                    if attempts == 0 {
+                        return Err(err).chain_err(|| {
+                            ErrorKind::Connection(format!("failed to connect daemon at {}", addr))
+                        });
+                    }
+                    warn!("failed to connect daemon at {}: {}", addr, err);
+                    self.backends.failover(backend);
+                }
+            }
+        }
+    }
+
+    /// Reconnect to an endpoint preferred over the connected one, once it
+    /// is presumed healthy again. Returns None if there is no such endpoint,
+    /// or if it is still unreachable.
+    fn try_failback(&self) -> Option<Connection> {
+        let preferred = self.backends.preferred(self.backend)?;
+        let addr = self.backends.addrs[preferred];
+        let conn = match connect_once(addr, self.timeout) {
+            Ok(conn) => conn,
+            Err(err) => {
+                debug!("daemon at {} is still unreachable: {}", addr, err);
+                self.backends.mark_failed(preferred);
+                return None;
+            }
+        };
+        self.backends.failback(preferred);
+        match Connection::from_stream(
+            conn,
+            Arc::clone(&self.backends),
+            preferred,
+            self.cookie_getter.clone(),
+            self.timeout,
+            self.signal.clone(),
+        ) {
+            Ok(conn) => Some(conn),
+            Err(err) => {
+                warn!("failed to fail back to daemon at {}: {}", addr, err);
+                None
+            }
+        }
     }
 
     fn send(&mut self, request: &str) -> Result<()> {
@@ -198,9 +489,9 @@ impl Connection {
             request.len(),
             request,
         );
-        self.tx.write_all(msg.as_bytes()).chain_err(|| {
-            ErrorKind::Connection("disconnected from daemon while sending".to_owned())
-        })
+        self.tx
+            .write_all(msg.as_bytes())
+            .map_err(|e| socket_error(e, "disconnected from daemon while sending"))
     }
 
     fn recv(&mut self) -> Result<String> {
@@ -213,10 +504,10 @@ impl Connection {
             .chain_err(|| {
                 ErrorKind::Connection("disconnected from daemon while receiving".to_owned())
             })?
-            .chain_err(|| "failed to read status")?;
+            .map_err(|e| socket_error(e, "failed to read status"))?;
         let mut headers = HashMap::new();
         for line in iter {
-            let line = line.chain_err(|| ErrorKind::Connection("failed to read".to_owned()))?;
+            let line = line.map_err(|e| socket_error(e, "failed to read"))?;
             if line.is_empty() {
                 in_header = false; // next line should contain the actual response.
             } else if in_header {
@@ -266,6 +557,11 @@ impl Connection {
     }
 }
 
+/// Exponential backoff between daemon reconnection attempts, capped at 32 seconds.
+fn retry_delay(retry: usize) -> Duration {
+    Duration::from_secs(1 << retry.saturating_sub(1).min(5))
+}
+
 struct Counter {
     value: AtomicU64,
 }
@@ -288,22 +584,29 @@ pub struct Daemon {
     conn: Mutex<Connection>,
     message_id: Counter, // for monotonic JSONRPC 'id'
     signal: Waiter,
+    retry_limit: usize, // 0 = retry forever
     blocktxids_cache: Arc<BlockTxIDsCache>,
 
     // monitoring
     latency: prometheus::HistogramVec,
     size: prometheus::HistogramVec,
+    timeouts: prometheus::IntCounter,
+    calls: prometheus::IntCounterVec,
 }
 
 impl Daemon {
+    /// Connect to bitcoind at the first of `daemon_rpc_addrs`. The others
+    /// are used as fallbacks, when it fails.
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         daemon_dir: &Path,
         blocks_dir: &Path,
-        daemon_rpc_addr: SocketAddr,
+        daemon_rpc_addrs: Vec<SocketAddr>,
         cookie_getter: Arc<dyn CookieGetter>,
         network: Network,
         signal: Waiter,
+        retry_limit: usize,
+        timeout: Option<Duration>,
         blocktxids_cache: Arc<BlockTxIDsCache>,
         metrics: &Metrics,
     ) -> Result<Daemon> {
@@ -312,13 +615,15 @@ impl Daemon {
             blocks_dir: blocks_dir.to_path_buf(),
             network,
             conn: Mutex::new(Connection::new(
-                daemon_rpc_addr,
+                Arc::new(Backends::new(daemon_rpc_addrs, metrics)),
                 cookie_getter,
+                timeout,
                 signal.clone(),
             )?),
             message_id: Counter::new(),
             blocktxids_cache,
             signal: signal.clone(),
+            retry_limit,
             latency: metrics.histogram_vec(
                 prometheus::HistogramOpts::new(
                     "electrscash_daemon_rpc",
@@ -334,6 +639,17 @@ impl Daemon {
                 ),
                 &["method", "dir"],
             ),
+            timeouts: metrics.counter_int(prometheus::Opts::new(
+                "electrscash_daemon_timeout_total",
+                "# of bitcoind requests that timed out",
+            )),
+            calls: metrics.counter_int_vec(
+                prometheus::Opts::new(
+                    "electrscash_daemon_calls_total",
+                    "# of bitcoind RPC calls (counting each call of a batch)",
+                ),
+                &["method"],
+            ),
         };
         let network_info = daemon.getnetworkinfo()?;
         info!("{:?}", network_info);
@@ -373,9 +689,12 @@ impl Daemon {
             conn: Mutex::new(self.conn.lock().unwrap().reconnect()?),
             message_id: Counter::new(),
             signal: self.signal.clone(),
+            retry_limit: self.retry_limit,
             blocktxids_cache: Arc::clone(&self.blocktxids_cache),
             latency: self.latency.clone(),
             size: self.size.clone(),
+            timeouts: self.timeouts.clone(),
+            calls: self.calls.clone(),
         })
     }
 
@@ -396,6 +715,9 @@ impl Daemon {
 
     fn call_jsonrpc(&self, method: &str, request: &Value) -> Result<Value> {
         let mut conn = self.conn.lock().unwrap();
+        if let Some(preferred_conn) = conn.try_failback() {
+            *conn = preferred_conn;
+        }
         let timer = self.latency.with_label_values(&[method]).start_timer();
         let request = request.to_string();
         conn.send(&request)?;
@@ -413,6 +735,9 @@ impl Daemon {
 
     fn handle_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
         let id = self.message_id.next();
+        self.calls
+            .with_label_values(&[method])
+            .inc_by(params_list.len() as u64);
         let reqs = params_list
             .iter()
             .map(|params| json!({"method": method, "params": params, "id": id}))
@@ -429,13 +754,41 @@ impl Daemon {
     }
 
     fn retry_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
+        let mut retries = 0;
         loop {
             match self.handle_request_batch(method, params_list) {
+                Err(e @ Error(ErrorKind::DaemonTimeout(_), _)) => {
+                    // Retrying would likely time out again. The reply may
+                    // still arrive, so the connection can't be reused.
+                    self.timeouts.inc();
+                    warn!("{} request to bitcoind: {}", method, e);
+                    let mut conn = self.conn.lock().unwrap();
+                    match conn.try_reconnect() {
+                        Ok(new_conn) => *conn = new_conn,
+                        Err(e) => warn!("{}", e),
+                    }
+                    return Err(e);
+                }
                 Err(Error(ErrorKind::Connection(msg), _)) => {
-                    warn!("reconnecting to bitcoind: {}", msg);
-                    self.signal.wait(Duration::from_secs(3))?;
+                    retries += 1;
+                    if self.retry_limit > 0 && retries > self.retry_limit {
+                        bail!(ErrorKind::Connection(format!(
+                            "{} failed after {} retries: {}",
+                            method, self.retry_limit, msg
+                        )));
+                    }
+                    let delay = retry_delay(retries);
+                    warn!(
+                        "reconnecting to bitcoind in {:?} (retry #{}): {}",
+                        delay, retries, msg
+                    );
+                    self.signal.wait(delay)?;
                     let mut conn = self.conn.lock().unwrap();
-                    *conn = conn.reconnect()?;
+                    match conn.try_reconnect() {
+                        Ok(new_conn) => *conn = new_conn,
+                        // the next request fails and is retried as well
+                        Err(e) => warn!("{}", e),
+                    }
                     continue;
                 }
                 result => return result,
@@ -473,6 +826,11 @@ impl Daemon {
         Ok(self.getnetworkinfo()?.relayfee)
     }
 
+    /// Chain warnings of the daemon, such as unknown new rules activating.
+    pub fn get_warnings(&self) -> Result<Vec<String>> {
+        Ok(parse_warnings(&self.getblockchaininfo()?.warnings))
+    }
+
     pub fn getbestblockhash(&self) -> Result<BlockHash> {
         parse_hash(&self.request("getbestblockhash", json!([]))?).chain_err(|| "invalid blockhash")
     }
@@ -507,14 +865,9 @@ impl Daemon {
     }
 
     fn load_blocktxids(&self, blockhash: &BlockHash) -> Result<Vec<Txid>> {
-        self.request("getblock", json!([blockhash.to_hex(), /*verbose=*/ 1]))?
-            .get("tx")
-            .chain_err(|| "block missing txids")?
-            .as_array()
-            .chain_err(|| "invalid block txids")?
-            .iter()
-            .map(parse_hash)
-            .collect::<Result<Vec<Txid>>>()
+        blocktxids_from_value(
+            self.request("getblock", json!([blockhash.to_hex(), /*verbose=*/ 1]))?,
+        )
     }
 
     pub fn getblocktxids(&self, blockhash: &BlockHash) -> Result<Vec<Txid>> {
@@ -522,6 +875,39 @@ impl Daemon {
             .get_or_else(blockhash, || self.load_blocktxids(blockhash))
     }
 
+    /// Number of transactions in a block, served from the blocktxids cache
+    /// when possible.
+    pub fn getblocktxcount(&self, blockhash: &BlockHash) -> Result<usize> {
+        Ok(self.getblocktxids(blockhash)?.len())
+    }
+
+    /// Load txids of the given blocks into the blocktxids cache, using a
+    /// single batched request for the blocks that are not cached yet.
+    /// Returns the number of blocks fetched.
+    pub fn prefetch_blocktxids(&self, blockhashes: &[BlockHash]) -> Result<usize> {
+        let missing: Vec<&BlockHash> = blockhashes
+            .iter()
+            .filter(|hash| !self.blocktxids_cache.contains(hash))
+            .collect();
+        if missing.is_empty() {
+            return Ok(0);
+        }
+        let params_list: Vec<Value> = missing
+            .iter()
+            .map(|hash| json!([hash.to_hex(), /*verbose=*/ 1]))
+            .collect();
+        let values = self.requests("getblock", &params_list)?;
+        for (hash, value) in missing.iter().zip(values) {
+            self.blocktxids_cache
+                .put(hash, blocktxids_from_value(value)?);
+        }
+        Ok(missing.len())
+    }
+
+    pub fn blocktxids_cache_usage(&self) -> (u64, u64) {
+        self.blocktxids_cache.usage()
+    }
+
     pub fn gettransaction(
         &self,
         txhash: &Txid,
@@ -626,7 +1012,7 @@ impl Daemon {
         let null_hash = BlockHash::default();
         let mut blockhash = *bestblockhash;
         while blockhash != null_hash {
-            if indexed_headers.header_by_blockhash(&blockhash).is_some() {
+            if indexed_headers.height_by_blockhash(&blockhash).is_some() {
                 break;
             }
             let header = self
@@ -640,3 +1026,577 @@ impl Daemon {
         Ok(new_headers)
     }
 }
+
+const ZMQ_TIMEOUT: Duration = Duration::from_secs(1);
+const ZMQ_RECONNECT_DELAY_SECS: u64 = 5;
+
+/// Group ZMQ notification topics by publisher address, so that a single
+/// connection is used when bitcoind publishes them on the same address.
+pub fn zmq_endpoints(
+    hashblock: Option<SocketAddr>,
+    hashtx: Option<SocketAddr>,
+) -> Vec<(SocketAddr, Vec<&'static str>)> {
+    let mut endpoints: Vec<(SocketAddr, Vec<&'static str>)> = vec![];
+    for (addr, topic) in [(hashblock, "hashblock"), (hashtx, "hashtx")].iter() {
+        let addr = match addr {
+            Some(addr) => *addr,
+            None => continue,
+        };
+        match endpoints.iter_mut().find(|(a, _)| *a == addr) {
+            Some((_, topics)) => topics.push(topic),
+            None => endpoints.push((addr, vec![topic])),
+        }
+    }
+    endpoints
+}
+
+fn zmq_listen(addr: SocketAddr, topics: &[&str], waiter: &Waiter) {
+    while !waiter.is_interrupted() {
+        match Subscriber::connect(addr, topics, ZMQ_TIMEOUT) {
+            Ok(mut subscriber) => {
+                info!("subscribed to {:?} notifications at {}", topics, addr);
+                while !waiter.is_interrupted() {
+                    match subscriber.recv() {
+                        Ok(Some(_)) => waiter.wake(),
+                        Ok(None) => (),
+                        Err(e) => {
+                            warn!("ZMQ subscription at {} failed: {}", addr, e);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => warn!("{}", e.display_chain()),
+        }
+        for _ in 0..ZMQ_RECONNECT_DELAY_SECS {
+            if waiter.is_interrupted() {
+                return;
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+/// Subscribe to bitcoind ZMQ notifications, waking up `waiter` on new blocks
+/// and transactions so that updates don't have to wait for the next poll.
+/// The listener threads exit once `waiter` is interrupted.
+pub fn start_zmq_listeners(
+    endpoints: Vec<(SocketAddr, Vec<&'static str>)>,
+    waiter: &Waiter,
+) -> Vec<thread::JoinHandle<()>> {
+    endpoints
+        .into_iter()
+        .map(|(addr, topics)| {
+            let waiter = waiter.clone();
+            spawn_thread("zmq", move || zmq_listen(addr, &topics, &waiter))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+impl Daemon {
+    /// Connected to a fake bitcoind, which knows no transactions.
+    pub fn fake(metrics: &Metrics) -> Daemon {
+        Daemon::new(
+            Path::new("/nonexistent"),
+            Path::new("/nonexistent/blocks"),
+            vec![tests::spawn_fake_bitcoind()],
+            Arc::new(tests::TestCookie),
+            Network::Regtest,
+            Waiter::start(),
+            0,
+            None,
+            Arc::new(BlockTxIDsCache::new(
+                0,
+                crate::rndcache::Watermarks::default(),
+                metrics,
+            )),
+            metrics,
+        )
+        .expect("failed to connect to the fake bitcoind")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rndcache::Watermarks;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    const BEST_BLOCKHASH: &str = "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206";
+
+    pub(super) struct TestCookie;
+
+    impl CookieGetter for TestCookie {
+        fn get(&self) -> Result<Vec<u8>> {
+            Ok(b"user:pass".to_vec())
+        }
+    }
+
+    /// Serve up to `max_requests` JSONRPC requests as a fake bitcoind, then
+    /// drop the connection (as if bitcoind was restarted).
+    fn serve_fake_bitcoind(stream: TcpStream, max_requests: usize) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        for _ in 0..max_requests {
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    return;
+                }
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(len) = line.strip_prefix("Content-Length: ") {
+                    content_length = len.parse().unwrap();
+                }
+            }
+            let mut body = vec![0u8; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let requests: Value = serde_json::from_slice(&body).unwrap();
+            let replies: Vec<Value> = requests
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|request| {
+                    let result = match request["method"].as_str().unwrap() {
+                        "getnetworkinfo" => json!({
+                            "version": 25_00_00,
+                            "subversion": "/Fake:25.0.0/",
+                            "relayfee": 0.00001,
+                        }),
+                        "getblockchaininfo" => json!({
+                            "chain": "regtest",
+                            "blocks": 0,
+                            "headers": 0,
+                            "verificationprogress": 1.0,
+                            "bestblockhash": BEST_BLOCKHASH,
+                            "pruned": false,
+                            "initialblockdownload": false,
+                            "warnings": FAKE_WARNING,
+                        }),
+                        "getbestblockhash" => json!(BEST_BLOCKHASH),
+                        // use the blockhash as the coinbase txid
+                        "getblock" => json!({"tx": [request["params"][0]]}),
+                        // no transactions, and only the best block is kept
+                        "getrawtransaction" => {
+                            let error = match request["params"].get(2) {
+                                Some(_) => json!({"code": -1, "message": "Block not available"}),
+                                None => json!({"code": -5, "message": NO_SUCH_TX}),
+                            };
+                            return json!({"result": null, "error": error, "id": request["id"]});
+                        }
+                        method => panic!("unexpected method {}", method),
+                    };
+                    json!({"result": result, "error": null, "id": request["id"]})
+                })
+                .collect();
+            let reply = json!(replies).to_string();
+            write!(
+                writer,
+                "HTTP/1.1 200 OK\nContent-Length: {}\n\n{}\n",
+                reply.len() + 1,
+                reply
+            )
+            .unwrap();
+        }
+    }
+
+    /// Address of a fake bitcoind serving any number of connections, for
+    /// as long as the tests run.
+    pub(super) fn spawn_fake_bitcoind() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                thread::spawn(move || serve_fake_bitcoind(stream, usize::MAX));
+            }
+        });
+        addr
+    }
+
+    fn connect_fake_bitcoind(addr: SocketAddr, retry_limit: usize) -> Result<Daemon> {
+        let metrics = Metrics::dummy();
+        connect_fake_bitcoind_with_cache(
+            vec![addr],
+            retry_limit,
+            None,
+            Arc::new(BlockTxIDsCache::new(0, Watermarks::default(), &metrics)),
+        )
+    }
+
+    fn connect_fake_bitcoind_with_cache(
+        addrs: Vec<SocketAddr>,
+        retry_limit: usize,
+        timeout: Option<Duration>,
+        blocktxids_cache: Arc<BlockTxIDsCache>,
+    ) -> Result<Daemon> {
+        let metrics = Metrics::dummy();
+        Daemon::new(
+            Path::new("/nonexistent"),
+            Path::new("/nonexistent/blocks"),
+            addrs,
+            Arc::new(TestCookie),
+            Network::Regtest,
+            Waiter::start(),
+            retry_limit,
+            timeout,
+            blocktxids_cache,
+            &metrics,
+        )
+    }
+
+    // Daemon::new() sends getnetworkinfo and getblockchaininfo (twice)
+    const STARTUP_REQUESTS: usize = 3;
+
+    const FAKE_WARNING: &str = "Warning: unknown new rules activated (versionbit 28)";
+
+    const NO_SUCH_TX: &str =
+        "No such mempool or blockchain transaction. Use gettransaction for wallet transactions.";
+
+    #[test]
+    fn test_retry_after_daemon_restart() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bitcoind = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_fake_bitcoind(stream, STARTUP_REQUESTS);
+            // "restarted" bitcoind accepts a new connection
+            let (stream, _) = listener.accept().unwrap();
+            serve_fake_bitcoind(stream, 1);
+        });
+        let daemon = connect_fake_bitcoind(addr, 3).unwrap();
+        assert_eq!(daemon.getbestblockhash().unwrap().to_hex(), BEST_BLOCKHASH);
+        bitcoind.join().unwrap();
+    }
+
+    #[test]
+    fn test_call_counts() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bitcoind = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_fake_bitcoind(stream, STARTUP_REQUESTS + 2);
+        });
+        let daemon = connect_fake_bitcoind(addr, 0).unwrap();
+        let calls = |method| daemon.calls.with_label_values(&[method]).get();
+        assert_eq!(calls("getnetworkinfo"), 1);
+        assert_eq!(calls("getblockchaininfo"), 2);
+        assert_eq!(calls("getbestblockhash"), 0);
+
+        daemon.getbestblockhash().unwrap();
+        assert_eq!(calls("getbestblockhash"), 1);
+
+        // each call of a batch is counted
+        let blockhashes: Vec<BlockHash> = ["11", "22", "33"]
+            .iter()
+            .map(|b| BlockHash::from_hex(&b.repeat(32)).unwrap())
+            .collect();
+        assert_eq!(daemon.prefetch_blocktxids(&blockhashes).unwrap(), 3);
+        assert_eq!(calls("getblock"), 3);
+        assert_eq!(calls("getnetworkinfo"), 1);
+        bitcoind.join().unwrap();
+    }
+
+    #[test]
+    fn test_retry_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bitcoind = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_fake_bitcoind(stream, STARTUP_REQUESTS);
+            // bitcoind stays down
+        });
+        let daemon = connect_fake_bitcoind(addr, 1).unwrap();
+        bitcoind.join().unwrap();
+        match daemon.getbestblockhash() {
+            Err(Error(ErrorKind::Connection(msg), _)) => {
+                assert!(msg.contains("after 1 retries"), "{}", msg)
+            }
+            other => panic!("unexpected result {:?}", other.map(|h| h.to_hex())),
+        }
+    }
+
+    #[test]
+    fn test_failover_to_secondary() {
+        let primary = TcpListener::bind("127.0.0.1:0").unwrap();
+        let secondary = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = vec![
+            primary.local_addr().unwrap(),
+            secondary.local_addr().unwrap(),
+        ];
+        let bitcoind = thread::spawn(move || {
+            let (stream, _) = primary.accept().unwrap();
+            serve_fake_bitcoind(stream, STARTUP_REQUESTS);
+            // primary goes down for good
+            drop(primary);
+            let (stream, _) = secondary.accept().unwrap();
+            serve_fake_bitcoind(stream, 1);
+        });
+        let metrics = Metrics::dummy();
+        let cache = Arc::new(BlockTxIDsCache::new(0, Watermarks::default(), &metrics));
+        let daemon = connect_fake_bitcoind_with_cache(addrs, 3, None, cache).unwrap();
+        assert_eq!(daemon.getbestblockhash().unwrap().to_hex(), BEST_BLOCKHASH);
+        bitcoind.join().unwrap();
+
+        let conn = daemon.conn.lock().unwrap();
+        assert_eq!(conn.backend, 1);
+        assert_eq!(conn.backends.active().0, 1);
+        assert_eq!(conn.backends.failovers.get(), 1);
+    }
+
+    #[test]
+    fn test_failback_to_primary() {
+        let primary = TcpListener::bind("127.0.0.1:0").unwrap();
+        let secondary = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = vec![
+            primary.local_addr().unwrap(),
+            secondary.local_addr().unwrap(),
+        ];
+        let primary_addr = addrs[0];
+        let bitcoind = thread::spawn(move || {
+            let (stream, _) = primary.accept().unwrap();
+            serve_fake_bitcoind(stream, STARTUP_REQUESTS);
+            drop(primary);
+            let (stream, _) = secondary.accept().unwrap();
+            serve_fake_bitcoind(stream, 1);
+        });
+        let metrics = Metrics::dummy();
+        let cache = Arc::new(BlockTxIDsCache::new(0, Watermarks::default(), &metrics));
+        let daemon = connect_fake_bitcoind_with_cache(addrs, 3, None, cache).unwrap();
+        assert_eq!(daemon.getbestblockhash().unwrap().to_hex(), BEST_BLOCKHASH);
+        bitcoind.join().unwrap();
+        assert_eq!(daemon.conn.lock().unwrap().backend, 1);
+
+        // the primary is back, but is skipped until its failure is old enough
+        let primary = TcpListener::bind(primary_addr).unwrap();
+        let bitcoind = thread::spawn(move || {
+            let (stream, _) = primary.accept().unwrap();
+            serve_fake_bitcoind(stream, 1);
+        });
+        {
+            let conn = daemon.conn.lock().unwrap();
+            assert_eq!(conn.backends.preferred(1), None);
+            conn.backends.forget_failures();
+        }
+        assert_eq!(daemon.getbestblockhash().unwrap().to_hex(), BEST_BLOCKHASH);
+        bitcoind.join().unwrap();
+
+        let conn = daemon.conn.lock().unwrap();
+        assert_eq!(conn.backend, 0);
+        assert_eq!(conn.backends.active().0, 0);
+        assert_eq!(conn.backends.failbacks.get(), 1);
+    }
+
+    #[test]
+    fn test_failover_skips_failed_backends() {
+        let addrs: Vec<SocketAddr> = (1..=3)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], port)))
+            .collect();
+        let backends = Backends::new(addrs, &Metrics::dummy());
+        assert_eq!(backends.failover(0), 1);
+        // the primary failed recently, so it is skipped
+        assert_eq!(backends.failover(1), 2);
+        assert_eq!(backends.preferred(2), None);
+        // all failed, so they are used in turn
+        assert_eq!(backends.failover(2), 0);
+        assert_eq!(backends.failovers.get(), 3);
+
+        backends.forget_failures();
+        backends.mark_failed(1);
+        assert_eq!(backends.failover(0), 2);
+        assert_eq!(backends.preferred(2), None);
+        // the failures are old enough
+        backends.forget_failures();
+        assert_eq!(backends.preferred(2), Some(0));
+        backends.failback(0);
+        assert_eq!(backends.active().0, 0);
+        assert_eq!(backends.failbacks.get(), 1);
+        // already switched back
+        backends.failback(0);
+        assert_eq!(backends.failbacks.get(), 1);
+    }
+
+    #[test]
+    fn test_slow_daemon_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (done, wait_done) = std::sync::mpsc::channel::<()>();
+        let bitcoind = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_fake_bitcoind(stream.try_clone().unwrap(), STARTUP_REQUESTS);
+            // bitcoind is wedged: the connection stays open, without replies
+            let _ = wait_done.recv();
+            drop(stream);
+        });
+        let metrics = Metrics::dummy();
+        let cache = Arc::new(BlockTxIDsCache::new(0, Watermarks::default(), &metrics));
+        let timeout = Duration::from_millis(200);
+        let daemon = connect_fake_bitcoind_with_cache(vec![addr], 0, Some(timeout), cache).unwrap();
+
+        let start = std::time::Instant::now();
+        match daemon.getbestblockhash() {
+            Err(Error(ErrorKind::DaemonTimeout(_), _)) => {}
+            other => panic!("unexpected result {:?}", other.map(|h| h.to_hex())),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(daemon.timeouts.get(), 1);
+        done.send(()).unwrap();
+        bitcoind.join().unwrap();
+    }
+
+    #[test]
+    fn test_get_warnings() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bitcoind = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_fake_bitcoind(stream, STARTUP_REQUESTS + 1);
+        });
+        let daemon = connect_fake_bitcoind(addr, 0).unwrap();
+        assert_eq!(daemon.get_warnings().unwrap(), vec![FAKE_WARNING]);
+        bitcoind.join().unwrap();
+    }
+
+    #[test]
+    fn test_gettransaction_error_codes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bitcoind = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_fake_bitcoind(stream, STARTUP_REQUESTS + 2);
+        });
+        let daemon = connect_fake_bitcoind(addr, 0).unwrap();
+        let code = |blockhash: Option<&BlockHash>| {
+            let txid = Txid::from_hex(&"11".repeat(32)).unwrap();
+            match daemon
+                .gettransaction_raw(&txid, blockhash, false)
+                .unwrap_err()
+                .kind()
+            {
+                ErrorKind::RpcError(code, _) => *code as i32,
+                e => panic!("unexpected error {}", e),
+            }
+        };
+        // unknown transactions are still reported as invalid params
+        assert_eq!(code(None), -32602);
+        let pruned = BlockHash::from_hex(BEST_BLOCKHASH).unwrap();
+        assert_eq!(code(Some(&pruned)), -32003);
+        bitcoind.join().unwrap();
+    }
+
+    #[test]
+    fn test_parse_warnings() {
+        assert!(parse_warnings(&Value::Null).is_empty());
+        assert!(parse_warnings(&json!("")).is_empty());
+        assert_eq!(parse_warnings(&json!("large reorg")), vec!["large reorg"]);
+        assert_eq!(
+            parse_warnings(&json!(["large reorg", " ", "unknown rules"])),
+            vec!["large reorg", "unknown rules"]
+        );
+    }
+
+    #[test]
+    fn test_check_error_code() {
+        let code = |code: i64, message: &str| {
+            let reply = json!({"error": {"code": code, "message": message}});
+            match check_error_code(reply.as_object().unwrap(), "method")
+                .unwrap_err()
+                .kind()
+            {
+                ErrorKind::RpcError(code, _) => Some(*code as i32),
+                _ => None,
+            }
+        };
+        let invalid_params = Some(RpcErrorCode::InvalidParams as i32);
+        assert_eq!(code(-5, "No such mempool or blockchain transaction. Use gettransaction for wallet transactions."), invalid_params);
+        assert_eq!(code(-5, "Block not found"), invalid_params);
+        assert_eq!(code(-5, "Invalid address"), invalid_params);
+        let pruned = Some(RpcErrorCode::Pruned as i32);
+        assert_eq!(code(-1, "Block not available (pruned data)"), pruned);
+        assert_eq!(code(-1, "Block not available"), pruned);
+        assert_eq!(code(-1, "Block not found on disk"), pruned);
+        assert_eq!(code(-1, "something else"), None);
+
+        let ok = json!({"error": null, "result": 1});
+        assert!(check_error_code(ok.as_object().unwrap(), "method").is_ok());
+    }
+
+    #[test]
+    fn test_rpc_daemon_timeout() {
+        let e: Error = ErrorKind::DaemonTimeout("failed to read".to_owned()).into();
+        match rpc_daemon_timeout(e).kind() {
+            ErrorKind::RpcError(RpcErrorCode::Timeout, _) => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+        let e: Error = ErrorKind::Connection("failed to read".to_owned()).into();
+        assert!(matches!(
+            rpc_daemon_timeout(e).kind(),
+            ErrorKind::Connection(_)
+        ));
+    }
+
+    #[test]
+    fn test_prefetch_blocktxids() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bitcoind = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            // startup, a single prefetch batch and nothing else
+            serve_fake_bitcoind(stream, STARTUP_REQUESTS + 1);
+        });
+        let metrics = Metrics::dummy();
+        let cache = Arc::new(BlockTxIDsCache::new(
+            1024 * 1024,
+            Watermarks::default(),
+            &metrics,
+        ));
+        let daemon = connect_fake_bitcoind_with_cache(vec![addr], 0, None, cache.clone()).unwrap();
+
+        let blockhashes: Vec<BlockHash> = (1..=3u8)
+            .map(|i| BlockHash::from_hex(&format!("{:064x}", i)).unwrap())
+            .collect();
+        assert!(blockhashes.iter().all(|hash| !cache.contains(hash)));
+        assert_eq!(daemon.prefetch_blocktxids(&blockhashes).unwrap(), 3);
+        assert!(blockhashes.iter().all(|hash| cache.contains(hash)));
+
+        // cached blocks are served without contacting bitcoind
+        assert_eq!(daemon.prefetch_blocktxids(&blockhashes).unwrap(), 0);
+        let txids = daemon.getblocktxids(&blockhashes[1]).unwrap();
+        assert_eq!(txids[0].to_hex(), blockhashes[1].to_hex());
+        assert_eq!(
+            daemon.getblocktxcount(&blockhashes[1]).unwrap(),
+            txids.len()
+        );
+        assert_eq!(daemon.calls.with_label_values(&["getblock"]).get(), 3);
+        bitcoind.join().unwrap();
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), Duration::from_secs(1));
+        assert_eq!(retry_delay(2), Duration::from_secs(2));
+        assert_eq!(retry_delay(6), Duration::from_secs(32));
+        assert_eq!(retry_delay(100), Duration::from_secs(32));
+    }
+
+    #[test]
+    fn test_zmq_endpoints() {
+        let a: SocketAddr = "127.0.0.1:28332".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:28333".parse().unwrap();
+        assert!(zmq_endpoints(None, None).is_empty());
+        assert_eq!(
+            zmq_endpoints(Some(a), Some(a)),
+            vec![(a, vec!["hashblock", "hashtx"])]
+        );
+        assert_eq!(
+            zmq_endpoints(Some(a), Some(b)),
+            vec![(a, vec!["hashblock"]), (b, vec!["hashtx"])]
+        );
+        assert_eq!(zmq_endpoints(None, Some(b)), vec![(b, vec!["hashtx"])]);
+    }
+}
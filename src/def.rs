@@ -2,5 +2,5 @@ pub const ELECTRSCASH_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const PROTOCOL_VERSION_MIN: &str = "1.4";
 pub const PROTOCOL_VERSION_MAX: &str = "1.4.3";
 pub const PROTOCOL_HASH_FUNCTION: &str = "sha256";
-pub const DATABASE_VERSION: &str = "1.1";
+pub const DATABASE_VERSION: &str = "1.2";
 pub const COIN: u64 = 100_000_000;
@@ -3,57 +3,154 @@ use crate::metrics::Metrics;
 
 use prometheus::{IntCounter, IntGauge};
 
-use std::convert::TryInto;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::atomic::AtomicI32;
 use std::sync::atomic::Ordering;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 struct ConnectionMetrics {
     connections: IntGauge,
     connections_rejected_global: IntCounter,
     connections_rejected_prefix: IntCounter,
     connections_total: IntCounter,
+    requests_rejected_ip: IntCounter,
+    subscriptions_rejected_global: IntCounter,
 }
 
 pub struct GlobalLimits {
     /// Maximum number of connections we accept in total.
     max_connections_total: i32,
 
-    /// Max connections from IP's sharing the first two octests (subnet mask
-    /// 255.255.0.0 for ipv4)
+    /// Max connections from IP's sharing the same prefix (see
+    /// `ipv4_prefix_len` and `ipv6_prefix_len`)
     max_connections_shared_prefix: u32,
 
+    /// Length (in bits) of the shared IPv4 prefix, e.g. 16 for 255.255.0.0
+    ipv4_prefix_len: u8,
+
+    /// Length (in bits) of the shared IPv6 prefix, e.g. 64 for a /64 network
+    ipv6_prefix_len: u8,
+
     /// Current total connections
     total_connections: AtomicI32,
 
-    /// Current connections by octet prefix
-    total_prefixed_connections: Mutex<HashMap<[u8; 2], u32>>,
+    /// Current connections by (masked) IP prefix
+    total_prefixed_connections: Mutex<HashMap<IpAddr, u32>>,
+
+    /// Maximum number of requests processed concurrently for a single IP
+    /// (0 = unlimited)
+    max_requests_per_ip: u32,
+
+    /// Requests currently being processed by IP
+    inflight_requests: Mutex<HashMap<IpAddr, u32>>,
+
+    /// Maximum number of subscriptions over all connections (0 = unlimited)
+    max_subscriptions_total: u32,
+
+    /// Don't count connections from loopback addresses against the limits
+    exempt_loopback: bool,
+
+    /// Connections of the websocket proxy, which are never exempt
+    proxied_peers: Arc<ProxiedPeers>,
 
     metrics: ConnectionMetrics,
 }
 
-fn get_prefix(addr: &IpAddr) -> [u8; 2] {
+/// Mask `addr`, keeping the first `ipv4_prefix_len` or `ipv6_prefix_len` bits.
+fn get_prefix(addr: &IpAddr, ipv4_prefix_len: u8, ipv6_prefix_len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(ipv4) => {
+            let zero_bits = 32 - u32::from(ipv4_prefix_len.min(32));
+            let mask = u32::MAX.checked_shl(zero_bits).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from(u32::from(*ipv4) & mask))
+        }
+        IpAddr::V6(ipv6) => {
+            let zero_bits = 128 - u32::from(ipv6_prefix_len.min(128));
+            let mask = u128::MAX.checked_shl(zero_bits).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from(u128::from(*ipv6) & mask))
+        }
+    }
+}
+
+/// IPv4 address of an IPv4-mapped IPv6 address
+fn ipv4_mapped(ipv6: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match ipv6.octets() {
+        [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => Some(Ipv4Addr::new(a, b, c, d)),
+        _ => None,
+    }
+}
+
+/// Loopback address, also as an IPv4-mapped IPv6 address
+fn is_loopback(addr: &IpAddr) -> bool {
     match addr {
-        IpAddr::V4(ipv4) => ipv4.octets()[..2].try_into().unwrap(),
-        IpAddr::V6(ipv6) => ipv6.octets()[..2].try_into().unwrap(),
+        IpAddr::V4(ipv4) => ipv4.is_loopback(),
+        IpAddr::V6(ipv6) => {
+            ipv6.is_loopback() || ipv4_mapped(ipv6).map_or(false, |ipv4| ipv4.is_loopback())
+        }
+    }
+}
+
+/// `addr`, with an IPv4-mapped IPv6 address as the IPv4 address
+fn canonical_addr(addr: &SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match ipv4_mapped(v6.ip()) {
+            Some(ipv4) => SocketAddr::new(ipv4.into(), v6.port()),
+            None => *addr,
+        },
+        SocketAddr::V4(_) => *addr,
+    }
+}
+
+/// Local addresses the websocket proxy connects to the RPC server from.
+///
+/// The proxy adds an address before connecting from it, and the RPC server
+/// removes it once the connection is closed. The clients of the proxy can
+/// then be limited, even though they all connect from a loopback address.
+#[derive(Debug, Default)]
+pub struct ProxiedPeers(Mutex<HashSet<SocketAddr>>);
+
+impl ProxiedPeers {
+    pub fn add(&self, addr: &SocketAddr) {
+        self.0.lock().unwrap().insert(canonical_addr(addr));
+    }
+
+    pub fn remove(&self, addr: &SocketAddr) -> bool {
+        self.0.lock().unwrap().remove(&canonical_addr(addr))
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.0.lock().unwrap().contains(&canonical_addr(addr))
     }
 }
 
 impl GlobalLimits {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         max_connections_total: u32,
         max_connections_shared_prefix: u32,
+        ipv4_prefix_len: u8,
+        ipv6_prefix_len: u8,
+        max_requests_per_ip: u32,
+        max_subscriptions_total: u32,
+        exempt_loopback: bool,
         metric: &Metrics,
     ) -> GlobalLimits {
         GlobalLimits {
             max_connections_total: max_connections_total as i32,
             max_connections_shared_prefix,
+            ipv4_prefix_len,
+            ipv6_prefix_len,
             total_connections: AtomicI32::new(0),
             total_prefixed_connections: Mutex::new(HashMap::new()),
+            max_requests_per_ip,
+            inflight_requests: Mutex::new(HashMap::new()),
+            max_subscriptions_total,
+            exempt_loopback,
+            proxied_peers: Arc::new(ProxiedPeers::default()),
             metrics: ConnectionMetrics {
                 connections: metric.gauge_int(prometheus::Opts::new(
                     "electrscash_rpc_connections",
@@ -71,17 +168,32 @@ impl GlobalLimits {
                     "electrscash_rpc_connections_total",
                     "# of RPC connections since server start",
                 )),
+                requests_rejected_ip: metric.counter_int(prometheus::Opts::new(
+                    "electrscash_rpc_requests_rejected_ip",
+                    "# of rejected RPC requests due to per-IP concurrent request limits",
+                )),
+                subscriptions_rejected_global: metric.counter_int(prometheus::Opts::new(
+                    "electrscash_rpc_subscriptions_rejected_global",
+                    "# of rejected subscriptions due to the global subscription limit",
+                )),
             },
         }
     }
 
     /// Increase connection count. Fails if maximum number of connections has
-    /// been reached. Returns the new connection count.
-    pub fn inc_connection(&self, addr: &IpAddr) -> Result<(u32, u32)> {
+    /// been reached. Returns the new connection count. Exempt loopback
+    /// connections are not counted.
+    pub fn inc_connection(&self, peer: &SocketAddr) -> Result<(u32, u32)> {
         self.metrics.connections_total.inc();
+        if self.is_exempt(peer, self.proxied_peers.contains(peer)) {
+            return Ok((self.total_connections(), 0));
+        }
+        // IPv4 clients accepted on a dual stack socket share the IPv4 prefix
+        let addr = &canonical_addr(peer).ip();
         let mut prefix_table = self.total_prefixed_connections.lock().unwrap();
 
-        let prefix_count = match prefix_table.entry(get_prefix(addr)) {
+        let prefix = self.get_prefix(addr);
+        let prefix_count = match prefix_table.entry(prefix) {
             Entry::Occupied(o) => o.into_mut(),
             Entry::Vacant(v) => v.insert(0),
         };
@@ -89,9 +201,8 @@ impl GlobalLimits {
         if *prefix_count >= self.max_connections_shared_prefix {
             self.metrics.connections_rejected_prefix.inc();
             bail!(format!(
-                "Maximum connection limit of {} reached for IP prefix {:?}.",
-                self.max_connections_shared_prefix,
-                get_prefix(addr)
+                "Maximum connection limit of {} reached for IP prefix {}.",
+                self.max_connections_shared_prefix, prefix
             ))
         }
 
@@ -124,9 +235,13 @@ impl GlobalLimits {
     }
 
     /// Decreases connection count.
-    pub fn dec_connection(&self, addr: &IpAddr) -> Result<(u32, u32)> {
+    pub fn dec_connection(&self, peer: &SocketAddr) -> Result<(u32, u32)> {
+        if self.is_exempt(peer, self.proxied_peers.remove(peer)) {
+            return Ok((self.total_connections(), 0));
+        }
+        let addr = &canonical_addr(peer).ip();
         let mut prefix_table = self.total_prefixed_connections.lock().unwrap();
-        let prefix_count = match prefix_table.get_mut(&get_prefix(addr)) {
+        let prefix_count = match prefix_table.get_mut(&self.get_prefix(addr)) {
             Some(count) => {
                 *count -= 1;
                 *count
@@ -154,6 +269,73 @@ impl GlobalLimits {
         Ok((c as u32, prefix_count))
     }
 
+    /// Register a request from `addr` being processed. Fails if the IP has
+    /// too many requests in flight. The request is done when the returned
+    /// guard is dropped.
+    pub fn start_request(&self, addr: &IpAddr) -> Result<RequestGuard<'_>> {
+        let mut inflight = self.inflight_requests.lock().unwrap();
+        let count = inflight.entry(*addr).or_insert(0);
+        if self.max_requests_per_ip > 0 && *count >= self.max_requests_per_ip {
+            self.metrics.requests_rejected_ip.inc();
+            bail!(ErrorKind::RpcError(
+                RpcErrorCode::ServerBusy,
+                format!(
+                    "Maximum of {} concurrent requests reached for IP {}, retry later.",
+                    self.max_requests_per_ip, addr
+                )
+            ));
+        }
+        *count += 1;
+        Ok(RequestGuard {
+            limits: self,
+            addr: *addr,
+        })
+    }
+
+    fn end_request(&self, addr: &IpAddr) {
+        let mut inflight = self.inflight_requests.lock().unwrap();
+        if let Entry::Occupied(mut count) = inflight.entry(*addr) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+            }
+        }
+    }
+
+    /// Fails if `total_subscriptions`, over all connections, exceeds the
+    /// global subscription limit.
+    pub fn check_subscriptions(&self, total_subscriptions: u32) -> Result<()> {
+        if self.max_subscriptions_total == 0 || total_subscriptions <= self.max_subscriptions_total
+        {
+            return Ok(());
+        }
+        self.metrics.subscriptions_rejected_global.inc();
+        Err(rpc_invalid_request(format!(
+            "Server subscriptions limit reached (max {})",
+            self.max_subscriptions_total
+        ))
+        .into())
+    }
+
+    fn is_exempt(&self, peer: &SocketAddr, proxied: bool) -> bool {
+        self.exempt_loopback && is_loopback(&peer.ip()) && !proxied
+    }
+
+    /// Registry of the websocket proxy connections
+    pub fn proxied_peers(&self) -> Arc<ProxiedPeers> {
+        Arc::clone(&self.proxied_peers)
+    }
+
+    /// The prefix `addr` is limited by, along with the other IPs sharing it.
+    pub fn get_prefix(&self, addr: &IpAddr) -> IpAddr {
+        get_prefix(addr, self.ipv4_prefix_len, self.ipv6_prefix_len)
+    }
+
+    /// Current number of connections
+    pub fn total_connections(&self) -> u32 {
+        self.total_connections.load(Ordering::SeqCst).max(0) as u32
+    }
+
     /// connection limits as a tuple
     pub fn connection_limits(&self) -> (u32, u32) {
         (
@@ -163,6 +345,18 @@ impl GlobalLimits {
     }
 }
 
+/// A request in flight, see `GlobalLimits::start_request`
+pub struct RequestGuard<'a> {
+    limits: &'a GlobalLimits,
+    addr: IpAddr,
+}
+
+impl Drop for RequestGuard<'_> {
+    fn drop(&mut self) {
+        self.limits.end_request(&self.addr);
+    }
+}
+
 /// DoS limits per connection
 #[derive(Clone, Copy)]
 pub struct ConnectionLimits {
@@ -175,18 +369,68 @@ pub struct ConnectionLimits {
     /// Maximum number of bytes used to alias scripthash subscriptions.
     /// (scripthash aliased by bitcoin cash address)
     pub max_alias_bytes: u32,
+
+    /// Maximum number of items in a batch request
+    pub max_batch_size: u32,
+
+    /// Maximum (estimated) memory used by scripthash subscriptions, including
+    /// aliases.
+    pub max_subscription_bytes: u32,
+
+    /// Maximum size of a broadcasted transaction (in bytes)
+    pub max_tx_size: u32,
+
+    /// Disconnect the peer when it makes no request for this long
+    pub idle_timeout: Option<Duration>,
+
+    /// Maximum number of headers returned by blockchain.block.headers
+    pub max_headers: u32,
+
+    /// Maximum number of history entries extended with tx_info
+    pub max_tx_info_items: u32,
+
+    /// Maximum number of previous transactions loaded per request to compute
+    /// the fees of history entries extended with tx_info
+    pub max_tx_info_prevouts: u32,
+
+    /// Maximum number of previous transactions loaded to add prevout amounts
+    /// to a verbose transaction
+    pub max_prevout_txs: u32,
+
+    /// Maximum number of merkle proofs returned with unspent outputs
+    pub max_utxo_proofs: u32,
+
+    /// Maximum number of registrations returned for a cashaccount name range
+    pub max_cashaccount_registrations: u32,
+
+    /// Maximum number of transactions returned for an OP_RETURN prefix
+    pub max_opreturn_txs: u32,
 }
 
-/// Limits specific for a connecting peer.
-impl ConnectionLimits {
-    pub fn new(rpc_timeout: u16, max_subscriptions: u32, max_alias_bytes: u32) -> ConnectionLimits {
+/// The defaults of the corresponding options, see `Config::connection_limits`
+impl Default for ConnectionLimits {
+    fn default() -> ConnectionLimits {
         ConnectionLimits {
-            rpc_timeout,
-            max_subscriptions,
-            max_alias_bytes,
+            rpc_timeout: 10,
+            max_subscriptions: 250_000,
+            max_alias_bytes: 100_000,
+            max_batch_size: 1000,
+            max_subscription_bytes: 50_000_000,
+            max_tx_size: 1_000_000,
+            idle_timeout: None,
+            max_headers: 2016,
+            max_tx_info_items: 1000,
+            max_tx_info_prevouts: 5000,
+            max_prevout_txs: 1000,
+            max_utxo_proofs: 1000,
+            max_cashaccount_registrations: 1000,
+            max_opreturn_txs: 1000,
         }
     }
+}
 
+/// Limits specific for a connecting peer.
+impl ConnectionLimits {
     pub fn check_subscriptions(&self, num_subscriptions: u32) -> Result<()> {
         if num_subscriptions <= self.max_subscriptions as u32 {
             return Ok(());
@@ -211,6 +455,30 @@ impl ConnectionLimits {
         ))
         .into())
     }
+
+    pub fn check_subscription_memory(&self, bytes_used: usize) -> Result<()> {
+        if bytes_used <= self.max_subscription_bytes as usize {
+            return Ok(());
+        }
+
+        Err(rpc_invalid_request(format!(
+            "Subscriptions memory limit reached (max {} bytes)",
+            self.max_subscription_bytes
+        ))
+        .into())
+    }
+
+    pub fn check_batch_size(&self, batch_size: usize) -> Result<()> {
+        if batch_size <= self.max_batch_size as usize {
+            return Ok(());
+        }
+
+        Err(rpc_invalid_request(format!(
+            "Batch size of {} exceeds limit (max {})",
+            batch_size, self.max_batch_size
+        ))
+        .into())
+    }
 }
 
 #[cfg(test)]
@@ -218,12 +486,69 @@ mod test {
     use super::*;
     use std::net::{Ipv4Addr, Ipv6Addr};
 
+    #[test]
+    fn test_concurrent_requests_per_ip() {
+        let metrics = Metrics::dummy();
+        let limits = Arc::new(GlobalLimits::new(100, 100, 16, 16, 3, 0, false, &metrics));
+        let ip: IpAddr = Ipv4Addr::new(1, 2, 3, 4).into();
+        let other_ip: IpAddr = Ipv4Addr::new(1, 2, 3, 5).into();
+
+        // Requests in flight on three connections from the same IP
+        let (started, done) = std::sync::mpsc::channel();
+        let release = Arc::new(std::sync::Barrier::new(4));
+        let threads: Vec<_> = (0..3)
+            .map(|_| {
+                let started = started.clone();
+                let (limits, release) = (Arc::clone(&limits), Arc::clone(&release));
+                std::thread::spawn(move || {
+                    let _request = limits.start_request(&ip).unwrap();
+                    started.send(()).unwrap();
+                    release.wait();
+                })
+            })
+            .collect();
+        for _ in 0..3 {
+            done.recv().unwrap();
+        }
+
+        // A fourth concurrent request is rejected with a retryable error
+        match limits.start_request(&ip) {
+            Err(Error(ErrorKind::RpcError(RpcErrorCode::ServerBusy, _), _)) => (),
+            _ => panic!("expected server busy error"),
+        }
+        assert_eq!(limits.metrics.requests_rejected_ip.get(), 1);
+        // Other IPs are not affected
+        assert!(limits.start_request(&other_ip).is_ok());
+        release.wait();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Slots are released when the requests return
+        let requests: Vec<RequestGuard> =
+            (0..3).map(|_| limits.start_request(&ip).unwrap()).collect();
+        assert!(limits.start_request(&ip).is_err());
+        drop(requests);
+        assert!(limits.inflight_requests.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unlimited_concurrent_requests() {
+        let metrics = Metrics::dummy();
+        let limits = GlobalLimits::new(100, 100, 16, 16, 0, 0, false, &metrics);
+        let ip: IpAddr = Ipv4Addr::new(1, 2, 3, 4).into();
+        let requests: Vec<RequestGuard> = (0..1000)
+            .map(|_| limits.start_request(&ip).unwrap())
+            .collect();
+        assert_eq!(requests.len(), 1000);
+    }
+
     #[test]
     fn test_ip_shared_prefix() {
         let metrics = Metrics::dummy();
 
         let prefix_limit = 2;
-        let limits = GlobalLimits::new(100, prefix_limit, &metrics);
+        let limits = GlobalLimits::new(100, prefix_limit, 16, 16, 0, 0, false, &metrics);
 
         // Set of 3 ips that share the same two-octest prefix
         let ipv4_addr1 = Ipv4Addr::new(1, 2, 0, 4);
@@ -240,24 +565,206 @@ mod test {
 
         // Ipv4
         //
-        assert_eq!(limits.inc_connection(&ipv4_addr1.into()).unwrap(), (1, 1));
-        assert_eq!(limits.inc_connection(&ipv4_addr2.into()).unwrap(), (2, 2));
-        assert!(limits.inc_connection(&ipv4_addr3.into()).is_err());
-        assert_eq!(limits.inc_connection(&ipv4_addr4.into()).unwrap(), (3, 1));
+        assert_eq!(
+            limits.inc_connection(&(ipv4_addr1, 0).into()).unwrap(),
+            (1, 1)
+        );
+        assert_eq!(
+            limits.inc_connection(&(ipv4_addr2, 0).into()).unwrap(),
+            (2, 2)
+        );
+        assert!(limits.inc_connection(&(ipv4_addr3, 0).into()).is_err());
+        assert_eq!(
+            limits.inc_connection(&(ipv4_addr4, 0).into()).unwrap(),
+            (3, 1)
+        );
 
         // Disconnecting addr1 should allow for addr3 to connect
-        assert_eq!(limits.dec_connection(&ipv4_addr1.into()).unwrap(), (2, 1));
-        assert_eq!(limits.inc_connection(&ipv4_addr3.into()).unwrap(), (3, 2));
+        assert_eq!(
+            limits.dec_connection(&(ipv4_addr1, 0).into()).unwrap(),
+            (2, 1)
+        );
+        assert_eq!(
+            limits.inc_connection(&(ipv4_addr3, 0).into()).unwrap(),
+            (3, 2)
+        );
 
         // Ipv6
         //
-        assert_eq!(limits.inc_connection(&ipv6_addr1.into()).unwrap(), (4, 1));
-        assert_eq!(limits.inc_connection(&ipv6_addr2.into()).unwrap(), (5, 2));
-        assert!(limits.inc_connection(&ipv6_addr3.into()).is_err());
-        assert_eq!(limits.inc_connection(&ipv6_addr4.into()).unwrap(), (6, 1));
+        assert_eq!(
+            limits.inc_connection(&(ipv6_addr1, 0).into()).unwrap(),
+            (4, 1)
+        );
+        assert_eq!(
+            limits.inc_connection(&(ipv6_addr2, 0).into()).unwrap(),
+            (5, 2)
+        );
+        assert!(limits.inc_connection(&(ipv6_addr3, 0).into()).is_err());
+        assert_eq!(
+            limits.inc_connection(&(ipv6_addr4, 0).into()).unwrap(),
+            (6, 1)
+        );
 
         // Disconnecting addr1 should allow for addr3 to connect
-        assert_eq!(limits.dec_connection(&ipv6_addr1.into()).unwrap(), (5, 1));
-        assert_eq!(limits.inc_connection(&ipv6_addr3.into()).unwrap(), (6, 2));
+        assert_eq!(
+            limits.dec_connection(&(ipv6_addr1, 0).into()).unwrap(),
+            (5, 1)
+        );
+        assert_eq!(
+            limits.inc_connection(&(ipv6_addr3, 0).into()).unwrap(),
+            (6, 2)
+        );
+    }
+
+    #[test]
+    fn test_loopback_exemption() {
+        let metrics = Metrics::dummy();
+        let remote: SocketAddr = (Ipv4Addr::new(1, 2, 3, 4), 1000).into();
+        let loopbacks: Vec<SocketAddr> = vec![
+            (Ipv4Addr::LOCALHOST, 1000).into(),
+            (Ipv4Addr::new(127, 0, 2, 3), 1000).into(),
+            (Ipv6Addr::LOCALHOST, 1000).into(),
+            (Ipv4Addr::LOCALHOST.to_ipv6_mapped(), 1001).into(),
+        ];
+
+        // Without the exemption, loopback clients are limited like others
+        let limits = GlobalLimits::new(2, 1, 16, 64, 0, 0, false, &metrics);
+        assert_eq!(limits.inc_connection(&loopbacks[0]).unwrap(), (1, 1));
+        assert!(limits.inc_connection(&loopbacks[1]).is_err());
+
+        let limits = GlobalLimits::new(2, 1, 16, 64, 0, 0, true, &Metrics::dummy());
+        for _ in 0..10 {
+            for addr in &loopbacks {
+                assert_eq!(limits.inc_connection(addr).unwrap(), (0, 0));
+            }
+        }
+        // Remote clients are fully limited, regardless of loopback clients
+        assert_eq!(limits.inc_connection(&remote).unwrap(), (1, 1));
+        assert!(limits.inc_connection(&remote).is_err());
+        let other: SocketAddr = (Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 1000).into();
+        assert_eq!(limits.inc_connection(&other).unwrap(), (2, 1));
+        let third: SocketAddr = (Ipv4Addr::new(5, 6, 7, 8), 1000).into();
+        assert!(limits.inc_connection(&third).is_err());
+        // but loopback clients can still connect
+        assert_eq!(limits.inc_connection(&loopbacks[2]).unwrap(), (2, 0));
+
+        for addr in &loopbacks {
+            assert_eq!(limits.dec_connection(addr).unwrap(), (2, 0));
+        }
+        assert_eq!(limits.dec_connection(&remote).unwrap(), (1, 0));
+        assert_eq!(limits.total_connections(), 1);
+    }
+
+    #[test]
+    fn test_proxied_peers_not_exempt() {
+        let limits = GlobalLimits::new(3, 2, 16, 64, 0, 0, true, &Metrics::dummy());
+        let proxied = limits.proxied_peers();
+        let ws_client: SocketAddr = (Ipv4Addr::LOCALHOST, 2000).into();
+        let ws_client_v6: SocketAddr = (Ipv4Addr::LOCALHOST.to_ipv6_mapped(), 2001).into();
+        let wallet: SocketAddr = (Ipv4Addr::LOCALHOST, 2002).into();
+        proxied.add(&ws_client);
+        // registered as IPv4, accepted on a dual stack socket
+        proxied.add(&(Ipv4Addr::LOCALHOST, 2001).into());
+
+        // the clients of the websocket proxy share the limits of 127.0.0.1
+        assert_eq!(limits.inc_connection(&ws_client).unwrap(), (1, 1));
+        assert_eq!(limits.inc_connection(&ws_client_v6).unwrap(), (2, 2));
+        assert_eq!(limits.inc_connection(&wallet).unwrap(), (2, 0));
+        proxied.add(&(Ipv4Addr::LOCALHOST, 2003).into());
+        assert!(limits
+            .inc_connection(&(Ipv4Addr::LOCALHOST, 2003).into())
+            .is_err());
+
+        // closed connections are removed from the registry
+        assert_eq!(limits.dec_connection(&ws_client).unwrap(), (1, 1));
+        assert!(!proxied.contains(&ws_client));
+        assert_eq!(limits.dec_connection(&wallet).unwrap(), (1, 0));
+        assert_eq!(limits.dec_connection(&ws_client_v6).unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn test_ipv6_prefix_len() {
+        let metrics = Metrics::dummy();
+        let limits = GlobalLimits::new(100, 2, 24, 64, 0, 0, false, &metrics);
+
+        // Same /64 network
+        let ipv6_addr1 = Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 0, 0, 0, 1);
+        let ipv6_addr2 = Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 0xffff, 0, 0, 2);
+        let ipv6_addr3 = Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 1, 2, 3, 4);
+        // Same /48, but a different /64
+        let ipv6_addr4 = Ipv6Addr::new(0x2001, 0xdb8, 1, 3, 0, 0, 0, 1);
+
+        assert_eq!(
+            limits.inc_connection(&(ipv6_addr1, 0).into()).unwrap(),
+            (1, 1)
+        );
+        assert_eq!(
+            limits.inc_connection(&(ipv6_addr2, 0).into()).unwrap(),
+            (2, 2)
+        );
+        assert!(limits.inc_connection(&(ipv6_addr3, 0).into()).is_err());
+        assert_eq!(
+            limits.inc_connection(&(ipv6_addr4, 0).into()).unwrap(),
+            (3, 1)
+        );
+        assert_eq!(
+            limits.dec_connection(&(ipv6_addr2, 0).into()).unwrap(),
+            (2, 1)
+        );
+        assert_eq!(
+            limits.inc_connection(&(ipv6_addr3, 0).into()).unwrap(),
+            (3, 2)
+        );
+
+        // IPv4 /24
+        let ipv4_addr1 = Ipv4Addr::new(1, 2, 3, 4);
+        let ipv4_addr2 = Ipv4Addr::new(1, 2, 3, 5);
+        let ipv4_addr3 = Ipv4Addr::new(1, 2, 3, 6);
+        let ipv4_addr4 = Ipv4Addr::new(1, 2, 4, 4);
+        assert_eq!(
+            limits.inc_connection(&(ipv4_addr1, 0).into()).unwrap(),
+            (4, 1)
+        );
+        assert_eq!(
+            limits.inc_connection(&(ipv4_addr2, 0).into()).unwrap(),
+            (5, 2)
+        );
+        assert!(limits.inc_connection(&(ipv4_addr3, 0).into()).is_err());
+        assert_eq!(
+            limits.inc_connection(&(ipv4_addr4, 0).into()).unwrap(),
+            (6, 1)
+        );
+    }
+
+    #[test]
+    fn test_get_prefix() {
+        let ipv4: IpAddr = Ipv4Addr::new(1, 2, 3, 4).into();
+        assert_eq!(
+            get_prefix(&ipv4, 16, 64),
+            "1.2.0.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            get_prefix(&ipv4, 0, 64),
+            "0.0.0.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(get_prefix(&ipv4, 32, 64), ipv4);
+        let ipv6: IpAddr = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+        assert_eq!(
+            get_prefix(&ipv6, 16, 64),
+            "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(get_prefix(&ipv6, 16, 128), ipv6);
+        assert_eq!(get_prefix(&ipv6, 16, 0), "::".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_subscription_memory_limit() {
+        let limits = ConnectionLimits {
+            max_subscription_bytes: 1000,
+            ..ConnectionLimits::default()
+        };
+        assert!(limits.check_subscription_memory(0).is_ok());
+        assert!(limits.check_subscription_memory(1000).is_ok());
+        assert!(limits.check_subscription_memory(1001).is_err());
     }
 }
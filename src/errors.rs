@@ -5,7 +5,10 @@ pub enum RpcErrorCode {
     MethodNotFound = -32601,
     InvalidParams = -32602,
     InternalError = -32603,
-    Other = -32000, /* Range -32000 to -32099 is serve defined */
+    Other = -32000,      /* Range -32000 to -32099 is serve defined */
+    ServerBusy = -32001, /* Request may be retried later */
+    Syncing = -32002,    /* Request may be retried once the server is synced */
+    Pruned = -32003,     /* Data was pruned by bitcoind, retrying won't help */
     NotFound = -32004,
     Timeout = -32005,
 }
@@ -21,6 +24,11 @@ error_chain! {
             display("Connection error: {}", msg)
         }
 
+        DaemonTimeout(msg: String) {
+            description("Daemon timeout")
+            display("Daemon timed out: {}", msg)
+        }
+
         Interrupt(sig: i32) {
             description("Interruption by external signal")
             display("Interrupted by signal {}", sig)
@@ -38,6 +46,30 @@ error_chain! {
     }
 }
 
+/// Report a daemon timeout to RPC clients as such, rather than as an internal
+/// error.
+pub fn rpc_daemon_timeout(e: Error) -> Error {
+    match e.kind() {
+        ErrorKind::DaemonTimeout(msg) => ErrorKind::RpcError(
+            RpcErrorCode::Timeout,
+            format!("bitcoind did not respond in time ({})", msg),
+        )
+        .into(),
+        _ => e,
+    }
+}
+
+/// Adds `msg` to the description of `e`. Unlike `chain_err`, the code of an
+/// RPC error is kept, so that clients can still tell its cause apart.
+pub fn rpc_context(e: Error, msg: &str) -> Error {
+    match e.kind() {
+        ErrorKind::RpcError(code, what) => {
+            ErrorKind::RpcError(*code, format!("{}: {}", msg, what)).into()
+        }
+        _ => Error::with_chain(e, msg),
+    }
+}
+
 pub fn rpc_invalid_request(what: String) -> ErrorKind {
     ErrorKind::RpcError(RpcErrorCode::InvalidRequest, what)
 }
@@ -1,11 +1,16 @@
-use crate::store::{ReadStore, Row, WriteStore};
+use crate::errors::*;
+use crate::store::{is_up_to, ReadStore, Row, WriteStore};
 use crate::util::Bytes;
+use bitcoincash::blockdata::block::BlockHeader;
+use bitcoincash::hash_types::{BlockHash, TxMerkleNode};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
 
 pub struct FakeStore;
 
 impl ReadStore for FakeStore {
-    fn get(&self, _key: &[u8]) -> Option<Bytes> {
-        None
+    fn get(&self, _key: &[u8]) -> Result<Option<Bytes>> {
+        Ok(None)
     }
     fn scan(&self, _prefix: &[u8]) -> Vec<Row> {
         vec![]
@@ -13,8 +18,159 @@ impl ReadStore for FakeStore {
 }
 
 impl WriteStore for FakeStore {
-    fn write<I: IntoIterator<Item = Row>>(&self, _rows: I, _sync: bool) {}
-    fn flush(&self) {}
+    fn write<I: IntoIterator<Item = Row>>(&self, _rows: I, _sync: bool) -> Result<()> {
+        Ok(())
+    }
+    fn delete<I: IntoIterator<Item = Bytes>>(&self, _keys: I) -> Result<()> {
+        Ok(())
+    }
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Store where every read and write fails, like a DB with a broken disk
+pub struct FailingStore;
+
+impl ReadStore for FailingStore {
+    fn get(&self, _key: &[u8]) -> Result<Option<Bytes>> {
+        bail!("get failed")
+    }
+    fn scan(&self, _prefix: &[u8]) -> Vec<Row> {
+        vec![]
+    }
+}
+
+impl WriteStore for FailingStore {
+    fn write<I: IntoIterator<Item = Row>>(&self, _rows: I, _sync: bool) -> Result<()> {
+        bail!("write failed")
+    }
+    fn delete<I: IntoIterator<Item = Bytes>>(&self, _keys: I) -> Result<()> {
+        bail!("delete failed")
+    }
+    fn flush(&self) -> Result<()> {
+        bail!("flush failed")
+    }
+}
+
+/// In-memory store
+#[derive(Default)]
+pub struct MemStore {
+    map: Mutex<BTreeMap<Bytes, Bytes>>,
+    /// Writes not flushed yet, when buffering writes
+    pending: Option<Mutex<Vec<Row>>>,
+}
+
+impl MemStore {
+    /// Store holding `rows`
+    pub fn with_rows(rows: Vec<Row>) -> MemStore {
+        let store = MemStore::default();
+        store.write(rows, false).unwrap();
+        store
+    }
+
+    /// Store where writes become visible on flush, and are lost on a
+    /// `crash` before it.
+    pub fn buffered() -> MemStore {
+        MemStore {
+            map: Mutex::default(),
+            pending: Some(Mutex::default()),
+        }
+    }
+
+    /// Drop the writes not flushed yet.
+    pub fn crash(&self) {
+        if let Some(pending) = &self.pending {
+            pending.lock().unwrap().clear();
+        }
+    }
+
+    fn insert<I: IntoIterator<Item = Row>>(&self, rows: I) {
+        let mut map = self.map.lock().unwrap();
+        for row in rows {
+            map.insert(row.key, row.value);
+        }
+    }
+}
+
+impl ReadStore for MemStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        Ok(self.map.lock().unwrap().get(key).cloned())
+    }
+    fn scan(&self, prefix: &[u8]) -> Vec<Row> {
+        self.map
+            .lock()
+            .unwrap()
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| Row {
+                key: k.clone(),
+                value: v.clone(),
+            })
+            .collect()
+    }
+    fn scan_range(&self, start: &[u8], last: &[u8], limit: usize) -> Vec<Row> {
+        self.map
+            .lock()
+            .unwrap()
+            .range(start.to_vec()..)
+            .take_while(|(k, _)| is_up_to(k, last))
+            .take(limit)
+            .map(|(k, v)| Row {
+                key: k.clone(),
+                value: v.clone(),
+            })
+            .collect()
+    }
+}
+
+impl WriteStore for MemStore {
+    fn write<I: IntoIterator<Item = Row>>(&self, rows: I, _sync: bool) -> Result<()> {
+        match &self.pending {
+            Some(pending) => pending.lock().unwrap().extend(rows),
+            None => self.insert(rows),
+        }
+        Ok(())
+    }
+    /// Deletes are applied right away, also to the writes not flushed yet.
+    fn delete<I: IntoIterator<Item = Bytes>>(&self, keys: I) -> Result<()> {
+        let mut map = self.map.lock().unwrap();
+        for key in keys {
+            map.remove(&key);
+            if let Some(pending) = &self.pending {
+                pending.lock().unwrap().retain(|row| row.key != key);
+            }
+        }
+        Ok(())
+    }
+    fn flush(&self) -> Result<()> {
+        if let Some(pending) = &self.pending {
+            let rows: Vec<Row> = pending.lock().unwrap().drain(..).collect();
+            self.insert(rows);
+        }
+        Ok(())
+    }
+}
+
+/// Headers of a chain from genesis at minimum difficulty, one per block time
+/// in `times`. Blocks at the same height and time are the same in any chain.
+pub fn chain_headers(times: &[u32]) -> Vec<BlockHeader> {
+    let mut prev_blockhash = BlockHash::default();
+    (0..)
+        .zip(times)
+        .map(|(nonce, time)| {
+            let header = BlockHeader {
+                version: 1,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::default(),
+                time: *time,
+                bits: 0x1d00ffff,
+                nonce,
+            };
+            prev_blockhash = header.block_hash();
+            header
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -25,16 +181,94 @@ mod tests {
         use crate::store::{ReadStore, Row, WriteStore};
 
         let store = fake::FakeStore {};
-        store.write(
-            vec![Row {
-                key: b"k".to_vec(),
-                value: b"v".to_vec(),
-            }],
-            true,
-        );
-        store.flush();
+        store
+            .write(
+                vec![Row {
+                    key: b"k".to_vec(),
+                    value: b"v".to_vec(),
+                }],
+                true,
+            )
+            .unwrap();
+        store.flush().unwrap();
         // nothing was actually written
-        assert!(store.get(b"").is_none());
+        assert!(store.get(b"").unwrap().is_none());
         assert!(store.scan(b"").is_empty());
     }
+
+    #[test]
+    fn test_memstore() {
+        use crate::fake;
+        use crate::store::{ReadStore, Row, WriteStore};
+
+        let store = fake::MemStore::default();
+        let row = |k: &[u8]| Row {
+            key: k.to_vec(),
+            value: b"v".to_vec(),
+        };
+        store
+            .write(vec![row(b"ab"), row(b"b"), row(b"aa")], false)
+            .unwrap();
+        assert_eq!(store.get(b"b").unwrap(), Some(b"v".to_vec()));
+        let keys: Vec<Vec<u8>> = store.scan(b"a").into_iter().map(|r| r.key).collect();
+        assert_eq!(keys, vec![b"aa".to_vec(), b"ab".to_vec()]);
+
+        store.write(vec![row(b"abc"), row(b"ac")], false).unwrap();
+        // uses the default scan_range, scanning the common prefix
+        struct PrefixScanStore<'a>(&'a fake::MemStore);
+        impl ReadStore for PrefixScanStore<'_> {
+            fn get(&self, key: &[u8]) -> crate::errors::Result<Option<Vec<u8>>> {
+                self.0.get(key)
+            }
+            fn scan(&self, prefix: &[u8]) -> Vec<Row> {
+                self.0.scan(prefix)
+            }
+        }
+        let scan_range = |start: &[u8], last: &[u8], limit| {
+            let keys =
+                |rows: Vec<Row>| -> Vec<Vec<u8>> { rows.into_iter().map(|r| r.key).collect() };
+            let result = keys(store.scan_range(start, last, limit));
+            let default = keys(PrefixScanStore(&store).scan_range(start, last, limit));
+            assert_eq!(result, default);
+            result
+        };
+        assert_eq!(
+            scan_range(b"aa", b"ab", 10),
+            vec![b"aa".to_vec(), b"ab".to_vec(), b"abc".to_vec()]
+        );
+        assert_eq!(
+            scan_range(b"ab", b"b", 2),
+            vec![b"ab".to_vec(), b"abc".to_vec()]
+        );
+        assert!(scan_range(b"ad", b"ae", 10).is_empty());
+
+        store.delete(vec![b"ab".to_vec(), b"ad".to_vec()]).unwrap();
+        assert!(store.get(b"ab").unwrap().is_none());
+        assert_eq!(
+            scan_range(b"aa", b"ab", 10),
+            vec![b"aa".to_vec(), b"abc".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_buffered_memstore() {
+        use crate::fake;
+        use crate::store::{ReadStore, Row, WriteStore};
+
+        let store = fake::MemStore::buffered();
+        let row = |k: &[u8]| Row {
+            key: k.to_vec(),
+            value: b"v".to_vec(),
+        };
+        store.write(vec![row(b"a")], false).unwrap();
+        assert!(store.get(b"a").unwrap().is_none());
+        store.flush().unwrap();
+        assert!(store.get(b"a").unwrap().is_some());
+        // unflushed writes are lost
+        store.write(vec![row(b"b")], false).unwrap();
+        store.crash();
+        store.flush().unwrap();
+        assert!(store.get(b"b").unwrap().is_none());
+        assert_eq!(store.scan(b"").len(), 1);
+    }
 }
@@ -4,13 +4,21 @@ use bitcoincash::blockdata::transaction::{Transaction, TxIn, TxOut};
 use bitcoincash::consensus::encode::{deserialize, serialize};
 use bitcoincash::hash_types::{BlockHash, Txid};
 use bitcoincash::hashes::Hash;
+use bitcoincash::util::uint::Uint256;
+use std::cell::Cell;
 use std::collections::{HashMap, HashSet};
-use std::sync::RwLock;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::thread;
 
+use crate::blockstats::{BlockFeeStats, BlockFeeStatsRow, BlockStats, BlockStatsRow};
+use crate::cache::TransactionCache;
 use crate::cashaccount::CashAccountParser;
 use crate::daemon::Daemon;
 use crate::errors::*;
 use crate::metrics::Metrics;
+use crate::opreturn::index_opreturn;
 use crate::scripthash::{compute_script_hash, full_hash, FullHash};
 use crate::signal::Waiter;
 use crate::store::{ReadStore, Row, WriteStore};
@@ -53,6 +61,12 @@ impl TxInRow {
         .unwrap()
     }
 
+    /// Filter for inputs spending any output of transactions with given
+    /// txid prefix.
+    pub fn filter_prev_txid(prev_hash_prefix: HashPrefix) -> Bytes {
+        [b"I", &prev_hash_prefix[..]].concat()
+    }
+
     pub fn to_row(&self) -> Row {
         Row {
             key: bincode::serialize(&self).unwrap(),
@@ -63,6 +77,10 @@ impl TxInRow {
     pub fn from_row(row: &Row) -> TxInRow {
         bincode::deserialize(&row.key).expect("failed to parse TxInRow")
     }
+
+    pub fn get_prev_index(&self) -> u32 {
+        decode_varint(&self.key.prev_index) as u32
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -179,16 +197,101 @@ impl TxRow {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct FirstUseKey {
+    code: u8,
+    pub script_hash_prefix: HashPrefix,
+    height: [u8; 4], // big endian, so that rows are scanned lowest height first
+    pub txid_prefix: HashPrefix,
+}
+
+/// Output to a scripthash confirmed at a height, for looking up the first
+/// use of a scripthash without loading its history. The block the output
+/// confirmed in is recorded, so that rows of blocks reorged out of the best
+/// chain can be told apart from their replacements. There is a row per
+/// output, as rows are written without reading the index (see schema.md).
+pub struct FirstUseRow {
+    pub key: FirstUseKey,
+    pub blockhash_prefix: HashPrefix, // value
+}
+
+impl FirstUseRow {
+    pub fn new(txid: &Txid, output: &TxOut, height: u32, blockhash: &BlockHash) -> FirstUseRow {
+        FirstUseRow {
+            key: FirstUseKey {
+                code: b'U',
+                script_hash_prefix: hash_prefix(&compute_script_hash(&output.script_pubkey[..])),
+                height: height.to_be_bytes(),
+                txid_prefix: hash_prefix(&txid[..]),
+            },
+            blockhash_prefix: hash_prefix(&blockhash[..]),
+        }
+    }
+
+    pub fn filter(script_hash: &[u8]) -> Bytes {
+        [b"U", &script_hash[..HASH_PREFIX_LEN]].concat()
+    }
+
+    pub fn to_row(&self) -> Row {
+        Row {
+            key: bincode::serialize(&self.key).unwrap(),
+            value: self.blockhash_prefix.to_vec(),
+        }
+    }
+
+    pub fn from_row(row: &Row) -> FirstUseRow {
+        FirstUseRow {
+            key: bincode::deserialize(&row.key).expect("failed to parse FirstUseKey"),
+            blockhash_prefix: hash_prefix(&row.value),
+        }
+    }
+
+    pub fn height(&self) -> u32 {
+        u32::from_be_bytes(self.key.height)
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct BlockKey {
     code: u8,
     hash: FullHash,
 }
 
+/// Outputs left out of the scripthash index, to limit the index growth from
+/// spam. Their transactions are still indexed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputFilter {
+    /// Outputs with a larger locking script (in bytes) are skipped, 0 for no
+    /// limit
+    pub max_script_size: usize,
+    /// Index outputs with a larger script than `max_script_size` anyway,
+    /// hashing their script as-is. They are only counted then.
+    pub hash_oversized: bool,
+    /// Outputs of a lower value (in satoshis) are skipped, 0 to index all
+    pub min_value: u64,
+}
+
+impl OutputFilter {
+    pub fn indexes(&self, output: &TxOut) -> bool {
+        (self.hash_oversized || !self.is_oversized(output)) && output.value >= self.min_value
+    }
+
+    /// Whether the locking script of `output` is over `max_script_size`.
+    pub fn is_oversized(&self, output: &TxOut) -> bool {
+        self.max_script_size != 0 && output.script_pubkey.len() > self.max_script_size
+    }
+}
+
+/// Index `txn`. Outputs rejected by `output_filter` are not added to the
+/// scripthash index. With `first_use`, the hash of the block confirming
+/// `txn`, the other outputs are also added to the first-use index.
 pub fn index_transaction<'a>(
     txn: &'a Transaction,
     height: usize,
     cashaccount: Option<&CashAccountParser>,
+    opreturn: bool,
+    first_use: Option<BlockHash>,
+    output_filter: OutputFilter,
 ) -> impl 'a + Iterator<Item = Row> {
     let null_hash = Txid::default();
     let txid = txn.txid();
@@ -204,39 +307,258 @@ pub fn index_transaction<'a>(
         .output
         .iter()
         .enumerate()
+        .filter(move |(_, output)| output_filter.indexes(output))
         .map(move |(i, output)| TxOutRow::new(&txid, output, i as u64).to_row());
+    let first_use_rows = first_use.into_iter().flat_map(move |blockhash| {
+        first_use_rows(txn, txid, height, blockhash, output_filter).map(|row| row.to_row())
+    });
 
     let cashaccount_row = match cashaccount {
         Some(cashaccount) => cashaccount.index_cashaccount(txn, height as u32),
         None => None,
     };
+    let opreturn_rows = if opreturn {
+        index_opreturn(txn)
+    } else {
+        vec![]
+    };
     // Persist transaction ID and confirmed height
     inputs
         .chain(outputs)
         .chain(std::iter::once(TxRow::new(&txid, height as u32).to_row()))
         .chain(cashaccount_row)
+        .chain(opreturn_rows)
+        .chain(first_use_rows)
+}
+
+/// First-use rows of the outputs of `txn` kept by `output_filter`, confirmed
+/// at `height` in block `blockhash`.
+fn first_use_rows(
+    txn: &Transaction,
+    txid: Txid,
+    height: usize,
+    blockhash: BlockHash,
+    output_filter: OutputFilter,
+) -> impl '_ + Iterator<Item = FirstUseRow> {
+    txn.output
+        .iter()
+        .filter(move |output| output_filter.indexes(output))
+        .map(move |output| FirstUseRow::new(&txid, output, height as u32, &blockhash))
+}
+
+/// Delete the first-use rows of `block`, disconnected from the best chain at
+/// `height`. Queries skip them anyway, as their block hash no longer matches,
+/// but they would be left in the DB for good. This has to happen before the
+/// replacing blocks are indexed, as those may confirm the same transactions at
+/// the same heights, writing the same keys.
+fn delete_first_use_rows(
+    store: &impl WriteStore,
+    block: &Block,
+    height: usize,
+    output_filter: OutputFilter,
+) -> Result<()> {
+    let blockhash = block.block_hash();
+    let keys = block.txdata.iter().flat_map(|txn| {
+        first_use_rows(txn, txn.txid(), height, blockhash, output_filter)
+            .map(|row| row.to_row().key)
+    });
+    store.delete(keys)
+}
+
+/// Headers of the indexed blocks that applying `new_headers`, ending at
+/// `tip`, disconnects from the best chain. Trimmed headers are left out.
+fn disconnected_headers(
+    headers: &HeaderList,
+    new_headers: &[HeaderEntry],
+    tip: &BlockHash,
+) -> Vec<HeaderEntry> {
+    let fork_height = match new_headers.first() {
+        Some(entry) => entry.height(),
+        None => match headers.height_by_blockhash(tip) {
+            Some(height) => height + 1,
+            None => return vec![],
+        },
+    };
+    (fork_height..headers.len())
+        .filter_map(|height| headers.header_by_height(height).cloned())
+        .collect()
 }
 
+/// Index `block` with `options`. With `block_stats`, the size statistics of
+/// the block are stored as well, collected while its transactions are
+/// indexed. With `first_use`, its outputs are added to the first-use index.
 pub fn index_block<'a>(
     block: &'a Block,
     height: usize,
     cashaccount: &'a CashAccountParser,
+    options: IndexOptions,
 ) -> impl 'a + Iterator<Item = Row> {
+    let IndexOptions {
+        opreturn,
+        block_stats,
+        first_use,
+        output_filter,
+        ..
+    } = options;
     let blockhash = block.block_hash();
+    let stats = Rc::new(Cell::new(BlockStats::header_only(block)));
+    let tx_stats = Rc::clone(&stats);
     // Persist block hash and header
-    let row = Row {
+    let row = block_header_row(&block.header);
+    block
+        .txdata
+        .iter()
+        .inspect(move |txn| {
+            if block_stats {
+                let mut stats = tx_stats.get();
+                stats.add_tx(txn);
+                tx_stats.set(stats);
+            }
+        })
+        .flat_map(move |txn| {
+            index_transaction(
+                txn,
+                height,
+                Some(cashaccount),
+                opreturn,
+                first_use.then(|| blockhash),
+                output_filter,
+            )
+        })
+        .chain(std::iter::once(row))
+        .chain(
+            std::iter::once_with(move || {
+                block_stats.then(|| BlockStatsRow::new(&blockhash, stats.get()).to_row())
+            })
+            .flatten(),
+        )
+}
+
+/// Row with the header of an indexed block, keyed by block hash.
+pub fn block_header_row(header: &BlockHeader) -> Row {
+    Row {
         key: bincode::serialize(&BlockKey {
             code: b'B',
-            hash: full_hash(&blockhash[..]),
+            hash: full_hash(&header.block_hash()[..]),
         })
         .unwrap(),
-        value: serialize(&block.header),
+        value: serialize(header),
+    }
+}
+
+/// Row recording the `index_start_height` the DB was indexed with. Changing
+/// it requires a reindex.
+pub fn start_height_marker(height: usize) -> Row {
+    Row {
+        key: b"H".to_vec(),
+        value: serialize(&(height as u64)),
+    }
+}
+
+/// The `index_start_height` the DB was indexed with, or None if nothing was
+/// indexed yet. DBs indexed before the start height was recorded start at 0.
+pub fn read_start_height(store: &dyn ReadStore) -> Result<Option<usize>> {
+    if let Some(value) = store.get(&start_height_marker(0).key)? {
+        let height = deserialize::<u64>(&value).chain_err(|| "invalid start height")?;
+        return Ok(Some(height as usize));
+    }
+    let last_indexed = store.get(&last_indexed_block(&BlockHash::default()).key)?;
+    Ok(last_indexed.map(|_| 0))
+}
+
+/// Options changing which rows are indexed. The DB records the options it
+/// was indexed with, as changing them on an existing index gives wrong
+/// results: enabling `opreturn` later would miss the prefixes of the
+/// transactions indexed before, enabling `first_use` later would report the
+/// outputs indexed before as never used, and outputs left out by an earlier
+/// `output_filter` would stay missing from the history of their scripthash.
+///
+/// Block stats and block fees are not recorded: they only apply to the blocks
+/// indexed after enabling them, and queries of other blocks report them as
+/// missing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexOptions {
+    pub opreturn: bool,
+    pub first_use: bool,
+    pub output_filter: OutputFilter,
+    #[serde(skip)]
+    pub block_stats: bool,
+    #[serde(skip)]
+    pub block_fees: bool,
+}
+
+impl IndexOptions {
+    /// The options recorded in the DB, see `index_options_marker`.
+    fn recorded(&self) -> IndexOptions {
+        IndexOptions {
+            block_stats: false,
+            block_fees: false,
+            ..*self
+        }
+    }
+}
+
+impl std::fmt::Display for IndexOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "index_opreturn={}, index_first_use={}, index_max_script_size={}, \
+            index_hash_oversized_scripts={}, index_min_output_value={}",
+            self.opreturn,
+            self.first_use,
+            self.output_filter.max_script_size,
+            self.output_filter.hash_oversized,
+            self.output_filter.min_value
+        )
+    }
+}
+
+/// Row recording the `IndexOptions` the DB was indexed with.
+pub fn index_options_marker(options: &IndexOptions) -> Row {
+    Row {
+        key: b"P".to_vec(),
+        value: bincode::serialize(options).unwrap(),
+    }
+}
+
+/// The options the DB was indexed with, or None if nothing was indexed yet.
+/// DBs indexed before the options were recorded have the default options.
+pub fn read_index_options(store: &dyn ReadStore) -> Result<Option<IndexOptions>> {
+    let key = index_options_marker(&IndexOptions::default()).key;
+    if let Some(value) = store.get(&key)? {
+        let options = bincode::deserialize(&value).chain_err(|| "invalid index options")?;
+        return Ok(Some(options));
+    }
+    let last_indexed = store.get(&last_indexed_block(&BlockHash::default()).key)?;
+    Ok(last_indexed.map(|_| IndexOptions::default()))
+}
+
+/// Whether the DB has to be rebuilt, as it was indexed with other options.
+/// Like for an incompatible DB version, rebuilding is only allowed with
+/// `reindex_incompatible`; otherwise changed options are an error.
+pub fn options_need_reindex(
+    store: &dyn ReadStore,
+    options: &IndexOptions,
+    reindex_incompatible: bool,
+) -> Result<bool> {
+    let indexed = match read_index_options(store)? {
+        Some(indexed) if indexed != options.recorded() => indexed,
+        _ => return Ok(false),
     };
-    block
-        .txdata
-        .iter()
-        .flat_map(move |txn| index_transaction(txn, height, Some(cashaccount)))
-        .chain(std::iter::once(row))
+    if !reindex_incompatible {
+        bail!(
+            "the database was indexed with {}, not {}. Restart with the \
+            same options, or with --reindex-incompatible-db to rebuild the index",
+            indexed,
+            options
+        );
+    }
+    warn!(
+        "the database was indexed with {}, not {}: \
+        DESTROYING THE INDEX, it will be rebuilt from scratch",
+        indexed, options
+    );
+    Ok(true)
 }
 
 pub fn last_indexed_block(blockhash: &BlockHash) -> Row {
@@ -247,6 +569,71 @@ pub fn last_indexed_block(blockhash: &BlockHash) -> Row {
     }
 }
 
+/// Index `block` and write the resulting rows to `store`.
+///
+/// When `checkpoint` is set, the 'last indexed' marker is moved to this block
+/// and the store is flushed. If indexing is interrupted, it resumes from the
+/// last checkpoint.
+fn write_block(
+    store: &impl WriteStore,
+    block: &Block,
+    height: usize,
+    cashaccount: &CashAccountParser,
+    options: IndexOptions,
+    checkpoint: bool,
+) -> Result<()> {
+    let indexed = index_block(block, height, cashaccount, options);
+    if checkpoint {
+        store.write(
+            indexed.chain(std::iter::once(last_indexed_block(&block.block_hash()))),
+            false,
+        )?;
+        store.flush()
+    } else {
+        store.write(indexed, false)
+    }
+}
+
+/// Compute the fee stats of `block` and write them to `store`, looking up the
+/// value of outputs it spends with `prevout_value`.
+///
+/// Prevouts below the index start height, or of a node without txindex, can't
+/// be looked up. Such a block is left without fee stats rather than stalling
+/// indexing.
+fn write_block_fee_stats<F>(store: &impl WriteStore, block: &Block, prevout_value: F) -> Result<()>
+where
+    F: FnMut(&OutPoint) -> Result<u64>,
+{
+    let blockhash = block.block_hash();
+    match BlockFeeStats::from_block(block, prevout_value) {
+        Ok(fees) => store.write(
+            std::iter::once(BlockFeeStatsRow::new(&blockhash, fees).to_row()),
+            false,
+        ),
+        Err(e) => {
+            warn!("no fee stats for block {}: {}", blockhash, e);
+            Ok(())
+        }
+    }
+}
+
+/// Store only the headers of blocks below `index_start_height`, and move the
+/// 'last indexed' marker past them.
+fn write_headers(store: &impl WriteStore, headers: &[HeaderEntry]) -> Result<()> {
+    let last = match headers.last() {
+        Some(last) => last,
+        None => return Ok(()),
+    };
+    store.write(
+        headers
+            .iter()
+            .map(|entry| block_header_row(entry.header()))
+            .chain(std::iter::once(last_indexed_block(last.hash()))),
+        false,
+    )?;
+    store.flush()
+}
+
 pub fn read_indexed_blockhashes(store: &dyn ReadStore) -> HashSet<BlockHash> {
     let mut result = HashSet::new();
     for row in store.scan(b"B") {
@@ -256,8 +643,27 @@ pub fn read_indexed_blockhashes(store: &dyn ReadStore) -> HashSet<BlockHash> {
     result
 }
 
-fn read_indexed_headers(store: &dyn ReadStore) -> HeaderList {
-    let latest_blockhash: BlockHash = match store.get(b"L") {
+/// Header of an indexed block, read from the DB.
+/// A failing read is logged and treated as a missing header, so that
+/// callers report the header as not found.
+fn read_indexed_header(store: &dyn ReadStore, blockhash: &BlockHash) -> Option<BlockHeader> {
+    let key = bincode::serialize(&BlockKey {
+        code: b'B',
+        hash: full_hash(&blockhash[..]),
+    })
+    .unwrap();
+    let value = match store.get(&key) {
+        Ok(value) => value?,
+        Err(e) => {
+            warn!("failed to read header {}: {}", blockhash, e);
+            return None;
+        }
+    };
+    Some(deserialize(&value).expect("failed to parse BlockHeader"))
+}
+
+fn read_indexed_headers(store: &dyn ReadStore) -> Result<HeaderList> {
+    let latest_blockhash: BlockHash = match store.get(b"L")? {
         // latest blockheader persisted in the DB.
         Some(row) => deserialize(&row).unwrap(),
         None => BlockHash::default(),
@@ -297,13 +703,14 @@ fn read_indexed_headers(store: &dyn ReadStore) -> HeaderList {
     let mut result = HeaderList::empty();
     let entries = result.order(headers);
     result.apply(&entries, latest_blockhash);
-    result
+    Ok(result)
 }
 
 struct Stats {
     blocks: prometheus::IntCounter,
     txns: prometheus::IntCounter,
     vsize: prometheus::IntCounter,
+    oversized_outputs: prometheus::IntCounter,
     height: prometheus::IntGauge,
     duration: prometheus::HistogramVec,
 }
@@ -323,6 +730,10 @@ impl Stats {
                 "electrscash_index_vsize",
                 "# of indexed vbytes",
             )),
+            oversized_outputs: metrics.counter_int(prometheus::Opts::new(
+                "electrscash_index_oversized_outputs",
+                "# of indexed outputs with a script over index_max_script_size",
+            )),
             height: metrics.gauge_int(prometheus::Opts::new(
                 "electrscash_index_height",
                 "Last indexed block's height",
@@ -337,11 +748,16 @@ impl Stats {
         }
     }
 
-    fn update(&self, block: &Block, height: usize) {
+    fn update(&self, block: &Block, height: usize, output_filter: &OutputFilter) {
         self.blocks.inc();
         self.txns.inc_by(block.txdata.len() as u64);
         for tx in &block.txdata {
             self.vsize.inc_by((tx.get_weight() / 4) as u64);
+            let oversized = tx
+                .output
+                .iter()
+                .filter(|output| output_filter.is_oversized(output));
+            self.oversized_outputs.inc_by(oversized.count() as u64);
         }
         self.update_height(height);
     }
@@ -355,38 +771,277 @@ impl Stats {
     }
 }
 
+/// Fetches blocks in parallel, using a thread per fetch function. Block `i`
+/// is fetched by fetcher `i % fetchers`, so that blocks can be received in
+/// order while later blocks are being fetched.
+struct OrderedFetcher<T> {
+    channels: Vec<SyncChannel<Result<T>>>,
+    threads: Vec<thread::JoinHandle<()>>,
+    next: usize,
+    count: usize,
+}
+
+impl<T: Send + 'static> OrderedFetcher<T> {
+    /// Each fetcher buffers up to `capacity` blocks.
+    fn start<F>(blockhashes: Vec<BlockHash>, fetchers: Vec<F>, capacity: usize) -> Self
+    where
+        F: FnMut(&BlockHash) -> Result<T> + Send + 'static,
+    {
+        assert!(!fetchers.is_empty());
+        let step = fetchers.len();
+        let count = blockhashes.len();
+        let blockhashes = Arc::new(blockhashes);
+        let mut channels = vec![];
+        let mut threads = vec![];
+        for (first, mut fetch) in fetchers.into_iter().enumerate() {
+            let chan = SyncChannel::new(capacity);
+            let sender = chan.sender();
+            let blockhashes = Arc::clone(&blockhashes);
+            threads.push(spawn_thread("fetcher", move || {
+                // Daemon requests are retried on connection errors (see
+                // `daemon_retry_limit`), so a failure here is final.
+                for blockhash in blockhashes.iter().skip(first).step_by(step) {
+                    let block = fetch(blockhash);
+                    let failed = block.is_err();
+                    if sender.send(block).is_err() || failed {
+                        return; // the indexer has stopped
+                    }
+                }
+            }));
+            channels.push(chan);
+        }
+        OrderedFetcher {
+            channels,
+            threads,
+            next: 0,
+            count,
+        }
+    }
+
+    /// Returns the next block, or None after the last one.
+    fn next(&mut self) -> Option<Result<T>> {
+        if self.next == self.count {
+            return None;
+        }
+        let chan = &self.channels[self.next % self.channels.len()];
+        self.next += 1;
+        Some(
+            chan.receiver()
+                .recv()
+                .unwrap_or_else(|_| Err("block fetcher exited prematurely".into())),
+        )
+    }
+
+    fn join(self) {
+        for thread in self.threads {
+            thread.join().expect("block fetcher failed");
+        }
+    }
+}
+
+/// Headers at the given heights, skipping unknown ones. Trimmed headers are
+/// read from `store`, after releasing the header lock.
+fn headers_at_heights(
+    headers: &RwLock<HeaderList>,
+    store: &dyn ReadStore,
+    heights: &[usize],
+) -> Vec<HeaderEntry> {
+    let entries: Vec<std::result::Result<HeaderEntry, (usize, BlockHash)>> = {
+        let headers = headers.read().unwrap();
+        heights
+            .iter()
+            .filter_map(|&height| match headers.header_by_height(height) {
+                Some(entry) => Some(Ok(entry.clone())),
+                None => headers.blockhash_at(height).map(|hash| Err((height, hash))),
+            })
+            .collect()
+    };
+    entries
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err((height, hash)) => read_indexed_header(store, &hash)
+                .map(|header| HeaderEntry::new(height, hash, header)),
+        })
+        .collect()
+}
+
+/// Tracks whether the index has caught up with the daemon, so that queries
+/// don't return partial results during the initial sync.
+#[derive(Default)]
+pub struct SyncStatus {
+    ready: AtomicBool,
+    blocks_behind: AtomicUsize,
+}
+
+impl SyncStatus {
+    fn set_blocks_behind(&self, blocks: usize) {
+        self.blocks_behind.store(blocks, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_ready(&self) {
+        self.blocks_behind.store(0, Ordering::Relaxed);
+        self.ready.store(true, Ordering::Release);
+    }
+
+    /// Returns true once the index has reached the daemon tip.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    /// Blocks left to index (0 if unknown or ready).
+    pub fn blocks_behind(&self) -> usize {
+        self.blocks_behind.load(Ordering::Relaxed)
+    }
+
+    /// Fails with an error for the client until the index is ready.
+    pub fn check(&self) -> Result<()> {
+        if self.is_ready() {
+            return Ok(());
+        }
+        let msg = match self.blocks_behind() {
+            0 => "server is still syncing".to_string(),
+            behind => format!("server is still syncing, {} blocks behind", behind),
+        };
+        bail!(ErrorKind::RpcError(RpcErrorCode::Syncing, msg))
+    }
+}
+
 pub struct Index {
     // TODO: store also latest snapshot.
     headers: RwLock<HeaderList>,
     daemon: Daemon,
     stats: Stats,
     batch_size: usize,
+    fetch_threads: usize,
+    checkpoint_interval: usize,
     cashaccount_activation_height: u32,
+    options: IndexOptions,
+    // set when block fee stats are indexed, to look up spent outputs
+    block_fees_tx_cache: Option<Arc<TransactionCache>>,
+    header_window: Option<usize>,
+    index_start_height: usize,
+    sync_status: SyncStatus,
 }
 
 impl Index {
+    #[allow(clippy::too_many_arguments)]
     pub fn load(
         store: &dyn ReadStore,
         daemon: &Daemon,
         metrics: &Metrics,
         batch_size: usize,
+        fetch_threads: usize,
+        checkpoint_interval: usize,
         cashaccount_activation_height: u32,
+        options: IndexOptions,
+        tx_cache: Arc<TransactionCache>,
+        header_window: Option<usize>,
+        index_start_height: usize,
     ) -> Result<Index> {
         let stats = Stats::new(metrics);
-        let headers = read_indexed_headers(store);
+        let mut headers = read_indexed_headers(store)?;
+        if let Some(window) = header_window {
+            headers.trim(window);
+        }
         stats.height.set((headers.len() as i64) - 1);
         Ok(Index {
             headers: RwLock::new(headers),
             daemon: daemon.reconnect()?,
             stats,
             batch_size,
+            fetch_threads: fetch_threads.max(1),
+            checkpoint_interval,
             cashaccount_activation_height,
+            options,
+            block_fees_tx_cache: options.block_fees.then(|| tx_cache),
+            header_window,
+            index_start_height,
+            sync_status: SyncStatus::default(),
         })
     }
 
-    pub fn reload(&self, store: &dyn ReadStore) {
+    /// Whether the index has caught up with the daemon.
+    pub fn sync_status(&self) -> &SyncStatus {
+        &self.sync_status
+    }
+
+    /// Returns true if OP_RETURN payloads are indexed.
+    pub fn index_opreturn(&self) -> bool {
+        self.options.opreturn
+    }
+
+    /// Returns true if the height each scripthash is first used at is
+    /// indexed.
+    pub fn index_first_use(&self) -> bool {
+        self.options.first_use
+    }
+
+    /// Returns true if block stats are stored for new blocks.
+    pub fn index_block_stats(&self) -> bool {
+        self.options.block_stats
+    }
+
+    /// Returns true if block fee stats are stored for new blocks.
+    pub fn index_block_fees(&self) -> bool {
+        self.block_fees_tx_cache.is_some()
+    }
+
+    /// Value of a confirmed output, for the fee stats of a block. The
+    /// transaction is loaded from `tx_cache` or bitcoind, from the block it
+    /// is confirmed in (looked up in `new_headers` first, as they are not in
+    /// the header list yet).
+    fn prevout_value(
+        &self,
+        store: &dyn ReadStore,
+        tx_cache: &TransactionCache,
+        new_headers: &[HeaderEntry],
+        outpoint: &OutPoint,
+    ) -> Result<u64> {
+        let txid = &outpoint.txid;
+        let tx = match tx_cache.get(txid) {
+            Some(tx) => tx,
+            None => {
+                let key = TxRow::filter_full(txid);
+                let blockhash = store
+                    .get(&key)?
+                    .map(|value| TxRow::from_row(&Row { key, value }).height as usize)
+                    .and_then(|height| {
+                        new_headers
+                            .iter()
+                            .find(|header| header.height() == height)
+                            .map(|header| *header.hash())
+                            .or_else(|| self.get_blockhash(height))
+                    });
+                let tx = self.daemon.gettransaction(txid, blockhash)?;
+                tx_cache.put(txid, serialize(&tx));
+                tx
+            }
+        };
+        tx.output
+            .get(outpoint.vout as usize)
+            .map(|output| output.value)
+            .chain_err(|| format!("missing output {}:{}", txid, outpoint.vout))
+    }
+
+    /// Outputs left out of the scripthash index, for the mempool to leave
+    /// out the same ones.
+    pub fn index_output_filter(&self) -> OutputFilter {
+        self.options.output_filter
+    }
+
+    pub fn reload(&self, store: &dyn ReadStore) -> Result<()> {
         let mut headers = self.headers.write().unwrap();
-        *headers = read_indexed_headers(store);
+        *headers = read_indexed_headers(store)?;
+        self.trim_headers(&mut headers);
+        Ok(())
+    }
+
+    /// Keep only the latest `header_window` headers in memory (if set).
+    fn trim_headers(&self, headers: &mut HeaderList) {
+        if let Some(window) = self.header_window {
+            headers.trim(window);
+        }
     }
 
     pub fn best_header(&self) -> Option<HeaderEntry> {
@@ -394,96 +1049,215 @@ impl Index {
         headers.header_by_blockhash(&headers.tiphash()).cloned()
     }
 
-    pub fn get_header(&self, height: usize) -> Option<HeaderEntry> {
-        self.headers
-            .read()
-            .unwrap()
-            .header_by_height(height)
-            .cloned()
+    /// Headers trimmed from memory are read from `store`.
+    pub fn get_header_by_hash(
+        &self,
+        store: &dyn ReadStore,
+        blockhash: &BlockHash,
+    ) -> Option<HeaderEntry> {
+        let height = {
+            let headers = self.headers.read().unwrap();
+            if let Some(entry) = headers.header_by_blockhash(blockhash) {
+                return Some(entry.clone());
+            }
+            headers.height_by_blockhash(blockhash)?
+        };
+        let header = read_indexed_header(store, blockhash)?;
+        Some(HeaderEntry::new(height, *blockhash, header))
     }
 
-    pub fn update(
+    /// Headers trimmed from memory are read from `store`.
+    pub fn get_header(&self, store: &dyn ReadStore, height: usize) -> Option<HeaderEntry> {
+        self.get_headers(store, &[height]).pop()
+    }
+
+    /// Headers at the given heights, skipping unknown ones. Headers trimmed
+    /// from memory are read from `store`.
+    pub fn get_headers(&self, store: &dyn ReadStore, heights: &[usize]) -> Vec<HeaderEntry> {
+        headers_at_heights(&self.headers, store, heights)
+    }
+
+    /// Hash of the block at `height`. Hashes of trimmed headers are kept in
+    /// memory, so this never reads from the DB.
+    pub fn get_blockhash(&self, height: usize) -> Option<BlockHash> {
+        self.headers.read().unwrap().blockhash_at(height)
+    }
+
+    /// Locks the header list, e.g. for reading many block hashes while the
+    /// best chain can't change.
+    pub fn read_headers(&self) -> RwLockReadGuard<'_, HeaderList> {
+        self.headers.read().unwrap()
+    }
+
+    /// Hashes of the blocks at the given heights, skipping unknown ones.
+    pub fn get_blockhashes(&self, heights: &[usize]) -> Vec<BlockHash> {
+        let headers = self.headers.read().unwrap();
+        heights
+            .iter()
+            .filter_map(|&height| headers.blockhash_at(height))
+            .collect()
+    }
+
+    /// Cumulative chain work up to (and including) the given block, or None
+    /// if it's no longer in the best chain.
+    pub fn get_chainwork(&self, entry: &HeaderEntry) -> Option<Uint256> {
+        let headers = self.headers.read().unwrap();
+        if headers.blockhash_at(entry.height())? != *entry.hash() {
+            return None;
+        }
+        headers.chainwork_at(entry.height())
+    }
+
+    /// Delete the first-use rows of the `disconnected` blocks, see
+    /// `delete_first_use_rows`. Blocks bitcoind can't return anymore keep
+    /// their rows, which queries skip.
+    fn delete_stale_first_use_rows(
         &self,
         store: &impl WriteStore,
+        daemon: &Daemon,
+        disconnected: &[HeaderEntry],
+    ) -> Result<()> {
+        for header in disconnected {
+            if header.height() < self.index_start_height {
+                continue;
+            }
+            match daemon.getblock(header.hash()) {
+                Ok(block) => delete_first_use_rows(
+                    store,
+                    &block,
+                    header.height(),
+                    self.options.output_filter,
+                )
+                .chain_err(|| format!("failed to delete first-use rows of {}", header.hash()))?,
+                Err(e) => warn!(
+                    "first-use rows of disconnected block {} are left: {}",
+                    header.hash(),
+                    e
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn update(
+        &self,
+        store: &(impl WriteStore + ReadStore),
         waiter: &Waiter,
     ) -> Result<(Vec<HeaderEntry>, HeaderEntry)> {
         let daemon = self.daemon.reconnect()?;
         let tip = daemon.getbestblockhash()?;
-        let new_headers: Vec<HeaderEntry> = {
+        let (new_headers, disconnected) = {
             let indexed_headers = self.headers.read().unwrap();
-            indexed_headers.order(daemon.get_new_headers(&indexed_headers, &tip)?)
+            let new_headers =
+                indexed_headers.order(daemon.get_new_headers(&indexed_headers, &tip)?);
+            let disconnected = disconnected_headers(&indexed_headers, &new_headers, &tip);
+            (new_headers, disconnected)
         };
+        if self.options.first_use {
+            self.delete_stale_first_use_rows(store, &daemon, &disconnected)?;
+        }
         if let Some(latest_header) = new_headers.last() {
             info!("{:?} ({} left to index)", latest_header, new_headers.len());
         };
+        self.sync_status.set_blocks_behind(new_headers.len());
         let height_map: HashMap<BlockHash, usize> = new_headers
             .iter()
             .map(|h| (*h.hash(), h.height()))
             .collect();
 
-        let chan = SyncChannel::new(self.batch_size);
-        let sender = chan.sender();
-        let blockhashes: Vec<BlockHash> = new_headers.iter().map(|h| *h.hash()).collect();
-        let fetcher = spawn_thread("fetcher", move || {
-            for blockhash in blockhashes.iter() {
-                sender
-                    .send(Some(daemon.getblock(blockhash)))
-                    .expect("failed sending blocks to be indexed");
-            }
-            sender
-                .send(None)
-                .expect("failed sending explicit end of stream");
-        });
+        // Blocks below the start height are not fetched, only their headers
+        // are stored (so that the header list still starts at genesis).
+        let skipped = new_headers
+            .iter()
+            .take_while(|h| h.height() < self.index_start_height)
+            .count();
+        write_headers(store, &new_headers[..skipped])?;
+
+        let blockhashes: Vec<BlockHash> =
+            new_headers[skipped..].iter().map(|h| *h.hash()).collect();
+        let fetch_count = blockhashes.len();
+        // each fetcher uses its own connection to bitcoind
+        let mut daemons = vec![daemon];
+        for _ in 1..self.fetch_threads.min(blockhashes.len()) {
+            daemons.push(self.daemon.reconnect()?);
+        }
+        let fetchers: Vec<_> = daemons
+            .into_iter()
+            .map(|daemon| move |blockhash: &BlockHash| daemon.getblock(blockhash))
+            .collect();
+        let capacity = (self.batch_size / fetchers.len()).max(1);
+        let mut fetcher = OrderedFetcher::start(blockhashes, fetchers, capacity);
         let cashaccount = CashAccountParser::new(Some(self.cashaccount_activation_height));
 
         let mut i = 0;
         let mut prev_blockhash = None;
-        loop {
-            waiter.poll()?;
+        let result: Result<()> = loop {
+            if let Err(e) = waiter.poll() {
+                break Err(e);
+            }
             let timer = self.stats.start_timer("fetch");
-            let block = chan
-                .receiver()
-                .recv()
-                .expect("block fetch exited prematurely");
+            let block = fetcher.next();
             timer.observe_duration();
-            if block.is_none() {
-                break;
-            }
-            let block = block.unwrap();
-            let block = block?;
+            let block = match block {
+                Some(Ok(block)) => block,
+                Some(Err(e)) => break Err(e),
+                None => break Ok(()),
+            };
 
             let blockhash = block.block_hash();
             let height = *height_map
                 .get(&blockhash)
                 .unwrap_or_else(|| panic!("missing header for block {}", blockhash));
 
+            if let Some(tx_cache) = &self.block_fees_tx_cache {
+                let timer = self.stats.start_timer("fees");
+                let written = write_block_fee_stats(store, &block, |outpoint| {
+                    self.prevout_value(store, tx_cache, &new_headers, outpoint)
+                });
+                timer.observe_duration();
+                if let Err(e) = written {
+                    break Err(e);
+                }
+            }
+
             let timer = self.stats.start_timer("index+write");
             i += 1;
-            let indexed = index_block(&block, height, &cashaccount);
-            if i % 1000 == 0 {
-                // Occationally update the 'last indexed' marker. If indexing is
-                // interrupted, it will restart at last marker.
-                store.write(
-                    indexed.chain(std::iter::once(last_indexed_block(&blockhash))),
-                    false,
-                );
-            } else {
-                store.write(indexed, false);
-            };
+            let written = write_block(
+                store,
+                &block,
+                height,
+                &cashaccount,
+                self.options,
+                i % self.checkpoint_interval == 0,
+            );
             timer.observe_duration();
-            self.stats.update(&block, height);
+            if let Err(e) = written {
+                break Err(e.chain_err(|| format!("failed to index block {}", blockhash)));
+            }
+            self.stats
+                .update(&block, height, &self.options.output_filter);
+            self.sync_status.set_blocks_behind(fetch_count - i);
             prev_blockhash = Some(blockhash);
-        }
+        };
 
+        // Checkpoint the progress also on failure, so that indexing resumes
+        // from the last indexed block after a restart.
         let timer = self.stats.start_timer("flush");
-        if let Some(h) = prev_blockhash {
-            store.write(std::iter::once(last_indexed_block(&h)), false);
+        let checkpoint = match prev_blockhash {
+            Some(h) => store.write(std::iter::once(last_indexed_block(&h)), false),
+            None => Ok(()),
         }
-        store.flush(); // make sure no row is left behind
+        .and_then(|_| store.flush()); // make sure no row is left behind
         timer.observe_duration();
+        result?;
+        checkpoint.chain_err(|| "failed to checkpoint indexed blocks")?;
 
-        fetcher.join().expect("block fetcher failed");
-        self.headers.write().unwrap().apply(&new_headers, tip);
+        fetcher.join();
+        {
+            let mut headers = self.headers.write().unwrap();
+            headers.apply(&new_headers, tip);
+            self.trim_headers(&mut headers);
+        }
         let tip_header = self
             .headers
             .read()
@@ -493,6 +1267,664 @@ impl Index {
         assert_eq!(&tip, tip_header.hash());
         self.stats
             .update_height(self.headers.read().unwrap().len() - 1);
+        self.sync_status.set_ready();
         Ok((new_headers, tip_header))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_chain(len: u32) -> Vec<Block> {
+        crate::fake::chain_headers(&vec![0; len as usize])
+            .into_iter()
+            .map(|header| Block {
+                header,
+                txdata: vec![],
+            })
+            .collect()
+    }
+
+    fn blockhash(i: u8) -> BlockHash {
+        BlockHash::from_slice(&[i; 32]).unwrap()
+    }
+
+    fn index_of(blockhash: &BlockHash) -> u8 {
+        blockhash[0]
+    }
+
+    #[test]
+    fn test_ordered_fetcher() {
+        let blockhashes: Vec<BlockHash> = (0..100).map(blockhash).collect();
+        // fetchers with different latencies
+        let fetchers: Vec<_> = (0..4u64)
+            .map(|k| {
+                move |blockhash: &BlockHash| {
+                    std::thread::sleep(std::time::Duration::from_micros(k * 100));
+                    Ok(index_of(blockhash))
+                }
+            })
+            .collect();
+        let mut fetcher = OrderedFetcher::start(blockhashes, fetchers, 2);
+        let mut received = vec![];
+        while let Some(block) = fetcher.next() {
+            received.push(block.unwrap());
+        }
+        fetcher.join();
+        assert_eq!(received, (0..100).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_ordered_fetcher_failure() {
+        let blockhashes: Vec<BlockHash> = (0..10).map(blockhash).collect();
+        let fetchers: Vec<_> = (0..3)
+            .map(|_| {
+                |blockhash: &BlockHash| match index_of(blockhash) {
+                    5 => Err("bitcoind is down".into()),
+                    i => Ok(i),
+                }
+            })
+            .collect();
+        let mut fetcher = OrderedFetcher::start(blockhashes, fetchers, 1);
+        for i in 0..5 {
+            assert_eq!(fetcher.next().unwrap().unwrap(), i);
+        }
+        assert!(fetcher.next().unwrap().is_err());
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let store = crate::fake::MemStore::buffered();
+        let cashaccount = CashAccountParser::new(None);
+        let blocks = make_chain(10);
+        let checkpoint_interval = 3;
+
+        // Index 8 blocks, then crash before the final flush.
+        for (height, block) in blocks.iter().enumerate().take(8) {
+            let checkpoint = (height + 1) % checkpoint_interval == 0;
+            write_block(
+                &store,
+                block,
+                height,
+                &cashaccount,
+                IndexOptions::default(),
+                checkpoint,
+            )
+            .unwrap();
+        }
+        store.crash();
+
+        // Indexing resumes from the last checkpoint (6th block).
+        let headers = read_indexed_headers(&store).unwrap();
+        assert_eq!(headers.len(), 6);
+        assert_eq!(headers.tiphash(), blocks[5].block_hash());
+
+        // Resume indexing, and finish the run.
+        for (height, block) in blocks.iter().enumerate().skip(headers.len()) {
+            let last = height == blocks.len() - 1;
+            write_block(
+                &store,
+                block,
+                height,
+                &cashaccount,
+                IndexOptions::default(),
+                last,
+            )
+            .unwrap();
+        }
+        let headers = read_indexed_headers(&store).unwrap();
+        assert_eq!(headers.len(), 10);
+        assert_eq!(headers.tiphash(), blocks[9].block_hash());
+    }
+
+    #[test]
+    fn test_trimmed_headers() {
+        use crate::fake::MemStore;
+
+        let store = MemStore::default();
+        let cashaccount = CashAccountParser::new(None);
+        let blocks = make_chain(10);
+        for (height, block) in blocks.iter().enumerate() {
+            let last = height == blocks.len() - 1;
+            write_block(
+                &store,
+                block,
+                height,
+                &cashaccount,
+                IndexOptions::default(),
+                last,
+            )
+            .unwrap();
+        }
+        let mut headers = read_indexed_headers(&store).unwrap();
+        headers.trim(3);
+        assert_eq!(headers.len(), 10);
+        assert_eq!(headers.trimmed_len(), 7);
+        assert_eq!(headers.tiphash(), blocks[9].block_hash());
+        assert!(headers.header_by_height(6).is_none());
+        assert!(headers.header_by_height(7).is_some());
+
+        let headers = RwLock::new(headers);
+        let heights: Vec<usize> = (0..12).collect();
+        let entries = headers_at_heights(&headers, &store, &heights);
+        assert_eq!(entries.len(), 10);
+        for (height, entry) in entries.iter().enumerate() {
+            assert_eq!(entry.height(), height);
+            assert_eq!(*entry.hash(), blocks[height].block_hash());
+            assert_eq!(*entry.header(), blocks[height].header);
+        }
+        let headers = headers.read().unwrap();
+        assert_eq!(headers.blockhash_at(2), Some(blocks[2].block_hash()));
+        assert_eq!(
+            headers.height_by_blockhash(&blocks[2].block_hash()),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn test_sync_status() {
+        let status = SyncStatus::default();
+        let err = status.check().unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::RpcError(RpcErrorCode::Syncing, msg) if msg == "server is still syncing"
+        ));
+
+        status.set_blocks_behind(42);
+        assert!(!status.is_ready());
+        let err = status.check().unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::RpcError(RpcErrorCode::Syncing, msg)
+                if msg == "server is still syncing, 42 blocks behind"
+        ));
+
+        status.set_ready();
+        assert!(status.is_ready());
+        assert_eq!(status.blocks_behind(), 0);
+        assert!(status.check().is_ok());
+    }
+
+    #[test]
+    fn test_start_height() {
+        use crate::fake::MemStore;
+
+        let store = MemStore::default();
+        let cashaccount = CashAccountParser::new(None);
+        let mut blocks = make_chain(10);
+        for (height, block) in blocks.iter_mut().enumerate() {
+            block.txdata.push(Transaction {
+                version: 1,
+                lock_time: height as u32,
+                input: vec![],
+                output: vec![],
+            });
+        }
+        let entries = HeaderList::empty().order(blocks.iter().map(|b| b.header).collect());
+        let start_height = 4;
+
+        write_headers(&store, &entries[..start_height]).unwrap();
+        for (height, block) in blocks.iter().enumerate().skip(start_height) {
+            write_block(
+                &store,
+                block,
+                height,
+                &cashaccount,
+                IndexOptions::default(),
+                false,
+            )
+            .unwrap();
+        }
+        store
+            .write(std::iter::once(start_height_marker(start_height)), true)
+            .unwrap();
+
+        // All headers are stored...
+        let headers = read_indexed_headers(&store).unwrap();
+        assert_eq!(headers.len(), 4);
+        store
+            .write(
+                std::iter::once(last_indexed_block(&blocks[9].block_hash())),
+                true,
+            )
+            .unwrap();
+        let headers = read_indexed_headers(&store).unwrap();
+        assert_eq!(headers.len(), 10);
+        assert_eq!(headers.tiphash(), blocks[9].block_hash());
+
+        // ...but only transactions from the start height on are indexed
+        for (height, block) in blocks.iter().enumerate() {
+            let txid = block.txdata[0].txid();
+            let indexed = !store.scan(&TxRow::filter_full(&txid)).is_empty();
+            assert_eq!(indexed, height >= start_height, "height {}", height);
+        }
+        assert_eq!(read_start_height(&store).unwrap(), Some(start_height));
+        assert_eq!(read_start_height(&MemStore::default()).unwrap(), None);
+    }
+
+    #[test]
+    fn test_index_options() {
+        use crate::fake::MemStore;
+
+        let first_use = IndexOptions {
+            first_use: true,
+            ..IndexOptions::default()
+        };
+
+        // any options go for an empty DB
+        let store = MemStore::default();
+        assert_eq!(read_index_options(&store).unwrap(), None);
+        assert!(!options_need_reindex(&store, &first_use, false).unwrap());
+
+        // a DB indexed before the options were recorded has the defaults
+        let blocks = make_chain(1);
+        store
+            .write(
+                std::iter::once(last_indexed_block(&blocks[0].block_hash())),
+                true,
+            )
+            .unwrap();
+        assert_eq!(
+            read_index_options(&store).unwrap(),
+            Some(IndexOptions::default())
+        );
+        let err = options_need_reindex(&store, &first_use, false)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains(
+                "indexed with index_opreturn=false, index_first_use=false, \
+                index_max_script_size=0, index_hash_oversized_scripts=false, \
+                index_min_output_value=0, not index_opreturn=false, \
+                index_first_use=true, index_max_script_size=0, \
+                index_hash_oversized_scripts=false, index_min_output_value=0"
+            ),
+            "{}",
+            err
+        );
+        assert!(options_need_reindex(&store, &first_use, true).unwrap());
+
+        store
+            .write(std::iter::once(index_options_marker(&first_use)), true)
+            .unwrap();
+        assert_eq!(read_index_options(&store).unwrap(), Some(first_use));
+        assert!(!options_need_reindex(&store, &first_use, false).unwrap());
+        assert!(options_need_reindex(&store, &IndexOptions::default(), false).is_err());
+
+        // changing the outputs left out of the scripthash index
+        let dust_filter = IndexOptions {
+            output_filter: OutputFilter {
+                min_value: 546,
+                ..OutputFilter::default()
+            },
+            ..first_use
+        };
+        assert!(options_need_reindex(&store, &dust_filter, false).is_err());
+        assert!(options_need_reindex(&store, &dust_filter, true).unwrap());
+
+        // enabling the OP_RETURN prefix index
+        let opreturn = IndexOptions {
+            opreturn: true,
+            ..first_use
+        };
+        assert!(options_need_reindex(&store, &opreturn, false).is_err());
+        assert!(options_need_reindex(&store, &opreturn, true).unwrap());
+
+        // block stats and fees only apply to blocks indexed later, so
+        // they're not recorded
+        let block_stats = IndexOptions {
+            block_stats: true,
+            block_fees: true,
+            ..first_use
+        };
+        store
+            .write(std::iter::once(index_options_marker(&block_stats)), true)
+            .unwrap();
+        assert_eq!(read_index_options(&store).unwrap(), Some(first_use));
+        assert!(!options_need_reindex(&store, &block_stats, false).unwrap());
+    }
+
+    #[test]
+    fn test_failing_store() {
+        use crate::fake::FailingStore;
+
+        let cashaccount = CashAccountParser::new(None);
+        let blocks = make_chain(2);
+        let entries = HeaderList::empty().order(blocks.iter().map(|b| b.header).collect());
+        for checkpoint in [false, true] {
+            assert!(write_block(
+                &FailingStore,
+                &blocks[0],
+                0,
+                &cashaccount,
+                IndexOptions::default(),
+                checkpoint
+            )
+            .is_err());
+        }
+        assert!(write_headers(&FailingStore, &entries).is_err());
+        assert!(read_indexed_headers(&FailingStore).is_err());
+        assert!(read_start_height(&FailingStore).is_err());
+        assert!(read_index_options(&FailingStore).is_err());
+        // a header that can't be read is reported as missing
+        assert!(read_indexed_header(&FailingStore, &blocks[0].block_hash()).is_none());
+    }
+
+    #[test]
+    fn test_block_stats() {
+        use crate::blockstats::read_block_stats;
+        use crate::fake::MemStore;
+        use bitcoincash::blockdata::script::Script;
+
+        let cashaccount = CashAccountParser::new(None);
+        let mut block = make_chain(1).remove(0);
+        block.txdata = (0..3u32)
+            .map(|i| Transaction {
+                version: 1,
+                lock_time: i,
+                input: vec![TxIn {
+                    previous_output: OutPoint::default(),
+                    script_sig: Script::new(),
+                    sequence: 0xffffffff,
+                    witness: vec![],
+                }],
+                output: vec![TxOut {
+                    value: 1000 * u64::from(i),
+                    script_pubkey: Script::from(vec![0x51; i as usize]),
+                }],
+            })
+            .collect();
+        let blockhash = block.block_hash();
+
+        let store = MemStore::default();
+        write_block(
+            &store,
+            &block,
+            0,
+            &cashaccount,
+            IndexOptions::default(),
+            true,
+        )
+        .unwrap();
+        assert_eq!(read_block_stats(&store, &blockhash).unwrap(), None);
+
+        write_block(
+            &store,
+            &block,
+            0,
+            &cashaccount,
+            IndexOptions {
+                block_stats: true,
+                ..IndexOptions::default()
+            },
+            true,
+        )
+        .unwrap();
+        let stats = read_block_stats(&store, &blockhash).unwrap().unwrap();
+        assert_eq!(stats.size, block.get_size() as u64);
+        assert_eq!(stats.weight, block.get_weight() as u64);
+        assert_eq!(stats.tx_count, 3);
+    }
+
+    #[test]
+    fn test_block_fee_stats() {
+        use crate::blockstats::read_block_fee_stats;
+        use crate::fake::{FailingStore, MemStore};
+        use bitcoincash::blockdata::script::Script;
+
+        let mut block = make_chain(1).remove(0);
+        let prevout = OutPoint::new(Txid::from_slice(&[1; 32]).unwrap(), 0);
+        block.txdata = vec![Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: prevout,
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 900,
+                script_pubkey: Script::new(),
+            }],
+        }];
+        let blockhash = block.block_hash();
+
+        // a prevout that can't be looked up leaves the block without fee stats
+        let store = MemStore::default();
+        write_block_fee_stats(&store, &block, |_| Err("no txindex".into())).unwrap();
+        assert!(read_block_fee_stats(&store, &blockhash).unwrap().is_none());
+
+        write_block_fee_stats(&store, &block, |outpoint| {
+            assert_eq!(*outpoint, prevout);
+            Ok(1000)
+        })
+        .unwrap();
+        let stats = read_block_fee_stats(&store, &blockhash).unwrap().unwrap();
+        assert_eq!(stats.to_json()["total_fee"], 100);
+
+        // failing to write is still an error
+        assert!(write_block_fee_stats(&FailingStore, &block, |_| Ok(1000)).is_err());
+    }
+
+    #[test]
+    fn test_max_script_size() {
+        use bitcoincash::blockdata::script::Script;
+
+        let txn = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: [5, 20, 10]
+                .iter()
+                .map(|&len| TxOut {
+                    value: 1000,
+                    script_pubkey: Script::from(vec![0x6a; len]),
+                })
+                .collect(),
+        };
+        let count_outputs = |max_script_size, hash_oversized| {
+            let filter = OutputFilter {
+                max_script_size,
+                hash_oversized,
+                min_value: 0,
+            };
+            index_transaction(&txn, 1, None, false, None, filter)
+                .filter(|row| row.key[0] == b'O')
+                .count()
+        };
+        assert_eq!(count_outputs(0, false), 3);
+        assert_eq!(count_outputs(10, false), 2);
+        assert_eq!(count_outputs(4, false), 0);
+        // oversized scripts can be indexed as-is instead
+        assert_eq!(count_outputs(4, true), 3);
+        // the transaction itself is still indexed
+        let filter = OutputFilter {
+            max_script_size: 4,
+            ..OutputFilter::default()
+        };
+        assert_eq!(
+            txn.output
+                .iter()
+                .filter(|output| filter.is_oversized(output))
+                .count(),
+            3
+        );
+        let txrows = index_transaction(&txn, 1, None, false, None, filter)
+            .filter(|row| row.key[0] == b'T')
+            .count();
+        assert_eq!(txrows, 1);
+    }
+
+    #[test]
+    fn test_min_output_value() {
+        use bitcoincash::blockdata::script::Script;
+
+        let txn = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: [0, 545, 546, 100_000]
+                .iter()
+                .map(|&value| TxOut {
+                    value,
+                    script_pubkey: Script::from(vec![0x51]),
+                })
+                .collect(),
+        };
+        let count_rows = |min_value, code| {
+            let filter = OutputFilter {
+                min_value,
+                ..OutputFilter::default()
+            };
+            let blockhash = BlockHash::default();
+            index_transaction(&txn, 1, None, false, Some(blockhash), filter)
+                .filter(|row| row.key[0] == code)
+                .count()
+        };
+        assert_eq!(count_rows(0, b'O'), 4);
+        assert_eq!(count_rows(546, b'O'), 2);
+        assert_eq!(count_rows(u64::MAX, b'O'), 0);
+        // dust outputs are not seen as first use of their scripthash either
+        assert_eq!(count_rows(0, b'U'), 4);
+        assert_eq!(count_rows(546, b'U'), 2);
+        // the transaction itself is still indexed
+        assert_eq!(count_rows(u64::MAX, b'T'), 1);
+    }
+
+    #[test]
+    fn test_first_use_rows() {
+        use bitcoincash::blockdata::script::Script;
+
+        let script = Script::from(vec![0x51]);
+        let txn = |lock_time| Transaction {
+            version: 1,
+            lock_time,
+            input: vec![],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: script.clone(),
+            }],
+        };
+        let first_use_rows = |txn: &Transaction, height, first_use| {
+            index_transaction(txn, height, None, false, first_use, OutputFilter::default())
+                .filter(|row| row.key[0] == b'U')
+                .collect::<Vec<Row>>()
+        };
+        // only indexed when enabled
+        assert!(first_use_rows(&txn(0), 1, None).is_empty());
+
+        let (low, high) = (txn(1), txn(2));
+        let mut rows = first_use_rows(&high, 300, Some(blockhash(2)));
+        rows.extend(first_use_rows(&low, 2, Some(blockhash(1))));
+        let script_hash = compute_script_hash(&script[..]);
+        assert!(rows
+            .iter()
+            .all(|row| row.key.starts_with(&FirstUseRow::filter(&script_hash))));
+
+        // rows sort by height, regardless of the txid
+        rows.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        let rows: Vec<FirstUseRow> = rows.iter().map(FirstUseRow::from_row).collect();
+        assert_eq!(rows[0].height(), 2);
+        assert_eq!(rows[0].key.txid_prefix, hash_prefix(&low.txid()[..]));
+        assert_eq!(rows[0].blockhash_prefix, hash_prefix(&blockhash(1)[..]));
+        assert_eq!(rows[1].height(), 300);
+        assert_eq!(rows[1].key.txid_prefix, hash_prefix(&high.txid()[..]));
+    }
+
+    #[test]
+    fn test_delete_first_use_rows() {
+        use crate::fake::MemStore;
+        use bitcoincash::blockdata::script::Script;
+
+        let txn = |lock_time| Transaction {
+            version: 1,
+            lock_time,
+            input: vec![],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+        };
+        // blocks at the same height, both confirming txn 1
+        let block = |time, txdata| Block {
+            header: crate::fake::chain_headers(&[time])[0],
+            txdata,
+        };
+        let stale = block(0, vec![txn(1), txn(2)]);
+        let replacing = block(1, vec![txn(1), txn(3)]);
+        let cashaccount = CashAccountParser::new(None);
+        let filter = OutputFilter::default();
+        let store = MemStore::default();
+        let write = |block: &Block| {
+            write_block(
+                &store,
+                block,
+                5,
+                &cashaccount,
+                IndexOptions {
+                    first_use: true,
+                    ..IndexOptions::default()
+                },
+                false,
+            )
+            .unwrap()
+        };
+        let first_use_blocks = || -> Vec<HashPrefix> {
+            store
+                .scan(b"U")
+                .iter()
+                .map(|row| FirstUseRow::from_row(row).blockhash_prefix)
+                .collect()
+        };
+
+        write(&stale);
+        assert_eq!(first_use_blocks().len(), 2);
+        delete_first_use_rows(&store, &stale, 5, filter).unwrap();
+        assert!(first_use_blocks().is_empty());
+        // only the first-use rows are deleted
+        assert_eq!(store.scan(b"T").len(), 2);
+
+        write(&replacing);
+        let replacing_prefix = hash_prefix(&replacing.block_hash()[..]);
+        assert_eq!(first_use_blocks(), vec![replacing_prefix; 2]);
+
+        // deleting after indexing the replacing block would drop the row of
+        // txn 1, which has the same key in both blocks
+        delete_first_use_rows(&store, &stale, 5, filter).unwrap();
+        assert_eq!(first_use_blocks(), vec![replacing_prefix]);
+    }
+
+    #[test]
+    fn test_disconnected_headers() {
+        use crate::fake::chain_headers;
+
+        let hashes = |entries: &[HeaderEntry]| -> Vec<BlockHash> {
+            entries.iter().map(|entry| *entry.hash()).collect()
+        };
+        let mut headers = HeaderList::empty();
+        let indexed = headers.order(chain_headers(&[0, 0, 0, 0]));
+        headers.apply(&indexed, *indexed[3].hash());
+
+        // extending the best chain disconnects nothing
+        let new_headers = headers.order(chain_headers(&[0, 0, 0, 0, 0])[4..].to_vec());
+        let tip = *new_headers[0].hash();
+        assert!(disconnected_headers(&headers, &new_headers, &tip).is_empty());
+
+        // a fork at height 2 disconnects blocks 2 and 3
+        let new_headers = headers.order(chain_headers(&[0, 0, 1, 1, 1])[2..].to_vec());
+        let tip = *new_headers.last().unwrap().hash();
+        assert_eq!(
+            hashes(&disconnected_headers(&headers, &new_headers, &tip)),
+            hashes(&indexed[2..])
+        );
+
+        // the tip moving back disconnects the blocks above it
+        let tip = *indexed[1].hash();
+        assert_eq!(
+            hashes(&disconnected_headers(&headers, &[], &tip)),
+            hashes(&indexed[2..])
+        );
+    }
+}
@@ -10,11 +10,15 @@ extern crate serde;
 #[macro_use]
 extern crate configure_me;
 
+#[cfg(feature = "jemalloc")]
 extern crate jemallocator;
+#[cfg(feature = "jemalloc")]
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
 pub mod app;
+pub mod banner;
+pub mod blockstats;
 pub mod bulk;
 pub mod cache;
 pub mod cashaccount;
@@ -27,6 +31,7 @@ pub mod fake;
 pub mod index;
 pub mod mempool;
 pub mod metrics;
+pub mod opreturn;
 pub mod query;
 pub mod rndcache;
 pub mod rpc;
@@ -36,3 +41,4 @@ pub mod store;
 pub mod timeout;
 pub mod util;
 pub mod wstcp;
+pub mod zmq;
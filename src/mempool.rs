@@ -1,12 +1,15 @@
 use bitcoincash::blockdata::transaction::Transaction;
 use bitcoincash::hash_types::Txid;
+use error_chain::ChainedError;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ops::Bound;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
 
 use crate::daemon::{Daemon, MempoolEntry};
 use crate::errors::*;
-use crate::index::index_transaction;
+use crate::index::{index_transaction, OutputFilter};
 use crate::metrics::Metrics;
 use crate::query::tx::TxQuery;
 use crate::store::{ReadStore, Row};
@@ -17,6 +20,7 @@ const VSIZE_BIN_WIDTH: u32 = 100_000; // in vbytes
 /// Fake height value used to signify that a transaction is in the memory pool.
 pub const MEMPOOL_HEIGHT: u32 = 0x7FFF_FFFF;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ConfirmationState {
     Indeterminate,
     Confirmed,
@@ -26,56 +30,83 @@ pub enum ConfirmationState {
 
 struct MempoolStore {
     map: BTreeMap<Bytes, Vec<Bytes>>,
+    output_filter: OutputFilter,
 }
 
 impl MempoolStore {
-    fn new() -> MempoolStore {
+    fn new(output_filter: OutputFilter) -> MempoolStore {
         MempoolStore {
             map: BTreeMap::new(),
+            output_filter,
         }
     }
 
     #[allow(clippy::redundant_closure)]
     fn add(&mut self, tx: &Transaction) {
-        let rows = index_transaction(tx, MEMPOOL_HEIGHT as usize, None);
+        let rows = index_transaction(
+            tx,
+            MEMPOOL_HEIGHT as usize,
+            None,
+            false,
+            None,
+            self.output_filter,
+        );
         for row in rows {
             let (key, value) = row.into_pair();
             self.map.entry(key).or_insert_with(|| vec![]).push(value);
         }
     }
 
-    fn remove(&mut self, tx: &Transaction) {
-        let rows = index_transaction(tx, MEMPOOL_HEIGHT as usize, None);
+    /// Removes the rows of `tx`. Rows that can't be found are skipped, and
+    /// reported by the returned error after removing the others.
+    fn remove(&mut self, tx: &Transaction) -> Result<()> {
+        let rows = index_transaction(
+            tx,
+            MEMPOOL_HEIGHT as usize,
+            None,
+            false,
+            None,
+            self.output_filter,
+        );
+        let mut missing = vec![];
         for row in rows {
             let (key, value) = row.into_pair();
-            let no_values_left = {
-                let values = self
-                    .map
-                    .get_mut(&key)
-                    .unwrap_or_else(|| panic!("missing key {} in mempool", hex::encode(&key)));
-                let last_value = values
-                    .pop()
-                    .unwrap_or_else(|| panic!("no values found for key {}", hex::encode(&key)));
-                // TxInRow and TxOutRow have an empty value, TxRow has height=0 as value.
-                assert_eq!(
-                    value,
-                    last_value,
-                    "wrong value for key {}: {}",
-                    hex::encode(&key),
-                    hex::encode(&last_value)
-                );
-                values.is_empty()
+            let values = match self.map.get_mut(&key) {
+                Some(values) => values,
+                None => {
+                    missing.push(hex::encode(&key));
+                    continue;
+                }
             };
-            if no_values_left {
-                self.map.remove(&key).unwrap();
+            // TxInRow and TxOutRow have an empty value, TxRow has height=0 as value.
+            // The row is usually the last one added for its key.
+            match values.iter().rposition(|v| *v == value) {
+                Some(pos) => {
+                    values.remove(pos);
+                }
+                None => {
+                    missing.push(hex::encode(&key));
+                    continue;
+                }
+            }
+            if values.is_empty() {
+                self.map.remove(&key);
             }
         }
+        if !missing.is_empty() {
+            bail!("missing mempool rows for keys {}", missing.join(", "));
+        }
+        Ok(())
     }
 }
 
 impl ReadStore for MempoolStore {
-    fn get(&self, key: &[u8]) -> Option<Bytes> {
-        Some(self.map.get(key)?.last()?.to_vec())
+    fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        Ok(self
+            .map
+            .get(key)
+            .and_then(|values| values.last())
+            .map(|value| value.to_vec()))
     }
     fn scan(&self, prefix: &[u8]) -> Vec<Row> {
         let range = self
@@ -125,7 +156,10 @@ impl Stats {
             }
             vsize += e.vsize();
         }
-        let mut max_fee_rate = self.max_fee_rate.lock().unwrap();
+        let mut max_fee_rate = self
+            .max_fee_rate
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
         loop {
             bands.push((fee_rate, vsize));
             if fee_rate < *max_fee_rate {
@@ -146,18 +180,80 @@ impl Stats {
     }
 }
 
+/// Transactions that failed to be added to or removed from the mempool
+/// index. They are logged and counted, and skipped instead of crashing the
+/// server.
+struct DeadLetters {
+    count: prometheus::IntCounter,
+}
+
+impl DeadLetters {
+    fn add(&mut self, txid: &Txid, err: &Error) {
+        error!("skipping mempool tx {}: {}", txid, err.display_chain());
+        self.count.inc();
+    }
+}
+
+/// Set once a poisoned mempool lock has been reported, as the lock stays
+/// poisoned and every later access would log it again.
+static POISON_REPORTED: AtomicBool = AtomicBool::new(false);
+
+fn report_poisoned() {
+    if !POISON_REPORTED.swap(true, Ordering::Relaxed) {
+        warn!("recovering poisoned mempool lock");
+    }
+}
+
+/// Locks `tracker` for reading. A panic while the tracker was locked poisons
+/// the lock, which would then fail every later query. The tracker is used
+/// as-is instead, as the next mempool update resyncs it with bitcoind.
+pub fn read_tracker(tracker: &RwLock<Tracker>) -> RwLockReadGuard<'_, Tracker> {
+    tracker.read().unwrap_or_else(|err| {
+        report_poisoned();
+        err.into_inner()
+    })
+}
+
+/// Locks `tracker` for writing, recovering a poisoned lock as `read_tracker`
+/// does.
+pub fn write_tracker(tracker: &RwLock<Tracker>) -> RwLockWriteGuard<'_, Tracker> {
+    tracker.write().unwrap_or_else(|err| {
+        report_poisoned();
+        err.into_inner()
+    })
+}
+
 pub struct Tracker {
     items: HashMap<Txid, Item>,
     index: MempoolStore,
     histogram: Vec<(f32, u32)>,
     stats: Stats,
+    dead_letters: DeadLetters,
+    // Recompute the fee histogram only when this many vbytes changed, or
+    // when it's older than the max age
+    histogram_threshold: u64,
+    histogram_max_age: Option<Duration>,
+    histogram_time: Instant,
+    // Txs and vbytes added or removed since the fee histogram was computed
+    pending_txs: usize,
+    pending_vsize: u64,
 }
 
 impl Tracker {
-    pub fn new(metrics: &Metrics) -> Tracker {
+    /// `output_filter` limits the scripthash index as the confirmed index
+    /// does, see `Index::index_output_filter`. The fee histogram is
+    /// recomputed once transactions of at least `histogram_threshold` vbytes
+    /// were added or removed (or on any change, if 0), and on any change once
+    /// it's older than `histogram_max_age`.
+    pub fn new(
+        metrics: &Metrics,
+        output_filter: OutputFilter,
+        histogram_threshold: u64,
+        histogram_max_age: Option<Duration>,
+    ) -> Tracker {
         Tracker {
             items: HashMap::new(),
-            index: MempoolStore::new(),
+            index: MempoolStore::new(output_filter),
             histogram: vec![],
             stats: Stats {
                 count: metrics.gauge_int(prometheus::Opts::new(
@@ -180,6 +276,17 @@ impl Tracker {
                 ),
                 max_fee_rate: Mutex::new(1.0),
             },
+            dead_letters: DeadLetters {
+                count: metrics.counter_int(prometheus::Opts::new(
+                    "electrscash_mempool_dead_letters",
+                    "# of mempool transactions skipped due to indexing errors",
+                )),
+            },
+            histogram_threshold,
+            histogram_max_age,
+            histogram_time: Instant::now(),
+            pending_txs: 0,
+            pending_vsize: 0,
         }
     }
 
@@ -199,6 +306,20 @@ impl Tracker {
         self.items.contains_key(txid)
     }
 
+    pub fn tx_count(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Txids of the mempool transactions greater than `after` (all if None),
+    /// in no particular order.
+    pub fn txids_after(&self, after: Option<&Txid>) -> Vec<Txid> {
+        self.items
+            .keys()
+            .filter(|txid| after.map_or(true, |after| *txid > after))
+            .cloned()
+            .collect()
+    }
+
     /// Returns vector of (fee_rate, vsize) pairs, where fee_{n-1} > fee_n and vsize_n is the
     /// total virtual size of mempool transactions with fee in the bin [fee_{n-1}, fee_n].
     /// Note: fee_{-1} is implied to be infinite.
@@ -234,8 +355,11 @@ impl Tracker {
         });
         for (txid, entry) in entries {
             match txquery.get_unconfirmed(txid) {
+                Ok(tx) if tx.txid() != *txid => {
+                    let err = format!("bitcoind returned tx {}", tx.txid()).into();
+                    self.dead_letters.add(txid, &err);
+                }
                 Ok(tx) => {
-                    assert_eq!(tx.txid(), *txid);
                     self.add(txid, tx, entry);
                     changed_txs.insert(*txid);
                 }
@@ -248,13 +372,15 @@ impl Tracker {
 
         let timer = self.stats.start_timer("remove");
         for txid in old_txids.difference(&new_txids) {
-            self.remove(txid);
+            if let Err(err) = self.remove(txid) {
+                self.dead_letters.add(txid, &err);
+            }
             changed_txs.insert(*txid);
         }
         timer.observe_duration();
 
         let timer = self.stats.start_timer("fees");
-        self.update_fee_histogram();
+        self.maybe_update_fee_histogram();
         timer.observe_duration();
 
         self.stats.count.set(self.items.len() as i64);
@@ -283,17 +409,48 @@ impl Tracker {
         }
     }
 
+    /// Add `tx` to the mempool, as if fetched from bitcoind.
+    #[cfg(test)]
+    pub fn insert(&mut self, tx: Transaction, entry: MempoolEntry) {
+        self.add(&tx.txid(), tx, entry);
+    }
+
     fn add(&mut self, txid: &Txid, tx: Transaction, entry: MempoolEntry) {
+        self.pending_txs += 1;
+        self.pending_vsize += u64::from(entry.vsize());
         self.index.add(&tx);
         self.items.insert(*txid, Item { tx, entry });
     }
 
-    fn remove(&mut self, txid: &Txid) {
+    fn remove(&mut self, txid: &Txid) -> Result<()> {
         let stats = self
             .items
             .remove(txid)
-            .unwrap_or_else(|| panic!("missing mempool tx {}", txid));
-        self.index.remove(&stats.tx);
+            .chain_err(|| format!("missing mempool tx {}", txid))?;
+        self.pending_txs += 1;
+        self.pending_vsize += u64::from(stats.entry.vsize());
+        self.index
+            .remove(&stats.tx)
+            .chain_err(|| format!("failed to unindex mempool tx {}", txid))
+    }
+
+    /// Recompute the fee histogram if enough changed since the last time.
+    /// Returns true if it was recomputed.
+    fn maybe_update_fee_histogram(&mut self) -> bool {
+        if self.pending_txs == 0 {
+            return false;
+        }
+        let expired = self
+            .histogram_max_age
+            .map_or(false, |max_age| self.histogram_time.elapsed() >= max_age);
+        if self.pending_vsize < self.histogram_threshold && !expired {
+            return false;
+        }
+        self.update_fee_histogram();
+        self.histogram_time = Instant::now();
+        self.pending_txs = 0;
+        self.pending_vsize = 0;
+        true
     }
 
     fn update_fee_histogram(&mut self) {
@@ -329,6 +486,184 @@ fn electrum_fees(entries: &[&MempoolEntry]) -> Vec<(f32, u32)> {
 
 #[cfg(test)]
 mod tests {
+    #[test]
+    fn test_txids_after() {
+        use super::{OutputFilter, Tracker};
+        use crate::daemon::MempoolEntry;
+        use crate::metrics::Metrics;
+        use bitcoincash::blockdata::transaction::Transaction;
+        use bitcoincash::hash_types::Txid;
+        use std::collections::HashSet;
+
+        let tx = |i: u32| Transaction {
+            version: 1,
+            lock_time: i,
+            input: vec![],
+            output: vec![],
+        };
+        let mut tracker = Tracker::new(&Metrics::dummy(), OutputFilter::default(), 0, None);
+        assert!(tracker.txids_after(None).is_empty());
+        let txs: Vec<Transaction> = (0..10).map(tx).collect();
+        for tx in &txs {
+            tracker.add(&tx.txid(), tx.clone(), MempoolEntry::new(1000, 100));
+        }
+        tracker.remove(&txs[3].txid()).unwrap();
+
+        let tracked: HashSet<Txid> = tracker.items.keys().cloned().collect();
+        let txids: HashSet<Txid> = tracker.txids_after(None).into_iter().collect();
+        assert_eq!(txids, tracked);
+        assert_eq!(txids.len(), 9);
+        assert!(!txids.contains(&txs[3].txid()));
+
+        let mut sorted: Vec<Txid> = txids.into_iter().collect();
+        sorted.sort_unstable();
+        let mut after = tracker.txids_after(Some(&sorted[4]));
+        after.sort_unstable();
+        assert_eq!(after, sorted[5..].to_vec());
+        assert!(tracker.txids_after(Some(&sorted[8])).is_empty());
+    }
+
+    #[test]
+    fn test_dead_letters() {
+        use super::{OutputFilter, Tracker};
+        use crate::daemon::MempoolEntry;
+        use crate::metrics::Metrics;
+        use bitcoincash::blockdata::script::Script;
+        use bitcoincash::blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut};
+        use bitcoincash::hash_types::Txid;
+        use bitcoincash::hashes::Hash;
+
+        let tx = |i: u8| Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_slice(&[i; 32]).unwrap(), 0),
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+        };
+        let mut tracker = Tracker::new(&Metrics::dummy(), OutputFilter::default(), 0, None);
+        let (tx1, tx2) = (tx(1), tx(2));
+        tracker.add(&tx1.txid(), tx1.clone(), MempoolEntry::new(1000, 100));
+        tracker.add(&tx2.txid(), tx2.clone(), MempoolEntry::new(1000, 100));
+
+        // Removing an unknown tx fails, without touching the index
+        let unknown = tx(3).txid();
+        assert!(tracker.remove(&unknown).is_err());
+        assert_eq!(tracker.tx_count(), 2);
+
+        // Corrupt the index, so that removing tx1 hits a missing row. The
+        // other rows of tx1 are still removed, and tx2 is unaffected.
+        tracker.index.remove(&tx1).unwrap();
+        tracker.index.add(&tx2);
+        assert!(tracker.remove(&tx1.txid()).is_err());
+        assert!(!tracker.has_txn(&tx1.txid()));
+        tracker.index.remove(&tx2).unwrap();
+        tracker.remove(&tx2.txid()).unwrap();
+        assert!(tracker.index.map.is_empty());
+
+        let err = "failed".into();
+        tracker.dead_letters.add(&unknown, &err);
+        assert_eq!(tracker.dead_letters.count.get(), 1);
+    }
+
+    #[test]
+    fn test_fee_histogram_threshold() {
+        use super::{OutputFilter, Tracker};
+        use crate::daemon::MempoolEntry;
+        use crate::metrics::Metrics;
+        use bitcoincash::blockdata::transaction::Transaction;
+        use std::time::Duration;
+
+        let tx = |i: u32| Transaction {
+            version: 1,
+            lock_time: i,
+            input: vec![],
+            output: vec![],
+        };
+        let mut tracker = Tracker::new(&Metrics::dummy(), OutputFilter::default(), 1000, None);
+        // An unchanged mempool skips the recompute
+        assert!(!tracker.maybe_update_fee_histogram());
+
+        let (tx1, tx2) = (tx(1), tx(2));
+        tracker.add(&tx1.txid(), tx1.clone(), MempoolEntry::new(600, 600));
+        assert!(!tracker.maybe_update_fee_histogram());
+        assert!(tracker.fee_histogram().is_empty());
+
+        tracker.add(&tx2.txid(), tx2.clone(), MempoolEntry::new(500, 500));
+        assert!(tracker.maybe_update_fee_histogram());
+        assert_eq!(tracker.fee_histogram(), &vec![(1.0, 1100)]);
+        assert!(!tracker.maybe_update_fee_histogram());
+
+        // Without a threshold, any change triggers a recompute
+        let mut tracker = Tracker::new(&Metrics::dummy(), OutputFilter::default(), 0, None);
+        assert!(!tracker.maybe_update_fee_histogram());
+        tracker.add(&tx1.txid(), tx1.clone(), MempoolEntry::new(600, 600));
+        assert!(tracker.maybe_update_fee_histogram());
+        tracker.remove(&tx1.txid()).unwrap();
+        assert!(tracker.maybe_update_fee_histogram());
+        assert!(tracker.fee_histogram().is_empty());
+        assert!(!tracker.maybe_update_fee_histogram());
+
+        // Past the max age, any change triggers a recompute
+        let max_age = Some(Duration::from_secs(0));
+        let mut tracker = Tracker::new(&Metrics::dummy(), OutputFilter::default(), 1000, max_age);
+        assert!(!tracker.maybe_update_fee_histogram());
+        tracker.add(&tx1.txid(), tx1.clone(), MempoolEntry::new(600, 600));
+        assert!(tracker.maybe_update_fee_histogram());
+        assert_eq!(tracker.fee_histogram(), &vec![(1.0, 600)]);
+        assert!(!tracker.maybe_update_fee_histogram());
+
+        // Before it, the threshold still applies
+        let max_age = Some(Duration::from_secs(3600));
+        let mut tracker = Tracker::new(&Metrics::dummy(), OutputFilter::default(), 1000, max_age);
+        tracker.add(&tx1.txid(), tx1, MempoolEntry::new(600, 600));
+        assert!(!tracker.maybe_update_fee_histogram());
+        tracker.add(&tx2.txid(), tx2, MempoolEntry::new(500, 500));
+        assert!(tracker.maybe_update_fee_histogram());
+    }
+
+    #[test]
+    fn test_poisoned_tracker() {
+        use super::{read_tracker, write_tracker, OutputFilter, Tracker};
+        use crate::daemon::MempoolEntry;
+        use crate::metrics::Metrics;
+        use bitcoincash::blockdata::transaction::Transaction;
+        use std::sync::{Arc, RwLock};
+
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        };
+        let tracker = Arc::new(RwLock::new(Tracker::new(
+            &Metrics::dummy(),
+            OutputFilter::default(),
+            0,
+            None,
+        )));
+        write_tracker(&tracker).add(&tx.txid(), tx.clone(), MempoolEntry::new(1000, 100));
+
+        let panicking = Arc::clone(&tracker);
+        std::thread::spawn(move || {
+            let _tracker = panicking.write().unwrap();
+            panic!("panic while holding the mempool lock");
+        })
+        .join()
+        .unwrap_err();
+        assert!(tracker.is_poisoned());
+
+        assert!(read_tracker(&tracker).has_txn(&tx.txid()));
+        write_tracker(&tracker).remove(&tx.txid()).unwrap();
+        assert_eq!(read_tracker(&tracker).tx_count(), 0);
+    }
+
     #[test]
     fn test_fakestore() {
         use crate::daemon::MempoolEntry;
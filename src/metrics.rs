@@ -15,13 +15,16 @@ use crate::util::spawn_thread;
 pub struct Metrics {
     reg: prometheus::Registry,
     addr: SocketAddr,
+    // When set, scrapes must send an 'Authorization: Bearer <token>' header
+    auth_token: Option<String>,
 }
 
 impl Metrics {
-    pub fn new(addr: SocketAddr) -> Metrics {
+    pub fn new(addr: SocketAddr, auth_token: Option<String>) -> Metrics {
         Metrics {
             reg: prometheus::Registry::new(),
             addr,
+            auth_token,
         }
     }
 
@@ -30,6 +33,7 @@ impl Metrics {
         Metrics {
             reg: prometheus::Registry::new(),
             addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 1234),
+            auth_token: None,
         }
     }
 
@@ -89,20 +93,59 @@ impl Metrics {
             )
         });
         start_process_exporter(self);
+        self.serve(server);
+    }
+
+    fn serve(&self, server: tiny_http::Server) {
         let reg = self.reg.clone();
+        let auth_token = self.auth_token.clone();
         spawn_thread("metrics", move || loop {
-            if let Err(e) = handle_request(&reg, server.recv()) {
+            if let Err(e) = handle_request(&reg, auth_token.as_deref(), server.recv()) {
                 error!("http error: {}", e);
             }
         });
     }
 }
 
+/// Compare in constant time, to avoid leaking the token via timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn is_authorized(request: &tiny_http::Request, auth_token: &str) -> bool {
+    request.headers().iter().any(|header| {
+        header.field.equiv("Authorization")
+            && header
+                .value
+                .as_str()
+                .strip_prefix("Bearer ")
+                .map_or(false, |token| {
+                    constant_time_eq(token.trim().as_bytes(), auth_token.as_bytes())
+                })
+    })
+}
+
 fn handle_request(
     reg: &prometheus::Registry,
+    auth_token: Option<&str>,
     request: io::Result<tiny_http::Request>,
 ) -> io::Result<()> {
     let request = request?;
+    if let Some(auth_token) = auth_token {
+        if !is_authorized(&request, auth_token) {
+            debug!(
+                "rejected unauthorized metrics request from {}",
+                request.remote_addr()
+            );
+            let response = tiny_http::Response::from_string("Unauthorized")
+                .with_status_code(401)
+                .with_header(
+                    tiny_http::Header::from_bytes(&b"WWW-Authenticate"[..], &b"Bearer"[..])
+                        .unwrap(),
+                );
+            return request.respond(response);
+        }
+    }
     let mut buffer = vec![];
     prometheus::TextEncoder::new()
         .encode(&reg.gather(), &mut buffer)
@@ -159,6 +202,26 @@ fn start_process_exporter(metrics: &Metrics) {
         "# of file descriptors",
     ));
 
+    #[cfg(feature = "jemalloc")]
+    let mut update_jemalloc_stats = jemalloc_stats(metrics);
+
+    spawn_thread("exporter", move || loop {
+        if let Ok(stats) = parse_stats() {
+            cpu.with_label_values(&["utime"]).set(stats.utime as f64);
+            rss.set(stats.rss as i64);
+            fds.set(stats.fds as i64);
+        }
+        #[cfg(feature = "jemalloc")]
+        update_jemalloc_stats();
+
+        thread::sleep(Duration::from_secs(5));
+    });
+}
+
+/// Register the jemalloc allocator metrics, returning a function to update
+/// them.
+#[cfg(feature = "jemalloc")]
+fn jemalloc_stats(metrics: &Metrics) -> impl FnMut() + Send + 'static {
     let jemalloc_allocated = metrics.gauge_int(Opts::new(
         "electrscash_process_jemalloc_allocated",
         "# of bytes allocated by the application.",
@@ -169,21 +232,65 @@ fn start_process_exporter(metrics: &Metrics) {
         "# of bytes in physically resident data pages mapped by the allocator",
     ));
 
-    spawn_thread("exporter", move || {
-        let e = jemalloc_ctl::epoch::mib().unwrap();
-        let allocated = jemalloc_ctl::stats::allocated::mib().unwrap();
-        let resident = jemalloc_ctl::stats::resident::mib().unwrap();
-        loop {
-            if let Ok(stats) = parse_stats() {
-                cpu.with_label_values(&["utime"]).set(stats.utime as f64);
-                rss.set(stats.rss as i64);
-                fds.set(stats.fds as i64);
-            }
-            e.advance().unwrap();
-            jemalloc_allocated.set(allocated.read().unwrap() as i64);
-            jemalloc_resident.set(resident.read().unwrap() as i64);
+    let e = jemalloc_ctl::epoch::mib().unwrap();
+    let allocated = jemalloc_ctl::stats::allocated::mib().unwrap();
+    let resident = jemalloc_ctl::stats::resident::mib().unwrap();
+    move || {
+        e.advance().unwrap();
+        jemalloc_allocated.set(allocated.read().unwrap() as i64);
+        jemalloc_resident.set(resident.read().unwrap() as i64);
+    }
+}
 
-            thread::sleep(Duration::from_secs(5));
-        }
-    });
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    fn scrape(addr: SocketAddr, auth_header: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET /metrics HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+            auth_header
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_auth_token() {
+        let metrics = Metrics::new(
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0),
+            Some("secret".to_string()),
+        );
+        metrics
+            .counter_int(Opts::new("electrscash_test_counter", "test counter"))
+            .inc();
+        let server = tiny_http::Server::http(metrics.addr).unwrap();
+        let addr = server.server_addr();
+        metrics.serve(server);
+
+        let response = scrape(addr, "");
+        assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+        assert!(!response.contains("electrscash_test_counter"));
+
+        let response = scrape(addr, "Authorization: Bearer wrong\r\n");
+        assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+
+        let response = scrape(addr, "Authorization: Bearer secret\r\n");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains("electrscash_test_counter 1"));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+        assert!(constant_time_eq(b"", b""));
+    }
 }
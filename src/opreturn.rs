@@ -0,0 +1,191 @@
+use crate::errors::*;
+use crate::store::ReadStore;
+use crate::store::Row;
+use crate::util::{hash_prefix, Bytes, HashPrefix, HASH_PREFIX_LEN};
+use bitcoincash::blockdata::script::{Instruction, Script};
+use bitcoincash::blockdata::transaction::Transaction;
+use bitcoincash::hash_types::Txid;
+
+/// Number of payload bytes stored in the index. Enough to cover the protocol
+/// identifiers used by e.g. memo.cash (2 bytes) and SLP (4 bytes lokad id).
+pub const OPRETURN_PREFIX_LEN: usize = 4;
+
+/// Shortest prefix that can be looked up. A single byte would match most of
+/// the index.
+pub const MIN_OPRETURN_PREFIX_LEN: usize = 2;
+
+/// Returns the first data push of an OP_RETURN output script, which is
+/// where protocols put their identifier.
+pub fn opreturn_payload(script: &Script) -> Option<&[u8]> {
+    if !script.is_op_return() {
+        return None;
+    }
+    match script.instructions().nth(1) {
+        Some(Ok(Instruction::PushBytes(data))) if !data.is_empty() => Some(data),
+        _ => None,
+    }
+}
+
+/// Returns true if any OP_RETURN output in `txn` has a payload starting with
+/// `prefix`.
+pub fn has_opreturn_prefix(txn: &Transaction, prefix: &[u8]) -> bool {
+    txn.output
+        .iter()
+        .filter_map(|out| opreturn_payload(&out.script_pubkey))
+        .any(|payload| payload.starts_with(prefix))
+}
+
+/// Index row mapping an OP_RETURN payload prefix to a transaction.
+///
+/// The key is the row code, followed by (up to) `OPRETURN_PREFIX_LEN` bytes
+/// of the payload and the txid prefix. As the payload part is of variable
+/// length, a scan may return rows for other prefixes. Matches need to be
+/// verified against the transaction.
+pub struct TxOpReturnRow {
+    payload_prefix: Bytes,
+    pub txid_prefix: HashPrefix,
+}
+
+impl TxOpReturnRow {
+    pub fn new(txid: &Txid, payload: &[u8]) -> TxOpReturnRow {
+        TxOpReturnRow {
+            payload_prefix: payload[..payload.len().min(OPRETURN_PREFIX_LEN)].to_vec(),
+            txid_prefix: hash_prefix(&txid[..]),
+        }
+    }
+
+    pub fn filter(prefix: &[u8]) -> Bytes {
+        [b"R", &prefix[..prefix.len().min(OPRETURN_PREFIX_LEN)]].concat()
+    }
+
+    pub fn to_row(&self) -> Row {
+        Row {
+            key: [b"R", &self.payload_prefix[..], &self.txid_prefix[..]].concat(),
+            value: vec![],
+        }
+    }
+
+    pub fn from_row(row: &Row) -> TxOpReturnRow {
+        let split = row.key.len() - HASH_PREFIX_LEN;
+        TxOpReturnRow {
+            payload_prefix: row.key[1..split].to_vec(),
+            txid_prefix: hash_prefix(&row.key[split..]),
+        }
+    }
+}
+
+pub fn index_opreturn(txn: &Transaction) -> Vec<Row> {
+    let txid = txn.txid();
+    let mut payloads: Vec<&[u8]> = txn
+        .output
+        .iter()
+        .filter_map(|out| opreturn_payload(&out.script_pubkey))
+        .map(|payload| &payload[..payload.len().min(OPRETURN_PREFIX_LEN)])
+        .collect();
+    payloads.sort_unstable();
+    payloads.dedup();
+    payloads
+        .into_iter()
+        .map(|payload| TxOpReturnRow::new(&txid, payload).to_row())
+        .collect()
+}
+
+/// Prefixes of the transactions with an OP_RETURN payload starting with
+/// `prefix`. Fails if there are more than `limit` of them.
+pub fn txids_by_opreturn(
+    store: &dyn ReadStore,
+    prefix: &[u8],
+    limit: usize,
+) -> Result<Vec<HashPrefix>> {
+    let filter = TxOpReturnRow::filter(prefix);
+    let mut txid_prefixes: Vec<HashPrefix> = store
+        .scan_range(&filter, &filter, limit.saturating_add(1))
+        .iter()
+        .map(|row| TxOpReturnRow::from_row(row).txid_prefix)
+        .collect();
+    if txid_prefixes.len() > limit {
+        bail!(ErrorKind::RpcError(
+            RpcErrorCode::Other,
+            format!(
+                "too many transactions with OP_RETURN prefix {} (max {})",
+                hex::encode(prefix),
+                limit
+            )
+        ));
+    }
+    txid_prefixes.sort_unstable();
+    txid_prefixes.dedup();
+    Ok(txid_prefixes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::MemStore;
+    use bitcoincash::blockdata::opcodes::all::OP_RETURN;
+    use bitcoincash::blockdata::script::Builder;
+    use bitcoincash::blockdata::transaction::TxOut;
+
+    fn opreturn_tx(pushes: &[&[u8]], lock_time: u32) -> Transaction {
+        let mut builder = Builder::new().push_opcode(OP_RETURN);
+        for push in pushes {
+            builder = builder.push_slice(push);
+        }
+        Transaction {
+            version: 1,
+            lock_time,
+            input: vec![],
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: builder.into_script(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_opreturn_payload() {
+        let tx = opreturn_tx(&[b"\x6d\x02", b"hello"], 0);
+        assert_eq!(
+            opreturn_payload(&tx.output[0].script_pubkey),
+            Some(&b"\x6d\x02"[..])
+        );
+        let tx = opreturn_tx(&[], 0);
+        assert_eq!(opreturn_payload(&tx.output[0].script_pubkey), None);
+        let p2pkh = Builder::new().push_slice(b"\x6d\x02").into_script();
+        assert_eq!(opreturn_payload(&p2pkh), None);
+    }
+
+    #[test]
+    fn test_index_and_query() {
+        let memo = opreturn_tx(&[b"\x6d\x02", b"hello"], 0);
+        let slp = opreturn_tx(&[b"SLP\x00", b"\x01"], 1);
+        let other = opreturn_tx(&[b"\x6d\x0c\xff\xff\xff", b"hello"], 2);
+        let store = MemStore::with_rows(
+            [&memo, &slp, &other]
+                .iter()
+                .flat_map(|tx| index_opreturn(tx))
+                .collect(),
+        );
+
+        let txid_prefix = |tx: &Transaction| hash_prefix(&tx.txid()[..]);
+
+        assert_eq!(
+            txids_by_opreturn(&store, b"SLP\x00", 10).unwrap(),
+            vec![txid_prefix(&slp)]
+        );
+        // Payload beyond OPRETURN_PREFIX_LEN is not indexed.
+        assert_eq!(
+            txids_by_opreturn(&store, b"SLP\x00\x01\x02", 10).unwrap(),
+            vec![txid_prefix(&slp)]
+        );
+        let mut expected = vec![txid_prefix(&memo), txid_prefix(&other)];
+        expected.sort_unstable();
+        assert_eq!(txids_by_opreturn(&store, b"\x6d", 2).unwrap(), expected);
+        // More matches than the limit allows
+        assert!(txids_by_opreturn(&store, b"\x6d", 1).is_err());
+
+        assert!(has_opreturn_prefix(&memo, b"\x6d\x02"));
+        assert!(!has_opreturn_prefix(&other, b"\x6d\x02"));
+        assert!(txids_by_opreturn(&store, b"\x00", 10).unwrap().is_empty());
+    }
+}
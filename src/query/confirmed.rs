@@ -1,8 +1,9 @@
 use crate::errors::*;
+use crate::index::TxOutRow;
 use crate::query::primitives::{FundingOutput, SpendingInput};
 use crate::query::queryutil::{
-    find_spending_input, get_tx_spending_prevout, txoutrow_to_fundingoutput,
-    txoutrows_by_script_hash,
+    get_tx_spending_prevout, resolve_spending_input, txids_by_funding_outputs,
+    txoutrow_to_fundingoutput, txoutrows_by_script_hash,
 };
 use crate::query::tx::TxQuery;
 use crate::scripthash::FullHash;
@@ -13,14 +14,44 @@ use bitcoincash::blockdata::transaction::Transaction;
 use rayon::prelude::*;
 use std::sync::Arc;
 
+/// Get the confirmed outputs funding scripthash, failing if there are more
+/// than `txid_limit` (0 for no limit).
+fn funding_rows(
+    read_store: &dyn ReadStore,
+    scripthash: &FullHash,
+    txid_limit: usize,
+) -> Result<Vec<TxOutRow>> {
+    let rows = txoutrows_by_script_hash(read_store, scripthash);
+    if txid_limit > 0 && rows.len() > txid_limit {
+        bail!(ErrorKind::RpcError(
+            RpcErrorCode::Other,
+            format!(
+                "too many transactions ({}, max {}), narrow your query",
+                rows.len(),
+                txid_limit
+            )
+        ));
+    }
+    Ok(rows)
+}
+
 pub struct ConfirmedQuery {
     txquery: Arc<TxQuery>,
     duration: Arc<prometheus::HistogramVec>,
+    txid_limit: usize,
 }
 
 impl ConfirmedQuery {
-    pub fn new(txquery: Arc<TxQuery>, duration: Arc<prometheus::HistogramVec>) -> ConfirmedQuery {
-        ConfirmedQuery { txquery, duration }
+    pub fn new(
+        txquery: Arc<TxQuery>,
+        duration: Arc<prometheus::HistogramVec>,
+        txid_limit: usize,
+    ) -> ConfirmedQuery {
+        ConfirmedQuery {
+            txquery,
+            duration,
+            txid_limit,
+        }
     }
 
     /// Query for confirmed outputs that funding scripthash.
@@ -35,7 +66,7 @@ impl ConfirmedQuery {
             .duration
             .with_label_values(&["confirmed_status_funding"])
             .start_timer();
-        let funding = txoutrows_by_script_hash(read_store, scripthash);
+        let funding = funding_rows(read_store, scripthash, self.txid_limit)?;
         timeout.check()?;
         let funding = funding
             .par_iter()
@@ -60,11 +91,21 @@ impl ConfirmedQuery {
             .with_label_values(&["confirmed_status_spending"])
             .start_timer();
 
+        let prevouts: Vec<OutPoint> = confirmed_funding.iter().map(|f| f.funding_output).collect();
+        let spending_txns = txids_by_funding_outputs(read_store, &prevouts);
         let spending: Result<Vec<Option<SpendingInput>>> = confirmed_funding
             .par_iter()
-            .map(|funding_output| {
+            .zip(spending_txns.into_par_iter())
+            .map(|(funding_output, spending_txns)| {
                 timeout.check().and_then(|_| {
-                    find_spending_input(read_store, funding_output, None, &*self.txquery, timeout)
+                    resolve_spending_input(
+                        read_store,
+                        funding_output,
+                        spending_txns,
+                        None,
+                        &*self.txquery,
+                        timeout,
+                    )
                 })
             })
             .collect();
@@ -89,3 +130,48 @@ impl ConfirmedQuery {
         get_tx_spending_prevout(read_store, &*self.txquery, timeout, prevout)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::MemStore;
+    use crate::scripthash::compute_script_hash;
+    use crate::store::WriteStore;
+    use bitcoincash::blockdata::script::Builder;
+    use bitcoincash::blockdata::transaction::TxOut;
+    use bitcoincash::hash_types::Txid;
+    use bitcoincash::hashes::Hash;
+
+    #[test]
+    fn test_txid_limit() {
+        let script_pubkey = Builder::new().push_int(1).into_script();
+        let scripthash = compute_script_hash(&script_pubkey[..]);
+        let output = TxOut {
+            value: 1000,
+            script_pubkey,
+        };
+        let store = MemStore::default();
+        store
+            .write(
+                (0..5u8).map(|i| {
+                    let txid = Txid::from_slice(&[i; 32]).unwrap();
+                    TxOutRow::new(&txid, &output, 0).to_row()
+                }),
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(funding_rows(&store, &scripthash, 5).unwrap().len(), 5);
+        assert_eq!(funding_rows(&store, &scripthash, 0).unwrap().len(), 5);
+        let err = match funding_rows(&store, &scripthash, 4) {
+            Ok(_) => panic!("expected txid limit to be exceeded"),
+            Err(e) => e,
+        };
+        match err.kind() {
+            ErrorKind::RpcError(RpcErrorCode::Other, msg) => {
+                assert!(msg.contains("too many transactions"))
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+}
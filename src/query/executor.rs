@@ -0,0 +1,193 @@
+use crate::errors::*;
+use crate::metrics::Metrics;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Thread pool for query work that blocks on bitcoind (such as loading the
+/// transactions of a scripthash history), so that a burst of expensive
+/// queries doesn't tie up all peer threads. At most `queue_size` jobs wait
+/// for a thread, further jobs are rejected as busy.
+pub struct QueryExecutor {
+    pool: rayon::ThreadPool,
+    capacity: usize, // threads + queue_size
+    pending: AtomicUsize,
+    pending_gauge: prometheus::IntGauge,
+    rejected: prometheus::IntCounter,
+}
+
+/// Registers a job as pending until dropped
+struct PendingGuard<'a> {
+    executor: &'a QueryExecutor,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        let pending = self.executor.pending.fetch_sub(1, Ordering::SeqCst) - 1;
+        self.executor.pending_gauge.set(pending as i64);
+    }
+}
+
+impl QueryExecutor {
+    /// Start `threads` threads (0 for one per CPU).
+    pub fn new(threads: usize, queue_size: usize, metrics: &Metrics) -> Result<QueryExecutor> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("query-{}", i))
+            .build()
+            .chain_err(|| "failed to start query threads")?;
+        let pending_gauge = metrics.gauge_int(prometheus::Opts::new(
+            "electrscash_query_executor_pending",
+            "# of expensive queries running or waiting for a query thread",
+        ));
+        pending_gauge.set(0);
+        Ok(QueryExecutor {
+            capacity: pool.current_num_threads() + queue_size,
+            pool,
+            pending: AtomicUsize::new(0),
+            pending_gauge,
+            rejected: metrics.counter_int(prometheus::Opts::new(
+                "electrscash_query_executor_rejected_total",
+                "# of expensive queries rejected as all query threads were busy",
+            )),
+        })
+    }
+
+    /// Run `job` on a query thread, waiting for its result. Fails with a
+    /// busy error if too many jobs are pending. Parallel iterators in `job`
+    /// also run on the query threads.
+    pub fn run<F, T>(&self, job: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send,
+        T: Send,
+    {
+        let _guard = self.start_job()?;
+        self.pool.install(job)
+    }
+
+    /// Run `job` on a query thread like `run`, but never reject it as busy.
+    /// For work the client can't retry, such as updating the status of a
+    /// subscription to notify it.
+    pub fn run_unbounded<F, T>(&self, job: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send,
+        T: Send,
+    {
+        let _guard = self.track_job().0;
+        self.pool.install(job)
+    }
+
+    fn track_job(&self) -> (PendingGuard<'_>, usize) {
+        let pending = self.pending.fetch_add(1, Ordering::SeqCst) + 1;
+        self.pending_gauge.set(pending as i64);
+        (PendingGuard { executor: self }, pending)
+    }
+
+    fn start_job(&self) -> Result<PendingGuard<'_>> {
+        let (guard, pending) = self.track_job();
+        if pending > self.capacity {
+            self.rejected.inc();
+            bail!(ErrorKind::RpcError(
+                RpcErrorCode::ServerBusy,
+                format!(
+                    "server busy: {} queries pending, retry later",
+                    self.capacity
+                )
+            ));
+        }
+        Ok(guard)
+    }
+
+    /// Number of jobs running or waiting for a thread
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    fn assert_busy(result: Result<()>) {
+        match result.unwrap_err().kind() {
+            ErrorKind::RpcError(RpcErrorCode::ServerBusy, msg) => {
+                assert!(msg.contains("retry later"), "{}", msg)
+            }
+            e => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn test_query_executor() {
+        let executor = QueryExecutor::new(2, 0, &Metrics::dummy()).unwrap();
+        let name = executor
+            .run(|| Ok(thread::current().name().map(str::to_owned)))
+            .unwrap();
+        assert!(name.unwrap().starts_with("query-"));
+        assert_eq!(executor.pending(), 0);
+
+        // errors of the job are passed as-is
+        let result: Result<()> = executor.run(|| bail!("failed"));
+        assert_eq!(result.unwrap_err().to_string(), "failed");
+        assert_eq!(executor.pending(), 0);
+        assert_eq!(executor.rejected.get(), 0);
+    }
+
+    #[test]
+    fn test_query_executor_busy() {
+        let executor = Arc::new(QueryExecutor::new(1, 1, &Metrics::dummy()).unwrap());
+        // a running and a queued job saturate the executor
+        let started = Arc::new(Barrier::new(2));
+        let (release, wait) = crossbeam_channel::unbounded::<()>();
+        let jobs: Vec<_> = (0..2)
+            .map(|_| {
+                let executor = Arc::clone(&executor);
+                let started = Arc::clone(&started);
+                let wait = wait.clone();
+                thread::spawn(move || {
+                    executor.run(|| {
+                        started.wait();
+                        let _ = wait.recv();
+                        Ok(())
+                    })
+                })
+            })
+            .collect();
+        // wait for the first job to run, and the second one to be queued
+        started.wait();
+        let mut attempts = 0;
+        while executor.pending() < 2 {
+            attempts += 1;
+            assert!(attempts < 500, "job was not queued");
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_busy(executor.run(|| Ok(())));
+        assert_eq!(executor.rejected.get(), 1);
+        assert_eq!(executor.pending_gauge.get(), 2);
+
+        // unbounded jobs are queued instead of rejected
+        let unbounded = {
+            let executor = Arc::clone(&executor);
+            thread::spawn(move || executor.run_unbounded(|| Ok(())))
+        };
+        let mut attempts = 0;
+        while executor.pending() < 3 {
+            attempts += 1;
+            assert!(attempts < 500, "unbounded job was not queued");
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(executor.rejected.get(), 1);
+
+        // the queued job runs once the first one is done
+        release.send(()).unwrap();
+        started.wait();
+        drop(release);
+        for job in jobs {
+            job.join().unwrap().unwrap();
+        }
+        unbounded.join().unwrap().unwrap();
+        assert_eq!(executor.pending(), 0);
+        assert_eq!(executor.pending_gauge.get(), 0);
+        executor.run(|| Ok(())).unwrap();
+    }
+}
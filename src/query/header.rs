@@ -1,18 +1,156 @@
 use crate::app::App;
 use crate::errors::*;
 use crate::mempool::MEMPOOL_HEIGHT;
+use crate::metrics::Metrics;
 use crate::query::queryutil::txrow_by_txid;
 use crate::util::HeaderEntry;
-use bitcoincash::hash_types::Txid;
-use std::sync::Arc;
+use bitcoincash::hash_types::{BlockHash, Txid};
+use bitcoincash::util::uint::Uint256;
+use prometheus::IntCounterVec;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// Least recently used cache of the block transactions were confirmed in.
+struct TxHeightCache {
+    capacity: usize,
+    entries: HashMap<Txid, (u32, BlockHash, u64)>,
+    // last use -> txid
+    lru: BTreeMap<u64, Txid>,
+    tick: u64,
+    lookups: IntCounterVec,
+}
+
+impl TxHeightCache {
+    fn new(capacity: usize, lookups: IntCounterVec) -> TxHeightCache {
+        TxHeightCache {
+            capacity,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            lookups,
+        }
+    }
+
+    fn touch(&mut self, txid: &Txid) {
+        self.tick += 1;
+        if let Some(entry) = self.entries.get_mut(txid) {
+            self.lru.remove(&entry.2);
+            self.lru.insert(self.tick, *txid);
+            entry.2 = self.tick;
+        }
+    }
+
+    fn remove(&mut self, txid: &Txid) {
+        if let Some((_, _, last_use)) = self.entries.remove(txid) {
+            self.lru.remove(&last_use);
+        }
+    }
+
+    /// Get header of the block `txid` was confirmed in. Entries for blocks
+    /// no longer in the chain (reorged) are invalidated.
+    fn get(
+        &mut self,
+        txid: &Txid,
+        header_at: impl Fn(usize) -> Option<HeaderEntry>,
+    ) -> Option<HeaderEntry> {
+        if let Some((height, blockhash, _)) = self.entries.get(txid).cloned() {
+            match header_at(height as usize) {
+                Some(header) if *header.hash() == blockhash => {
+                    self.touch(txid);
+                    self.lookups.with_label_values(&["hit"]).inc();
+                    return Some(header);
+                }
+                _ => self.remove(txid),
+            }
+        }
+        self.lookups.with_label_values(&["miss"]).inc();
+        None
+    }
+
+    fn put(&mut self, txid: Txid, header: &HeaderEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(&txid);
+        while self.entries.len() >= self.capacity {
+            let (&last_use, &oldest) = self.lru.iter().next().unwrap();
+            self.lru.remove(&last_use);
+            self.entries.remove(&oldest);
+        }
+        self.tick += 1;
+        self.entries
+            .insert(txid, (header.height() as u32, *header.hash(), self.tick));
+        self.lru.insert(self.tick, txid);
+    }
+}
+
+/// Number of blocks used for the median time past
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Median timestamp of the last 11 blocks up to (and including) `height`.
+fn median_time_past_at(
+    height: usize,
+    header_at: impl Fn(usize) -> Option<HeaderEntry>,
+) -> Option<u32> {
+    let first_height = (height + 1).saturating_sub(MEDIAN_TIME_SPAN);
+    let mut times = (first_height..=height)
+        .map(|h| header_at(h).map(|entry| entry.header().time))
+        .collect::<Option<Vec<u32>>>()?;
+    times.sort_unstable();
+    Some(times[times.len() / 2])
+}
 
 pub struct HeaderQuery {
     app: Arc<App>,
+    cache: Mutex<TxHeightCache>,
 }
 
 impl HeaderQuery {
-    pub fn new(app: Arc<App>) -> HeaderQuery {
-        HeaderQuery { app }
+    pub fn new(app: Arc<App>, cache_capacity: usize, metrics: &Metrics) -> HeaderQuery {
+        let lookups = metrics.counter_int_vec(
+            prometheus::Opts::new(
+                "electrscash_cache_txheight_lookups",
+                "# of cache lookups in the transaction height cache",
+            ),
+            &["type"],
+        );
+        HeaderQuery {
+            app,
+            cache: Mutex::new(TxHeightCache::new(cache_capacity, lookups)),
+        }
+    }
+
+    /// Cumulative chain work up to (and including) the given block
+    pub fn chainwork(&self, entry: &HeaderEntry) -> Result<Uint256> {
+        self.app
+            .index()
+            .get_chainwork(entry)
+            .chain_err(|| format!("block {} is not in the best chain", entry.hash()))
+    }
+
+    /// Median time past of the given block
+    pub fn median_time_past(&self, entry: &HeaderEntry) -> Result<u32> {
+        median_time_past_at(entry.height(), |height| self.at_height(height))
+            .chain_err(|| format!("missing headers below height {}", entry.height()))
+    }
+
+    /// Get header for the block that given confirmed transaction is in.
+    fn confirmed_header(&self, txid: &Txid) -> Result<Option<HeaderEntry>> {
+        let header_at = |height| self.at_height(height);
+        if let Some(header) = self.cache.lock().unwrap().get(txid, header_at) {
+            return Ok(Some(header));
+        }
+        // Only confirmed transactions are in the store, so mempool
+        // transactions are never cached.
+        let height = match txrow_by_txid(self.app.read_store(), txid)? {
+            Some(txrow) => txrow.height,
+            None => return Ok(None),
+        };
+        let header = self.at_height(height as usize);
+        if let Some(header) = &header {
+            self.cache.lock().unwrap().put(*txid, header);
+        }
+        Ok(header)
     }
 
     /// Get header for the block that given transaction was confirmed in.
@@ -31,35 +169,192 @@ impl HeaderQuery {
                 height
             }
             None => {
-                txrow_by_txid(self.app.read_store(), txid)
-                    .chain_err(|| format!("not indexed tx {}", txid))?
-                    .height
+                let header = self
+                    .confirmed_header(txid)?
+                    .chain_err(|| format!("not indexed tx {}", txid))?;
+                return Ok(Some(header));
             }
         };
 
         let header = self
             .app
             .index()
-            .get_header(height as usize)
+            .get_header(self.app.read_store(), height as usize)
             .chain_err(|| format!("missing header at height {}", height))?;
         Ok(Some(header))
     }
 
+    /// Get headers for the blocks that given transactions were confirmed in,
+    /// or None for transactions that are unconfirmed (or don't exist).
+    pub fn get_by_txids(&self, txids: &[Txid]) -> Result<Vec<Option<HeaderEntry>>> {
+        let heights: Vec<Option<u32>> = txids
+            .iter()
+            .map(|txid| self.get_confirmed_height_for_tx(txid))
+            .collect::<Result<_>>()?;
+        Ok(headers_at_heights(&heights, |height| {
+            self.at_height(height)
+        }))
+    }
+
     pub fn best(&self) -> Option<HeaderEntry> {
         self.app.index().best_header()
     }
 
     pub fn at_height(&self, height: usize) -> Option<HeaderEntry> {
-        self.app.index().get_header(height)
+        self.app.index().get_header(self.app.read_store(), height)
+    }
+
+    /// Header of a block in the best chain, or None if unknown or orphaned.
+    pub fn by_blockhash(&self, blockhash: &BlockHash) -> Option<HeaderEntry> {
+        self.app
+            .index()
+            .get_header_by_hash(self.app.read_store(), blockhash)
     }
 
     /// Get the height of block where a transaction was confirmed, or None if it's
     /// not confirmed.
     /// TODO: Move to TxQuery
-    pub fn get_confirmed_height_for_tx(&self, txid: &Txid) -> Option<u32> {
-        match txrow_by_txid(self.app.read_store(), txid) {
-            Some(txrow) => Some(txrow.height),
-            None => None,
-        }
+    pub fn get_confirmed_height_for_tx(&self, txid: &Txid) -> Result<Option<u32>> {
+        Ok(self
+            .confirmed_header(txid)?
+            .map(|header| header.height() as u32))
+    }
+}
+
+/// Looks up header for each height, looking up each distinct height only once.
+fn headers_at_heights(
+    heights: &[Option<u32>],
+    lookup: impl Fn(usize) -> Option<HeaderEntry>,
+) -> Vec<Option<HeaderEntry>> {
+    let mut headers: HashMap<u32, Option<HeaderEntry>> = HashMap::new();
+    heights
+        .iter()
+        .map(|height| {
+            let height = (*height)?;
+            headers
+                .entry(height)
+                .or_insert_with(|| lookup(height as usize))
+                .clone()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::chain_headers;
+    use crate::util::HeaderList;
+    use bitcoincash::hashes::Hash;
+    use std::cell::Cell;
+
+    fn make_headers(len: u32) -> Vec<HeaderEntry> {
+        make_fork(len, 0)
+    }
+
+    /// Chain sharing the first two blocks with any other fork
+    fn make_fork(len: u32, time: u32) -> Vec<HeaderEntry> {
+        let mut times = vec![time; len as usize];
+        times.iter_mut().take(2).for_each(|t| *t = 0);
+        make_chain(&times)
+    }
+
+    fn make_chain(times: &[u32]) -> Vec<HeaderEntry> {
+        HeaderList::empty().order(chain_headers(times))
+    }
+
+    #[test]
+    fn test_median_time_past() {
+        let times = [5, 1, 9, 3, 7, 2, 8, 4, 6, 10, 0, 11, 12];
+        let chain = make_chain(&times);
+        let header_at = |height: usize| chain.get(height).cloned();
+        assert_eq!(median_time_past_at(0, header_at), Some(5));
+        assert_eq!(median_time_past_at(2, header_at), Some(5));
+        // 0..=10 sorted is 0..=10
+        assert_eq!(median_time_past_at(10, header_at), Some(5));
+        // 2..=12 sorted is [0, 2, 3, 4, 6, 7, 8, 9, 10, 11, 12]
+        assert_eq!(median_time_past_at(12, header_at), Some(7));
+        assert_eq!(median_time_past_at(13, header_at), None);
+    }
+
+    #[test]
+    fn test_headers_at_heights() {
+        let chain = make_headers(5);
+        let lookups = Cell::new(0);
+        let lookup = |height: usize| {
+            lookups.set(lookups.get() + 1);
+            chain.get(height).cloned()
+        };
+
+        // Mix of confirmed, unconfirmed (None) and unknown heights.
+        let heights = vec![Some(1), None, Some(3), Some(1), None, Some(42), Some(3)];
+        let headers = headers_at_heights(&heights, lookup);
+
+        assert_eq!(headers.len(), heights.len());
+        assert_eq!(headers[0].as_ref().unwrap().height(), 1);
+        assert!(headers[1].is_none());
+        assert_eq!(headers[2].as_ref().unwrap().height(), 3);
+        assert_eq!(headers[3].as_ref().unwrap().height(), 1);
+        assert!(headers[4].is_none());
+        assert!(headers[5].is_none());
+        assert_eq!(headers[6].as_ref().unwrap().height(), 3);
+
+        // Heights 1, 3 and 42 looked up once each
+        assert_eq!(lookups.get(), 3);
+    }
+
+    fn cache(capacity: usize) -> TxHeightCache {
+        let lookups = IntCounterVec::new(prometheus::Opts::new("name", "help"), &["type"]).unwrap();
+        TxHeightCache::new(capacity, lookups)
+    }
+
+    fn txid(n: u8) -> Txid {
+        Txid::from_slice(&[n; 32]).unwrap()
+    }
+
+    fn hits(cache: &TxHeightCache) -> u64 {
+        cache.lookups.with_label_values(&["hit"]).get()
+    }
+
+    #[test]
+    fn test_txheight_cache_lru() {
+        let chain = make_headers(5);
+        let header_at = |height: usize| chain.get(height).cloned();
+        let mut cache = cache(2);
+
+        cache.put(txid(1), &chain[1]);
+        cache.put(txid(2), &chain[2]);
+        // use 1, so that 2 is the least recently used
+        assert_eq!(cache.get(&txid(1), header_at).unwrap().height(), 1);
+        cache.put(txid(3), &chain[3]);
+
+        assert!(cache.get(&txid(2), header_at).is_none());
+        assert_eq!(cache.get(&txid(1), header_at).unwrap().height(), 1);
+        assert_eq!(cache.get(&txid(3), header_at).unwrap().height(), 3);
+        assert_eq!(hits(&cache), 3);
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.lru.len(), 2);
+    }
+
+    #[test]
+    fn test_txheight_cache_reorg() {
+        let chain = make_fork(5, 0);
+        let fork = make_fork(5, 1);
+        assert_eq!(chain[1].hash(), fork[1].hash());
+        assert_ne!(chain[3].hash(), fork[3].hash());
+        let mut cache = cache(10);
+        cache.put(txid(1), &chain[1]);
+        cache.put(txid(3), &chain[3]);
+
+        let header_at = |height: usize| fork.get(height).cloned();
+        // block 1 is still in the chain
+        assert_eq!(
+            cache.get(&txid(1), header_at).unwrap().hash(),
+            fork[1].hash()
+        );
+        // block 3 was reorged out, entry is invalidated
+        assert!(cache.get(&txid(3), header_at).is_none());
+        assert!(!cache.entries.contains_key(&txid(3)));
+        assert_eq!(cache.lru.len(), 1);
+        assert_eq!(hits(&cache), 1);
     }
 }
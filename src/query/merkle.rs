@@ -0,0 +1,230 @@
+use crate::errors::*;
+use crate::metrics::Metrics;
+use crate::query::merklize;
+use bitcoincash::hash_types::{BlockHash, TxMerkleNode, Txid};
+use bitcoincash::hashes::Hash;
+use prometheus::IntCounterVec;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// All levels of the merkle tree of a block, from the txids up to the merkle
+/// root, so that the branch of any of its transactions can be extracted
+/// without hashing.
+pub struct MerkleTree {
+    levels: Vec<Vec<TxMerkleNode>>,
+}
+
+impl MerkleTree {
+    pub fn new(txids: &[Txid]) -> MerkleTree {
+        let leaves: Vec<TxMerkleNode> = txids
+            .iter()
+            .map(|txid| TxMerkleNode::from_inner(txid.into_inner()))
+            .collect();
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            // the last node of an odd-length level is paired with itself
+            let level = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| merklize(pair[0], *pair.last().unwrap()))
+                .collect();
+            levels.push(level);
+        }
+        MerkleTree { levels }
+    }
+
+    pub fn txid_count(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    pub fn txid_at(&self, pos: usize) -> Option<Txid> {
+        self.levels[0]
+            .get(pos)
+            .map(|node| Txid::from_inner(node.into_inner()))
+    }
+
+    pub fn position(&self, txid: &Txid) -> Option<usize> {
+        self.levels[0].iter().position(|node| node[..] == txid[..])
+    }
+
+    pub fn root(&self) -> Option<TxMerkleNode> {
+        self.levels.last().unwrap().first().cloned()
+    }
+
+    /// Merkle branch of the transaction at `pos`, from the txids up.
+    pub fn branch(&self, mut pos: usize) -> Vec<TxMerkleNode> {
+        assert!(pos < self.txid_count());
+        let mut branch = vec![];
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = level.get(pos ^ 1).unwrap_or(&level[pos]);
+            branch.push(*sibling);
+            pos /= 2;
+        }
+        branch
+    }
+}
+
+/// Least recently used cache of the merkle trees of blocks, as wallets tend
+/// to request proofs of several transactions of a (recent) block.
+struct MerkleTreeLru {
+    capacity: usize,
+    entries: HashMap<BlockHash, (Arc<MerkleTree>, u64)>,
+    // last use -> blockhash
+    lru: BTreeMap<u64, BlockHash>,
+    tick: u64,
+}
+
+impl MerkleTreeLru {
+    fn get(&mut self, blockhash: &BlockHash) -> Option<Arc<MerkleTree>> {
+        self.tick += 1;
+        let (tree, last_use) = self.entries.get_mut(blockhash)?;
+        self.lru.remove(last_use);
+        self.lru.insert(self.tick, *blockhash);
+        *last_use = self.tick;
+        Some(Arc::clone(tree))
+    }
+
+    fn put(&mut self, blockhash: BlockHash, tree: Arc<MerkleTree>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some((_, last_use)) = self.entries.remove(&blockhash) {
+            self.lru.remove(&last_use);
+        }
+        while self.entries.len() >= self.capacity {
+            let (&last_use, &oldest) = self.lru.iter().next().unwrap();
+            self.lru.remove(&last_use);
+            self.entries.remove(&oldest);
+        }
+        self.tick += 1;
+        self.entries.insert(blockhash, (tree, self.tick));
+        self.lru.insert(self.tick, blockhash);
+    }
+}
+
+pub struct MerkleTreeCache {
+    lru: Mutex<MerkleTreeLru>,
+    lookups: IntCounterVec,
+}
+
+impl MerkleTreeCache {
+    /// Cache the merkle trees of up to `capacity` blocks (0 to disable).
+    pub fn new(capacity: usize, metrics: &Metrics) -> MerkleTreeCache {
+        MerkleTreeCache {
+            lru: Mutex::new(MerkleTreeLru {
+                capacity,
+                entries: HashMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+            }),
+            lookups: metrics.counter_int_vec(
+                prometheus::Opts::new(
+                    "electrscash_cache_merkle_lookups",
+                    "# of cache lookups in the merkle tree cache",
+                ),
+                &["type"],
+            ),
+        }
+    }
+
+    /// Merkle tree of block `blockhash`, computed from the txids loaded by
+    /// `load_txids_func` if not cached.
+    pub fn get_or_else<F>(
+        &self,
+        blockhash: &BlockHash,
+        load_txids_func: F,
+    ) -> Result<Arc<MerkleTree>>
+    where
+        F: FnOnce() -> Result<Vec<Txid>>,
+    {
+        if let Some(tree) = self.lru.lock().unwrap().get(blockhash) {
+            self.lookups.with_label_values(&["hit"]).inc();
+            return Ok(tree);
+        }
+        self.lookups.with_label_values(&["miss"]).inc();
+        // computed without holding the lock, as it takes a while for large
+        // blocks
+        let tree = Arc::new(MerkleTree::new(&load_txids_func()?));
+        self.lru.lock().unwrap().put(*blockhash, Arc::clone(&tree));
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::create_merkle_branch_and_root;
+    use std::cell::Cell;
+
+    fn txids(count: usize) -> Vec<Txid> {
+        (0..count).map(|i| Txid::hash(&i.to_le_bytes())).collect()
+    }
+
+    fn blockhash(n: u8) -> BlockHash {
+        BlockHash::from_inner([n; 32])
+    }
+
+    #[test]
+    fn test_merkle_tree() {
+        for count in 1..20 {
+            let txids = txids(count);
+            let tree = MerkleTree::new(&txids);
+            let leaves: Vec<TxMerkleNode> = txids
+                .iter()
+                .map(|txid| TxMerkleNode::from_inner(txid.into_inner()))
+                .collect();
+            for (pos, txid) in txids.iter().enumerate() {
+                let (branch, root) = create_merkle_branch_and_root(leaves.clone(), pos);
+                assert_eq!(tree.branch(pos), branch, "count={} pos={}", count, pos);
+                assert_eq!(tree.root(), Some(root));
+                assert_eq!(tree.position(txid), Some(pos));
+                assert_eq!(tree.txid_at(pos), Some(*txid));
+            }
+            assert_eq!(tree.txid_at(count), None);
+        }
+    }
+
+    #[test]
+    fn test_merkle_tree_cache() {
+        let cache = MerkleTreeCache::new(2, &Metrics::dummy());
+        let block_txids = txids(7);
+        let loads = Cell::new(0);
+        let load = || {
+            loads.set(loads.get() + 1);
+            Ok(block_txids.clone())
+        };
+        let leaves: Vec<TxMerkleNode> = block_txids
+            .iter()
+            .map(|txid| TxMerkleNode::from_inner(txid.into_inner()))
+            .collect();
+
+        // proofs of two transactions of the same block share the tree
+        for pos in [2, 5] {
+            let tree = cache.get_or_else(&blockhash(1), load).unwrap();
+            let (branch, _root) = create_merkle_branch_and_root(leaves.clone(), pos);
+            assert_eq!(tree.branch(pos), branch);
+        }
+        assert_eq!(loads.get(), 1);
+        assert_eq!(cache.lookups.with_label_values(&["hit"]).get(), 1);
+
+        // the least recently used block is evicted
+        cache.get_or_else(&blockhash(2), load).unwrap();
+        cache.get_or_else(&blockhash(1), load).unwrap();
+        cache.get_or_else(&blockhash(3), load).unwrap();
+        assert_eq!(loads.get(), 3);
+        cache.get_or_else(&blockhash(1), load).unwrap();
+        assert_eq!(loads.get(), 3);
+        cache.get_or_else(&blockhash(2), load).unwrap();
+        assert_eq!(loads.get(), 4);
+
+        // failures are not cached
+        let cache = MerkleTreeCache::new(0, &Metrics::dummy());
+        assert!(cache
+            .get_or_else(&blockhash(1), || bail!("failed"))
+            .is_err());
+        cache.get_or_else(&blockhash(1), load).unwrap();
+        cache.get_or_else(&blockhash(1), load).unwrap();
+        assert_eq!(loads.get(), 6);
+    }
+}
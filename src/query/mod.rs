@@ -5,31 +5,46 @@ use bitcoincash::hashes::hex::ToHex;
 use bitcoincash::hashes::sha256d::Hash as Sha256dHash;
 use bitcoincash::hashes::Hash;
 use bitcoincash::network::constants::Network;
+use bitcoincash::util::uint::Uint256;
 use serde_json::Value;
 use sha2::{Digest, Sha256};
-use std::collections::{HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::RangeInclusive;
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use crate::app::App;
-use crate::cache::TransactionCache;
-use crate::cashaccount::{txids_by_cashaccount, CashAccountParser};
+use crate::banner::BannerVars;
+use crate::blockstats::{read_block_fee_stats, read_block_stats};
+use crate::cache::{AddressCache, TransactionCache};
+use crate::cashaccount::{txids_by_cashaccount, txids_by_cashaccount_range, CashAccountParser};
 use crate::errors::*;
-use crate::index::TxRow;
-use crate::mempool::{ConfirmationState, Tracker};
+use crate::index::{FirstUseRow, SyncStatus, TxRow};
+use crate::mempool::{read_tracker, write_tracker, ConfirmationState, Tracker};
 use crate::metrics::Metrics;
+use crate::opreturn::{has_opreturn_prefix, txids_by_opreturn};
 use crate::query::confirmed::ConfirmedQuery;
+use crate::query::executor::QueryExecutor;
 use crate::query::header::HeaderQuery;
+use crate::query::merkle::{MerkleTree, MerkleTreeCache};
 use crate::query::primitives::{FundingOutput, SpendingInput};
-use crate::query::queryutil::{load_txns_by_prefix, txoutrows_by_script_hash, txrows_by_prefix};
-use crate::query::tx::TxQuery;
+use crate::query::queryutil::{
+    has_token_data, load_txns_by_prefix, txoutrows_by_script_hash, txrows_by_prefix,
+};
+use crate::query::tx::{prevout_tx_count, TxQuery};
 use crate::query::unconfirmed::UnconfirmedQuery;
 use crate::scripthash::{compute_script_hash, FullHash};
+use crate::store::ReadStore;
 use crate::timeout::TimeoutTrigger;
-use crate::util::HeaderEntry;
+use crate::util::{hash_prefix, HashPrefix, HeaderEntry};
 use bitcoincash::blockdata::transaction::OutPoint;
 
 pub mod confirmed;
+pub mod executor;
 pub mod header;
+pub mod merkle;
 pub mod primitives;
 pub mod queryutil;
 pub mod tx;
@@ -39,18 +54,106 @@ pub struct Status {
     confirmed: (Vec<FundingOutput>, Vec<SpendingInput>),
     mempool: (Vec<FundingOutput>, Vec<SpendingInput>),
     txn_fees: HashMap<Txid, u64>,
+    // position in block of confirmed transactions sharing a height, see
+    // `block_positions`
+    block_positions: HashMap<Txid, usize>,
 }
 
-fn calc_balance((funding, spending): &(Vec<FundingOutput>, Vec<SpendingInput>)) -> i64 {
-    let funded: u64 = funding.iter().map(|output| output.value).sum();
-    let spent: u64 = spending.iter().map(|input| input.value).sum();
-    funded as i64 - spent as i64
+/// Height of a history entry in given state: the block height if confirmed,
+/// -1 if it spends an unconfirmed output and 0 otherwise.
+fn history_height(state: &ConfirmationState, height: u32) -> i32 {
+    match state {
+        ConfirmationState::Confirmed => height as i32,
+        ConfirmationState::InMempool => 0,
+        ConfirmationState::UnconfirmedParent => -1,
+        ConfirmationState::Indeterminate => {
+            debug_assert!(false, "ConfirmationState cannot be Indeterminate");
+            0
+        }
+    }
+}
+
+/// Precedence of a history height, when a transaction is seen with more
+/// than one: confirmed > unconfirmed parent (-1) > mempool (0). Among
+/// confirmed heights, the lowest wins.
+fn history_height_precedence(height: i32) -> (u8, i32) {
+    match height {
+        h if h > 0 => (2, -h),
+        -1 => (1, 0),
+        _ => (0, 0),
+    }
+}
+
+/// Funded minus spent value. Summed with 128 bits, so that (fabricated)
+/// amounts beyond the i64 range saturate instead of wrapping around.
+fn calc_balance(
+    (funding, spending): &(Vec<FundingOutput>, Vec<SpendingInput>),
+    exclude_tokens: bool,
+) -> i64 {
+    let funded: u128 = funding
+        .iter()
+        .filter(|output| !(exclude_tokens && output.has_token))
+        .map(|output| u128::from(output.value))
+        .sum();
+    let spent: u128 = spending
+        .iter()
+        .filter(|input| !(exclude_tokens && input.has_token))
+        .map(|input| u128::from(input.value))
+        .sum();
+    let balance = funded as i128 - spent as i128;
+    balance.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+/// Order of history entries. Ascending is by height, with unconfirmed
+/// transactions last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryOrder {
+    Ascending,
+    Descending,
+}
+
+/// Position in history to page from, see `Status::history_page`. Encoded as
+/// an opaque hex string for clients, the start of history as an empty string.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistoryCursor {
+    Start,
+    After { height: i32, txid: Txid },
+}
+
+impl HistoryCursor {
+    pub fn from_hex(hex: &str) -> Result<HistoryCursor> {
+        if hex.is_empty() {
+            return Ok(HistoryCursor::Start);
+        }
+        let bytes = hex::decode(hex).chain_err(|| "cursor is not hex")?;
+        if bytes.len() != 4 + 32 {
+            bail!("invalid cursor length");
+        }
+        let mut height = [0; 4];
+        height.copy_from_slice(&bytes[..4]);
+        Ok(HistoryCursor::After {
+            height: i32::from_be_bytes(height),
+            txid: Txid::from_slice(&bytes[4..]).chain_err(|| "invalid cursor txid")?,
+        })
+    }
+
+    pub fn to_hex(&self) -> String {
+        match self {
+            HistoryCursor::Start => String::new(),
+            HistoryCursor::After { height, txid } => {
+                let mut bytes = height.to_be_bytes().to_vec();
+                bytes.extend_from_slice(&txid[..]);
+                hex::encode(bytes)
+            }
+        }
+    }
 }
 
 pub struct HistoryItem {
     height: i32,
     tx_hash: Txid,
     fee: Option<u64>, // need to be set only for unconfirmed transactions (i.e. height <= 0)
+    size: Option<usize>, // set only when requested
 }
 
 impl HistoryItem {
@@ -62,6 +165,12 @@ impl HistoryItem {
                 .unwrap()
                 .insert("fee".to_string(), json!(f))
         });
+        self.size.map(|s| {
+            result
+                .as_object_mut()
+                .unwrap()
+                .insert("size".to_string(), json!(s))
+        });
         result
     }
 }
@@ -75,40 +184,56 @@ impl Status {
         self.confirmed.1.iter().chain(self.mempool.1.iter())
     }
 
-    pub fn confirmed_balance(&self) -> i64 {
-        calc_balance(&self.confirmed)
+    /// Balance of confirmed transactions. When `exclude_tokens` is set, the
+    /// value of token-bearing outputs is left out (see `mark_token_outputs`).
+    pub fn confirmed_balance(&self, exclude_tokens: bool) -> i64 {
+        calc_balance(&self.confirmed, exclude_tokens)
     }
 
-    pub fn mempool_balance(&self) -> i64 {
-        calc_balance(&self.mempool)
+    pub fn mempool_balance(&self, exclude_tokens: bool) -> i64 {
+        calc_balance(&self.mempool, exclude_tokens)
+    }
+
+    /// Mark the funding outputs for which `is_token` holds, and the inputs
+    /// spending them, as token-bearing.
+    fn mark_token_outputs<F>(&mut self, mut is_token: F) -> Result<()>
+    where
+        F: FnMut(&FundingOutput) -> Result<bool>,
+    {
+        let mut token_outputs = HashSet::new();
+        for funding in self.confirmed.0.iter_mut().chain(self.mempool.0.iter_mut()) {
+            funding.has_token = is_token(funding)?;
+            if funding.has_token {
+                token_outputs.insert(funding.funding_output);
+            }
+        }
+        for spending in self.confirmed.1.iter_mut().chain(self.mempool.1.iter_mut()) {
+            spending.has_token = token_outputs.contains(&spending.funding_output);
+        }
+        Ok(())
     }
 
+    /// History of the scripthash, one entry per transaction. A transaction
+    /// that both funds and spends (e.g. a self-transfer) may be seen in
+    /// different states while the mempool changes, its height is then picked
+    /// by `history_height_precedence`.
     pub fn history(&self) -> Vec<HistoryItem> {
         let mut txns_map = HashMap::<Txid, i32>::new();
-        for f in self.funding() {
-            let height: i32 = match f.state {
-                ConfirmationState::Confirmed => f.height as i32,
-                ConfirmationState::InMempool => 0,
-                ConfirmationState::UnconfirmedParent => -1,
-                ConfirmationState::Indeterminate => {
-                    debug_assert!(false, "ConfirmationState cannot be Indeterminate");
-                    0
+        let mut insert = |txid: Txid, height: i32| match txns_map.entry(txid) {
+            Entry::Occupied(mut entry) => {
+                if history_height_precedence(height) > history_height_precedence(*entry.get()) {
+                    entry.insert(height);
                 }
-            };
-
-            txns_map.insert(f.funding_output.txid, height);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(height);
+            }
+        };
+        for f in self.funding() {
+            insert(f.funding_output.txid, history_height(&f.state, f.height));
         }
         for s in self.spending() {
-            let height: i32 = match s.state {
-                ConfirmationState::Confirmed => s.height as i32,
-                ConfirmationState::InMempool => 0,
-                ConfirmationState::UnconfirmedParent => -1,
-                ConfirmationState::Indeterminate => {
-                    debug_assert!(false, "ConfirmationState cannot be Indeterminate");
-                    0
-                }
-            };
-            txns_map.insert(s.txn_id, height as i32);
+            insert(s.txn_id, history_height(&s.state, s.height));
         }
         let mut items: Vec<HistoryItem> = txns_map
             .into_iter()
@@ -116,30 +241,90 @@ impl Status {
                 height: item.1,
                 tx_hash: item.0,
                 fee: self.txn_fees.get(&item.0).cloned(),
+                size: None,
             })
             .collect();
 
-        items.sort_unstable_by(|a, b| {
-            if a.height == b.height {
-                // Order by little endian tx hash if height is the same,
-                // in most cases, this order is the same as on the blockchain.
-                return b.tx_hash.cmp(&a.tx_hash);
-            }
-            if a.height > 0 && b.height > 0 {
-                return a.height.cmp(&b.height);
-            }
+        items.sort_unstable_by_key(|item| self.history_key(item.height, &item.tx_hash));
+        items
+    }
 
-            // mempool txs should be sorted last, so add to it a large number
-            let mut a_height = a.height;
-            let mut b_height = b.height;
-            if a_height <= 0 {
-                a_height = 0xEE_EEEE + a_height.abs();
-            }
-            if b_height <= 0 {
-                b_height = 0xEE_EEEE + b_height.abs();
-            }
-            a_height.cmp(&b_height)
+    /// Sort key of the history entry of `txid` at `height`, history is in
+    /// ascending order of it.
+    fn history_key(&self, height: i32, txid: &Txid) -> (i32, usize, Reverse<Txid>) {
+        // mempool txs should be sorted last, so add to it a large number
+        let height = if height <= 0 {
+            0xEE_EEEE + height.abs()
+        } else {
+            height
+        };
+        // Order by position in block when known, otherwise by little endian
+        // tx hash if height is the same; in most cases, this order is the
+        // same as on the blockchain.
+        let position = self
+            .block_positions
+            .get(txid)
+            .cloned()
+            .unwrap_or(usize::MAX);
+        (height, position, Reverse(*txid))
+    }
+
+    /// Page of at most `limit` history entries in given order (see
+    /// `history_ordered`), continuing strictly after `cursor`. As the cursor
+    /// is a position in the sort order (not an index), history added since
+    /// the previous page is neither skipped nor returned twice, unless it
+    /// sorts before the cursor. Returns the cursor of the last entry if more
+    /// entries follow.
+    pub fn history_page(
+        &self,
+        order: HistoryOrder,
+        include_unconfirmed: bool,
+        cursor: &HistoryCursor,
+        limit: usize,
+    ) -> (Vec<HistoryItem>, Option<HistoryCursor>) {
+        let mut items = self.history_ordered(order, include_unconfirmed);
+        if let HistoryCursor::After { height, txid } = cursor {
+            let after = self.history_key(*height, txid);
+            items.retain(|item| {
+                let key = self.history_key(item.height, &item.tx_hash);
+                match order {
+                    HistoryOrder::Ascending => key > after,
+                    HistoryOrder::Descending => key < after,
+                }
+            });
+        }
+        if items.len() <= limit {
+            return (items, None);
+        }
+        items.truncate(limit);
+        let last = items.last().map(|item| HistoryCursor::After {
+            height: item.height,
+            txid: item.tx_hash,
         });
+        (items, last)
+    }
+
+    /// Height of the latest history entry (as in `history`) and the number
+    /// of entries. Height is None without history.
+    pub fn history_summary(&self) -> (Option<i32>, usize) {
+        let history = self.history();
+        (history.last().map(|item| item.height), history.len())
+    }
+
+    /// History in given order, see `history`. Without `include_unconfirmed`,
+    /// entries of unconfirmed transactions (height <= 0) are left out.
+    pub fn history_ordered(
+        &self,
+        order: HistoryOrder,
+        include_unconfirmed: bool,
+    ) -> Vec<HistoryItem> {
+        let mut items = self.history();
+        if !include_unconfirmed {
+            items.retain(|item| item.height > 0);
+        }
+        if order == HistoryOrder::Descending {
+            items.reverse();
+        }
         items
     }
 
@@ -176,11 +361,45 @@ impl Status {
     }
 }
 
+/// Position in block of the confirmed `history` transactions that share a
+/// height with another history transaction, for the lowest `max_blocks` such
+/// heights (`block_txids` gives the txids of the block at a height).
+fn block_positions<F>(
+    history: &[HistoryItem],
+    max_blocks: usize,
+    mut block_txids: F,
+) -> Result<HashMap<Txid, usize>>
+where
+    F: FnMut(u32) -> Result<Vec<Txid>>,
+{
+    let mut heights = BTreeMap::<i32, Vec<&Txid>>::new();
+    for item in history.iter().filter(|item| item.height > 0) {
+        heights.entry(item.height).or_default().push(&item.tx_hash);
+    }
+    let mut positions = HashMap::new();
+    for (height, txids) in heights
+        .into_iter()
+        .filter(|(_, txids)| txids.len() > 1)
+        .take(max_blocks)
+    {
+        let block_txids = block_txids(height as u32)?;
+        for txid in txids {
+            if let Some(pos) = block_txids.iter().position(|block_txid| block_txid == txid) {
+                positions.insert(*txid, pos);
+            }
+        }
+    }
+    Ok(positions)
+}
+
 fn merklize<T: Hash>(left: T, right: T) -> T {
     let data = [&left[..], &right[..]].concat();
     <T as Hash>::hash(&data)
 }
 
+/// Reference implementation of the merkle branch, see `MerkleTree` and
+/// `merkle_branch_and_root`
+#[cfg(test)]
 fn create_merkle_branch_and_root<T: Hash>(mut hashes: Vec<T>, mut index: usize) -> (Vec<T>, T) {
     let mut merkle = vec![];
     while hashes.len() > 1 {
@@ -199,6 +418,274 @@ fn create_merkle_branch_and_root<T: Hash>(mut hashes: Vec<T>, mut index: usize)
     (merkle, hashes[0])
 }
 
+/// Number of nodes at `level` of a merkle tree with `leaf_count` leaves (the
+/// leaves are at level 0).
+fn merkle_level_len(leaf_count: usize, level: u32) -> usize {
+    (leaf_count + (1 << level) - 1) >> level
+}
+
+/// Node `pos` at `level` of the merkle tree over `leaf_count` leaves, reading
+/// the leaves with `leaf`. Like in `create_merkle_branch_and_root`, the last
+/// node of an odd-length level is paired with itself.
+fn merkle_node<T: Hash>(
+    level: u32,
+    pos: usize,
+    leaf_count: usize,
+    leaf: &impl Fn(usize) -> T,
+) -> T {
+    if level == 0 {
+        return leaf(pos);
+    }
+    let left = merkle_node(level - 1, 2 * pos, leaf_count, leaf);
+    let right = if 2 * pos + 1 < merkle_level_len(leaf_count, level - 1) {
+        merkle_node(level - 1, 2 * pos + 1, leaf_count, leaf)
+    } else {
+        left
+    };
+    merklize(left, right)
+}
+
+/// Same as `create_merkle_branch_and_root`, without materializing the list of
+/// leaves (or any level of the tree). Memory use is logarithmic in
+/// `leaf_count`.
+fn merkle_branch_and_root<T: Hash>(
+    leaf_count: usize,
+    mut index: usize,
+    leaf: impl Fn(usize) -> T,
+) -> (Vec<T>, T) {
+    assert!(index < leaf_count);
+    let mut merkle = vec![];
+    let mut node = leaf(index);
+    let mut level = 0;
+    while merkle_level_len(leaf_count, level) > 1 {
+        let sibling = index ^ 1;
+        let sibling_node = if sibling < merkle_level_len(leaf_count, level) {
+            merkle_node(level, sibling, leaf_count, &leaf)
+        } else {
+            node
+        };
+        node = if index & 1 == 0 {
+            merklize(node, sibling_node)
+        } else {
+            merklize(sibling_node, node)
+        };
+        merkle.push(sibling_node);
+        index /= 2;
+        level += 1;
+    }
+    (merkle, node)
+}
+
+/// Fails if `cp_height` exceeds `max_cp_height` (0 for no limit).
+fn check_cp_height(cp_height: usize, max_cp_height: usize) -> Result<()> {
+    if max_cp_height > 0 && cp_height > max_cp_height {
+        bail!(rpc_invalid_params(format!(
+            "cp_height {} exceeds the limit of {} on this server",
+            cp_height, max_cp_height
+        )));
+    }
+    Ok(())
+}
+
+/// First transaction in `store` with an output to `scripthash`, by height.
+/// Index rows match on scripthash prefix only, so each candidate transaction
+/// is loaded to verify its outputs. Fails if more than `max_txs` candidates
+/// would have to be loaded (0 for no limit).
+fn first_use_in_store<F>(
+    store: &dyn ReadStore,
+    scripthash: &FullHash,
+    max_txs: usize,
+    mut load_tx: F,
+) -> Result<Option<(u32, Txid)>>
+where
+    F: FnMut(&Txid, u32) -> Result<Transaction>,
+{
+    let rows = txoutrows_by_script_hash(store, scripthash);
+    let mut txs: Vec<TxRow> = rows
+        .iter()
+        .flat_map(|p| txrows_by_prefix(store, p.txid_prefix))
+        .collect();
+
+    txs.sort_unstable_by(|a, b| a.height.cmp(&b.height));
+
+    for (loaded, txrow) in txs.drain(..).enumerate() {
+        check_first_use_candidates(loaded, max_txs)?;
+        // verify that tx contains scripthash as output
+        let txid = Txid::from_slice(&txrow.key.txid[..]).expect("invalid txid");
+        let tx = load_tx(&txid, txrow.height)?;
+
+        for o in tx.output.iter() {
+            if compute_script_hash(&o.script_pubkey[..]) == *scripthash {
+                return Ok(Some((txrow.height, txid)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Fails once `loaded` candidates reach `max_txs` (0 for no limit).
+fn check_first_use_candidates(loaded: usize, max_txs: usize) -> Result<()> {
+    if max_txs > 0 && loaded >= max_txs {
+        bail!(ErrorKind::RpcError(
+            RpcErrorCode::Other,
+            format!(
+                "first use is too ambiguous, more than {} candidate transactions",
+                max_txs
+            )
+        ));
+    }
+    Ok(())
+}
+
+/// Same as `first_use_in_store`, using the first-use index. Its rows are
+/// scanned lowest height first, so usually only the first candidate is
+/// loaded. Rows of blocks that are no longer in the best chain (according to
+/// `blockhash_at`) are skipped, as after a reorg the replacing blocks are
+/// indexed with rows of their own.
+fn first_use_in_index<B, F>(
+    store: &dyn ReadStore,
+    scripthash: &FullHash,
+    max_txs: usize,
+    blockhash_at: B,
+    mut load_tx: F,
+) -> Result<Option<(u32, Txid)>>
+where
+    B: Fn(u32) -> Option<BlockHash>,
+    F: FnMut(&Txid, u32) -> Result<Transaction>,
+{
+    let mut loaded = 0;
+    for row in store.scan(&FirstUseRow::filter(scripthash)) {
+        let row = FirstUseRow::from_row(&row);
+        let height = row.height();
+        match blockhash_at(height) {
+            Some(blockhash) if hash_prefix(&blockhash[..]) == row.blockhash_prefix => {}
+            _ => continue, // reorged out
+        }
+        // a txid prefix may match transactions confirmed at other heights
+        for txrow in txrows_by_prefix(store, row.key.txid_prefix) {
+            if txrow.height != height {
+                continue;
+            }
+            check_first_use_candidates(loaded, max_txs)?;
+            loaded += 1;
+            let txid = txrow.get_txid();
+            let tx = load_tx(&txid, height)?;
+            if tx
+                .output
+                .iter()
+                .any(|o| compute_script_hash(&o.script_pubkey[..]) == *scripthash)
+            {
+                return Ok(Some((height, txid)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Page of at most `limit` txids, in ascending order. Returns whether more
+/// txids follow.
+fn txids_page(mut txids: Vec<Txid>, limit: usize) -> (Vec<Txid>, bool) {
+    txids.sort_unstable();
+    let more = txids.len() > limit;
+    txids.truncate(limit);
+    (txids, more)
+}
+
+fn confirmed_blockhash_json(header: &HeaderEntry) -> Value {
+    json!({
+        "block_hash": header.hash(),
+        "block_height": header.height()
+    })
+}
+
+/// Add size and fee of the transactions to (at most) the first `max_items`
+/// history items, loading them with `load_tx`. Fees are computed with
+/// `get_fee` as long as the previous transactions it loads fit in
+/// `max_prevout_txs`; items whose fee doesn't fit are left without.
+fn add_tx_info<L, F>(
+    items: &mut [HistoryItem],
+    max_items: usize,
+    max_prevout_txs: usize,
+    timeout: &TimeoutTrigger,
+    mut load_tx: L,
+    mut get_fee: F,
+) -> Result<()>
+where
+    L: FnMut(&HistoryItem) -> Result<Transaction>,
+    F: FnMut(&Transaction, usize) -> Result<Option<u64>>,
+{
+    let mut prevout_budget = max_prevout_txs;
+    for item in items.iter_mut().take(max_items) {
+        timeout.check()?;
+        let tx = load_tx(item)?;
+        item.size = Some(serialize(&tx).len());
+        let prevout_txs = prevout_tx_count(&tx);
+        if item.fee.is_none() && prevout_txs <= prevout_budget {
+            item.fee = get_fee(&tx, prevout_txs)?;
+            prevout_budget -= prevout_txs;
+        }
+    }
+    Ok(())
+}
+
+/// Status of a scripthash for a client: fails while the index is syncing,
+/// or when `executor` has no room for loading it.
+fn checked_status<F>(
+    sync_status: &SyncStatus,
+    executor: &QueryExecutor,
+    load_status: F,
+) -> Result<Status>
+where
+    F: FnOnce() -> Result<Status> + Send,
+{
+    sync_status.check()?;
+    executor.run(load_status)
+}
+
+/// Merkle proof of `tx_hash` in the block at `height`, whose hash is
+/// `blockhash` if known. The tree of the block is loaded with `merkle_tree`.
+fn merkle_proof<F>(
+    blockhash: Option<BlockHash>,
+    tx_hash: &Txid,
+    height: usize,
+    merkle_tree: F,
+) -> Result<(Vec<TxMerkleNode>, usize)>
+where
+    F: FnOnce(&BlockHash) -> Result<Arc<MerkleTree>>,
+{
+    let blockhash = blockhash_or_not_found(blockhash, height)?;
+    let tree = merkle_tree(&blockhash)?;
+    let pos = tree.position(tx_hash).chain_err(|| {
+        ErrorKind::RpcError(
+            RpcErrorCode::NotFound,
+            format!("missing txid {} in block #{}", tx_hash, height),
+        )
+    })?;
+    Ok((tree.branch(pos), pos))
+}
+
+fn blockhash_or_not_found(blockhash: Option<BlockHash>, height: usize) -> Result<BlockHash> {
+    blockhash.chain_err(|| {
+        ErrorKind::RpcError(RpcErrorCode::NotFound, format!("missing block #{}", height))
+    })
+}
+
+/// Options of `Query`, see `Config::query_options`.
+#[derive(Clone, Debug)]
+pub struct QueryOptions {
+    pub txheight_cache_size: usize,
+    pub txid_limit: usize,
+    pub network: Network,
+    pub max_cp_height: usize,
+    pub mempool_histogram_threshold: u64,
+    pub mempool_histogram_max_age: Option<Duration>,
+    pub max_first_use_txs: usize,
+    pub max_mempool_txids: usize,
+    pub history_block_order_max_blocks: usize,
+    pub history_page_size: usize,
+    pub merkle_cache_size: usize,
+}
+
 pub struct Query {
     app: Arc<App>,
     tracker: Arc<RwLock<Tracker>>,
@@ -207,15 +694,38 @@ pub struct Query {
     unconfirmed: UnconfirmedQuery,
     tx: Arc<TxQuery>,
     header: Arc<HeaderQuery>,
+    max_cp_height: usize,
+    max_first_use_txs: usize,
+    max_mempool_txids: usize,
+    history_block_order_max_blocks: usize,
+    history_page_size: usize,
+    executor: QueryExecutor,
+    address_cache: AddressCache,
+    merkle_trees: MerkleTreeCache,
 }
 
 impl Query {
     pub fn new(
         app: Arc<App>,
         metrics: &Metrics,
-        tx_cache: TransactionCache,
-        network: Network,
+        tx_cache: Arc<TransactionCache>,
+        options: QueryOptions,
+        executor: QueryExecutor,
+        address_cache: AddressCache,
     ) -> Result<Arc<Query>> {
+        let QueryOptions {
+            txheight_cache_size,
+            txid_limit,
+            network,
+            max_cp_height,
+            mempool_histogram_threshold,
+            mempool_histogram_max_age,
+            max_first_use_txs,
+            max_mempool_txids,
+            history_block_order_max_blocks,
+            history_page_size,
+            merkle_cache_size,
+        } = options;
         let daemon = app.daemon().reconnect()?;
         let duration = Arc::new(metrics.histogram_vec(
             prometheus::HistogramOpts::new(
@@ -224,8 +734,13 @@ impl Query {
             ),
             &["type"],
         ));
-        let tracker = Arc::new(RwLock::new(Tracker::new(metrics)));
-        let header = Arc::new(HeaderQuery::new(app.clone()));
+        let tracker = Arc::new(RwLock::new(Tracker::new(
+            metrics,
+            app.index().index_output_filter(),
+            mempool_histogram_threshold,
+            mempool_histogram_max_age,
+        )));
+        let header = Arc::new(HeaderQuery::new(app.clone(), txheight_cache_size, metrics));
         let tx = Arc::new(TxQuery::new(
             tx_cache,
             daemon,
@@ -234,7 +749,7 @@ impl Query {
             duration.clone(),
             network,
         ));
-        let confirmed = ConfirmedQuery::new(tx.clone(), duration.clone());
+        let confirmed = ConfirmedQuery::new(tx.clone(), duration.clone(), txid_limit);
         let unconfirmed = UnconfirmedQuery::new(tx.clone(), duration.clone());
         Ok(Arc::new(Query {
             app,
@@ -244,6 +759,14 @@ impl Query {
             unconfirmed,
             tx,
             header,
+            max_cp_height,
+            max_first_use_txs,
+            max_mempool_txids,
+            history_block_order_max_blocks,
+            history_page_size,
+            executor,
+            address_cache,
+            merkle_trees: MerkleTreeCache::new(merkle_cache_size, metrics),
         }))
     }
 
@@ -251,6 +774,15 @@ impl Query {
         &self,
         scripthash: &FullHash,
         timeout: &TimeoutTrigger,
+    ) -> Result<Status> {
+        self.executor
+            .run(|| self.load_status_mempool(scripthash, timeout))
+    }
+
+    fn load_status_mempool(
+        &self,
+        scripthash: &FullHash,
+        timeout: &TimeoutTrigger,
     ) -> Result<Status> {
         let store = self.app.read_store();
         let confirmed_funding = self
@@ -258,7 +790,7 @@ impl Query {
             .get_funding(store, scripthash, &*self.tx, timeout)
             .chain_err(|| "failed to get confirmed funding status")?;
 
-        let tracker = self.tracker.read().unwrap();
+        let tracker = read_tracker(&self.tracker);
         let unconfirmed_funding = self
             .unconfirmed
             .get_funding(&tracker, scripthash, timeout)
@@ -279,22 +811,71 @@ impl Query {
             confirmed,
             mempool,
             txn_fees,
+            block_positions: HashMap::new(),
+        })
+    }
+
+    /// Mark the token-bearing outputs of `status`. This loads the funding
+    /// transactions, so is only done when requested.
+    pub fn mark_token_outputs(&self, status: &mut Status, timeout: &TimeoutTrigger) -> Result<()> {
+        self.executor
+            .run(|| self.load_token_outputs(status, timeout))
+    }
+
+    fn load_token_outputs(&self, status: &mut Status, timeout: &TimeoutTrigger) -> Result<()> {
+        let mut txs: HashMap<Txid, Transaction> = HashMap::new();
+        status.mark_token_outputs(|funding| {
+            timeout.check()?;
+            let outpoint = &funding.funding_output;
+            let tx = match txs.entry(outpoint.txid) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(self.tx.get(&outpoint.txid, None, Some(funding.height))?)
+                }
+            };
+            Ok(tx
+                .output
+                .get(outpoint.vout as usize)
+                .map_or(false, has_token_data))
         })
     }
 
     pub fn status(&self, scripthash: &FullHash, timeout: &TimeoutTrigger) -> Result<Status> {
+        checked_status(self.app.index().sync_status(), &self.executor, || {
+            self.load_status(scripthash, timeout)
+        })
+    }
+
+    /// Status of a subscribed scripthash, to notify the subscriber of a
+    /// change. Unlike `status`, this is never rejected as busy or syncing: a
+    /// busy server must not drop its subscribers, and the index catching up
+    /// notifies them again.
+    pub fn subscription_status(
+        &self,
+        scripthash: &FullHash,
+        timeout: &TimeoutTrigger,
+    ) -> Result<Status> {
+        self.executor
+            .run_unbounded(|| self.load_status(scripthash, timeout))
+    }
+
+    fn load_status(&self, scripthash: &FullHash, timeout: &TimeoutTrigger) -> Result<Status> {
         let store = self.app.read_store();
-        let confirmed_funding = self
+        let confirmed_funding = match self
             .confirmed
             .get_funding(store, scripthash, &*self.tx, timeout)
-            .chain_err(|| "failed to get confirmed funding status")?;
+        {
+            // pass errors meant for the client (such as exceeding txid_limit) as-is
+            Err(e) if matches!(e.kind(), ErrorKind::RpcError(..)) => return Err(e),
+            result => result.chain_err(|| "failed to get confirmed funding status")?,
+        };
 
         let confirmed_spending = self
             .confirmed
             .get_spending(store, &confirmed_funding, timeout)
             .chain_err(|| "failed to get confirmed spending status")?;
 
-        let tracker = self.tracker.read().unwrap();
+        let tracker = read_tracker(&self.tracker);
         let unconfirmed_funding = self
             .unconfirmed
             .get_funding(&tracker, scripthash, timeout)
@@ -308,14 +889,32 @@ impl Query {
         let txn_fees =
             self.unconfirmed
                 .get_tx_fees(&tracker, &unconfirmed_funding, &unconfirmed_spending);
+        drop(tracker);
         let confirmed = (confirmed_funding, confirmed_spending);
         let mempool = (unconfirmed_funding, unconfirmed_spending);
 
-        Ok(Status {
+        let mut status = Status {
             confirmed,
             mempool,
             txn_fees,
-        })
+            block_positions: HashMap::new(),
+        };
+        if self.history_block_order_max_blocks > 0 {
+            status.block_positions = block_positions(
+                &status.history(),
+                self.history_block_order_max_blocks,
+                |height| {
+                    timeout.check()?;
+                    let blockhash = self
+                        .app
+                        .index()
+                        .get_blockhash(height as usize)
+                        .chain_err(|| format!("missing block #{}", height))?;
+                    self.app.daemon().getblocktxids(&blockhash)
+                },
+            )?;
+        }
+        Ok(status)
     }
 
     pub fn get_confirmed_blockhash(&self, tx_hash: &Txid) -> Result<Value> {
@@ -323,11 +922,20 @@ impl Query {
         if header.is_none() {
             bail!("tx {} is unconfirmed or does not exist", tx_hash);
         }
-        let header = header.unwrap();
-        Ok(json!({
-            "block_hash": header.hash(),
-            "block_height": header.height()
-        }))
+        Ok(confirmed_blockhash_json(&header.unwrap()))
+    }
+
+    /// Same as get_confirmed_blockhash, but for many transactions. Unconfirmed
+    /// (or non-existing) transactions are null in the result.
+    pub fn get_confirmed_blockhashes(&self, tx_hashes: &[Txid]) -> Result<Value> {
+        Ok(json!(self
+            .header
+            .get_by_txids(tx_hashes)?
+            .iter()
+            .map(|header| header
+                .as_ref()
+                .map_or(Value::Null, confirmed_blockhash_json))
+            .collect::<Vec<Value>>()))
     }
 
     pub fn get_headers(&self, heights: &[usize]) -> Vec<HeaderEntry> {
@@ -335,11 +943,7 @@ impl Query {
             .duration
             .with_label_values(&["get_headers"])
             .start_timer();
-        let index = self.app.index();
-        heights
-            .iter()
-            .filter_map(|height| index.get_header(*height))
-            .collect()
+        self.app.index().get_headers(self.app.read_store(), heights)
     }
 
     pub fn get_best_header(&self) -> Result<HeaderEntry> {
@@ -347,31 +951,58 @@ impl Query {
         last_header.chain_err(|| "no headers indexed")
     }
 
+    /// Cumulative chain work and median time past of the given block
+    pub fn get_header_chainwork_and_mtp(&self, entry: &HeaderEntry) -> Result<(Uint256, u32)> {
+        Ok((
+            self.header.chainwork(entry)?,
+            self.header.median_time_past(entry)?,
+        ))
+    }
+
     pub fn getblocktxids(&self, blockhash: &BlockHash) -> Result<Vec<Txid>> {
         self.app.daemon().getblocktxids(blockhash)
     }
 
+    /// Number of transactions in the block at `height`
+    pub fn get_block_tx_count(&self, height: usize) -> Result<usize> {
+        let blockhash = self
+            .app
+            .index()
+            .get_blockhash(height)
+            .chain_err(|| rpc_invalid_params(format!("no block at height {}", height)))?;
+        self.app.daemon().getblocktxcount(&blockhash)
+    }
+
+    /// Prefetch txids of the last `blocks` blocks into the blocktxids cache,
+    /// so that merkle proof requests after a restart don't all hit bitcoind.
+    pub fn warmup_blocktxids_cache(&self, blocks: usize) -> Result<usize> {
+        let best_height = self.get_best_header()?.height();
+        let first_height = (best_height + 1).saturating_sub(blocks);
+        let heights: Vec<usize> = (first_height..=best_height).collect();
+        let blockhashes = self.app.index().get_blockhashes(&heights);
+        self.app.daemon().prefetch_blocktxids(&blockhashes)
+    }
+
     pub fn get_merkle_proof(
         &self,
         tx_hash: &Txid,
         height: usize,
     ) -> Result<(Vec<TxMerkleNode>, usize)> {
-        let header_entry = self
-            .app
-            .index()
-            .get_header(height)
-            .chain_err(|| format!("missing block #{}", height))?;
-        let txids = self.app.daemon().getblocktxids(header_entry.hash())?;
-        let pos = txids
-            .iter()
-            .position(|txid| txid == tx_hash)
-            .chain_err(|| format!("missing txid {}", tx_hash))?;
-        let tx_nodes: Vec<TxMerkleNode> = txids
-            .into_iter()
-            .map(|txid| TxMerkleNode::from_inner(txid.into_inner()))
-            .collect();
-        let (branch, _root) = create_merkle_branch_and_root(tx_nodes, pos);
-        Ok((branch, pos))
+        merkle_proof(
+            self.app.index().get_blockhash(height),
+            tx_hash,
+            height,
+            |blockhash| self.merkle_tree(blockhash),
+        )
+    }
+
+    fn blockhash_at(&self, height: usize) -> Result<BlockHash> {
+        blockhash_or_not_found(self.app.index().get_blockhash(height), height)
+    }
+
+    fn merkle_tree(&self, blockhash: &BlockHash) -> Result<Arc<MerkleTree>> {
+        self.merkle_trees
+            .get_or_else(blockhash, || self.app.daemon().getblocktxids(blockhash))
     }
 
     pub fn get_header_merkle_proof(
@@ -382,28 +1013,25 @@ impl Query {
         if cp_height < height {
             bail!("cp_height #{} < height #{}", cp_height, height);
         }
+        check_cp_height(cp_height, self.max_cp_height)?;
 
-        let best_height = self.get_best_header()?.height();
-        if best_height < cp_height {
+        // Hold the lock, so that the best chain can't change meanwhile.
+        // Only the hashes are needed, which are kept in memory also for
+        // trimmed headers.
+        let headers = self.app.index().read_headers();
+        if headers.len() <= cp_height {
             bail!(
                 "cp_height #{} above best block height #{}",
                 cp_height,
-                best_height
+                headers.len() as isize - 1
             );
         }
-
-        let heights: Vec<usize> = (0..=cp_height).collect();
-        let header_hashes: Vec<BlockHash> = self
-            .get_headers(&heights)
-            .into_iter()
-            .map(|h| *h.hash())
-            .collect();
-        let merkle_nodes: Vec<Sha256dHash> = header_hashes
-            .iter()
-            .map(|block_hash| Sha256dHash::from_inner(block_hash.into_inner()))
-            .collect();
-        assert_eq!(header_hashes.len(), heights.len());
-        Ok(create_merkle_branch_and_root(merkle_nodes, height))
+        Ok(merkle_branch_and_root(cp_height + 1, height, |height| {
+            let blockhash = headers
+                .blockhash_at(height)
+                .expect("missing block hash below tip");
+            Sha256dHash::from_inner(blockhash.into_inner())
+        }))
     }
 
     pub fn get_id_from_pos(
@@ -412,24 +1040,17 @@ impl Query {
         tx_pos: usize,
         want_merkle: bool,
     ) -> Result<(Txid, Vec<TxMerkleNode>)> {
-        let header_entry = self
-            .app
-            .index()
-            .get_header(height)
-            .chain_err(|| format!("missing block #{}", height))?;
-
-        let txids = self.app.daemon().getblocktxids(header_entry.hash())?;
-        let txid = *txids
-            .get(tx_pos)
-            .chain_err(|| format!("No tx in position #{} in block #{}", tx_pos, height))?;
-
-        let tx_nodes = txids
-            .into_iter()
-            .map(|txid| TxMerkleNode::from_inner(txid.into_inner()))
-            .collect();
+        let blockhash = self.blockhash_at(height)?;
+        let tree = self.merkle_tree(&blockhash)?;
+        let txid = tree.txid_at(tx_pos).chain_err(|| {
+            ErrorKind::RpcError(
+                RpcErrorCode::NotFound,
+                format!("No tx in position #{} in block #{}", tx_pos, height),
+            )
+        })?;
 
         let branch = if want_merkle {
-            create_merkle_branch_and_root(tx_nodes, tx_pos).0
+            tree.branch(tx_pos)
         } else {
             vec![]
         };
@@ -445,42 +1066,83 @@ impl Query {
             .duration
             .with_label_values(&["update_mempool"])
             .start_timer();
-        self.tracker
-            .write()
-            .unwrap()
-            .update(self.app.daemon(), self.tx())
+        write_tracker(&self.tracker).update(self.app.daemon(), self.tx())
     }
 
     /// Returns [vsize, fee_rate] pairs (measured in vbytes and satoshis).
     pub fn get_fee_histogram(&self) -> Vec<(f32, u32)> {
-        self.tracker.read().unwrap().fee_histogram().clone()
+        read_tracker(&self.tracker).fee_histogram().clone()
+    }
+
+    /// Mempool txids greater than `after`, in ascending order, at most
+    /// `max_mempool_txids` of them. Returns whether more txids follow.
+    pub fn get_mempool_txids(&self, after: Option<&Txid>) -> (Vec<Txid>, bool) {
+        // sort without holding the mempool lock
+        let txids = read_tracker(&self.tracker).txids_after(after);
+        txids_page(txids, self.max_mempool_txids)
     }
 
     // Fee rate [BTC/kB] to be confirmed in `blocks` from now.
     pub fn estimate_fee(&self, blocks: usize) -> f64 {
-        let mut total_vsize = 0u32;
-        let mut last_fee_rate = 0.0;
-        let blocks_in_vbytes = (blocks * 1_000_000) as u32; // assume ~1MB blocks
-        for (fee_rate, vsize) in self.tracker.read().unwrap().fee_histogram() {
-            last_fee_rate = *fee_rate;
-            total_vsize += vsize;
-            if total_vsize >= blocks_in_vbytes {
-                break; // under-estimate the fee rate a bit
-            }
-        }
-        (last_fee_rate as f64) * 1e-5 // [BTC/kB] = 10^5 [sat/B]
+        self.estimate_fees(&[blocks])[0]
     }
 
-    pub fn get_banner(&self) -> Result<String> {
-        self.app.get_banner()
+    /// Fee rates [BTC/kB] for each of the confirmation targets in `blocks`,
+    /// estimated from the same mempool snapshot.
+    pub fn estimate_fees(&self, blocks: &[usize]) -> Vec<f64> {
+        let tracker = read_tracker(&self.tracker);
+        let histogram = tracker.fee_histogram();
+        blocks
+            .iter()
+            .map(|blocks| estimate_fee_from_histogram(histogram, *blocks))
+            .collect()
+    }
+
+    pub fn get_banner(&self, vars: &BannerVars) -> Result<String> {
+        self.app.get_banner(vars)
     }
 
     pub fn get_cashaccount_txs(&self, name: &str, height: u32) -> Result<Value> {
-        let cashaccount_txs = load_txns_by_prefix(
-            self.app.read_store(),
-            txids_by_cashaccount(self.app.read_store(), name, height),
-            &self.tx,
-        );
+        let txid_prefixes = txids_by_cashaccount(self.app.read_store(), name, height);
+        self.cashaccount_txs(name, txid_prefixes, height..=height)
+    }
+
+    /// Registrations of `name` within a range of block heights, returning
+    /// at most `limit` registrations.
+    pub fn get_cashaccount_txs_range(
+        &self,
+        name: &str,
+        heights: RangeInclusive<u32>,
+        limit: usize,
+    ) -> Result<Value> {
+        let mut txid_prefixes: Vec<HashPrefix> =
+            txids_by_cashaccount_range(self.app.read_store(), name, heights.clone(), limit)?
+                .into_iter()
+                .map(|(_, txid_prefix)| txid_prefix)
+                .collect();
+        // ordered by height, keep the first occurrence
+        let mut seen = HashSet::new();
+        txid_prefixes.retain(|txid_prefix| seen.insert(*txid_prefix));
+        self.cashaccount_txs(name, txid_prefixes, heights)
+    }
+
+    fn cashaccount_txs(
+        &self,
+        name: &str,
+        txid_prefixes: Vec<HashPrefix>,
+        heights: RangeInclusive<u32>,
+    ) -> Result<Value> {
+        self.executor
+            .run(|| self.load_cashaccount_txs(name, txid_prefixes, heights))
+    }
+
+    fn load_cashaccount_txs(
+        &self,
+        name: &str,
+        txid_prefixes: Vec<HashPrefix>,
+        heights: RangeInclusive<u32>,
+    ) -> Result<Value> {
+        let cashaccount_txs = load_txns_by_prefix(self.app.read_store(), txid_prefixes, &self.tx);
 
         #[derive(Serialize, Deserialize, Debug)]
         struct AccountTx {
@@ -488,28 +1150,27 @@ impl Query {
             height: u32,
             blockhash: String,
         }
-        let header = self
-            .header()
-            .at_height(height as usize)
-            .chain_err(|| format!("missing header at height {}", height))?;
-        let blockhash = header.hash().to_hex();
 
         let mut result: Vec<AccountTx> = vec![];
         let parser = CashAccountParser::new(None);
 
-        for (_, tx) in cashaccount_txs {
+        for (height, tx) in cashaccount_txs {
             let tx = tx?;
 
-            // Filter on name in case of txid prefix collision
-            if !parser.has_cashaccount(&tx, name) {
+            // Filter on height and name in case of txid prefix collision
+            if !heights.contains(&height) || !parser.has_cashaccount(&tx, name) {
                 continue;
             }
+            let header = self
+                .header()
+                .at_height(height as usize)
+                .chain_err(|| format!("missing header at height {}", height))?;
 
             result.push({
                 AccountTx {
                     tx: hex::encode(&serialize(&tx)),
                     height,
-                    blockhash: blockhash.clone(),
+                    blockhash: header.hash().to_hex(),
                 }
             })
         }
@@ -517,39 +1178,154 @@ impl Query {
         Ok(json!(result))
     }
 
-    /// Find first outputs to scripthash
-    pub fn scripthash_first_use(&self, scripthash: &FullHash) -> Result<(u32, Txid)> {
-        let get_tx = |store| {
-            let rows = txoutrows_by_script_hash(store, scripthash);
-            let mut txs: Vec<TxRow> = rows
-                .iter()
-                .flat_map(|p| txrows_by_prefix(store, p.txid_prefix))
-                .collect();
-
-            txs.sort_unstable_by(|a, b| a.height.cmp(&b.height));
-
-            for txrow in txs.drain(..) {
-                // verify that tx contains scripthash as output
-                let txid = Txid::from_slice(&txrow.key.txid[..]).expect("invalid txid");
-                let tx = self.tx.get(&txid, None, Some(txrow.height))?;
+    /// Add size and fee of the transactions to (at most) the first
+    /// `max_items` history items, loading each transaction. At most
+    /// `max_prevout_txs` previous transactions are loaded for the fees of
+    /// all items together.
+    pub fn add_history_tx_info(
+        &self,
+        items: &mut [HistoryItem],
+        max_items: usize,
+        max_prevout_txs: usize,
+        timeout: &TimeoutTrigger,
+    ) -> Result<()> {
+        add_tx_info(
+            items,
+            max_items,
+            max_prevout_txs,
+            timeout,
+            |item| {
+                let height = if item.height > 0 {
+                    Some(item.height as u32)
+                } else {
+                    None
+                };
+                self.tx.get(&item.tx_hash, None, height)
+            },
+            |tx, prevout_txs| self.tx.get_fee(tx, prevout_txs, timeout),
+        )
+    }
 
-                for o in tx.output.iter() {
-                    if compute_script_hash(&o.script_pubkey[..]) == *scripthash {
-                        return Ok((txrow.height, txid));
-                    }
+    /// Find confirmed transactions with an OP_RETURN payload starting with
+    /// `prefix`. Returns a list of txids and heights, ordered by height.
+    pub fn get_opreturn_history(
+        &self,
+        prefix: &[u8],
+        limit: usize,
+        timeout: &TimeoutTrigger,
+    ) -> Result<Value> {
+        if !self.app.index().index_opreturn() {
+            bail!(ErrorKind::RpcError(
+                RpcErrorCode::Other,
+                "OP_RETURN index is not enabled on this server".to_string()
+            ));
+        }
+        self.app.index().sync_status().check()?;
+        let mut result: Vec<(u32, Txid)> = self.executor.run(|| {
+            let txns = load_txns_by_prefix(
+                self.app.read_store(),
+                txids_by_opreturn(self.app.read_store(), prefix, limit)?,
+                &self.tx,
+            );
+            let mut result = vec![];
+            for (height, tx) in txns {
+                timeout.check()?;
+                let tx = tx?;
+                // Filter on payload in case of prefix collision
+                if has_opreturn_prefix(&tx, prefix) {
+                    result.push((height, tx.txid()));
                 }
             }
-            Ok((0, Txid::default()))
+            Ok(result)
+        })?;
+        result.sort_unstable();
+        Ok(json!(result
+            .into_iter()
+            .map(|(height, txid)| json!({"tx_hash": txid.to_hex(), "height": height}))
+            .collect::<Vec<Value>>()))
+    }
+
+    /// Fee statistics of the block at `height` in the best chain.
+    pub fn get_block_fee_stats(&self, height: usize) -> Result<Value> {
+        if !self.app.index().index_block_fees() {
+            bail!(ErrorKind::RpcError(
+                RpcErrorCode::Other,
+                "block fee index is not enabled on this server".to_string()
+            ));
+        }
+        let header = self.header.at_height(height).chain_err(|| {
+            ErrorKind::RpcError(
+                RpcErrorCode::NotFound,
+                format!("no block at height {}", height),
+            )
+        })?;
+        let stats =
+            read_block_fee_stats(self.app.read_store(), header.hash())?.chain_err(|| {
+                ErrorKind::RpcError(
+                    RpcErrorCode::NotFound,
+                    format!("no fee stats for block at height {}", height),
+                )
+            })?;
+        let mut result = stats.to_json();
+        result["height"] = json!(height);
+        result["hash"] = json!(header.hash().to_hex());
+        Ok(result)
+    }
+
+    /// Size statistics of the block at `height` in the best chain.
+    pub fn get_block_stats(&self, height: usize) -> Result<Value> {
+        if !self.app.index().index_block_stats() {
+            bail!(ErrorKind::RpcError(
+                RpcErrorCode::Other,
+                "block stats index is not enabled on this server".to_string()
+            ));
+        }
+        let header = self.header.at_height(height).chain_err(|| {
+            ErrorKind::RpcError(
+                RpcErrorCode::NotFound,
+                format!("no block at height {}", height),
+            )
+        })?;
+        let stats = read_block_stats(self.app.read_store(), header.hash())?.chain_err(|| {
+            ErrorKind::RpcError(
+                RpcErrorCode::NotFound,
+                format!("no stats for block at height {}", height),
+            )
+        })?;
+        let mut result = stats.to_json();
+        result["height"] = json!(height);
+        result["hash"] = json!(header.hash().to_hex());
+        Ok(result)
+    }
+
+    /// Find first outputs to scripthash. Returns the height (MEMPOOL_HEIGHT
+    /// if only used in mempool) and txid, or None if scripthash was never used.
+    pub fn scripthash_first_use(&self, scripthash: &FullHash) -> Result<Option<(u32, Txid)>> {
+        self.app.index().sync_status().check()?;
+        let get_tx = |store| {
+            first_use_in_store(store, scripthash, self.max_first_use_txs, |txid, height| {
+                self.tx.get(txid, None, Some(height))
+            })
         };
 
         // Look at blockchain first
-        let tx = get_tx(self.app.read_store())?;
-        if tx.0 != 0 {
+        let tx = if self.app.index().index_first_use() {
+            first_use_in_index(
+                self.app.read_store(),
+                scripthash,
+                self.max_first_use_txs,
+                |height| self.app.index().get_blockhash(height as usize),
+                |txid, height| self.tx.get(txid, None, Some(height)),
+            )?
+        } else {
+            get_tx(self.app.read_store())?
+        };
+        if tx.is_some() {
             return Ok(tx);
         }
 
         // No match in the blockchain, try the mempool also.
-        let tracker = self.tracker.read().unwrap();
+        let tracker = read_tracker(&self.tracker);
         get_tx(tracker.index())
     }
 
@@ -557,6 +1333,34 @@ impl Query {
         self.app.daemon().get_relayfee()
     }
 
+    pub fn get_daemon_warnings(&self) -> Result<Vec<String>> {
+        self.app.daemon().get_warnings()
+    }
+
+    /// Blocks left to index, or None once the index caught up with the
+    /// daemon.
+    pub fn blocks_behind(&self) -> Option<usize> {
+        let status = self.app.index().sync_status();
+        (!status.is_ready()).then(|| status.blocks_behind())
+    }
+
+    /// Number of entries per page of history paged with a cursor
+    pub fn history_page_size(&self) -> usize {
+        self.history_page_size
+    }
+
+    pub fn mempool_tx_count(&self) -> usize {
+        read_tracker(&self.tracker).tx_count()
+    }
+
+    pub fn blocktxids_cache_usage(&self) -> (u64, u64) {
+        self.app.daemon().blocktxids_cache_usage()
+    }
+
+    pub fn address_cache(&self) -> &AddressCache {
+        &self.address_cache
+    }
+
     pub fn tx(&self) -> &TxQuery {
         &self.tx
     }
@@ -577,7 +1381,7 @@ impl Query {
         )>,
     > {
         {
-            let tracker = self.tracker.read().unwrap();
+            let tracker = read_tracker(&self.tracker);
             let spent = self
                 .unconfirmed
                 .get_tx_spending_prevout(&tracker, timeout, prevout)?;
@@ -590,3 +1394,1099 @@ impl Query {
             .get_tx_spending_prevout(store, timeout, prevout)
     }
 }
+
+/// Query over an index in a temporary DB, backed by a fake bitcoind which
+/// knows no transactions. The DB is removed on drop.
+#[cfg(test)]
+pub struct TestQuery {
+    query: Option<Arc<Query>>,
+    tx_cache: Arc<TransactionCache>,
+    db_path: std::path::PathBuf,
+}
+
+#[cfg(test)]
+impl Default for TestQuery {
+    fn default() -> TestQuery {
+        TestQuery::new()
+    }
+}
+
+#[cfg(test)]
+impl TestQuery {
+    /// Query over an empty index
+    pub fn new() -> TestQuery {
+        TestQuery::with_confirmed(&[])
+    }
+
+    /// Query over an index of the transactions `confirmed` at given heights.
+    /// They're cached, as the fake bitcoind can't return them.
+    pub fn with_confirmed(confirmed: &[(Transaction, u32)]) -> TestQuery {
+        use crate::daemon::Daemon;
+        use crate::index::{index_transaction, Index, IndexOptions, OutputFilter};
+        use crate::rndcache::Watermarks;
+        use crate::store::{DbStore, WriteStore};
+
+        let metrics = Metrics::dummy();
+        let db_path = std::env::temp_dir().join(format!(
+            "electrscash-test-{}",
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        let store = DbStore::open(&db_path, false, false, &metrics);
+        let daemon = Arc::new(Daemon::fake(&metrics));
+        let tx_cache = Arc::new(TransactionCache::new(
+            1 << 20,
+            Watermarks::default(),
+            &metrics,
+        ));
+        for (tx, height) in confirmed {
+            let rows = index_transaction(
+                tx,
+                *height as usize,
+                None,
+                false,
+                None,
+                OutputFilter::default(),
+            );
+            store.write(rows, false).unwrap();
+            tx_cache.put(&tx.txid(), serialize(tx));
+        }
+        let index = Index::load(
+            &store,
+            &daemon,
+            &metrics,
+            1,
+            1,
+            0,
+            0,
+            IndexOptions::default(),
+            Arc::clone(&tx_cache),
+            None,
+            0,
+        )
+        .unwrap();
+        index.sync_status().set_ready();
+        let app = App::new(store, index, daemon, "").unwrap();
+        let options = QueryOptions {
+            txheight_cache_size: 1000,
+            txid_limit: 0,
+            network: Network::Regtest,
+            max_cp_height: 0,
+            mempool_histogram_threshold: 0,
+            mempool_histogram_max_age: None,
+            max_first_use_txs: 1000,
+            max_mempool_txids: 1000,
+            history_block_order_max_blocks: 0,
+            history_page_size: 1000,
+            merkle_cache_size: 10,
+        };
+        let query = Query::new(
+            app,
+            &metrics,
+            Arc::clone(&tx_cache),
+            options,
+            QueryExecutor::new(1, 10, &metrics).unwrap(),
+            AddressCache::new(0, Watermarks::default(), &metrics),
+        )
+        .unwrap();
+        TestQuery {
+            query: Some(query),
+            tx_cache,
+            db_path,
+        }
+    }
+
+    pub fn query(&self) -> &Arc<Query> {
+        self.query.as_ref().unwrap()
+    }
+
+    /// Add `tx` to the mempool, as if fetched from bitcoind. It's cached,
+    /// as the fake bitcoind can't return it.
+    pub fn add_mempool_tx(&self, tx: Transaction, fee: u64) {
+        let entry = crate::daemon::MempoolEntry::new(fee, tx.get_size() as u32);
+        self.tx_cache.put(&tx.txid(), serialize(&tx));
+        self.query().tracker.write().unwrap().insert(tx, entry);
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestQuery {
+    fn drop(&mut self) {
+        // the DB is closed along with the last reference to the query
+        drop(self.query.take());
+        crate::store::DbStore::destroy(&self.db_path);
+    }
+}
+
+/// Fee rate [BTC/kB] to be confirmed in `blocks` from now, given a
+/// mempool fee histogram ordered by decreasing fee rate.
+fn estimate_fee_from_histogram(histogram: &[(f32, u32)], blocks: usize) -> f64 {
+    let mut total_vsize = 0u64;
+    let mut last_fee_rate = 0.0;
+    let blocks_in_vbytes = blocks as u64 * 1_000_000; // assume ~1MB blocks
+    for (fee_rate, vsize) in histogram {
+        last_fee_rate = *fee_rate;
+        total_vsize += *vsize as u64;
+        if total_vsize >= blocks_in_vbytes {
+            break; // under-estimate the fee rate a bit
+        }
+    }
+    (last_fee_rate as f64) * 1e-5 // [BTC/kB] = 10^5 [sat/B]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txid(i: u8) -> Txid {
+        Txid::from_slice(&[i; 32]).unwrap()
+    }
+
+    /// Output 0 of tx `i`, funding the scripthash with 1000 satoshis.
+    fn funding(i: u8, height: u32, state: ConfirmationState) -> FundingOutput {
+        FundingOutput {
+            funding_output: OutPoint::new(txid(i), 0),
+            height,
+            value: 1000,
+            state,
+            has_token: false,
+        }
+    }
+
+    /// Input of tx `i`, spending the funding output of tx `spent`.
+    fn spending(i: u8, spent: u8, height: u32, state: ConfirmationState) -> SpendingInput {
+        SpendingInput {
+            txn_id: txid(i),
+            height,
+            funding_output: OutPoint::new(txid(spent), 0),
+            value: 1000,
+            state,
+            has_token: false,
+        }
+    }
+
+    fn error_code<T>(result: Result<T>) -> i32 {
+        match result.err().expect("expected an error").kind() {
+            ErrorKind::RpcError(code, _) => *code as i32,
+            e => panic!("expected an RPC error, got {}", e),
+        }
+    }
+
+    #[test]
+    fn test_status_error_codes() {
+        let empty_status = || {
+            Ok(Status {
+                confirmed: (vec![], vec![]),
+                mempool: (vec![], vec![]),
+                txn_fees: HashMap::new(),
+                block_positions: HashMap::new(),
+            })
+        };
+        let sync_status = SyncStatus::default();
+        let executor = QueryExecutor::new(1, 0, &Metrics::dummy()).unwrap();
+        assert_eq!(
+            error_code(checked_status(&sync_status, &executor, empty_status)),
+            -32002
+        );
+
+        sync_status.set_ready();
+        assert!(checked_status(&sync_status, &executor, empty_status).is_ok());
+        // the only query thread is taken by the outer request
+        let busy = executor.run(|| {
+            Ok(error_code(checked_status(
+                &sync_status,
+                &executor,
+                empty_status,
+            )))
+        });
+        assert_eq!(busy.unwrap(), -32001);
+    }
+
+    #[test]
+    fn test_merkle_proof_error_codes() {
+        let txids: Vec<Txid> = (1..=3u8)
+            .map(|i| Txid::from_slice(&[i; 32]).unwrap())
+            .collect();
+        let blockhash = BlockHash::from_slice(&[9; 32]).unwrap();
+        let tree = || Ok(Arc::new(MerkleTree::new(&txids)));
+
+        let (branch, pos) = merkle_proof(Some(blockhash), &txids[2], 5, |_| tree()).unwrap();
+        assert_eq!(pos, 2);
+        assert_eq!(branch.len(), 2);
+
+        // unknown height
+        assert_eq!(
+            error_code(merkle_proof(None, &txids[0], 5, |_| tree())),
+            -32004
+        );
+        // the transaction is not in the block
+        let other = Txid::from_slice(&[7; 32]).unwrap();
+        assert_eq!(
+            error_code(merkle_proof(Some(blockhash), &other, 5, |_| tree())),
+            -32004
+        );
+        // the block was pruned by bitcoind
+        let pruned = |_: &BlockHash| -> Result<Arc<MerkleTree>> {
+            bail!(ErrorKind::RpcError(
+                RpcErrorCode::Pruned,
+                "Block not available (pruned data)".to_string()
+            ))
+        };
+        assert_eq!(
+            error_code(merkle_proof(Some(blockhash), &txids[0], 5, pruned)),
+            -32003
+        );
+    }
+
+    #[test]
+    fn test_tx_info_prevout_budget() {
+        use bitcoincash::blockdata::script::Script;
+        use bitcoincash::blockdata::transaction::{TxIn, TxOut};
+
+        // each transaction spends outputs of two other transactions
+        let tx = |i: u8| Transaction {
+            version: 1,
+            lock_time: 0,
+            input: (0..2)
+                .map(|k| TxIn {
+                    previous_output: OutPoint::new(Txid::from_slice(&[i + k; 32]).unwrap(), 0),
+                    script_sig: Script::new(),
+                    sequence: 0xffffffff,
+                    witness: vec![],
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: u64::from(i),
+                script_pubkey: Script::new(),
+            }],
+        };
+        let item = |i: u8, height: i32, fee: Option<u64>| HistoryItem {
+            height,
+            tx_hash: Txid::from_slice(&[i; 32]).unwrap(),
+            fee,
+            size: None,
+        };
+        let mut items = vec![
+            item(1, 0, Some(42)),
+            item(2, 100, None),
+            item(3, 101, None),
+            item(4, 102, None),
+            item(5, 103, None),
+        ];
+        let timeout = TimeoutTrigger::new(Duration::from_secs(10));
+        let mut loaded_prevouts = 0;
+        add_tx_info(
+            &mut items,
+            4,
+            5,
+            &timeout,
+            |item| Ok(tx(item.tx_hash[0])),
+            |tx, prevout_txs| {
+                loaded_prevouts += prevout_txs;
+                Ok(Some(tx.output[0].value * 10))
+            },
+        )
+        .unwrap();
+
+        // the known fee of the unconfirmed tx is kept, without loading
+        assert_eq!(items[0].fee, Some(42));
+        // the budget covers the prevouts of two more transactions
+        assert_eq!(items[1].fee, Some(20));
+        assert_eq!(items[2].fee, Some(30));
+        assert_eq!(items[3].fee, None);
+        assert_eq!(loaded_prevouts, 4);
+        // only `max_items` items are extended
+        assert!(items[..4].iter().all(|item| item.size.is_some()));
+        assert_eq!(items[4].size, None);
+    }
+
+    #[test]
+    fn test_estimate_fee_from_histogram() {
+        // [sat/B], [vbytes]
+        let histogram = vec![
+            (50.0, 400_000),
+            (20.0, 800_000),
+            (10.0, 1_000_000),
+            (5.0, 2_000_000),
+            (1.0, 500_000),
+        ];
+        let targets = [1, 2, 3, 4, 5, 10, 25];
+        let fees: Vec<f64> = targets
+            .iter()
+            .map(|blocks| estimate_fee_from_histogram(&histogram, *blocks))
+            .collect();
+        assert!(fees.windows(2).all(|pair| pair[0] >= pair[1]), "{:?}", fees);
+        assert_eq!(fees[0], 20.0 * 1e-5);
+        assert_eq!(fees[1], 10.0 * 1e-5);
+        assert_eq!(fees[6], 1.0 * 1e-5);
+        assert_eq!(estimate_fee_from_histogram(&[], 1), 0.0);
+    }
+
+    #[test]
+    fn test_merkle_branch_and_root() {
+        for leaf_count in 1..40 {
+            let leaves: Vec<Sha256dHash> = (0..leaf_count)
+                .map(|i: usize| Sha256dHash::hash(&i.to_le_bytes()))
+                .collect();
+            for index in 0..leaf_count {
+                assert_eq!(
+                    merkle_branch_and_root(leaf_count, index, |i| leaves[i]),
+                    create_merkle_branch_and_root(leaves.clone(), index),
+                    "leaf_count={} index={}",
+                    leaf_count,
+                    index
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_merkle_proof_validates() {
+        use bitcoincash::blockdata::block::{Block, BlockHeader};
+        use bitcoincash::blockdata::transaction::Transaction;
+
+        let txdata: Vec<Transaction> = (0..5)
+            .map(|i| Transaction {
+                version: 1,
+                lock_time: i,
+                input: vec![],
+                output: vec![],
+            })
+            .collect();
+        let mut block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: BlockHash::default(),
+                merkle_root: TxMerkleNode::default(),
+                time: 0,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata,
+        };
+        block.header.merkle_root = block.merkle_root();
+
+        let nodes: Vec<TxMerkleNode> = block
+            .txdata
+            .iter()
+            .map(|tx| TxMerkleNode::from_inner(tx.txid().into_inner()))
+            .collect();
+        for (pos, node) in nodes.iter().enumerate() {
+            let (branch, root) = create_merkle_branch_and_root(nodes.clone(), pos);
+            assert_eq!(root, block.header.merkle_root);
+            // Verify as an SPV client would, from the txid up
+            let mut index = pos;
+            let mut hash = *node;
+            for sibling in branch {
+                hash = if index % 2 == 0 {
+                    merklize(hash, sibling)
+                } else {
+                    merklize(sibling, hash)
+                };
+                index /= 2;
+            }
+            assert_eq!(hash, block.header.merkle_root, "pos {}", pos);
+        }
+    }
+
+    #[test]
+    fn test_check_cp_height() {
+        assert!(check_cp_height(800_000, 0).is_ok());
+        assert!(check_cp_height(100_000, 100_000).is_ok());
+        let err = check_cp_height(100_001, 100_000).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::RpcError(RpcErrorCode::InvalidParams, _)
+        ));
+    }
+
+    #[test]
+    fn test_history_order() {
+        let status = Status {
+            confirmed: (
+                vec![
+                    funding(1, 102, ConfirmationState::Confirmed),
+                    funding(2, 100, ConfirmationState::Confirmed),
+                    funding(3, 100, ConfirmationState::Confirmed),
+                ],
+                vec![],
+            ),
+            mempool: (vec![funding(4, 0, ConfirmationState::InMempool)], vec![]),
+            txn_fees: HashMap::new(),
+            block_positions: HashMap::new(),
+        };
+        let entries = |order| {
+            status
+                .history_ordered(order, true)
+                .iter()
+                .map(|item| (item.height, item.tx_hash[0]))
+                .collect::<Vec<_>>()
+        };
+        let ascending = vec![(100, 3), (100, 2), (102, 1), (0, 4)];
+        assert_eq!(entries(HistoryOrder::Ascending), ascending);
+        let descending: Vec<_> = ascending.into_iter().rev().collect();
+        assert_eq!(entries(HistoryOrder::Descending), descending);
+    }
+
+    #[test]
+    fn test_history_include_unconfirmed() {
+        // tx 1 is spent by mempool tx 3, which is spent by tx 4
+        let status = Status {
+            confirmed: (
+                vec![
+                    funding(1, 100, ConfirmationState::Confirmed),
+                    funding(2, 101, ConfirmationState::Confirmed),
+                ],
+                vec![],
+            ),
+            mempool: (
+                vec![
+                    funding(3, 0, ConfirmationState::InMempool),
+                    funding(4, 0, ConfirmationState::UnconfirmedParent),
+                ],
+                vec![spending(3, 1, 0, ConfirmationState::InMempool)],
+            ),
+            txn_fees: HashMap::new(),
+            block_positions: HashMap::new(),
+        };
+        let entries = |order, include_unconfirmed| {
+            status
+                .history_ordered(order, include_unconfirmed)
+                .iter()
+                .map(|item| (item.height, item.tx_hash[0]))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            entries(HistoryOrder::Ascending, true),
+            vec![(100, 1), (101, 2), (0, 3), (-1, 4)]
+        );
+        assert_eq!(
+            entries(HistoryOrder::Ascending, false),
+            vec![(100, 1), (101, 2)]
+        );
+        assert_eq!(
+            entries(HistoryOrder::Descending, false),
+            vec![(101, 2), (100, 1)]
+        );
+    }
+
+    #[test]
+    fn test_history_summary() {
+        let mut status = Status {
+            confirmed: (vec![], vec![]),
+            mempool: (vec![], vec![]),
+            txn_fees: HashMap::new(),
+            block_positions: HashMap::new(),
+        };
+        assert_eq!(status.history_summary(), (None, 0));
+
+        // Summary matches the last entry of the history returned by get_history
+        let check = |status: &Status| {
+            let history: Vec<Value> = status.history().iter().map(|h| h.to_json()).collect();
+            let (height, tx_count) = status.history_summary();
+            assert_eq!(tx_count, history.len());
+            assert_eq!(json!(height), history.last().unwrap()["height"]);
+            height
+        };
+        status.confirmed.0 = vec![
+            funding(1, 102, ConfirmationState::Confirmed),
+            funding(2, 100, ConfirmationState::Confirmed),
+        ];
+        assert_eq!(check(&status), Some(102));
+        status.mempool.0 = vec![funding(3, 0, ConfirmationState::InMempool)];
+        assert_eq!(check(&status), Some(0));
+    }
+
+    #[test]
+    fn test_balance_exclude_tokens() {
+        let output = |i: u8, value: u64, state| FundingOutput {
+            value,
+            ..funding(i, 100, state)
+        };
+        let input = |i: u8, spent: u8, value: u64, state| SpendingInput {
+            value,
+            ..spending(i, spent, 101, state)
+        };
+        // Outputs of transactions 2 and 5 carry tokens
+        let mut status = Status {
+            confirmed: (
+                vec![
+                    output(1, 10_000, ConfirmationState::Confirmed),
+                    output(2, 800, ConfirmationState::Confirmed),
+                    output(3, 5_000, ConfirmationState::Confirmed),
+                ],
+                vec![
+                    input(4, 3, 5_000, ConfirmationState::Confirmed),
+                    input(4, 2, 800, ConfirmationState::Confirmed),
+                ],
+            ),
+            mempool: (vec![output(5, 1_000, ConfirmationState::InMempool)], vec![]),
+            txn_fees: HashMap::new(),
+            block_positions: HashMap::new(),
+        };
+        // Unmarked, all outputs are counted, with or without the flag
+        assert_eq!(status.confirmed_balance(false), 10_000);
+        assert_eq!(status.mempool_balance(false), 1_000);
+        assert_eq!(status.confirmed_balance(true), 10_000);
+
+        status
+            .mark_token_outputs(|f| Ok([txid(2), txid(5)].contains(&f.funding_output.txid)))
+            .unwrap();
+        assert!(status.confirmed.1[1].has_token);
+        assert!(!status.confirmed.1[0].has_token);
+
+        // Default is unchanged
+        assert_eq!(status.confirmed_balance(false), 10_000);
+        assert_eq!(status.mempool_balance(false), 1_000);
+
+        assert_eq!(status.confirmed_balance(true), 10_000);
+        assert_eq!(status.mempool_balance(true), 0);
+
+        // An unspent token output
+        status.confirmed.1.pop();
+        assert_eq!(status.confirmed_balance(false), 10_800);
+        assert_eq!(status.confirmed_balance(true), 10_000);
+    }
+
+    #[test]
+    fn test_balance_overflow() {
+        let output = |value: u64| FundingOutput {
+            value,
+            ..funding(1, 100, ConfirmationState::Confirmed)
+        };
+        let input = |value: u64| SpendingInput {
+            value,
+            ..spending(2, 1, 101, ConfirmationState::Confirmed)
+        };
+        let max = i64::MAX as u64;
+        let balance = |funded: Vec<u64>, spent: Vec<u64>| {
+            calc_balance(
+                &(
+                    funded.into_iter().map(output).collect(),
+                    spent.into_iter().map(input).collect(),
+                ),
+                false,
+            )
+        };
+        // near the boundary, no wraparound
+        assert_eq!(balance(vec![max], vec![]), i64::MAX);
+        assert_eq!(balance(vec![max, 1], vec![1]), i64::MAX);
+        assert_eq!(balance(vec![u64::MAX, u64::MAX], vec![u64::MAX]), i64::MAX);
+        assert_eq!(balance(vec![u64::MAX], vec![u64::MAX - 5]), 5);
+        // beyond it, saturated
+        assert_eq!(balance(vec![max, 1], vec![]), i64::MAX);
+        assert_eq!(balance(vec![u64::MAX, u64::MAX], vec![]), i64::MAX);
+        assert_eq!(balance(vec![], vec![max, 1]), i64::MIN);
+        assert_eq!(balance(vec![], vec![u64::MAX, u64::MAX]), i64::MIN);
+    }
+
+    #[test]
+    fn test_has_token_data() {
+        use crate::query::queryutil::has_token_data;
+        use bitcoincash::blockdata::script::Script;
+        use bitcoincash::blockdata::transaction::TxOut;
+        let p2pkh = "76a914dfdd3e914d73fee85ad40cd71430327f0404c15488ac";
+        let txout = |script: &str| TxOut {
+            value: 800,
+            script_pubkey: Script::from(hex::decode(script).unwrap()),
+        };
+        assert!(!has_token_data(&txout(p2pkh)));
+        assert!(!has_token_data(&txout("")));
+        // token prefix, category id, bitfield (fungible amount), amount
+        let token = format!("ef{}10{}{}", "ab".repeat(32), "64", p2pkh);
+        assert!(has_token_data(&txout(&token)));
+    }
+
+    #[test]
+    fn test_mempool_only_history() {
+        let mempool = || {
+            (
+                vec![
+                    funding(4, 0, ConfirmationState::InMempool),
+                    funding(5, 0, ConfirmationState::UnconfirmedParent),
+                ],
+                vec![
+                    // spends a confirmed output
+                    spending(6, 1, 0, ConfirmationState::InMempool),
+                    spending(7, 4, 0, ConfirmationState::UnconfirmedParent),
+                ],
+            )
+        };
+        let txn_fees = || vec![(txid(4), 200), (txid(6), 300)].into_iter().collect();
+        let full = Status {
+            confirmed: (
+                vec![
+                    funding(1, 100, ConfirmationState::Confirmed),
+                    funding(2, 101, ConfirmationState::Confirmed),
+                ],
+                vec![spending(3, 2, 102, ConfirmationState::Confirmed)],
+            ),
+            mempool: mempool(),
+            txn_fees: txn_fees(),
+            block_positions: HashMap::new(),
+        };
+        // as returned by status_mempool()
+        let mempool_only = Status {
+            confirmed: (vec![], vec![]),
+            mempool: mempool(),
+            txn_fees: txn_fees(),
+            block_positions: HashMap::new(),
+        };
+        let expected: Vec<Value> = full
+            .history()
+            .iter()
+            .filter(|item| item.height <= 0)
+            .map(HistoryItem::to_json)
+            .collect();
+        let history: Vec<Value> = mempool_only
+            .history()
+            .iter()
+            .map(HistoryItem::to_json)
+            .collect();
+        assert_eq!(history.len(), 4);
+        assert_eq!(history, expected);
+    }
+
+    #[test]
+    fn test_first_use_scan_limit() {
+        use crate::fake::MemStore;
+        use crate::index::TxOutRow;
+        use crate::store::WriteStore;
+        use bitcoincash::blockdata::script::Script;
+        use bitcoincash::blockdata::transaction::TxOut;
+
+        let output = |script: &[u8]| TxOut {
+            value: 1000,
+            script_pubkey: Script::from(script.to_vec()),
+        };
+        let tx = |script: &[u8]| Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![output(script)],
+        };
+        let scripthash = compute_script_hash(b"used");
+
+        // 10 transactions match the scripthash prefix, but only the last
+        // (highest) one pays to it.
+        let store = MemStore::default();
+        store
+            .write(
+                (1..=10u8).flat_map(|i| {
+                    vec![
+                        TxOutRow::new(&txid(i), &output(b"used"), 0).to_row(),
+                        TxRow::new(&txid(i), 100 + i as u32).to_row(),
+                    ]
+                }),
+                false,
+            )
+            .unwrap();
+        let loaded = std::cell::Cell::new(0);
+        let mut load_tx = |txid: &Txid, _height| {
+            loaded.set(loaded.get() + 1);
+            Ok(if *txid == Txid::from_slice(&[10; 32]).unwrap() {
+                tx(b"used")
+            } else {
+                tx(b"collision")
+            })
+        };
+        assert_eq!(
+            first_use_in_store(&store, &scripthash, 10, &mut load_tx).unwrap(),
+            Some((110, txid(10)))
+        );
+        assert_eq!(
+            first_use_in_store(&store, &scripthash, 0, &mut load_tx).unwrap(),
+            Some((110, txid(10)))
+        );
+        match first_use_in_store(&store, &scripthash, 9, &mut load_tx)
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::RpcError(RpcErrorCode::Other, msg) => {
+                assert!(msg.contains("too ambiguous"), "{}", msg)
+            }
+            e => panic!("unexpected error {}", e),
+        }
+        // the limit is hit before loading more than 9 candidates
+        assert_eq!(loaded.get(), 10 + 10 + 9);
+
+        let unused = compute_script_hash(b"unused");
+        assert_eq!(
+            first_use_in_store(&store, &unused, 1, &mut load_tx).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_first_use_index() {
+        use crate::fake::MemStore;
+        use crate::index::FirstUseRow;
+        use crate::store::WriteStore;
+        use bitcoincash::blockdata::script::Script;
+        use bitcoincash::blockdata::transaction::TxOut;
+
+        let blockhash = |i: u8| BlockHash::from_slice(&[i; 32]).unwrap();
+        let output = |script: &[u8]| TxOut {
+            value: 1000,
+            script_pubkey: Script::from(script.to_vec()),
+        };
+        let tx = |script: &[u8]| Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![output(script)],
+        };
+        let confirm = |store: &MemStore, i: u8, height: u32, block: u8| {
+            let rows = vec![
+                FirstUseRow::new(&txid(i), &output(b"used"), height, &blockhash(block)).to_row(),
+                TxRow::new(&txid(i), height).to_row(),
+            ];
+            store.write(rows, false).unwrap();
+        };
+        let scripthash = compute_script_hash(b"used");
+
+        // txid 1 only matches the scripthash prefix, txids 2 and 3 pay to it
+        let store = MemStore::default();
+        confirm(&store, 3, 110, 3);
+        confirm(&store, 2, 105, 2);
+        confirm(&store, 1, 100, 1);
+        let loaded = std::cell::Cell::new(0);
+        let mut load_tx = |txid: &Txid, _height| {
+            loaded.set(loaded.get() + 1);
+            Ok(if *txid == Txid::from_slice(&[1; 32]).unwrap() {
+                tx(b"collision")
+            } else {
+                tx(b"used")
+            })
+        };
+        let best_chain = |blocks: Vec<(u32, u8)>| {
+            let blocks: HashMap<u32, BlockHash> =
+                blocks.into_iter().map(|(h, i)| (h, blockhash(i))).collect();
+            move |height| blocks.get(&height).copied()
+        };
+        let chain = best_chain(vec![(100, 1), (105, 2), (110, 3)]);
+        assert_eq!(
+            first_use_in_index(&store, &scripthash, 10, &chain, &mut load_tx).unwrap(),
+            Some((105, txid(2)))
+        );
+        // only the collision and the first use are loaded
+        assert_eq!(loaded.get(), 2);
+        match first_use_in_index(&store, &scripthash, 1, &chain, &mut load_tx)
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::RpcError(RpcErrorCode::Other, msg) => {
+                assert!(msg.contains("too ambiguous"), "{}", msg)
+            }
+            e => panic!("unexpected error {}", e),
+        }
+
+        // a reorg below the first use orphans blocks 2 and 3, txid 2 confirms
+        // again at height 107 and txid 3 at the same height as before. The
+        // rows of block 2 are left, as if bitcoind couldn't return it.
+        confirm(&store, 2, 107, 7);
+        confirm(&store, 3, 110, 8);
+        let chain = best_chain(vec![(100, 1), (105, 5), (107, 7), (110, 8)]);
+        assert_eq!(
+            first_use_in_index(&store, &scripthash, 0, &chain, &mut load_tx).unwrap(),
+            Some((107, txid(2)))
+        );
+        // a reorg below the first use, dropping txid 2
+        confirm(&store, 3, 110, 9);
+        let chain = best_chain(vec![(100, 1), (105, 5), (107, 6), (110, 9)]);
+        assert_eq!(
+            first_use_in_index(&store, &scripthash, 0, &chain, &mut load_tx).unwrap(),
+            Some((110, txid(3)))
+        );
+
+        let unused = compute_script_hash(b"unused");
+        assert_eq!(
+            first_use_in_index(&store, &unused, 1, &chain, &mut load_tx).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_scripthash_first_use() {
+        use bitcoincash::blockdata::script::Script;
+        use bitcoincash::blockdata::transaction::TxOut;
+
+        let tx = |lock_time, script: &[u8]| Transaction {
+            version: 1,
+            lock_time,
+            input: vec![],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::from(script.to_vec()),
+            }],
+        };
+        let confirmed = compute_script_hash(b"confirmed");
+        let mempool = compute_script_hash(b"mempool");
+        let unused = compute_script_hash(b"unused");
+        let (tx1, tx2) = (tx(1, b"confirmed"), tx(2, b"confirmed"));
+        let tx3 = tx(3, b"mempool");
+        let test_query = TestQuery::with_confirmed(&[(tx2, 110), (tx1.clone(), 100)]);
+        test_query.add_mempool_tx(tx3.clone(), 300);
+        test_query.add_mempool_tx(tx(4, b"confirmed"), 300);
+        let query = test_query.query();
+
+        // the earliest confirmation, ignoring the mempool
+        assert_eq!(
+            query.scripthash_first_use(&confirmed).unwrap(),
+            Some((100, tx1.txid()))
+        );
+        // only used in the mempool
+        assert_eq!(
+            query.scripthash_first_use(&mempool).unwrap(),
+            Some((crate::mempool::MEMPOOL_HEIGHT, tx3.txid()))
+        );
+        // never used
+        assert_eq!(query.scripthash_first_use(&unused).unwrap(), None);
+    }
+
+    #[test]
+    fn test_history_cursor() {
+        let txid = Txid::from_slice(&[7; 32]).unwrap();
+        for cursor in [
+            HistoryCursor::Start,
+            HistoryCursor::After {
+                height: 700000,
+                txid,
+            },
+            HistoryCursor::After { height: -1, txid },
+        ] {
+            assert_eq!(HistoryCursor::from_hex(&cursor.to_hex()).unwrap(), cursor);
+        }
+        assert_eq!(HistoryCursor::Start.to_hex(), "");
+        assert!(HistoryCursor::from_hex("zz").is_err());
+        assert!(HistoryCursor::from_hex("00112233").is_err());
+    }
+
+    #[test]
+    fn test_history_paging() {
+        let confirmed = |i: u8, height: u32| funding(i, height, ConfirmationState::Confirmed);
+        let mempool = |i: u8| funding(i, 0, ConfirmationState::InMempool);
+        let mut status = Status {
+            confirmed: (
+                vec![
+                    confirmed(1, 10),
+                    confirmed(2, 11),
+                    confirmed(3, 11),
+                    confirmed(4, 12),
+                ],
+                vec![],
+            ),
+            mempool: (vec![mempool(5)], vec![]),
+            txn_fees: HashMap::new(),
+            block_positions: HashMap::new(),
+        };
+        let txids = |items: &[HistoryItem]| -> Vec<Txid> {
+            items.iter().map(|item| item.tx_hash).collect()
+        };
+
+        for order in [HistoryOrder::Ascending, HistoryOrder::Descending] {
+            for include_unconfirmed in [true, false] {
+                let expected = txids(&status.history_ordered(order, include_unconfirmed));
+                let mut paged = vec![];
+                let mut cursor = HistoryCursor::Start;
+                loop {
+                    let (page, next) = status.history_page(order, include_unconfirmed, &cursor, 2);
+                    assert!(page.len() <= 2);
+                    paged.extend(txids(&page));
+                    match next {
+                        Some(next) => cursor = next,
+                        None => break,
+                    }
+                }
+                assert_eq!(paged, expected);
+            }
+        }
+        // without unconfirmed history the mempool tx is left out, so the
+        // four confirmed entries fit in a single, last page
+        let (page, next) =
+            status.history_page(HistoryOrder::Ascending, false, &HistoryCursor::Start, 4);
+        assert_eq!(page.len(), 4);
+        assert!(next.is_none());
+
+        // new history between pages is neither skipped nor duplicated: a
+        // block confirms the mempool tx and another tx, and a tx enters the
+        // mempool
+        let (first, cursor) =
+            status.history_page(HistoryOrder::Ascending, true, &HistoryCursor::Start, 3);
+        let cursor = cursor.unwrap();
+        status.mempool.0 = vec![mempool(8)];
+        status.confirmed.0.push(confirmed(5, 13));
+        status.confirmed.0.push(confirmed(6, 13));
+        let (second, next) = status.history_page(HistoryOrder::Ascending, true, &cursor, 10);
+        assert!(next.is_none());
+        let mut paged = txids(&first);
+        paged.extend(txids(&second));
+        assert_eq!(paged, txids(&status.history()));
+        assert_eq!(paged.len(), 7);
+    }
+
+    #[test]
+    fn test_history_block_order() {
+        let confirmed = |i: u8, height: u32| funding(i, height, ConfirmationState::Confirmed);
+        let mut status = Status {
+            confirmed: (
+                vec![
+                    confirmed(1, 10),
+                    confirmed(2, 10),
+                    confirmed(3, 10),
+                    confirmed(4, 11),
+                    confirmed(5, 11),
+                    confirmed(6, 12),
+                ],
+                vec![],
+            ),
+            mempool: (vec![], vec![]),
+            txn_fees: HashMap::new(),
+            block_positions: HashMap::new(),
+        };
+        let order = |status: &Status| -> Vec<Txid> {
+            status.history().iter().map(|item| item.tx_hash).collect()
+        };
+        assert_eq!(
+            order(&status),
+            vec![txid(3), txid(2), txid(1), txid(5), txid(4), txid(6)]
+        );
+
+        // the order within blocks 10 and 11 differs from the tx hash order,
+        // block 12 has a single history transaction and is not looked up
+        let block_txids = |height: u32| match height {
+            10 => Ok(vec![txid(9), txid(1), txid(3), txid(2)]),
+            11 => Ok(vec![txid(9), txid(4), txid(5)]),
+            _ => panic!("unexpected lookup of block {}", height),
+        };
+        status.block_positions = block_positions(&status.history(), 10, block_txids).unwrap();
+        assert_eq!(
+            order(&status),
+            vec![txid(1), txid(3), txid(2), txid(4), txid(5), txid(6)]
+        );
+
+        // lookups are bounded, lowest heights first
+        let mut lookups = vec![];
+        status.block_positions = block_positions(&status.history(), 1, |height| {
+            lookups.push(height);
+            block_txids(height)
+        })
+        .unwrap();
+        assert_eq!(lookups, vec![10]);
+        assert_eq!(
+            order(&status),
+            vec![txid(1), txid(3), txid(2), txid(5), txid(4), txid(6)]
+        );
+    }
+
+    #[test]
+    fn test_txids_page() {
+        let txids = vec![txid(3), txid(1), txid(2)];
+        assert_eq!(
+            txids_page(txids.clone(), 10),
+            (vec![txid(1), txid(2), txid(3)], false)
+        );
+        assert_eq!(
+            txids_page(txids.clone(), 3),
+            (vec![txid(1), txid(2), txid(3)], false)
+        );
+        assert_eq!(txids_page(txids, 2), (vec![txid(1), txid(2)], true));
+        assert_eq!(txids_page(vec![], 2), (vec![], false));
+    }
+
+    #[test]
+    fn test_status_hash() {
+        let mut status = Status {
+            confirmed: (vec![funding(1, 100, ConfirmationState::Confirmed)], vec![]),
+            mempool: (vec![], vec![]),
+            txn_fees: HashMap::new(),
+            block_positions: HashMap::new(),
+        };
+        // sha256 of the "tx_hash:height:" concatenation, as Electrum does
+        let expected = |parts: &[(u8, i32)]| -> FullHash {
+            let data: String = parts
+                .iter()
+                .map(|(i, height)| format!("{}:{}:", txid(*i).to_hex(), height))
+                .collect();
+            Sha256::digest(data.as_bytes()).into()
+        };
+        assert_eq!(status.hash(), Some(expected(&[(1, 100)])));
+
+        status
+            .mempool
+            .0
+            .push(funding(2, 0, ConfirmationState::InMempool));
+        assert_eq!(status.hash(), Some(expected(&[(1, 100), (2, 0)])));
+
+        status.mempool.0.clear();
+        assert_eq!(status.hash(), Some(expected(&[(1, 100)])));
+
+        status.confirmed.0.clear();
+        assert_eq!(status.hash(), None);
+    }
+
+    #[test]
+    fn test_self_transfer_history() {
+        let history_of = |funding_state, spending_state| {
+            let (funding_height, spending_height) = (
+                if funding_state == ConfirmationState::Confirmed {
+                    100
+                } else {
+                    0
+                },
+                if spending_state == ConfirmationState::Confirmed {
+                    100
+                } else {
+                    0
+                },
+            );
+            let status = Status {
+                confirmed: (vec![], vec![]),
+                mempool: (
+                    vec![funding(2, funding_height, funding_state)],
+                    vec![spending(2, 1, spending_height, spending_state)],
+                ),
+                txn_fees: HashMap::new(),
+                block_positions: HashMap::new(),
+            };
+            let history = status.history();
+            assert_eq!(history.len(), 1);
+            assert_eq!(history[0].tx_hash, txid(2));
+            history[0].height
+        };
+        use ConfirmationState::*;
+        // A self-transfer seen in the same state on both sides
+        assert_eq!(history_of(Confirmed, Confirmed), 100);
+        assert_eq!(history_of(UnconfirmedParent, UnconfirmedParent), -1);
+        assert_eq!(history_of(InMempool, InMempool), 0);
+        // Mixed states resolve the same way, regardless of the side
+        for (a, b, height) in [
+            (Confirmed, UnconfirmedParent, 100),
+            (Confirmed, InMempool, 100),
+            (UnconfirmedParent, InMempool, -1),
+        ] {
+            assert_eq!(history_of(a, b), height);
+            assert_eq!(history_of(b, a), height);
+        }
+    }
+
+    #[test]
+    fn test_history_item_json() {
+        let mut item = HistoryItem {
+            height: 100,
+            tx_hash: Txid::from_slice(&[1; 32]).unwrap(),
+            fee: None,
+            size: None,
+        };
+        assert_eq!(
+            item.to_json(),
+            json!({"height": 100, "tx_hash": item.tx_hash.to_hex()})
+        );
+
+        item.size = Some(225);
+        item.fee = Some(226);
+        assert_eq!(
+            item.to_json(),
+            json!({"height": 100, "tx_hash": item.tx_hash.to_hex(), "size": 225, "fee": 226})
+        );
+    }
+}
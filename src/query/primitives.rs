@@ -7,6 +7,9 @@ pub struct FundingOutput {
     pub height: u32,
     pub value: u64,
     pub state: ConfirmationState,
+    /// Output carries CashTokens (only set once marked by
+    /// `Query::mark_token_outputs`)
+    pub has_token: bool,
 }
 
 pub struct SpendingInput {
@@ -15,4 +18,6 @@ pub struct SpendingInput {
     pub funding_output: OutPoint,
     pub value: u64,
     pub state: ConfirmationState,
+    /// The spent output carries CashTokens
+    pub has_token: bool,
 }
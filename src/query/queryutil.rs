@@ -8,16 +8,17 @@ use crate::store::{ReadStore, Row};
 use crate::timeout::TimeoutTrigger;
 use crate::util::{hash_prefix, HashPrefix};
 use bitcoincash::blockdata::transaction::OutPoint;
-use bitcoincash::blockdata::transaction::Transaction;
+use bitcoincash::blockdata::transaction::{Transaction, TxOut};
 use bitcoincash::consensus::encode::deserialize;
 use bitcoincash::hash_types::Txid;
 use genawaiter::{sync::gen, yield_};
+use std::collections::HashMap;
 
 // TODO: the functions below can be part of ReadStore.
-pub fn txrow_by_txid(store: &dyn ReadStore, txid: &Txid) -> Option<TxRow> {
+pub fn txrow_by_txid(store: &dyn ReadStore, txid: &Txid) -> Result<Option<TxRow>> {
     let key = TxRow::filter_full(txid);
     let value = store.get(&key)?;
-    Some(TxRow::from_row(&Row { key, value }))
+    Ok(value.map(|value| TxRow::from_row(&Row { key, value })))
 }
 
 pub fn txrows_by_prefix(store: &dyn ReadStore, txid_prefix: HashPrefix) -> Vec<TxRow> {
@@ -44,6 +45,50 @@ pub fn txids_by_funding_output(store: &dyn ReadStore, prevout: &OutPoint) -> Vec
         .collect()
 }
 
+/// Batched `txids_by_funding_output`. Spenders of outputs of the same
+/// transaction are looked up with a single scan.
+pub fn txids_by_funding_outputs(
+    store: &dyn ReadStore,
+    prevouts: &[OutPoint],
+) -> Vec<Vec<HashPrefix>> {
+    let mut outputs_per_tx: HashMap<HashPrefix, usize> = HashMap::new();
+    for prevout in prevouts {
+        *outputs_per_tx
+            .entry(hash_prefix(&prevout.txid[..]))
+            .or_default() += 1;
+    }
+    // Scanning all spenders of a transaction only pays off when more than one
+    // of its outputs is looked up.
+    let spenders_by_tx: HashMap<HashPrefix, HashMap<u32, Vec<HashPrefix>>> = outputs_per_tx
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(prev_hash_prefix, _)| {
+            let mut spenders: HashMap<u32, Vec<HashPrefix>> = HashMap::new();
+            for row in store
+                .scan(&TxInRow::filter_prev_txid(prev_hash_prefix))
+                .iter()
+                .map(TxInRow::from_row)
+            {
+                spenders
+                    .entry(row.get_prev_index())
+                    .or_default()
+                    .push(row.txid_prefix);
+            }
+            (prev_hash_prefix, spenders)
+        })
+        .collect();
+
+    prevouts
+        .iter()
+        .map(
+            |prevout| match spenders_by_tx.get(&hash_prefix(&prevout.txid[..])) {
+                Some(spenders) => spenders.get(&prevout.vout).cloned().unwrap_or_default(),
+                None => txids_by_funding_output(store, prevout),
+            },
+        )
+        .collect()
+}
+
 /// Mempool parameter is optional if it's known that the transaction is
 /// confired.
 pub fn txoutrow_to_fundingoutput(
@@ -61,6 +106,7 @@ pub fn txoutrow_to_fundingoutput(
         height: txrow.height,
         value: txoutrow.get_output_value(),
         state: confirmation_state(mempool, &txid, txrow.height),
+        has_token: false,
     })
 }
 
@@ -96,6 +142,15 @@ fn txn_has_output(txn: &Transaction, n: u32, scripthash_prefix: HashPrefix) -> b
     hash_prefix(&hash) == scripthash_prefix
 }
 
+/// Token-bearing outputs have their CashTokens data prefixed to the locking
+/// script.
+const TOKEN_PREFIX: u8 = 0xef;
+
+/// Output carries CashTokens (fungible tokens or an NFT)
+pub fn has_token_data(txout: &TxOut) -> bool {
+    txout.script_pubkey.as_bytes().first() == Some(&TOKEN_PREFIX)
+}
+
 fn confirmation_state(mempool: Option<&Tracker>, txid: &Txid, height: u32) -> ConfirmationState {
     // If mempool parameter is not passed, this implies that it is known
     // that the transaction is confirmed.
@@ -106,15 +161,40 @@ fn confirmation_state(mempool: Option<&Tracker>, txid: &Txid, height: u32) -> Co
     mempool.tx_confirmation_state(txid, Some(height))
 }
 
-pub fn find_spending_input(
+/// Find the inputs spending `fundings`, if any.
+pub fn find_spending_inputs(
+    store: &dyn ReadStore,
+    fundings: &[&FundingOutput],
+    mempool: Option<&Tracker>,
+    txquery: &TxQuery,
+    timeout: &TimeoutTrigger,
+) -> Result<Vec<SpendingInput>> {
+    let prevouts: Vec<OutPoint> = fundings.iter().map(|f| f.funding_output).collect();
+    let mut spending = vec![];
+    for (funding, spending_txns) in fundings
+        .iter()
+        .zip(txids_by_funding_outputs(store, &prevouts))
+    {
+        timeout.check()?;
+        if let Some(spent) =
+            resolve_spending_input(store, funding, spending_txns, mempool, txquery, timeout)?
+        {
+            spending.push(spent);
+        }
+    }
+    Ok(spending)
+}
+
+/// Find the input spending `funding` among `spending_txns`, the txid
+/// prefixes of the transactions indexed as spending it.
+pub fn resolve_spending_input(
     store: &dyn ReadStore,
     funding: &FundingOutput,
+    spending_txns: Vec<HashPrefix>,
     mempool: Option<&Tracker>,
     txquery: &TxQuery,
     timeout: &TimeoutTrigger,
 ) -> Result<Option<SpendingInput>> {
-    let spending_txns = txids_by_funding_output(store, &funding.funding_output);
-
     if spending_txns.len() == 1 {
         let spender_txid = &spending_txns[0];
         let txrows = txrows_by_prefix(store, *spender_txid);
@@ -127,6 +207,7 @@ pub fn find_spending_input(
                 funding_output: funding.funding_output,
                 value: funding.value,
                 state: confirmation_state(mempool, &txid, txrows[0].height),
+                has_token: funding.has_token,
             }));
         }
     }
@@ -149,6 +230,7 @@ pub fn find_spending_input(
                 funding_output: funding.funding_output,
                 value: funding.value,
                 state,
+                has_token: funding.has_token,
             }));
         }
         timeout.check()?;
@@ -213,3 +295,97 @@ pub fn load_txns_by_prefix<'a>(
     })
     .into_iter()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::MemStore;
+    use crate::store::WriteStore;
+    use bitcoincash::blockdata::script::Script;
+    use bitcoincash::blockdata::transaction::TxIn;
+    use bitcoincash::hashes::Hash;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct CountingStore {
+        store: MemStore,
+        scans: AtomicUsize,
+    }
+
+    impl ReadStore for CountingStore {
+        fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            self.store.get(key)
+        }
+        fn scan(&self, prefix: &[u8]) -> Vec<Row> {
+            self.scans.fetch_add(1, Ordering::SeqCst);
+            self.store.scan(prefix)
+        }
+    }
+
+    fn txid(n: u32) -> Txid {
+        let mut hash = [0u8; 32];
+        hash[..4].copy_from_slice(&n.to_le_bytes());
+        Txid::from_slice(&hash).unwrap()
+    }
+
+    fn spend(spender: &Txid, prevout: OutPoint) -> Row {
+        let input = TxIn {
+            previous_output: prevout,
+            script_sig: Script::new(),
+            sequence: 0xffffffff,
+            witness: vec![],
+        };
+        TxInRow::new(spender, &input).to_row()
+    }
+
+    #[test]
+    fn test_txids_by_funding_outputs() {
+        // 1000 outputs funding a scripthash: 10 outputs in each of 100
+        // transactions. Every other output is spent, some more than once
+        // (double spends of unconfirmed transactions).
+        let prevouts: Vec<OutPoint> = (0..100)
+            .flat_map(|tx| (0..10).map(move |vout| OutPoint::new(txid(tx), vout * 2)))
+            .collect();
+        let store = CountingStore {
+            store: MemStore::default(),
+            scans: AtomicUsize::new(0),
+        };
+        store
+            .store
+            .write(
+                prevouts.iter().enumerate().flat_map(|(i, prevout)| {
+                    let mut rows = vec![];
+                    if i % 2 == 0 {
+                        rows.push(spend(&txid(1000 + i as u32), *prevout));
+                    }
+                    if i % 7 == 0 {
+                        rows.push(spend(&txid(5000 + i as u32), *prevout));
+                    }
+                    // spent output not funding the scripthash
+                    let other = OutPoint::new(prevout.txid, prevout.vout + 1);
+                    rows.push(spend(&txid(9000 + i as u32), other));
+                    rows
+                }),
+                false,
+            )
+            .unwrap();
+
+        let expected: Vec<Vec<HashPrefix>> = prevouts
+            .iter()
+            .map(|prevout| txids_by_funding_output(&store, prevout))
+            .collect();
+        assert_eq!(store.scans.load(Ordering::SeqCst), prevouts.len());
+
+        store.scans.store(0, Ordering::SeqCst);
+        let batched = txids_by_funding_outputs(&store, &prevouts);
+        assert_eq!(store.scans.load(Ordering::SeqCst), 100);
+        assert_eq!(batched, expected);
+        assert_eq!(batched.iter().filter(|s| !s.is_empty()).count(), 571);
+        assert_eq!(batched.iter().filter(|s| s.len() == 2).count(), 72);
+
+        // Single outputs per transaction are looked up directly.
+        store.scans.store(0, Ordering::SeqCst);
+        let single = txids_by_funding_outputs(&store, &prevouts[..1]);
+        assert_eq!(store.scans.load(Ordering::SeqCst), 1);
+        assert_eq!(single, expected[..1].to_vec());
+    }
+}
@@ -3,10 +3,11 @@ use crate::daemon::Daemon;
 use crate::def::COIN;
 use crate::errors::*;
 use crate::mempool::ConfirmationState;
-use crate::mempool::Tracker;
+use crate::mempool::{read_tracker, Tracker};
 use crate::query::header::HeaderQuery;
+use crate::timeout::TimeoutTrigger;
 use bitcoincash::blockdata::script::Script;
-use bitcoincash::blockdata::transaction::Transaction;
+use bitcoincash::blockdata::transaction::{Transaction, TxOut};
 use bitcoincash::consensus::encode::{deserialize, serialize};
 use bitcoincash::hash_types::{BlockHash, Txid};
 use bitcoincash::hashes::hex::ToHex;
@@ -15,8 +16,41 @@ use bitcoincash::util::address::Payload::{PubkeyHash, ScriptHash};
 use bitcoincash::util::address::{Address, AddressType};
 use rust_decimal::prelude::*;
 use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 
+/// Load a serialized transaction with `fetch`, first without its blockhash.
+/// The blockhash is only looked up if that fails, as bitcoind needs it for
+/// confirmed transactions when it runs without `-txindex`. Once that's seen,
+/// `needs_blockhash` is set and the blockhash is looked up first, sparing
+/// bitcoind the failing request.
+fn fetch_raw_tx(
+    fetch: impl Fn(Option<&BlockHash>) -> Result<Vec<u8>>,
+    lookup_blockhash: impl FnOnce() -> Option<BlockHash>,
+    needs_blockhash: &AtomicBool,
+) -> Result<Vec<u8>> {
+    if needs_blockhash.load(Ordering::Relaxed) {
+        return fetch(lookup_blockhash().as_ref());
+    }
+    let err = match fetch(None) {
+        Ok(raw) => return Ok(raw),
+        // don't make a slow bitcoind wait for another request
+        Err(e) if matches!(e.kind(), ErrorKind::RpcError(RpcErrorCode::Timeout, _)) => {
+            return Err(e)
+        }
+        Err(e) => e,
+    };
+    match lookup_blockhash() {
+        Some(blockhash) => {
+            let raw = fetch(Some(&blockhash))?;
+            needs_blockhash.store(true, Ordering::Relaxed);
+            Ok(raw)
+        }
+        None => Err(err),
+    }
+}
+
 ///  String returned is intended to be the same as produced by bitcoind
 ///  GetTxnOutputType
 fn get_address_type(script: &Script, network: Network) -> Option<&str> {
@@ -95,18 +129,76 @@ fn value_from_amount(amount: u64) -> Value {
     json!(satoshis.checked_div(Decimal::new(COIN as i64, 0)).unwrap())
 }
 
+fn script_pubkey_json(script: &Script, network: Network) -> Value {
+    json!({
+        "asm": script.asm(),
+        "hex": script.to_hex(),
+        "type": get_address_type(script, network).unwrap_or_default(),
+        "addresses": get_addresses(script, network),
+    })
+}
+
+/// Fee paid by `tx`, given the outputs it spends. Coinbase transactions pay
+/// no fee.
+fn tx_fee(tx: &Transaction, prevouts: &[TxOut]) -> Option<u64> {
+    if tx.is_coin_base() || prevouts.len() != tx.input.len() {
+        return None;
+    }
+    let spent: u64 = prevouts.iter().map(|o| o.value).sum();
+    let created: u64 = tx.output.iter().map(|o| o.value).sum();
+    spent.checked_sub(created)
+}
+
+/// Number of transactions whose outputs `tx` spends, which have to be loaded
+/// to get its fee (0 for coinbase transactions).
+pub fn prevout_tx_count(tx: &Transaction) -> usize {
+    if tx.is_coin_base() {
+        return 0;
+    }
+    let prev_txids: HashSet<&Txid> = tx.input.iter().map(|i| &i.previous_output.txid).collect();
+    prev_txids.len()
+}
+
+fn vin_json(tx: &Transaction, prevouts: &[TxOut], network: Network) -> Vec<Value> {
+    tx.input
+        .iter()
+        .enumerate()
+        .map(|(i, txin)| {
+            let mut vin = json!({
+                // bitcoind adds scriptSig hex as 'coinbase' when the transaction is a coinbase
+                "coinbase": if tx.is_coin_base() { Some(txin.script_sig.to_hex()) } else { None },
+                "sequence": txin.sequence,
+                "txid": txin.previous_output.txid.to_hex(),
+                "vout": txin.previous_output.vout,
+                "scriptSig": {
+                    "asm": txin.script_sig.asm(),
+                    "hex": txin.script_sig.to_hex(),
+                },
+            });
+            if let Some(prevout) = prevouts.get(i) {
+                vin["value_satoshi"] = json!(prevout.value);
+                vin["value_coin"] = value_from_amount(prevout.value);
+                vin["scriptPubKey"] = script_pubkey_json(&prevout.script_pubkey, network);
+            }
+            vin
+        })
+        .collect()
+}
+
 pub struct TxQuery {
-    tx_cache: TransactionCache,
+    tx_cache: Arc<TransactionCache>,
     daemon: Daemon,
     mempool: Arc<RwLock<Tracker>>,
     header: Arc<HeaderQuery>,
     duration: Arc<prometheus::HistogramVec>,
     network: Network,
+    // bitcoind runs without -txindex, see `fetch_raw_tx`
+    needs_blockhash: AtomicBool,
 }
 
 impl TxQuery {
     pub fn new(
-        tx_cache: TransactionCache,
+        tx_cache: Arc<TransactionCache>,
         daemon: Daemon,
         mempool: Arc<RwLock<Tracker>>,
         header: Arc<HeaderQuery>,
@@ -120,9 +212,19 @@ impl TxQuery {
             header,
             duration,
             network,
+            needs_blockhash: AtomicBool::new(false),
         }
     }
 
+    pub fn cache_usage(&self) -> (u64, u64) {
+        self.tx_cache.usage()
+    }
+
+    /// Addresses paid to by `script`, in cashaddr format.
+    pub fn addresses(&self, script: &Script) -> Vec<String> {
+        get_addresses(script, self.network)
+    }
+
     /// Get a transaction by Txid.
     pub fn get(
         &self,
@@ -144,6 +246,29 @@ impl TxQuery {
         self.load_txn_from_bitcoind(txid, hash.as_ref())
     }
 
+    /// Get a serialized transaction, confirmed or not.
+    ///
+    /// This is faster than `get` for callers that don't need to parse the
+    /// transaction, as the blockhash is only looked up if bitcoind needs it
+    /// (see `fetch_raw_tx`).
+    pub fn get_raw(&self, txid: &Txid) -> Result<Vec<u8>> {
+        let _timer = self
+            .duration
+            .with_label_values(&["load_raw_txn"])
+            .start_timer();
+        if let Some(raw) = self.tx_cache.get_raw(txid) {
+            return Ok(raw);
+        }
+        fetch_raw_tx(
+            |blockhash| self.load_raw_txn_from_bitcoind(txid, blockhash),
+            || match self.header.get_by_txid(txid, None) {
+                Ok(header) => header.map(|h| *h.hash()),
+                Err(_) => None,
+            },
+            &self.needs_blockhash,
+        )
+    }
+
     /// Get an transaction known to be unconfirmed.
     ///
     /// This is slightly faster that `get` as it avoids blockhash lookup. May
@@ -156,7 +281,71 @@ impl TxQuery {
         }
     }
 
-    pub fn get_verbose(&self, txid: &Txid) -> Result<Value> {
+    /// Get the outputs spent by `tx`, in input order. At most
+    /// `max_prevout_txs` transactions are loaded.
+    fn get_prevouts(
+        &self,
+        tx: &Transaction,
+        max_prevout_txs: usize,
+        timeout: &TimeoutTrigger,
+    ) -> Result<Vec<TxOut>> {
+        if tx.is_coin_base() {
+            return Ok(vec![]);
+        }
+        let mut prev_txs: HashMap<Txid, Transaction> = HashMap::new();
+        for txin in &tx.input {
+            let prev_txid = txin.previous_output.txid;
+            if prev_txs.contains_key(&prev_txid) {
+                continue;
+            }
+            if prev_txs.len() == max_prevout_txs {
+                bail!(rpc_invalid_request(format!(
+                    "transaction spends outputs of too many transactions (max {})",
+                    max_prevout_txs
+                )));
+            }
+            timeout.check()?;
+            prev_txs.insert(prev_txid, self.get(&prev_txid, None, None)?);
+        }
+        tx.input
+            .iter()
+            .map(|txin| {
+                let outpoint = &txin.previous_output;
+                prev_txs[&outpoint.txid]
+                    .output
+                    .get(outpoint.vout as usize)
+                    .cloned()
+                    .chain_err(|| format!("missing output {}", outpoint))
+            })
+            .collect()
+    }
+
+    /// Get the fee paid by `tx`, or None for coinbase transactions and
+    /// transactions spending outputs of more than `max_prevout_txs`
+    /// transactions.
+    pub fn get_fee(
+        &self,
+        tx: &Transaction,
+        max_prevout_txs: usize,
+        timeout: &TimeoutTrigger,
+    ) -> Result<Option<u64>> {
+        if tx.is_coin_base() || prevout_tx_count(tx) > max_prevout_txs {
+            return Ok(None);
+        }
+        let prevouts = self.get_prevouts(tx, max_prevout_txs, timeout)?;
+        Ok(tx_fee(tx, &prevouts))
+    }
+
+    /// Get transaction as JSON, in the format of bitcoind's
+    /// `getrawtransaction`. When `prevouts` is set, the value and script of
+    /// the outputs spent are added to each input, along with the fee paid.
+    pub fn get_verbose(
+        &self,
+        txid: &Txid,
+        prevouts: bool,
+        max_prevout_txs: usize,
+        timeout: &TimeoutTrigger,
+    ) -> Result<Value> {
         let header = self.header.get_by_txid(txid, None).unwrap_or_default();
         let blocktime = header.as_ref().map(|header| header.header().time);
         let height = header.as_ref().map(|header| header.height());
@@ -174,7 +363,12 @@ impl TxQuery {
         };
         let tx = self.get(txid, blockhash.as_ref(), None)?;
         let tx_serialized = serialize(&tx);
-        Ok(json!({
+        let prevouts = if prevouts {
+            Some(self.get_prevouts(&tx, max_prevout_txs, timeout)?)
+        } else {
+            None
+        };
+        let mut result = json!({
             "blockhash": blockhash_hex,
             "blocktime": blocktime,
             "height": height,
@@ -186,29 +380,20 @@ impl TxQuery {
             "locktime": tx.lock_time,
             "time": blocktime,
             "version": tx.version,
-            "vin": tx.input.iter().map(|txin| json!({
-                // bitcoind adds scriptSig hex as 'coinbase' when the transaction is a coinbase
-                "coinbase": if tx.is_coin_base() { Some(txin.script_sig.to_hex()) } else { None },
-                "sequence": txin.sequence,
-                "txid": txin.previous_output.txid.to_hex(),
-                "vout": txin.previous_output.vout,
-                "scriptSig": {
-                    "asm": txin.script_sig.asm(),
-                    "hex": txin.script_sig.to_hex(),
-                },
-            })).collect::<Vec<Value>>(),
+            "vin": vin_json(&tx, prevouts.as_deref().unwrap_or_default(), self.network),
             "vout": tx.output.iter().enumerate().map(|(n, txout)| json!({
                     "value_satoshi": txout.value,
                     "value_coin": value_from_amount(txout.value),
                     "n": n,
-                    "scriptPubKey": {
-                        "asm": txout.script_pubkey.asm(),
-                        "hex": txout.script_pubkey.to_hex(),
-                        "type": get_address_type(&txout.script_pubkey, self.network).unwrap_or_default(),
-                        "addresses": get_addresses(&txout.script_pubkey, self.network),
-                    },
+                    "scriptPubKey": script_pubkey_json(&txout.script_pubkey, self.network),
                     })).collect::<Vec<Value>>(),
-        }))
+        });
+        if let Some(prevouts) = prevouts {
+            let fee = tx_fee(&tx, &prevouts);
+            result["fee_satoshi"] = json!(fee);
+            result["fee_coin"] = fee.map_or(Value::Null, value_from_amount);
+        }
+        Ok(result)
     }
 
     fn load_txn_from_bitcoind(
@@ -216,33 +401,207 @@ impl TxQuery {
         txid: &Txid,
         blockhash: Option<&BlockHash>,
     ) -> Result<Transaction> {
-        let value: Value = self
-            .daemon
-            .gettransaction_raw(txid, blockhash, /*verbose*/ false)?;
-        let value_hex: &str = value.as_str().chain_err(|| "non-string tx")?;
-        let serialized_tx = hex::decode(&value_hex).chain_err(|| "non-hex tx")?;
+        let serialized_tx = self.fetch_raw_txn_from_bitcoind(txid, blockhash)?;
         let tx = deserialize(&serialized_tx).chain_err(|| "failed to parse serialized tx")?;
         self.tx_cache.put(txid, serialized_tx);
         Ok(tx)
     }
 
+    /// Same as `load_txn_from_bitcoind`, without parsing the transaction.
+    fn load_raw_txn_from_bitcoind(
+        &self,
+        txid: &Txid,
+        blockhash: Option<&BlockHash>,
+    ) -> Result<Vec<u8>> {
+        let serialized_tx = self.fetch_raw_txn_from_bitcoind(txid, blockhash)?;
+        self.tx_cache.put(txid, serialized_tx.clone());
+        Ok(serialized_tx)
+    }
+
+    fn fetch_raw_txn_from_bitcoind(
+        &self,
+        txid: &Txid,
+        blockhash: Option<&BlockHash>,
+    ) -> Result<Vec<u8>> {
+        let value: Value = self
+            .daemon
+            .gettransaction_raw(txid, blockhash, /*verbose*/ false)
+            .map_err(rpc_daemon_timeout)?;
+        let value_hex: &str = value.as_str().chain_err(|| "non-string tx")?;
+        hex::decode(&value_hex).chain_err(|| "non-hex tx")
+    }
+
     /// Returns the height the transaction is confirmed at.
     ///
     /// If the transaction is in mempool, it return -1 if it has unconfirmed
     /// parents, or 0 if not.
     ///
     /// Returns None if transaction does not exist.
-    pub fn get_confirmation_height(&self, txid: &Txid) -> Option<i64> {
+    pub fn get_confirmation_height(&self, txid: &Txid) -> Result<Option<i64>> {
         {
-            let mempool = self.mempool.read().unwrap();
+            let mempool = read_tracker(&self.mempool);
             match mempool.tx_confirmation_state(txid, None) {
-                ConfirmationState::InMempool => return Some(0),
-                ConfirmationState::UnconfirmedParent => return Some(-1),
+                ConfirmationState::InMempool => return Ok(Some(0)),
+                ConfirmationState::UnconfirmedParent => return Ok(Some(-1)),
                 _ => (),
             };
         }
-        self.header
-            .get_confirmed_height_for_tx(txid)
-            .map(|height| height as i64)
+        Ok(self
+            .header
+            .get_confirmed_height_for_tx(txid)?
+            .map(|height| height as i64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincash::blockdata::script::Builder;
+    use bitcoincash::blockdata::transaction::{OutPoint, TxIn};
+    use bitcoincash::hashes::Hash;
+
+    fn txout(value: u64) -> TxOut {
+        TxOut {
+            value,
+            script_pubkey: Builder::new().push_int(value as i64).into_script(),
+        }
+    }
+
+    fn tx(inputs: Vec<OutPoint>, outputs: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: inputs
+                .into_iter()
+                .map(|previous_output| TxIn {
+                    previous_output,
+                    script_sig: Script::new(),
+                    sequence: 0xffff_ffff,
+                    witness: vec![],
+                })
+                .collect(),
+            output: outputs,
+        }
+    }
+
+    #[test]
+    fn test_fetch_raw_tx() {
+        use std::cell::Cell;
+
+        let blockhash = BlockHash::from_slice(&[7; 32]).unwrap();
+        let lookups = Cell::new(0);
+        let lookup = || {
+            lookups.set(lookups.get() + 1);
+            Some(blockhash)
+        };
+        let needs_blockhash = AtomicBool::new(false);
+
+        // Found without the blockhash (mempool, or bitcoind with -txindex):
+        // the blockhash is not looked up.
+        let raw = fetch_raw_tx(|_| Ok(vec![1, 2, 3]), lookup, &needs_blockhash).unwrap();
+        assert_eq!(raw, vec![1, 2, 3]);
+        assert_eq!(lookups.get(), 0);
+        assert!(!needs_blockhash.load(Ordering::Relaxed));
+
+        // Unknown transaction: the first error is returned.
+        let err = fetch_raw_tx(|_| Err("not found".into()), || None, &needs_blockhash).unwrap_err();
+        assert_eq!(err.to_string(), "not found");
+        assert!(!needs_blockhash.load(Ordering::Relaxed));
+
+        // Confirmed, bitcoind without -txindex: retried with the blockhash.
+        let fetches = Cell::new(0);
+        let fetch = |hash: Option<&BlockHash>| {
+            fetches.set(fetches.get() + 1);
+            match hash {
+                Some(hash) if *hash == blockhash => Ok(vec![4, 5]),
+                _ => Err("No such mempool transaction".into()),
+            }
+        };
+        let without_txindex = AtomicBool::new(false);
+        assert_eq!(
+            fetch_raw_tx(fetch, lookup, &without_txindex).unwrap(),
+            vec![4, 5]
+        );
+        assert_eq!((fetches.get(), lookups.get()), (2, 1));
+        // which is remembered, sparing the failing request
+        assert!(without_txindex.load(Ordering::Relaxed));
+        assert_eq!(
+            fetch_raw_tx(fetch, lookup, &without_txindex).unwrap(),
+            vec![4, 5]
+        );
+        assert_eq!((fetches.get(), lookups.get()), (3, 2));
+        // mempool transactions are still fetched without the blockhash
+        let mempool_fetch = |hash: Option<&BlockHash>| {
+            assert!(hash.is_none());
+            Ok(vec![6])
+        };
+        assert_eq!(
+            fetch_raw_tx(mempool_fetch, || None, &without_txindex).unwrap(),
+            vec![6]
+        );
+
+        // The codes of bitcoind errors are kept, as transaction_get returns them
+        let code = |result: Result<Vec<u8>>| match result.unwrap_err().kind() {
+            ErrorKind::RpcError(code, _) => *code as i32,
+            e => panic!("unexpected error {}", e),
+        };
+        let unknown = |_: Option<&BlockHash>| -> Result<Vec<u8>> {
+            Err(rpc_invalid_params("No such mempool or blockchain transaction".to_string()).into())
+        };
+        assert_eq!(
+            code(fetch_raw_tx(unknown, || None, &needs_blockhash)),
+            -32602
+        );
+        let pruned = |hash: Option<&BlockHash>| -> Result<Vec<u8>> {
+            match hash {
+                Some(_) => bail!(ErrorKind::RpcError(
+                    RpcErrorCode::Pruned,
+                    "Block not available".to_string()
+                )),
+                None => unknown(None),
+            }
+        };
+        assert_eq!(code(fetch_raw_tx(pruned, lookup, &needs_blockhash)), -32003);
+        assert!(!needs_blockhash.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_prevouts_and_fee() {
+        let prev_txid = Txid::from_slice(&[1; 32]).unwrap();
+        let tx = tx(
+            vec![OutPoint::new(prev_txid, 0), OutPoint::new(prev_txid, 3)],
+            vec![txout(1500)],
+        );
+        let prevouts = vec![txout(1000), txout(600)];
+
+        assert_eq!(tx_fee(&tx, &prevouts), Some(100));
+        assert_eq!(tx_fee(&tx, &prevouts[..1]), None);
+        assert_eq!(prevout_tx_count(&tx), 1);
+
+        let vin = vin_json(&tx, &prevouts, Network::Bitcoin);
+        assert_eq!(vin[0]["value_satoshi"], json!(1000));
+        assert_eq!(vin[1]["value_satoshi"], json!(600));
+        assert_eq!(vin[1]["vout"], json!(3));
+        assert_eq!(
+            vin[1]["scriptPubKey"]["hex"],
+            json!(prevouts[1].script_pubkey.to_hex())
+        );
+        assert_eq!(vin[0]["coinbase"], Value::Null);
+
+        // Without prevouts, inputs have no value.
+        let vin = vin_json(&tx, &[], Network::Bitcoin);
+        assert!(vin[0].get("value_satoshi").is_none());
+    }
+
+    #[test]
+    fn test_coinbase_fee() {
+        let coinbase = tx(vec![OutPoint::null()], vec![txout(5_000_000_000)]);
+        assert!(coinbase.is_coin_base());
+        assert_eq!(tx_fee(&coinbase, &[]), None);
+        assert_eq!(prevout_tx_count(&coinbase), 0);
+
+        let vin = vin_json(&coinbase, &[], Network::Bitcoin);
+        assert_eq!(vin[0]["coinbase"], json!(""));
+        assert!(vin[0].get("value_satoshi").is_none());
     }
 }
@@ -2,7 +2,7 @@ use crate::errors::*;
 use crate::mempool::Tracker;
 use crate::query::primitives::{FundingOutput, SpendingInput};
 use crate::query::queryutil::{
-    find_spending_input, get_tx_spending_prevout, txoutrow_to_fundingoutput,
+    find_spending_inputs, get_tx_spending_prevout, txoutrow_to_fundingoutput,
     txoutrows_by_script_hash,
 };
 use crate::query::tx::TxQuery;
@@ -66,20 +66,17 @@ impl UnconfirmedQuery {
             .duration
             .with_label_values(&["mempool_status_spending"])
             .start_timer();
-        let mut spending = vec![];
-
-        for funding_output in unconfirmed_funding.iter().chain(confirmed_funding.iter()) {
-            timeout.check()?;
-            if let Some(spent) = find_spending_input(
-                tracker.index(),
-                funding_output,
-                Some(tracker),
-                &self.txquery,
-                timeout,
-            )? {
-                spending.push(spent);
-            }
-        }
+        let funding: Vec<&FundingOutput> = unconfirmed_funding
+            .iter()
+            .chain(confirmed_funding.iter())
+            .collect();
+        let spending = find_spending_inputs(
+            tracker.index(),
+            &funding,
+            Some(tracker),
+            &self.txquery,
+            timeout,
+        )?;
         timer.observe_duration();
         Ok(spending)
     }
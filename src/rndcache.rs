@@ -1,18 +1,57 @@
 use indexmap::IndexMap;
 use prometheus::{IntCounterVec, IntGauge};
 use rand::prelude::*;
+use std::borrow::Borrow;
 use std::hash::Hash;
 
+/// Cache usage, as fractions of its capacity. When an insertion would take
+/// the usage above `high`, random entries are evicted until the usage
+/// (including the new entry) is at most `low`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watermarks {
+    pub high: f32,
+    pub low: f32,
+}
+
+impl Watermarks {
+    pub fn new(high: f32, low: f32) -> Result<Watermarks, String> {
+        if !(0.0 < low && low <= high && high <= 1.0) {
+            return Err(format!(
+                "invalid cache watermarks (high {}, low {}): expected 0 < low <= high <= 1",
+                high, low
+            ));
+        }
+        Ok(Watermarks { high, low })
+    }
+
+    fn bytes(fraction: f32, capacity: u64) -> u64 {
+        (capacity as f64 * fraction as f64) as u64
+    }
+}
+
+impl Default for Watermarks {
+    /// Evict only as much as needed for each insertion
+    fn default() -> Watermarks {
+        Watermarks {
+            high: 1.0,
+            low: 1.0,
+        }
+    }
+}
+
 pub struct RndCache<K: Eq + Hash, V> {
     map: IndexMap<K, (u32, V)>,
     bytes_capacity: u64,
+    bytes_high_watermark: u64,
+    bytes_low_watermark: u64,
     bytes_used: u64,
     rng: StdRng,
     entry_overhead: u32,
 
     /// How many hits or misses
     metric_lookups: IntCounterVec,
-    /// How many inserts and evictions
+    /// How many inserts, evictions (and eviction passes) and entries
+    /// rejected for being too large
     metric_churn: IntCounterVec,
     /// How much cache is in use (in bytes)
     metric_size: IntGauge,
@@ -23,6 +62,7 @@ pub struct RndCache<K: Eq + Hash, V> {
 impl<K: Eq + Hash, V> RndCache<K, V> {
     pub fn new(
         bytes_capacity: u64,
+        watermarks: Watermarks,
         metric_lookups: IntCounterVec,
         metric_churn: IntCounterVec,
         metric_size: IntGauge,
@@ -41,6 +81,8 @@ impl<K: Eq + Hash, V> RndCache<K, V> {
         RndCache {
             map: IndexMap::new(),
             bytes_capacity,
+            bytes_high_watermark: Watermarks::bytes(watermarks.high, bytes_capacity),
+            bytes_low_watermark: Watermarks::bytes(watermarks.low, bytes_capacity),
             bytes_used: 0,
             rng: StdRng::seed_from_u64(42),
             entry_overhead: entry_overhead as u32,
@@ -66,17 +108,38 @@ impl<K: Eq + Hash, V> RndCache<K, V> {
         self.entry_overhead = size;
     }
 
+    fn reject_too_large(&self, size: u64) {
+        debug!(
+            "not caching entry of {} bytes (cache capacity is {} bytes)",
+            size, self.bytes_capacity
+        );
+        self.metric_churn.with_label_values(&["rejected"]).inc();
+    }
+
     pub fn put(&mut self, k: K, v: V, size: u64) {
-        if size > self.bytes_capacity {
+        // The entry must fit in the cache along with its overhead.
+        if size.saturating_add(self.entry_overhead as u64) > self.bytes_capacity {
+            self.reject_too_large(size);
             return;
         }
 
         if size + self.entry_overhead as u64 > std::u32::MAX as u64 {
             // Cache does not support entries of this size.
+            self.reject_too_large(size);
             return;
         }
         let size = size as u32;
 
+        if self.bytes_used + self.entry_bytes(size) > self.bytes_high_watermark {
+            self.metric_churn
+                .with_label_values(&["eviction_pass"])
+                .inc();
+            while !self.map.is_empty()
+                && self.bytes_used + self.entry_bytes(size) > self.bytes_low_watermark
+            {
+                self.evict_random();
+            }
+        }
         while !self.fits_in_cache(size) {
             self.evict_random();
         }
@@ -95,7 +158,11 @@ impl<K: Eq + Hash, V> RndCache<K, V> {
         self.metric_entries.set(self.map.len() as i64);
     }
 
-    pub fn get(&self, k: &K) -> Option<&V> {
+    pub fn get<Q>(&self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         match self.map.get(k) {
             Some(v) => {
                 self.metric_lookups.with_label_values(&["hit"]).inc();
@@ -117,8 +184,13 @@ impl<K: Eq + Hash, V> RndCache<K, V> {
         self.bytes_capacity
     }
 
+    /// Bytes used by an entry of `size` bytes, including its overhead
+    fn entry_bytes(&self, size: u32) -> u64 {
+        size as u64 + self.entry_overhead as u64
+    }
+
     fn fits_in_cache(&self, bytes: u32) -> bool {
-        self.bytes_used + bytes as u64 <= self.bytes_capacity
+        self.bytes_used + self.entry_bytes(bytes) <= self.bytes_capacity
     }
 
     /// Removes a random cache entry
@@ -146,6 +218,7 @@ mod tests {
     fn test_insert_newitem() {
         let mut cache: RndCache<i32, i32> = RndCache::new(
             100,
+            Watermarks::default(),
             dummy_int_vec_counter(),
             dummy_int_vec_counter(),
             dummy_int_gauge(),
@@ -166,6 +239,7 @@ mod tests {
     fn test_insert_replace() {
         let mut cache: RndCache<i32, i32> = RndCache::new(
             100,
+            Watermarks::default(),
             dummy_int_vec_counter(),
             dummy_int_vec_counter(),
             dummy_int_gauge(),
@@ -186,6 +260,7 @@ mod tests {
         let capacity = 100;
         let mut cache: RndCache<i32, i32> = RndCache::new(
             capacity,
+            Watermarks::default(),
             dummy_int_vec_counter(),
             dummy_int_vec_counter(),
             dummy_int_gauge(),
@@ -203,10 +278,61 @@ mod tests {
         assert!(cache.get(&10).is_some());
     }
 
+    #[test]
+    fn test_too_big_with_overhead() {
+        let mut cache: RndCache<i32, i32> = RndCache::new(
+            100,
+            Watermarks::default(),
+            dummy_int_vec_counter(),
+            dummy_int_vec_counter(),
+            dummy_int_gauge(),
+            dummy_int_gauge(),
+        );
+        cache.override_entry_overhead(10);
+        cache.put(10, 10, 50);
+
+        // fits by itself, but not with its overhead
+        cache.put(20, 20, 95);
+        assert!(cache.get(&20).is_none());
+        assert!(cache.get(&10).is_some());
+
+        cache.put(30, 30, 90);
+        assert!(cache.get(&30).is_some());
+        assert_eq!(100, cache.usage());
+    }
+
+    #[test]
+    fn test_too_big_metric() {
+        let churn = dummy_int_vec_counter();
+        let mut cache: RndCache<i32, i32> = RndCache::new(
+            100,
+            Watermarks::default(),
+            dummy_int_vec_counter(),
+            churn.clone(),
+            dummy_int_gauge(),
+            dummy_int_gauge(),
+        );
+        cache.override_entry_overhead(0);
+        let rejected = churn.with_label_values(&["rejected"]);
+
+        cache.put(10, 10, 100);
+        assert_eq!(0, rejected.get());
+
+        cache.put(20, 20, 101);
+        assert_eq!(1, rejected.get());
+        cache.put(30, 30, u64::MAX);
+        assert_eq!(2, rejected.get());
+
+        // rejections are not evictions
+        assert_eq!(0, churn.with_label_values(&["evicted"]).get());
+        assert!(cache.get(&10).is_some());
+    }
+
     #[test]
     fn test_capacity() {
         let mut cache: RndCache<&str, i32> = RndCache::new(
             300,
+            Watermarks::default(),
             dummy_int_vec_counter(),
             dummy_int_vec_counter(),
             dummy_int_gauge(),
@@ -248,6 +374,7 @@ mod tests {
 
         let mut cache: RndCache<&str, i32> = RndCache::new(
             capacity,
+            Watermarks::default(),
             dummy_int_vec_counter(),
             dummy_int_vec_counter(),
             dummy_int_gauge(),
@@ -271,4 +398,55 @@ mod tests {
         cache.put("key5", 5, capacity);
         assert_eq!(0, count_hits(&cache, vec!("key1", "key2", "key3")));
     }
+
+    /// Inserts `n` entries of 10 bytes into a cache of 1000 bytes, returns
+    /// number of eviction passes and evicted entries.
+    fn churn_with(watermarks: Watermarks, n: u32) -> (u64, u64) {
+        let churn = dummy_int_vec_counter();
+        let mut cache: RndCache<u32, u32> = RndCache::new(
+            1000,
+            watermarks,
+            dummy_int_vec_counter(),
+            churn.clone(),
+            dummy_int_gauge(),
+            dummy_int_gauge(),
+        );
+        cache.override_entry_overhead(0);
+        for i in 0..n {
+            cache.put(i, i, 10);
+            assert!(cache.usage() <= 1000);
+            assert!(cache.get(&i).is_some());
+        }
+        (
+            churn.with_label_values(&["eviction_pass"]).get(),
+            churn.with_label_values(&["evicted"]).get(),
+        )
+    }
+
+    #[test]
+    fn test_watermarks() {
+        let (passes, evicted) = churn_with(Watermarks::default(), 1000);
+        // one at a time, once full
+        assert_eq!(passes, 900);
+        assert_eq!(evicted, 900);
+
+        let (passes, evicted) = churn_with(Watermarks::new(1.0, 0.8).unwrap(), 1000);
+        // 21 entries evicted per pass, to fit the new one below 800 bytes
+        assert_eq!(passes, 43);
+        assert_eq!(evicted, 43 * 21);
+
+        // eviction starts at the high watermark
+        let (passes, _) = churn_with(Watermarks::new(0.5, 0.5).unwrap(), 50);
+        assert_eq!(passes, 0);
+        let (passes, _) = churn_with(Watermarks::new(0.5, 0.5).unwrap(), 51);
+        assert_eq!(passes, 1);
+    }
+
+    #[test]
+    fn test_invalid_watermarks() {
+        assert!(Watermarks::new(1.0, 1.0).is_ok());
+        assert!(Watermarks::new(1.1, 0.5).is_err());
+        assert!(Watermarks::new(0.5, 0.8).is_err());
+        assert!(Watermarks::new(0.5, 0.0).is_err());
+    }
 }
@@ -1,39 +1,558 @@
-use crate::doslimit::ConnectionLimits;
+use crate::cache::AddressCache;
+use crate::doslimit::{ConnectionLimits, GlobalLimits};
 use crate::errors::*;
-use crate::query::Query;
+use crate::query::{Query, Status};
 use crate::rpc::parseutil::{
-    bool_from_value_or, hash_from_value, rpc_arg_error, scripthash_from_value, str_from_value,
-    usize_from_value, usize_from_value_or,
+    bool_from_value_or, hash_from_value, history_cursor_from_value, history_order_from_value,
+    rpc_arg_error, scripthash_from_value, str_from_value, usize_from_value, usize_from_value_or,
 };
+use crate::rpc::relayfee::RelayFee;
 use crate::rpc::rpcstats::RpcStats;
-use crate::rpc::scripthash::{get_balance, get_first_use, get_history, get_mempool, listunspent};
+use crate::rpc::scripthash::{
+    get_balance, get_first_use, get_history, get_mempool, listunspent, listunspent_with_proofs,
+    HistoryOptions,
+};
 use crate::scripthash::addr_to_scripthash;
 use crate::scripthash::{compute_script_hash, FullHash, ToLeHex};
 use crate::timeout::TimeoutTrigger;
 use crate::util::HeaderEntry;
 use bitcoincash::blockdata::transaction::OutPoint;
-use bitcoincash::blockdata::transaction::Transaction;
+use bitcoincash::blockdata::transaction::{Transaction, TxOut};
 use bitcoincash::consensus::encode::{deserialize, serialize};
-use bitcoincash::hash_types::Txid;
+use bitcoincash::hash_types::{BlockHash, Txid};
 use bitcoincash::hashes::hex::ToHex;
+use bitcoincash::util::uint::Uint256;
 use serde_json::Value;
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+/// Decoded block header, as returned by verbose blockchain.block.header
+fn header_json(entry: &HeaderEntry) -> Value {
+    let header = entry.header();
+    json!({
+        "hash": entry.hash().to_hex(),
+        "height": entry.height(),
+        "version": header.version,
+        "prev_blockhash": header.prev_blockhash.to_hex(),
+        "merkle_root": header.merkle_root.to_hex(),
+        "time": header.time,
+        "bits": format!("{:08x}", header.bits),
+        "nonce": header.nonce,
+    })
+}
+
+/// Height of the block with given hash, which must be in the best chain.
+fn height_of_blockhash(blockhash: &BlockHash, entry: Option<HeaderEntry>) -> Result<usize> {
+    match entry {
+        Some(entry) => Ok(entry.height()),
+        None => bail!(ErrorKind::RpcError(
+            RpcErrorCode::NotFound,
+            format!("block {} not found in the best chain", blockhash)
+        )),
+    }
+}
+
+/// How the first parameter of a method names the scripthash it applies to.
+/// The `blockchain.address.*` and `blockchain.scripthash.*` variants of a
+/// method share the parsing of their other parameters, so that both accept
+/// the same flags.
+#[derive(Clone, Copy, Debug)]
+enum Target {
+    Address,
+    Scripthash,
+}
+
+fn scripthash_from_params(
+    params: &[Value],
+    target: Target,
+    address_cache: &AddressCache,
+) -> Result<FullHash> {
+    match target {
+        Target::Address => address_cache.get_or_else(
+            &str_from_value(params.first(), "address")?,
+            addr_to_scripthash,
+        ),
+        Target::Scripthash => scripthash_from_value(params.first()),
+    }
+}
+
+/// Parameters of get_balance following the address or scripthash
+#[derive(Debug, PartialEq)]
+struct BalanceParams {
+    exclude_tokens: bool,
+}
+
+impl BalanceParams {
+    fn parse(params: &[Value]) -> Result<BalanceParams> {
+        Ok(BalanceParams {
+            exclude_tokens: bool_from_value_or(params.get(1), "exclude_tokens", false)?,
+        })
+    }
+}
+
+/// Options of get_history following the address or scripthash
+fn history_options_from_params(params: &[Value]) -> Result<HistoryOptions> {
+    Ok(HistoryOptions {
+        tx_info: bool_from_value_or(params.get(1), "tx_info", false)?,
+        mempool_only: bool_from_value_or(params.get(2), "mempool_only", false)?,
+        order: history_order_from_value(params.get(3))?,
+        cursor: history_cursor_from_value(params.get(4))?,
+        include_unconfirmed: bool_from_value_or(params.get(5), "include_unconfirmed", true)?,
+    })
+}
+
+/// Parameters of listunspent following the address or scripthash
+#[derive(Debug, PartialEq)]
+struct ListUnspentParams {
+    include_script: bool,
+}
+
+impl ListUnspentParams {
+    fn parse(params: &[Value]) -> Result<ListUnspentParams> {
+        Ok(ListUnspentParams {
+            include_script: bool_from_value_or(params.get(1), "include_script", false)?,
+        })
+    }
+}
+
+/// Heights of the headers to return for a request of `count` headers,
+/// clamped to at most `max` headers (as the protocol allows) before anything
+/// is allocated for them.
+fn headers_range(start_height: usize, count: usize, max: usize) -> std::ops::Range<usize> {
+    start_height..start_height.saturating_add(count.min(max))
+}
+
+/// Height of the block to prove `tx_hash` in, given its confirmation height
+/// (see `TxQuery::get_confirmation_height`). Unconfirmed and unknown
+/// transactions have no merkle proof.
+fn merkle_proof_height(tx_hash: &Txid, confirmation_height: Option<i64>) -> Result<usize> {
+    match confirmation_height {
+        Some(height) if height > 0 => Ok(height as usize),
+        Some(_) => bail!(rpc_arg_error(&format!(
+            "Transaction '{}' is in mempool, no merkle proof until it is confirmed",
+            tx_hash.to_hex()
+        ))),
+        None => bail!(ErrorKind::RpcError(
+            RpcErrorCode::NotFound,
+            format!("Transaction '{}' not found", tx_hash.to_hex())
+        )),
+    }
+}
+
+/// Parse the `tx_hash` and `out_n` parameters of an outpoint.
+fn outpoint_from_params(params: &[Value]) -> Result<OutPoint> {
+    let txid = hash_from_value::<Txid>(params.first())?;
+    let out_n = usize_from_value(params.get(1), "out_n")?;
+    if out_n > u32::MAX as usize {
+        return Err(rpc_arg_error(&format!(
+            "Too large value for out_n parameter ({} > {})",
+            out_n,
+            u32::MAX
+        ))
+        .into());
+    }
+    Ok(OutPoint::new(txid, out_n as u32))
+}
+
+/// The output of `tx` at `outpoint`, or an error if it has no such output.
+fn outpoint_output<'a>(tx: &'a Transaction, outpoint: &OutPoint) -> Result<&'a TxOut> {
+    match tx.output.get(outpoint.vout as usize) {
+        Some(output) => Ok(output),
+        None => bail!(rpc_invalid_params(format!(
+            "out_n {} does not exist on tx {}, the transaction has {} outputs",
+            outpoint.vout,
+            outpoint.txid,
+            tx.output.len()
+        ))),
+    }
+}
+
+/// Entry of `blockchain.transaction.get_batch`: a txid, or an object with the
+/// txid and a verbose flag of its own.
+#[derive(Debug, PartialEq)]
+struct TxBatchEntry {
+    txid: Txid,
+    verbose: bool,
+}
+
+fn tx_batch_entries(params: &[Value]) -> Result<Vec<TxBatchEntry>> {
+    let txids = params
+        .first()
+        .chain_err(|| rpc_arg_error("missing tx_hashes"))?
+        .as_array()
+        .chain_err(|| rpc_arg_error("expected tx_hashes to be an array"))?;
+    let verbose = bool_from_value_or(params.get(1), "verbose", false)?;
+    txids
+        .iter()
+        .map(|entry| match entry {
+            Value::Object(entry) => Ok(TxBatchEntry {
+                txid: hash_from_value(entry.get("tx_hash"))?,
+                verbose: bool_from_value_or(entry.get("verbose"), "verbose", verbose)?,
+            }),
+            txid => Ok(TxBatchEntry {
+                txid: hash_from_value(Some(txid))?,
+                verbose,
+            }),
+        })
+        .collect()
+}
+
+/// Error of a single entry of a batch, as in a JSON-RPC error reply
+fn batch_error_json(e: Error) -> Value {
+    let e = rpc_daemon_timeout(e);
+    let code = match e.kind() {
+        ErrorKind::RpcError(code, _) => *code,
+        _ => RpcErrorCode::InternalError,
+    };
+    let errmsgs: Vec<String> = e.iter().take(2).map(|x| x.to_string()).collect();
+    json!({
+        "code": code as i32,
+        "message": errmsgs.join("; "),
+    })
+}
+
+/// Results of `entries` in the same order, each either `{"result": ...}`
+/// or `{"error": ...}`, so that a missing transaction doesn't fail the whole
+/// batch. Each distinct entry is fetched once. Only a timeout fails the
+/// batch.
+fn tx_batch_results<F>(
+    entries: &[TxBatchEntry],
+    timeout: &TimeoutTrigger,
+    mut fetch: F,
+) -> Result<Vec<Value>>
+where
+    F: FnMut(&Txid, bool) -> Result<Value>,
+{
+    let mut fetched: HashMap<(Txid, bool), Value> = HashMap::new();
+    entries
+        .iter()
+        .map(|entry| {
+            let key = (entry.txid, entry.verbose);
+            if let Some(result) = fetched.get(&key) {
+                return Ok(result.clone());
+            }
+            timeout.check()?;
+            let result = match fetch(&entry.txid, entry.verbose) {
+                Ok(tx) => json!({ "result": tx }),
+                Err(e) => json!({ "error": batch_error_json(e) }),
+            };
+            fetched.insert(key, result.clone());
+            Ok(result)
+        })
+        .collect()
+}
+
+/// Confirmation `height` of a transaction, or 0 (pending, like a mempool
+/// transaction) if it has less than `min_confirmations` confirmations at
+/// `tip_height`, as it may still be reorged out.
+fn min_confirmations_height(height: i64, tip_height: usize, min_confirmations: usize) -> i64 {
+    if height <= 0 {
+        return height;
+    }
+    let confirmations = (tip_height as i64 - height + 1).max(0) as usize;
+    if confirmations < min_confirmations {
+        0
+    } else {
+        height
+    }
+}
+
+/// The input spending an outpoint (see `Query::get_tx_spending_prevout`),
+/// or null if it is unspent.
+fn spender_json(spend: Option<(Transaction, u32, u32)>) -> Value {
+    match spend {
+        Some((tx, input_index, height)) => json!({
+            "tx_hash": tx.txid().to_hex(),
+            "tx_pos": input_index,
+            "height": height,
+        }),
+        None => Value::Null,
+    }
+}
+
+/// Result of a scripthash subscription: the status hash, or with `verbose`,
+/// also the height of the latest activity and the number of transactions.
+fn subscribe_result(statushash: Option<FullHash>, status: &Status, verbose: bool) -> Value {
+    let statushash = statushash.map_or(Value::Null, |h| json!(hex::encode(h)));
+    if !verbose {
+        return statushash;
+    }
+    let (height, tx_count) = status.history_summary();
+    json!({
+        "status": statushash,
+        "height": height,
+        "tx_count": tx_count,
+    })
+}
+
+/// Chain tip, as returned by blockchain.headers.subscribe. The verbose form
+/// includes chain work and median time past of the tip.
+fn tip_json(entry: &HeaderEntry, verbose: Option<(Uint256, u32)>) -> Value {
+    let mut result = json!({
+        "hex": hex::encode(serialize(entry.header())),
+        "height": entry.height(),
+    });
+    if let Some((chainwork, mediantime)) = verbose {
+        let words = chainwork.0;
+        let obj = result.as_object_mut().unwrap();
+        obj.insert(
+            "chainwork".to_string(),
+            json!(format!(
+                "{:016x}{:016x}{:016x}{:016x}",
+                words[3], words[2], words[1], words[0]
+            )),
+        );
+        obj.insert("mediantime".to_string(), json!(mediantime));
+    }
+    result
+}
+
+/// Serialized size of a P2PKH input, used for the dust threshold
+const SPEND_INPUT_SIZE: u64 = 148;
+
+/// Smallest value of an output that is not dust at the given relay fee
+/// (in BCH/kB), i.e. spending it costs less than a third of its value.
+fn dust_threshold(txout: &TxOut, relayfee: f64) -> u64 {
+    let relayfee_sat_per_kb = (relayfee * 100_000_000f64).round() as u64;
+    let spend_size = serialize(txout).len() as u64 + SPEND_INPUT_SIZE;
+    3 * spend_size * relayfee_sat_per_kb / 1000
+}
+
+/// Cheap sanity checks of a transaction before it is passed to bitcoind,
+/// for precise errors to the client. Policy checks are left to bitcoind.
+fn precheck_broadcast(
+    tx: &Transaction,
+    tx_size: usize,
+    max_size: usize,
+    relayfee: f64,
+) -> Result<()> {
+    if tx.input.is_empty() {
+        bail!(rpc_invalid_params("transaction has no inputs".to_string()));
+    }
+    if tx.output.is_empty() {
+        bail!(rpc_invalid_params("transaction has no outputs".to_string()));
+    }
+    if tx_size > max_size {
+        bail!(rpc_invalid_params(format!(
+            "transaction size {} exceeds the limit of {} bytes",
+            tx_size, max_size
+        )));
+    }
+    for (vout, txout) in tx.output.iter().enumerate() {
+        if txout.script_pubkey.is_op_return() {
+            continue;
+        }
+        let threshold = dust_threshold(txout, relayfee);
+        if txout.value < threshold {
+            bail!(rpc_invalid_params(format!(
+                "output {} is dust ({} sat, minimum is {} sat)",
+                vout, txout.value, threshold
+            )));
+        }
+    }
+    Ok(())
+}
+
 struct Subscription {
     statushash: Option<FullHash>,
     alias: Option<String>,
 }
 
+/// Status of a subscribed transaction: the height it is confirmed at, 0 if in
+/// mempool (-1 if with unconfirmed parents) or None if unknown.
+type TxStatus = Option<i64>;
+
+/// Update the status of the subscribed transactions among `txids`. Returns
+/// the transactions whose status changed. Transactions whose status can't be
+/// looked up keep their previous status, and are checked again on the next
+/// update.
+fn update_tx_statuses<'a>(
+    subscriptions: &mut HashMap<Txid, TxStatus>,
+    txids: impl Iterator<Item = &'a Txid>,
+    status_of: impl Fn(&Txid) -> Result<TxStatus>,
+) -> Vec<(Txid, TxStatus)> {
+    txids
+        .filter_map(|txid| {
+            let status = subscriptions.get_mut(txid)?;
+            let new_status = match status_of(txid) {
+                Ok(new_status) => new_status,
+                Err(e) => {
+                    warn!("failed to get status of tx {}: {}", txid, e);
+                    return None;
+                }
+            };
+            if *status == new_status {
+                return None;
+            }
+            *status = new_status;
+            Some((*txid, new_status))
+        })
+        .collect()
+}
+
+/// Estimated memory used by a subscription entry (excluding alias), including
+/// some hashmap overhead.
+const SUBSCRIPTION_ENTRY_BYTES: usize =
+    std::mem::size_of::<FullHash>() + std::mem::size_of::<Subscription>() + 8;
+
+/// Number of distinct scripthashes not already subscribed to.
+fn count_new_subscriptions(
+    subscriptions: &HashMap<FullHash, Subscription>,
+    scripthashes: &[FullHash],
+) -> usize {
+    scripthashes
+        .iter()
+        .filter(|s| !subscriptions.contains_key(*s))
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// Add a subscription to `scripthash`, updating the stats. An existing
+/// subscription to the same scripthash, by address or by scripthash, is
+/// merged into. The address alias is kept over none, so that the peer is
+/// notified once, by address. Returns the change in alias bytes used.
+fn merge_subscription(
+    subscriptions: &mut HashMap<FullHash, Subscription>,
+    scripthash: FullHash,
+    subscription: Subscription,
+    stats: &RpcStats,
+) -> isize {
+    match subscriptions.entry(scripthash) {
+        Entry::Occupied(mut entry) => {
+            let existing = entry.get_mut();
+            existing.statushash = subscription.statushash;
+            match subscription.alias {
+                Some(alias) => {
+                    let old_len = existing.alias.as_ref().map_or(0, String::len);
+                    let delta = alias.len() as isize - old_len as isize;
+                    existing.alias = Some(alias);
+                    stats.subscription_bytes.add(delta as i64);
+                    delta
+                }
+                None => 0,
+            }
+        }
+        Entry::Vacant(entry) => {
+            let alias_len = subscription.alias.as_ref().map_or(0, String::len);
+            entry.insert(subscription);
+            stats.subscriptions.inc();
+            stats
+                .subscription_bytes
+                .add((SUBSCRIPTION_ENTRY_BYTES + alias_len) as i64);
+            alias_len as isize
+        }
+    }
+}
+
+/// Remove all scripthash (and address) subscriptions, updating the stats.
+/// Returns the number of subscriptions and the alias bytes removed.
+fn clear_subscriptions(
+    subscriptions: &mut HashMap<FullHash, Subscription>,
+    stats: &RpcStats,
+) -> (usize, usize) {
+    let count = subscriptions.len();
+    let alias_bytes: usize = subscriptions
+        .drain()
+        .filter_map(|(_, subscription)| subscription.alias)
+        .map(|alias| alias.len())
+        .sum();
+    stats.subscriptions.sub(count as i64);
+    stats
+        .subscription_bytes
+        .sub((count * SUBSCRIPTION_ENTRY_BYTES + alias_bytes) as i64);
+    (count, alias_bytes)
+}
+
+/// Check the global subscription limit for `new_subscriptions` on top of
+/// the subscriptions of all connections (as counted in `stats`).
+fn check_total_subscriptions(
+    global_limits: &GlobalLimits,
+    stats: &RpcStats,
+    new_subscriptions: usize,
+) -> Result<()> {
+    let total = stats.subscriptions.get().max(0) as usize + new_subscriptions;
+    global_limits.check_subscriptions(total.min(u32::MAX as usize) as u32)
+}
+
+/// Estimated memory used by subscriptions and their aliases.
+fn subscription_bytes(num_subscriptions: usize, alias_bytes: usize) -> usize {
+    num_subscriptions * SUBSCRIPTION_ENTRY_BYTES + alias_bytes
+}
+
+/// Subscriptions taken from a disconnected peer, for resuming them on
+/// another connection. They stay counted in the stats until resumed or
+/// released.
+pub struct SavedSubscriptions {
+    subscriptions: HashMap<FullHash, Subscription>,
+    tx_subscriptions: HashMap<Txid, TxStatus>,
+    alias_bytes: usize,
+}
+
+impl SavedSubscriptions {
+    pub fn len(&self) -> usize {
+        self.subscriptions.len() + self.tx_subscriptions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains_scripthash(&self, scripthash: &FullHash) -> bool {
+        self.subscriptions.contains_key(scripthash)
+    }
+
+    pub fn contains_tx(&self, txid: &Txid) -> bool {
+        self.tx_subscriptions.contains_key(txid)
+    }
+
+    /// Remove the subscriptions from the stats, as they are dropped (or
+    /// merged into a connection).
+    pub fn release(&self, stats: &RpcStats) {
+        stats.subscriptions.sub(self.len() as i64);
+        stats
+            .subscription_bytes
+            .sub(subscription_bytes(self.len(), self.alias_bytes) as i64);
+    }
+
+    /// Subscriptions (without status) to `scripthashes` and `txids`, added
+    /// to the stats.
+    #[cfg(test)]
+    pub fn counted(scripthashes: &[FullHash], txids: &[Txid], stats: &RpcStats) -> Self {
+        let mut subscriptions = HashMap::new();
+        for scripthash in scripthashes {
+            let subscription = Subscription {
+                statushash: None,
+                alias: None,
+            };
+            merge_subscription(&mut subscriptions, *scripthash, subscription, stats);
+        }
+        let tx_subscriptions: HashMap<Txid, TxStatus> =
+            txids.iter().map(|txid| (*txid, None)).collect();
+        stats.subscriptions.add(txids.len() as i64);
+        stats
+            .subscription_bytes
+            .add(subscription_bytes(txids.len(), 0) as i64);
+        SavedSubscriptions {
+            subscriptions,
+            tx_subscriptions,
+            alias_bytes: 0,
+        }
+    }
+}
+
 pub struct BlockchainRpc {
     query: Arc<Query>,
     stats: Arc<RpcStats>,
     subscriptions: Mutex<HashMap<FullHash /* scripthash */, Subscription>>,
+    tx_subscriptions: Mutex<HashMap<Txid, TxStatus>>,
     last_header_entry: Mutex<Option<HeaderEntry>>,
-    relayfee: f64,
+    verbose_headers: AtomicBool,
+    relayfee: Arc<RelayFee>,
     doslimits: ConnectionLimits,
+    global_limits: Arc<GlobalLimits>,
 
     /* Resource tracking */
     alias_bytes_used: AtomicUsize,
@@ -43,107 +562,191 @@ impl BlockchainRpc {
     pub fn new(
         query: Arc<Query>,
         stats: Arc<RpcStats>,
-        relayfee: f64,
+        relayfee: Arc<RelayFee>,
         doslimits: ConnectionLimits,
+        global_limits: Arc<GlobalLimits>,
     ) -> BlockchainRpc {
         BlockchainRpc {
             query,
             stats,
             subscriptions: Mutex::new(HashMap::new()),
+            tx_subscriptions: Mutex::new(HashMap::new()),
             last_header_entry: Mutex::new(None), // disable header subscription for now
+            verbose_headers: AtomicBool::new(false),
             relayfee,
             doslimits,
+            global_limits,
             alias_bytes_used: AtomicUsize::new(0),
         }
     }
+
+    fn get_balance(
+        &self,
+        target: Target,
+        params: &[Value],
+        timeout: &TimeoutTrigger,
+    ) -> Result<Value> {
+        let scripthash = scripthash_from_params(params, target, self.query.address_cache())?;
+        let params = BalanceParams::parse(params)?;
+        get_balance(&*self.query, &scripthash, params.exclude_tokens, timeout)
+    }
+
+    fn get_first_use(&self, target: Target, params: &[Value]) -> Result<Value> {
+        let scripthash = scripthash_from_params(params, target, self.query.address_cache())?;
+        get_first_use(&*self.query, &scripthash)
+    }
+
+    fn get_history(
+        &self,
+        target: Target,
+        params: &[Value],
+        timeout: &TimeoutTrigger,
+    ) -> Result<Value> {
+        let scripthash = scripthash_from_params(params, target, self.query.address_cache())?;
+        let options = history_options_from_params(params)?;
+        get_history(
+            &self.query,
+            &scripthash,
+            &options,
+            self.doslimits.max_tx_info_items as usize,
+            self.doslimits.max_tx_info_prevouts as usize,
+            timeout,
+        )
+    }
+
+    fn get_mempool(
+        &self,
+        target: Target,
+        params: &[Value],
+        timeout: &TimeoutTrigger,
+    ) -> Result<Value> {
+        let scripthash = scripthash_from_params(params, target, self.query.address_cache())?;
+        get_mempool(&self.query, &scripthash, timeout)
+    }
+
+    fn listunspent(
+        &self,
+        target: Target,
+        params: &[Value],
+        timeout: &TimeoutTrigger,
+    ) -> Result<Value> {
+        let scripthash = scripthash_from_params(params, target, self.query.address_cache())?;
+        let params = ListUnspentParams::parse(params)?;
+        listunspent(&*self.query, &scripthash, params.include_script, timeout)
+    }
+
     pub fn address_get_balance(&self, params: &[Value], timeout: &TimeoutTrigger) -> Result<Value> {
-        let addr = str_from_value(params.get(0), "address")?;
-        let scripthash = addr_to_scripthash(&addr)?;
-        get_balance(&*self.query, &scripthash, timeout)
+        self.get_balance(Target::Address, params, timeout)
     }
     pub fn address_get_first_use(&self, params: &[Value]) -> Result<Value> {
-        let addr = str_from_value(params.get(0), "address")?;
-        let scripthash = addr_to_scripthash(&addr)?;
-        get_first_use(&*self.query, &scripthash)
+        self.get_first_use(Target::Address, params)
     }
     pub fn address_get_history(&self, params: &[Value], timeout: &TimeoutTrigger) -> Result<Value> {
-        let addr = str_from_value(params.get(0), "address")?;
-        let scripthash = addr_to_scripthash(&addr)?;
-        get_history(&self.query, &scripthash, timeout)
+        self.get_history(Target::Address, params, timeout)
     }
 
     pub fn address_get_mempool(&self, params: &[Value], timeout: &TimeoutTrigger) -> Result<Value> {
-        let addr = str_from_value(params.get(0), "address")?;
-        let scripthash = addr_to_scripthash(&addr)?;
-        get_mempool(&self.query, &scripthash, timeout)
+        self.get_mempool(Target::Address, params, timeout)
     }
 
     pub fn address_get_scripthash(&self, params: &[Value]) -> Result<Value> {
-        let scripthash = addr_to_scripthash(&str_from_value(params.get(0), "address")?)?;
+        let addr = str_from_value(params.get(0), "address")?;
+        let scripthash = self
+            .query
+            .address_cache()
+            .get_or_else(&addr, addr_to_scripthash)?;
         Ok(json!(scripthash.to_le_hex()))
     }
 
     pub fn address_listunspent(&self, params: &[Value], timeout: &TimeoutTrigger) -> Result<Value> {
-        let addr = str_from_value(params.get(0), "address")?;
-        let scripthash = addr_to_scripthash(&addr)?;
-        listunspent(&*self.query, &scripthash, timeout)
+        self.listunspent(Target::Address, params, timeout)
     }
 
     pub fn address_subscribe(&self, params: &[Value], timeout: &TimeoutTrigger) -> Result<Value> {
         let addr = str_from_value(params.get(0), "address")?;
-        let scripthash = addr_to_scripthash(&addr)?;
-        self.remove_subscription(&scripthash);
+        let scripthash = self
+            .query
+            .address_cache()
+            .get_or_else(&addr, addr_to_scripthash)?;
+        let verbose = bool_from_value_or(params.get(1), "verbose", false)?;
+        let existing_alias_len = self.existing_alias_len(&scripthash);
+        let new_subscriptions = existing_alias_len.is_none() as usize;
 
-        self.doslimits
-            .check_subscriptions(self.get_num_subscriptions() as u32 + 1)?;
+        self.doslimits.check_subscriptions(
+            (self.get_num_subscriptions() as usize + new_subscriptions) as u32,
+        )?;
+        check_total_subscriptions(&self.global_limits, &self.stats, new_subscriptions)?;
 
+        let alias_bytes = self.alias_bytes_used.load(Ordering::Relaxed) + addr.len()
+            - existing_alias_len.unwrap_or(0);
+        self.doslimits.check_alias_usage(alias_bytes)?;
         self.doslimits
-            .check_alias_usage(self.alias_bytes_used.load(Ordering::Relaxed) + addr.len())?;
+            .check_subscription_memory(subscription_bytes(
+                self.get_num_subscriptions() as usize + new_subscriptions,
+                alias_bytes,
+            ))?;
 
-        let statushash = self.query.status(&scripthash, timeout)?.hash();
-        let result = statushash.map_or(Value::Null, |h| json!(hex::encode(h)));
+        let status = self.query.status(&scripthash, timeout)?;
+        let statushash = status.hash();
+        let result = subscribe_result(statushash, &status, verbose);
 
         // We don't hold a lock on alias usage, so we could exceed limit here.
         // That's OK, it doesn't need to be a hard limit.
-        self.alias_bytes_used
-            .fetch_add(addr.len(), Ordering::Relaxed);
-        self.subscriptions.lock().unwrap().insert(
+        let alias_delta = merge_subscription(
+            &mut self.subscriptions.lock().unwrap(),
             scripthash,
             Subscription {
                 statushash,
                 alias: Some(addr),
             },
+            &self.stats,
         );
-        self.stats.subscriptions.inc();
+        self.update_alias_bytes(alias_delta);
         Ok(result)
     }
 
     pub fn address_unsubscribe(&self, params: &[Value]) -> Result<Value> {
         let addr = str_from_value(params.get(0), "address")?;
-        let scripthash = addr_to_scripthash(&addr)?;
+        let scripthash = self
+            .query
+            .address_cache()
+            .get_or_else(&addr, addr_to_scripthash)?;
         Ok(json!(self.remove_subscription(&scripthash)))
     }
 
+    pub fn block_tx_count(&self, params: &[Value]) -> Result<Value> {
+        let height = usize_from_value(params.first(), "height")?;
+        Ok(json!(self.query.get_block_tx_count(height)?))
+    }
+
     pub fn block_header(&self, params: &[Value]) -> Result<Value> {
         let height = usize_from_value(params.get(0), "height")?;
         let cp_height = usize_from_value_or(params.get(1), "cp_height", 0)?;
+        let verbose = bool_from_value_or(params.get(2), "verbose", false)?;
 
-        let raw_header_hex: String = self
-            .query
-            .get_headers(&[height])
-            .into_iter()
-            .map(|entry| hex::encode(&serialize(entry.header())))
-            .collect();
+        let headers = self.query.get_headers(&[height]);
+        let header = if verbose {
+            let entry = headers
+                .first()
+                .chain_err(|| rpc_invalid_params(format!("no header at height {}", height)))?;
+            header_json(entry)
+        } else {
+            let raw_header_hex: String = headers
+                .iter()
+                .map(|entry| hex::encode(&serialize(entry.header())))
+                .collect();
+            json!(raw_header_hex)
+        };
 
         if cp_height == 0 {
-            return Ok(json!(raw_header_hex));
+            return Ok(header);
         }
         let (branch, root) = self.query.get_header_merkle_proof(height, cp_height)?;
 
         let branch_vec: Vec<String> = branch.into_iter().map(|b| b.to_hex()).collect();
 
         Ok(json!({
-            "header": raw_header_hex,
+            "header": header,
             "root": root.to_hex(),
             "branch": branch_vec
         }))
@@ -153,7 +756,23 @@ impl BlockchainRpc {
         let start_height = usize_from_value(params.get(0), "start_height")?;
         let count = usize_from_value(params.get(1), "count")?;
         let cp_height = usize_from_value_or(params.get(2), "cp_height", 0)?;
-        let heights: Vec<usize> = (start_height..(start_height + count)).collect();
+        self.headers_json(start_height, count, cp_height)
+    }
+
+    /// Same as `block_headers`, starting at the block with given hash.
+    pub fn block_headers_from_hash(&self, params: &[Value]) -> Result<Value> {
+        let blockhash: BlockHash = hash_from_value(params.first())?;
+        let count = usize_from_value(params.get(1), "count")?;
+        let cp_height = usize_from_value_or(params.get(2), "cp_height", 0)?;
+        let entry = self.query.header().by_blockhash(&blockhash);
+        let start_height = height_of_blockhash(&blockhash, entry)?;
+        self.headers_json(start_height, count, cp_height)
+    }
+
+    fn headers_json(&self, start_height: usize, count: usize, cp_height: usize) -> Result<Value> {
+        let max = self.doslimits.max_headers as usize;
+        let heights: Vec<usize> = headers_range(start_height, count, max).collect();
+        let count = heights.len();
         let headers: Vec<String> = self
             .query
             .get_headers(&heights)
@@ -165,7 +784,7 @@ impl BlockchainRpc {
             return Ok(json!({
                 "count": headers.len(),
                 "hex": headers.join(""),
-                "max": 2016,
+                "max": max,
             }));
         }
 
@@ -178,7 +797,7 @@ impl BlockchainRpc {
         Ok(json!({
             "count": headers.len(),
             "hex": headers.join(""),
-            "max": 2016,
+            "max": max,
             "root": root.to_hex(),
             "branch" : branch_vec
         }))
@@ -187,20 +806,62 @@ impl BlockchainRpc {
     pub fn estimatefee(&self, params: &[Value]) -> Result<Value> {
         let blocks_count = usize_from_value(params.get(0), "blocks_count")?;
         let fee_rate = self.query.estimate_fee(blocks_count); // in BCH/kB
-        Ok(json!(fee_rate.max(self.relayfee)))
+        Ok(json!(fee_rate.max(self.relayfee.get())))
     }
 
-    pub fn headers_subscribe(&self) -> Result<Value> {
+    pub fn estimatefees(&self, params: &[Value]) -> Result<Value> {
+        let targets = params
+            .first()
+            .and_then(|v| v.as_array())
+            .chain_err(|| rpc_arg_error("expected an array of block counts"))?;
+        self.doslimits.check_batch_size(targets.len())?;
+        let targets = targets
+            .iter()
+            .map(|t| usize_from_value(Some(t), "blocks_count"))
+            .collect::<Result<Vec<usize>>>()?;
+        let relayfee = self.relayfee.get();
+        let fee_rates = self.query.estimate_fees(&targets); // in BCH/kB
+        let result: serde_json::Map<String, Value> = targets
+            .iter()
+            .zip(fee_rates)
+            .map(|(blocks, fee_rate)| (blocks.to_string(), json!(fee_rate.max(relayfee))))
+            .collect();
+        Ok(Value::Object(result))
+    }
+
+    fn chaintip_json(&self, entry: &HeaderEntry) -> Result<Value> {
+        let verbose = if self.verbose_headers.load(Ordering::Relaxed) {
+            Some(self.query.get_header_chainwork_and_mtp(entry)?)
+        } else {
+            None
+        };
+        Ok(tip_json(entry, verbose))
+    }
+
+    pub fn headers_subscribe(&self, params: &[Value]) -> Result<Value> {
+        let verbose = bool_from_value_or(params.first(), "verbose", false)?;
+        self.verbose_headers.store(verbose, Ordering::Relaxed);
         let entry = self.query.get_best_header()?;
-        let hex_header = hex::encode(serialize(entry.header()));
-        let result = json!({"hex": hex_header, "height": entry.height()});
+        let result = self.chaintip_json(&entry)?;
         let mut last_entry = self.last_header_entry.lock().unwrap();
         *last_entry = Some(entry);
         Ok(result)
     }
 
+    pub fn mempool_get_txids(&self, params: &[Value]) -> Result<Value> {
+        let after = match params.first() {
+            None | Some(Value::Null) => None,
+            after => Some(hash_from_value::<Txid>(after)?),
+        };
+        let (txids, more) = self.query.get_mempool_txids(after.as_ref());
+        Ok(json!({
+            "txids": txids.iter().map(|txid| txid.to_hex()).collect::<Vec<String>>(),
+            "more": more,
+        }))
+    }
+
     pub fn relayfee(&self) -> Result<Value> {
-        Ok(json!(self.relayfee)) // in BTC/kB
+        Ok(json!(self.relayfee.get())) // in BTC/kB
     }
 
     pub fn scripthash_get_balance(
@@ -208,13 +869,11 @@ impl BlockchainRpc {
         params: &[Value],
         timeout: &TimeoutTrigger,
     ) -> Result<Value> {
-        let scripthash = scripthash_from_value(params.get(0))?;
-        get_balance(&*self.query, &scripthash, timeout)
+        self.get_balance(Target::Scripthash, params, timeout)
     }
 
     pub fn scripthash_get_first_use(&self, params: &[Value]) -> Result<Value> {
-        let scripthash = scripthash_from_value(params.get(0))?;
-        get_first_use(&*self.query, &scripthash)
+        self.get_first_use(Target::Scripthash, params)
     }
 
     pub fn scripthash_get_history(
@@ -222,8 +881,7 @@ impl BlockchainRpc {
         params: &[Value],
         timeout: &TimeoutTrigger,
     ) -> Result<Value> {
-        let scripthash = scripthash_from_value(params.get(0))?;
-        get_history(&self.query, &scripthash, timeout)
+        self.get_history(Target::Scripthash, params, timeout)
     }
 
     pub fn scripthash_get_mempool(
@@ -231,8 +889,7 @@ impl BlockchainRpc {
         params: &[Value],
         timeout: &TimeoutTrigger,
     ) -> Result<Value> {
-        let scripthash = scripthash_from_value(params.get(0))?;
-        get_mempool(&self.query, &scripthash, timeout)
+        self.get_mempool(Target::Scripthash, params, timeout)
     }
 
     pub fn scripthash_listunspent(
@@ -240,8 +897,21 @@ impl BlockchainRpc {
         params: &[Value],
         timeout: &TimeoutTrigger,
     ) -> Result<Value> {
-        let scripthash = scripthash_from_value(params.get(0))?;
-        listunspent(&*self.query, &scripthash, timeout)
+        self.listunspent(Target::Scripthash, params, timeout)
+    }
+
+    pub fn scripthash_get_utxos_with_proofs(
+        &self,
+        params: &[Value],
+        timeout: &TimeoutTrigger,
+    ) -> Result<Value> {
+        let scripthash = scripthash_from_value(params.first())?;
+        listunspent_with_proofs(
+            &self.query,
+            &scripthash,
+            self.doslimits.max_utxo_proofs as usize,
+            timeout,
+        )
     }
 
     pub fn scripthash_subscribe(
@@ -250,24 +920,104 @@ impl BlockchainRpc {
         timeout: &TimeoutTrigger,
     ) -> Result<Value> {
         let scripthash = scripthash_from_value(params.get(0))?;
-        self.remove_subscription(&scripthash);
+        let verbose = bool_from_value_or(params.get(1), "verbose", false)?;
+        let new_subscriptions = self.existing_alias_len(&scripthash).is_none() as usize;
 
+        self.doslimits.check_subscriptions(
+            (self.get_num_subscriptions() as usize + new_subscriptions) as u32,
+        )?;
+        check_total_subscriptions(&self.global_limits, &self.stats, new_subscriptions)?;
         self.doslimits
-            .check_subscriptions(self.get_num_subscriptions() as u32 + 1)?;
+            .check_subscription_memory(subscription_bytes(
+                self.get_num_subscriptions() as usize + new_subscriptions,
+                self.alias_bytes_used.load(Ordering::Relaxed),
+            ))?;
 
-        let statushash = self.query.status(&scripthash, timeout)?.hash();
-        let result = statushash.map_or(Value::Null, |h| json!(hex::encode(h)));
-        self.subscriptions.lock().unwrap().insert(
+        let status = self.query.status(&scripthash, timeout)?;
+        let statushash = status.hash();
+        let result = subscribe_result(statushash, &status, verbose);
+        merge_subscription(
+            &mut self.subscriptions.lock().unwrap(),
             scripthash,
             Subscription {
                 statushash,
                 alias: None,
             },
+            &self.stats,
         );
-        self.stats.subscriptions.inc();
         Ok(result)
     }
 
+    /// Subscribe to many scripthashes at once. Either all or none of the
+    /// subscriptions are registered.
+    pub fn scripthash_subscribe_bulk(
+        &self,
+        params: &[Value],
+        timeout: &TimeoutTrigger,
+    ) -> Result<Value> {
+        let scripthashes = params
+            .first()
+            .chain_err(|| rpc_arg_error("missing scripthashes"))?
+            .as_array()
+            .chain_err(|| rpc_arg_error("expected scripthashes to be an array"))?;
+        self.doslimits.check_batch_size(scripthashes.len())?;
+        let scripthashes = scripthashes
+            .iter()
+            .map(|s| scripthash_from_value(Some(s)))
+            .collect::<Result<Vec<FullHash>>>()?;
+
+        {
+            let subscriptions = self.subscriptions.lock().unwrap();
+            let new_subscriptions = count_new_subscriptions(&subscriptions, &scripthashes);
+            let num_subscriptions = subscriptions.len()
+                + self.tx_subscriptions.lock().unwrap().len()
+                + new_subscriptions;
+            self.doslimits
+                .check_subscriptions(num_subscriptions as u32)?;
+            check_total_subscriptions(&self.global_limits, &self.stats, new_subscriptions)?;
+            self.doslimits
+                .check_subscription_memory(subscription_bytes(
+                    num_subscriptions,
+                    self.alias_bytes_used.load(Ordering::Relaxed),
+                ))?;
+        }
+
+        let mut statushashes: HashMap<FullHash, Option<FullHash>> = HashMap::new();
+        for scripthash in &scripthashes {
+            if !statushashes.contains_key(scripthash) {
+                let statushash = self.query.status(scripthash, timeout)?.hash();
+                statushashes.insert(*scripthash, statushash);
+            }
+        }
+
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        for (scripthash, statushash) in statushashes.iter() {
+            merge_subscription(
+                &mut subscriptions,
+                *scripthash,
+                Subscription {
+                    statushash: *statushash,
+                    alias: None,
+                },
+                &self.stats,
+            );
+        }
+
+        Ok(json!(scripthashes
+            .iter()
+            .map(|s| statushashes[s].map_or(Value::Null, |h| json!(hex::encode(h))))
+            .collect::<Vec<Value>>()))
+    }
+
+    /// Remove all scripthash and address subscriptions of the connection.
+    /// Returns the number of subscriptions removed.
+    pub fn scripthash_unsubscribe_all(&self) -> Result<Value> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let (count, _) = clear_subscriptions(&mut subscriptions, &self.stats);
+        self.alias_bytes_used.store(0, Ordering::Relaxed);
+        Ok(json!(count))
+    }
+
     pub fn scripthash_unsubscribe(&self, params: &[Value]) -> Result<Value> {
         let scripthash = scripthash_from_value(params.get(0))?;
         Ok(json!(self.remove_subscription(&scripthash)))
@@ -277,7 +1027,14 @@ impl BlockchainRpc {
         let tx = params.get(0).chain_err(|| rpc_arg_error("missing tx"))?;
         let tx = tx.as_str().chain_err(|| rpc_arg_error("non-string tx"))?;
         let tx = hex::decode(&tx).chain_err(|| rpc_arg_error("non-hex tx"))?;
+        let tx_size = tx.len();
         let tx: Transaction = deserialize(&tx).chain_err(|| rpc_arg_error("failed to parse tx"))?;
+        precheck_broadcast(
+            &tx,
+            tx_size,
+            self.doslimits.max_tx_size as usize,
+            self.relayfee.daemon_fee(),
+        )?;
         let txid = self
             .query
             .broadcast(&tx)
@@ -285,45 +1042,121 @@ impl BlockchainRpc {
         Ok(json!(txid.to_hex()))
     }
 
-    pub fn transaction_get(&self, params: &[Value]) -> Result<Value> {
+    /// Subscribe to confirmation status changes of a transaction. Returns
+    /// the current status.
+    pub fn transaction_subscribe(&self, params: &[Value]) -> Result<Value> {
+        let txid = hash_from_value::<Txid>(params.first())?;
+        if !self.tx_subscriptions.lock().unwrap().contains_key(&txid) {
+            self.doslimits
+                .check_subscriptions(self.get_num_subscriptions() as u32 + 1)?;
+            check_total_subscriptions(&self.global_limits, &self.stats, 1)?;
+            self.doslimits
+                .check_subscription_memory(subscription_bytes(
+                    self.get_num_subscriptions() as usize + 1,
+                    self.alias_bytes_used.load(Ordering::Relaxed),
+                ))?;
+            self.stats.subscriptions.inc();
+            self.stats
+                .subscription_bytes
+                .add(SUBSCRIPTION_ENTRY_BYTES as i64);
+        }
+        let status = self.query.tx().get_confirmation_height(&txid)?;
+        self.tx_subscriptions.lock().unwrap().insert(txid, status);
+        Ok(json!(status))
+    }
+
+    pub fn transaction_unsubscribe(&self, params: &[Value]) -> Result<Value> {
+        let txid = hash_from_value::<Txid>(params.first())?;
+        let removed = self
+            .tx_subscriptions
+            .lock()
+            .unwrap()
+            .remove(&txid)
+            .is_some();
+        if removed {
+            self.stats.subscriptions.dec();
+            self.stats
+                .subscription_bytes
+                .sub(SUBSCRIPTION_ENTRY_BYTES as i64);
+        }
+        Ok(json!(removed))
+    }
+
+    pub fn transaction_get(&self, params: &[Value], timeout: &TimeoutTrigger) -> Result<Value> {
         let tx_hash = hash_from_value::<Txid>(params.get(0))?;
         let verbose = match params.get(1) {
             Some(value) => value.as_bool().chain_err(|| "non-bool verbose value")?,
             None => false,
         };
+        let prevouts = verbose && bool_from_value_or(params.get(2), "prevouts", false)?;
+        self.tx_json(&tx_hash, verbose, prevouts, timeout)
+    }
+
+    fn tx_json(
+        &self,
+        tx_hash: &Txid,
+        verbose: bool,
+        prevouts: bool,
+        timeout: &TimeoutTrigger,
+    ) -> Result<Value> {
         if !verbose {
-            let tx = self.query.tx().get(&tx_hash, None, None)?;
-            Ok(json!(hex::encode(serialize(&tx))))
+            let raw = self.query.tx().get_raw(tx_hash)?;
+            Ok(json!(hex::encode(raw)))
         } else {
-            self.query.tx().get_verbose(&tx_hash)
+            self.query.tx().get_verbose(
+                tx_hash,
+                prevouts,
+                self.doslimits.max_prevout_txs as usize,
+                timeout,
+            )
         }
     }
 
+    pub fn transaction_get_batch(
+        &self,
+        params: &[Value],
+        timeout: &TimeoutTrigger,
+    ) -> Result<Value> {
+        let entries = tx_batch_entries(params)?;
+        self.doslimits.check_batch_size(entries.len())?;
+        let results = tx_batch_results(&entries, timeout, |txid, verbose| {
+            self.tx_json(txid, verbose, false, timeout)
+        })?;
+        Ok(json!(results))
+    }
+
     pub fn transaction_get_confirmed_blockhash(&self, params: &[Value]) -> Result<Value> {
         let tx_hash = hash_from_value(params.get(0)).chain_err(|| "bad tx_hash")?;
         self.query.get_confirmed_blockhash(&tx_hash)
     }
 
+    pub fn transaction_get_confirmed_blockhash_batch(&self, params: &[Value]) -> Result<Value> {
+        let tx_hashes = params
+            .first()
+            .chain_err(|| rpc_arg_error("missing tx_hashes"))?
+            .as_array()
+            .chain_err(|| rpc_arg_error("expected tx_hashes to be an array"))?;
+        self.doslimits.check_batch_size(tx_hashes.len())?;
+        let tx_hashes = tx_hashes
+            .iter()
+            .map(|tx_hash| hash_from_value::<Txid>(Some(tx_hash)))
+            .collect::<Result<Vec<Txid>>>()?;
+        self.query.get_confirmed_blockhashes(&tx_hashes)
+    }
+
     pub fn transaction_get_merkle(&self, params: &[Value]) -> Result<Value> {
         let tx_hash = hash_from_value::<Txid>(params.get(0))?;
         let height = if params.get(1).is_some() {
-            usize_from_value(params.get(1), "height")
+            usize_from_value(params.get(1), "height")?
         } else {
-            let header = self.query.header().get_by_txid(&tx_hash, None)?;
-            match header {
-                Some(header) => Ok(header.height()),
-                None => Err(rpc_arg_error(&format!(
-                    "Transaction '{}' is not confirmed in a block",
-                    tx_hash.to_hex()
-                ))
-                .into()),
-            }
-        }?;
+            let confirmation_height = self.query.tx().get_confirmation_height(&tx_hash)?;
+            merkle_proof_height(&tx_hash, confirmation_height)?
+        };
 
         let (merkle, pos) = self
             .query
             .get_merkle_proof(&tx_hash, height)
-            .chain_err(|| "cannot create merkle proof")?;
+            .map_err(|e| rpc_context(e, "cannot create merkle proof"))?;
         let merkle: Vec<String> = merkle.into_iter().map(|txid| txid.to_hex()).collect();
         Ok(json!({
                 "block_height": height,
@@ -350,38 +1183,20 @@ impl BlockchainRpc {
     }
 
     pub fn utxo_get(&self, params: &[Value], timeout: &TimeoutTrigger) -> Result<Value> {
-        let txid = hash_from_value::<Txid>(params.get(0))?;
-        let out_n = usize_from_value(params.get(1), "out_n")?;
-        if out_n > u32::MAX as usize {
-            return Err(rpc_arg_error(&format!(
-                "Too large value for out_n parameter ({} > {})",
-                out_n,
-                u32::MAX
-            ))
-            .into());
-        }
+        let outpoint = outpoint_from_params(params)?;
+        let txid = outpoint.txid;
+        let min_confirmations = usize_from_value_or(params.get(2), "min_confirmations", 0)?;
+        let tip_height = self.query.get_best_header()?.height();
+        let safe_height = |height| min_confirmations_height(height, tip_height, min_confirmations);
 
         // We want to provide the utxo amount regardless of if it's spent or
         // unspent.
         let utxo_creation_tx = self.query.tx().get(&txid, None, None)?;
         timeout.check()?;
-
-        let utxo = match utxo_creation_tx.output.get(out_n) {
-            Some(utxo) => utxo,
-            None => {
-                bail!(rpc_invalid_params(format!(
-                    "out_n {} does not exist on tx {}, the transaction has {} outputs",
-                    out_n,
-                    txid,
-                    utxo_creation_tx.output.len()
-                )));
-            }
-        };
+        let utxo = outpoint_output(&utxo_creation_tx, &outpoint)?;
 
         // Fetch the spending transaction (if the utxo is spent).
-        let spend = self
-            .query
-            .get_tx_spending_prevout(&OutPoint::new(txid, out_n as u32), timeout)?;
+        let spend = self.query.get_tx_spending_prevout(&outpoint, timeout)?;
 
         let status = if spend.is_some() { "spent" } else { "unspent" };
 
@@ -390,7 +1205,7 @@ impl BlockchainRpc {
                 json!({
                     "tx_hash": Some(tx.txid().to_string()),
                     "tx_pos": Some(input_index),
-                    "height": Some(height),
+                    "height": Some(safe_height(height as i64)),
                 })
             }
             None => {
@@ -402,7 +1217,11 @@ impl BlockchainRpc {
             }
         };
 
-        let utxo_confirmation_height = self.query.tx().get_confirmation_height(&txid);
+        let utxo_confirmation_height = self
+            .query
+            .tx()
+            .get_confirmation_height(&txid)?
+            .map(safe_height);
         let utxo_scripthash = compute_script_hash(&utxo.script_pubkey[..]);
 
         Ok(json!({
@@ -414,6 +1233,19 @@ impl BlockchainRpc {
         }))
     }
 
+    pub fn outpoint_get_spender(
+        &self,
+        params: &[Value],
+        timeout: &TimeoutTrigger,
+    ) -> Result<Value> {
+        let outpoint = outpoint_from_params(params)?;
+        let tx = self.query.tx().get(&outpoint.txid, None, None)?;
+        timeout.check()?;
+        outpoint_output(&tx, &outpoint)?;
+        let spend = self.query.get_tx_spending_prevout(&outpoint, timeout)?;
+        Ok(spender_json(spend))
+    }
+
     pub fn on_chaintip_change(&self, chaintip: HeaderEntry) -> Result<Option<Value>> {
         let timer = self
             .stats
@@ -429,9 +1261,8 @@ impl BlockchainRpc {
             return Ok(None);
         }
 
+        let header = self.chaintip_json(&chaintip)?;
         *last_entry = Some(chaintip);
-        let hex_header = hex::encode(serialize(last_entry.as_ref().unwrap().header()));
-        let header = json!({"hex": hex_header, "height": last_entry.as_ref().unwrap().height()});
         timer.observe_duration();
         Ok(Some(json!({
             "jsonrpc": "2.0",
@@ -439,6 +1270,39 @@ impl BlockchainRpc {
             "params": [header]})))
     }
 
+    /// Notifications for subscribed transactions whose status changed. Only
+    /// the transactions in `txids` are checked, or all of them if None (as
+    /// the chain tip changed).
+    pub fn on_txs_change(&self, txids: Option<&HashSet<Txid>>) -> Vec<Value> {
+        let mut subscriptions = self.tx_subscriptions.lock().unwrap();
+        if subscriptions.is_empty() {
+            return vec![];
+        }
+        let timer = self
+            .stats
+            .latency
+            .with_label_values(&["txs_update"])
+            .start_timer();
+        let subscribed: Vec<Txid> = subscriptions
+            .keys()
+            .filter(|txid| txids.map_or(true, |txids| txids.contains(*txid)))
+            .cloned()
+            .collect();
+        let changed = update_tx_statuses(&mut subscriptions, subscribed.iter(), |txid| {
+            self.query.tx().get_confirmation_height(txid)
+        });
+        timer.observe_duration();
+        changed
+            .into_iter()
+            .map(|(txid, status)| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "blockchain.transaction.subscribe",
+                    "params": [txid.to_hex(), status]})
+            })
+            .collect()
+    }
+
     pub fn on_scripthash_change(&self, scripthash: FullHash) -> Result<Option<Value>> {
         let old_statushash: Option<FullHash>;
         let subscription_name: String;
@@ -469,12 +1333,12 @@ impl BlockchainRpc {
             .start_timer();
 
         let timeout = TimeoutTrigger::new(Duration::from_secs(self.doslimits.rpc_timeout as u64));
-        let status = self.query.status(&scripthash, &timeout)?;
+        let status = self.query.subscription_status(&scripthash, &timeout)?;
         let new_statushash = status.hash();
         if new_statushash == old_statushash {
             return Ok(None);
         }
-        let new_statushash_hex = status.hash().map_or(Value::Null, |h| json!(hex::encode(h)));
+        let new_statushash_hex = new_statushash.map_or(Value::Null, |h| json!(hex::encode(h)));
         let notification = Some(json!({
                     "jsonrpc": "2.0",
                     "method": method,
@@ -484,22 +1348,882 @@ impl BlockchainRpc {
         Ok(notification)
     }
 
+    /// Take all subscriptions of the connection, keeping their status, to
+    /// be resumed on another connection. They are left counted in the stats.
+    pub fn save_subscriptions(&self) -> SavedSubscriptions {
+        SavedSubscriptions {
+            subscriptions: std::mem::take(&mut *self.subscriptions.lock().unwrap()),
+            tx_subscriptions: std::mem::take(&mut *self.tx_subscriptions.lock().unwrap()),
+            alias_bytes: self.alias_bytes_used.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    /// Restore subscriptions saved from another connection, without
+    /// recomputing their status. They are dropped if they don't fit in the
+    /// subscription limits. Returns the number of subscriptions restored.
+    pub fn resume_subscriptions(&self, saved: SavedSubscriptions) -> Result<usize> {
+        saved.release(&self.stats);
+        let scripthashes: Vec<FullHash> = saved.subscriptions.keys().cloned().collect();
+        let new_tx_subscriptions = {
+            let tx_subscriptions = self.tx_subscriptions.lock().unwrap();
+            saved
+                .tx_subscriptions
+                .keys()
+                .filter(|txid| !tx_subscriptions.contains_key(*txid))
+                .count()
+        };
+        let new_subscriptions =
+            count_new_subscriptions(&self.subscriptions.lock().unwrap(), &scripthashes)
+                + new_tx_subscriptions;
+        let num_subscriptions = self.get_num_subscriptions() as usize + new_subscriptions;
+        self.doslimits
+            .check_subscriptions(num_subscriptions.min(u32::MAX as usize) as u32)?;
+        check_total_subscriptions(&self.global_limits, &self.stats, new_subscriptions)?;
+        let alias_bytes = self.alias_bytes_used.load(Ordering::Relaxed) + saved.alias_bytes;
+        self.doslimits.check_alias_usage(alias_bytes)?;
+        self.doslimits
+            .check_subscription_memory(subscription_bytes(num_subscriptions, alias_bytes))?;
+
+        let count = saved.len();
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        for (scripthash, subscription) in saved.subscriptions {
+            let delta =
+                merge_subscription(&mut subscriptions, scripthash, subscription, &self.stats);
+            self.update_alias_bytes(delta);
+        }
+        let mut tx_subscriptions = self.tx_subscriptions.lock().unwrap();
+        for (txid, status) in saved.tx_subscriptions {
+            if tx_subscriptions.insert(txid, status).is_none() {
+                self.stats.subscriptions.inc();
+                self.stats
+                    .subscription_bytes
+                    .add(SUBSCRIPTION_ENTRY_BYTES as i64);
+            }
+        }
+        Ok(count)
+    }
+
+    /// Number of scripthash and transaction subscriptions
     pub fn get_num_subscriptions(&self) -> i64 {
-        self.subscriptions.lock().unwrap().len() as i64
+        (self.subscriptions.lock().unwrap().len() + self.tx_subscriptions.lock().unwrap().len())
+            as i64
+    }
+
+    /// Estimated memory used by subscriptions, including aliases.
+    pub fn get_subscription_bytes(&self) -> usize {
+        subscription_bytes(
+            self.get_num_subscriptions() as usize,
+            self.alias_bytes_used.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Length of the address alias of an existing subscription to
+    /// `scripthash` (0 if subscribed by scripthash), or None if not
+    /// subscribed.
+    fn existing_alias_len(&self, scripthash: &FullHash) -> Option<usize> {
+        self.subscriptions
+            .lock()
+            .unwrap()
+            .get(scripthash)
+            .map(|subscription| subscription.alias.as_ref().map_or(0, String::len))
+    }
+
+    fn update_alias_bytes(&self, delta: isize) {
+        if delta >= 0 {
+            self.alias_bytes_used
+                .fetch_add(delta as usize, Ordering::Relaxed);
+        } else {
+            self.alias_bytes_used
+                .fetch_sub(-delta as usize, Ordering::Relaxed);
+        }
     }
 
     fn remove_subscription(&self, scripthash: &FullHash) -> bool {
         let removed = self.subscriptions.lock().unwrap().remove(scripthash);
         match removed {
             Some(subscription) => {
+                let mut bytes = SUBSCRIPTION_ENTRY_BYTES;
                 if let Some(alias) = subscription.alias {
                     self.alias_bytes_used
                         .fetch_sub(alias.len(), Ordering::Relaxed);
+                    bytes += alias.len();
                 }
                 self.stats.subscriptions.dec();
+                self.stats.subscription_bytes.sub(bytes as i64);
                 true
             }
             None => false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use crate::query::{HistoryCursor, HistoryOrder};
+    use crate::rndcache::Watermarks;
+    use bitcoincash::blockdata::script::Script;
+    use bitcoincash::blockdata::transaction::TxIn;
+    use bitcoincash::hashes::hex::FromHex;
+
+    const RELAYFEE: f64 = 0.00001; // 1000 sat/kB
+
+    fn p2pkh_out(value: u64) -> TxOut {
+        TxOut {
+            value,
+            script_pubkey: Script::from(
+                hex::decode("76a914ee61d57ab51b9d212335b1dba62794ac20d2bcf988ac").unwrap(),
+            ),
+        }
+    }
+
+    fn broadcast_tx(outputs: Vec<TxOut>) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_hex(&"aa".repeat(32)).unwrap(), 0),
+                script_sig: Script::new(),
+                sequence: 0xffff_ffff,
+                witness: vec![],
+            }],
+            output: outputs,
+        }
+    }
+
+    fn assert_invalid_params(result: Result<()>, expected: &str) {
+        match result.unwrap_err().kind() {
+            ErrorKind::RpcError(RpcErrorCode::InvalidParams, msg) => {
+                assert!(msg.contains(expected), "{}", msg)
+            }
+            e => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn test_precheck_broadcast_valid() {
+        let tx = broadcast_tx(vec![p2pkh_out(546), p2pkh_out(100_000)]);
+        let size = serialize(&tx).len();
+        assert!(precheck_broadcast(&tx, size, 1000, RELAYFEE).is_ok());
+    }
+
+    #[test]
+    fn test_precheck_broadcast_no_inputs() {
+        let mut tx = broadcast_tx(vec![p2pkh_out(1000)]);
+        tx.input.clear();
+        let size = serialize(&tx).len();
+        assert_invalid_params(precheck_broadcast(&tx, size, 1000, RELAYFEE), "no inputs");
+    }
+
+    #[test]
+    fn test_precheck_broadcast_no_outputs() {
+        let tx = broadcast_tx(vec![]);
+        let size = serialize(&tx).len();
+        assert_invalid_params(precheck_broadcast(&tx, size, 1000, RELAYFEE), "no outputs");
+    }
+
+    #[test]
+    fn test_precheck_broadcast_oversized() {
+        let tx = broadcast_tx(vec![p2pkh_out(1000)]);
+        let size = serialize(&tx).len();
+        assert!(precheck_broadcast(&tx, size, size, RELAYFEE).is_ok());
+        assert_invalid_params(
+            precheck_broadcast(&tx, size, size - 1, RELAYFEE),
+            "exceeds the limit",
+        );
+    }
+
+    #[test]
+    fn test_precheck_broadcast_dust() {
+        // P2PKH dust threshold at 1000 sat/kB is 546 sat
+        assert_eq!(dust_threshold(&p2pkh_out(0), RELAYFEE), 546);
+        let tx = broadcast_tx(vec![p2pkh_out(1000), p2pkh_out(545)]);
+        let size = serialize(&tx).len();
+        assert_invalid_params(
+            precheck_broadcast(&tx, size, 1000, RELAYFEE),
+            "output 1 is dust",
+        );
+
+        // OP_RETURN outputs carry no value
+        let op_return = TxOut {
+            value: 0,
+            script_pubkey: Script::from(hex::decode("6a0568656c6c6f").unwrap()),
+        };
+        let tx = broadcast_tx(vec![p2pkh_out(1000), op_return]);
+        let size = serialize(&tx).len();
+        assert!(precheck_broadcast(&tx, size, 1000, RELAYFEE).is_ok());
+    }
+
+    #[test]
+    fn test_outpoint_from_params() {
+        let txid = "aa".repeat(32);
+        let outpoint = outpoint_from_params(&[json!(txid), json!(3)]).unwrap();
+        assert_eq!(outpoint, OutPoint::new(Txid::from_hex(&txid).unwrap(), 3));
+
+        let too_large = outpoint_from_params(&[json!(txid), json!(u64::from(u32::MAX) + 1)]);
+        assert_invalid_params(too_large.map(|_| ()), "Too large value for out_n");
+        assert_invalid_params(
+            outpoint_from_params(&[json!(txid), json!(-1)]).map(|_| ()),
+            "non-integer out_n",
+        );
+    }
+
+    #[test]
+    fn test_outpoint_output() {
+        let tx = broadcast_tx(vec![p2pkh_out(1000), p2pkh_out(2000)]);
+        let outpoint = OutPoint::new(tx.txid(), 1);
+        assert_eq!(outpoint_output(&tx, &outpoint).unwrap().value, 2000);
+
+        let invalid = OutPoint::new(tx.txid(), 2);
+        assert_invalid_params(
+            outpoint_output(&tx, &invalid).map(|_| ()),
+            "out_n 2 does not exist",
+        );
+    }
+
+    #[test]
+    fn test_spender_json() {
+        assert_eq!(spender_json(None), Value::Null);
+
+        let spender = broadcast_tx(vec![p2pkh_out(1000)]);
+        assert_eq!(
+            spender_json(Some((spender.clone(), 0, 600001))),
+            json!({
+                "tx_hash": spender.txid().to_hex(),
+                "tx_pos": 0,
+                "height": 600001,
+            })
+        );
+        // unconfirmed spender
+        assert_eq!(spender_json(Some((spender, 2, 0)))["height"], json!(0));
+    }
+
+    #[test]
+    fn test_tx_batch_entries() {
+        let txid = |i: u8| Txid::from_hex(&format!("{:064x}", i)).unwrap();
+        let params = vec![json!([
+            txid(1).to_hex(),
+            {"tx_hash": txid(2).to_hex(), "verbose": true},
+            {"tx_hash": txid(3).to_hex()},
+        ])];
+        assert_eq!(
+            tx_batch_entries(&params).unwrap(),
+            vec![
+                TxBatchEntry {
+                    txid: txid(1),
+                    verbose: false
+                },
+                TxBatchEntry {
+                    txid: txid(2),
+                    verbose: true
+                },
+                TxBatchEntry {
+                    txid: txid(3),
+                    verbose: false
+                },
+            ]
+        );
+        // the default verbose flag applies to entries without their own
+        let mut params = params;
+        params.push(json!(true));
+        let verbose: Vec<bool> = tx_batch_entries(&params)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.verbose)
+            .collect();
+        assert_eq!(verbose, vec![true, true, true]);
+
+        assert!(tx_batch_entries(&[]).is_err());
+        assert!(tx_batch_entries(&[json!(txid(1).to_hex())]).is_err());
+        assert!(tx_batch_entries(&[json!(["zz"])]).is_err());
+        assert!(tx_batch_entries(&[json!([{"verbose": true}])]).is_err());
+    }
+
+    #[test]
+    fn test_tx_batch_results() {
+        let txid = |i: u8| Txid::from_hex(&format!("{:064x}", i)).unwrap();
+        let entry = |i, verbose| TxBatchEntry {
+            txid: txid(i),
+            verbose,
+        };
+        // txid 1 is cached, txid 2 is fetched from the daemon, txid 3 is
+        // missing and the daemon times out on txid 4
+        let mut daemon_requests = vec![];
+        let mut fetch = |txid: &Txid, verbose: bool| -> Result<Value> {
+            match txid.to_hex().as_str() {
+                hex if hex.ends_with('1') => Ok(json!(format!("cached {}", verbose))),
+                hex if hex.ends_with('2') => {
+                    daemon_requests.push(*txid);
+                    Ok(json!(format!("daemon {}", verbose)))
+                }
+                hex if hex.ends_with('3') => {
+                    daemon_requests.push(*txid);
+                    bail!("No such mempool or blockchain transaction")
+                }
+                _ => bail!(ErrorKind::DaemonTimeout("no reply".to_string())),
+            }
+        };
+        let entries = vec![
+            entry(1, false),
+            entry(3, false),
+            entry(2, false),
+            entry(2, true),
+            entry(2, false),
+            entry(4, false),
+        ];
+        let timeout = TimeoutTrigger::new(Duration::from_secs(60));
+        let results = tx_batch_results(&entries, &timeout, &mut fetch).unwrap();
+        assert_eq!(results[0], json!({"result": "cached false"}));
+        assert_eq!(
+            results[1]["error"]["code"],
+            json!(RpcErrorCode::InternalError as i32)
+        );
+        assert_eq!(results[2], json!({"result": "daemon false"}));
+        assert_eq!(results[3], json!({"result": "daemon true"}));
+        assert_eq!(results[4], results[2]);
+        assert_eq!(
+            results[5]["error"]["code"],
+            json!(RpcErrorCode::Timeout as i32)
+        );
+        // a missing transaction doesn't fail the batch, and repeated entries
+        // are fetched once
+        assert_eq!(daemon_requests, vec![txid(3), txid(2), txid(2)]);
+
+        let timeout = TimeoutTrigger::new(Duration::from_secs(0));
+        assert!(tx_batch_results(&entries, &timeout, |_, _| panic!("timed out")).is_err());
+    }
+
+    #[test]
+    fn test_min_confirmations_height() {
+        // by default, the actual state is reported
+        assert_eq!(min_confirmations_height(100, 100, 0), 100);
+        assert_eq!(min_confirmations_height(0, 100, 0), 0);
+        assert_eq!(min_confirmations_height(-1, 100, 0), -1);
+
+        // confirmed at the tip: 1 confirmation
+        assert_eq!(min_confirmations_height(100, 100, 1), 100);
+        assert_eq!(min_confirmations_height(100, 100, 2), 0);
+        // 6 confirmations
+        assert_eq!(min_confirmations_height(95, 100, 6), 95);
+        assert_eq!(min_confirmations_height(95, 100, 7), 0);
+        // unconfirmed transactions stay as-is
+        assert_eq!(min_confirmations_height(0, 100, 6), 0);
+        assert_eq!(min_confirmations_height(-1, 100, 6), -1);
+        // confirmed above a tip that lags behind
+        assert_eq!(min_confirmations_height(101, 100, 1), 0);
+    }
+
+    // Bitcoin genesis block header
+    const GENESIS_HEADER: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c";
+
+    fn genesis_entry() -> HeaderEntry {
+        let header: bitcoincash::BlockHeader =
+            deserialize(&hex::decode(GENESIS_HEADER).unwrap()).unwrap();
+        crate::util::HeaderList::empty()
+            .order(vec![header])
+            .remove(0)
+    }
+
+    #[test]
+    fn test_tip_json() {
+        let entry = genesis_entry();
+        assert_eq!(
+            tip_json(&entry, None),
+            json!({"hex": GENESIS_HEADER, "height": 0})
+        );
+
+        let chainwork = entry.header().work();
+        let mediantime = entry.header().time;
+        assert_eq!(
+            tip_json(&entry, Some((chainwork, mediantime))),
+            json!({
+                "hex": GENESIS_HEADER,
+                "height": 0,
+                // as reported by bitcoind's getblockheader
+                "chainwork": "0000000000000000000000000000000000000000000000000000000100010001",
+                "mediantime": 1231006505,
+            })
+        );
+    }
+
+    #[test]
+    fn test_address_scripthash_params_parity() {
+        use crate::query::TestQuery;
+
+        let addr = "bitcoincash:qp3wjpa3tjlj042z2wv7hahsldgwhwy0rq9sywjpyy";
+        let token_addr = "bitcoincash:zp3wjpa3tjlj042z2wv7hahsldgwhwy0rqz6hsu8mh";
+        let scripthash = addr_to_scripthash(addr).unwrap();
+        let script = Script::from(
+            hex::decode("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac").unwrap(),
+        );
+        assert_eq!(compute_script_hash(&script[..]), scripthash);
+        let cache = AddressCache::new(1 << 20, Watermarks::default(), &Metrics::dummy());
+
+        // some unconfirmed history, for the results to compare
+        let test_query = TestQuery::new();
+        for value in [1000, 2000] {
+            let output = TxOut {
+                value,
+                script_pubkey: script.clone(),
+            };
+            test_query.add_mempool_tx(broadcast_tx(vec![output]), 300);
+        }
+        let rpc = BlockchainRpc::new(
+            Arc::clone(test_query.query()),
+            Arc::new(RpcStats::dummy()),
+            Arc::new(RelayFee::new(RELAYFEE, Duration::from_secs(3600), 0.0)),
+            ConnectionLimits::default(),
+            Arc::new(GlobalLimits::new(
+                100,
+                100,
+                16,
+                16,
+                0,
+                0,
+                false,
+                &Metrics::dummy(),
+            )),
+        );
+        let timeout = TimeoutTrigger::new(Duration::from_secs(10));
+        // the order of outputs at the same height is unspecified
+        let utxos = |result: Result<Value>| {
+            let mut utxos = result.unwrap().as_array().unwrap().clone();
+            utxos.sort_by_key(|utxo| utxo["tx_hash"].to_string());
+            utxos
+        };
+
+        let cursor = HistoryCursor::After {
+            height: 700000,
+            txid: Txid::from_hex(&"07".repeat(32)).unwrap(),
+        };
+        let flags = [
+            vec![],
+            vec![json!(true)],
+            vec![json!(true), json!(true), json!("desc")],
+            vec![
+                json!(false),
+                json!(false),
+                json!("asc"),
+                json!(cursor.to_hex()),
+                json!(false),
+            ],
+            vec![
+                json!(false),
+                json!(true),
+                json!("asc"),
+                Value::Null,
+                json!(true),
+            ],
+        ];
+        for flags in flags {
+            let params = |first: String| -> Vec<Value> {
+                std::iter::once(json!(first))
+                    .chain(flags.iter().cloned())
+                    .collect()
+            };
+            let by_scripthash = params(scripthash.to_le_hex());
+            let balance = rpc
+                .scripthash_get_balance(&by_scripthash, &timeout)
+                .unwrap();
+            let history = rpc
+                .scripthash_get_history(&by_scripthash, &timeout)
+                .unwrap();
+            let unspent = utxos(rpc.scripthash_listunspent(&by_scripthash, &timeout));
+            assert_eq!(unspent.len(), 2);
+            for addr in [addr, token_addr] {
+                let by_address = params(addr.to_string());
+                assert_eq!(
+                    scripthash_from_params(&by_address, Target::Address, &cache).unwrap(),
+                    scripthash_from_params(&by_scripthash, Target::Scripthash, &cache).unwrap()
+                );
+                assert_eq!(
+                    rpc.address_get_balance(&by_address, &timeout).unwrap(),
+                    balance
+                );
+                assert_eq!(
+                    rpc.address_get_history(&by_address, &timeout).unwrap(),
+                    history
+                );
+                assert_eq!(
+                    utxos(rpc.address_listunspent(&by_address, &timeout)),
+                    unspent
+                );
+            }
+        }
+
+        let params = vec![json!(addr), json!(true), json!(true), json!("desc")];
+        assert_eq!(
+            history_options_from_params(&params).unwrap(),
+            HistoryOptions {
+                tx_info: true,
+                mempool_only: true,
+                order: HistoryOrder::Descending,
+                cursor: None,
+                include_unconfirmed: true,
+            }
+        );
+        assert!(BalanceParams::parse(&params).unwrap().exclude_tokens);
+        // each method rejects the other kind of first parameter
+        assert!(scripthash_from_params(&params, Target::Scripthash, &cache).is_err());
+        let params = vec![json!(scripthash.to_le_hex())];
+        assert!(scripthash_from_params(&params, Target::Address, &cache).is_err());
+    }
+
+    #[test]
+    fn test_headers_range() {
+        assert_eq!(headers_range(10, 5, 2016), 10..15);
+        assert_eq!(headers_range(10, 0, 2016), 10..10);
+        // huge counts are clamped rather than allocated
+        assert_eq!(headers_range(10, usize::MAX, 2016), 10..2026);
+        assert_eq!(headers_range(usize::MAX, usize::MAX, 2016).len(), 0);
+        assert_eq!(headers_range(0, 100, 0).len(), 0);
+    }
+
+    #[test]
+    fn test_height_of_blockhash() {
+        let mut headers = crate::util::HeaderList::empty();
+        let genesis = genesis_entry();
+        headers.apply(std::slice::from_ref(&genesis), *genesis.hash());
+
+        let entry = headers.header_by_blockhash(genesis.hash()).cloned();
+        assert_eq!(height_of_blockhash(genesis.hash(), entry).unwrap(), 0);
+
+        let unknown = BlockHash::from_hex(&"ab".repeat(32)).unwrap();
+        let entry = headers.header_by_blockhash(&unknown).cloned();
+        assert!(entry.is_none());
+        match height_of_blockhash(&unknown, entry).unwrap_err().kind() {
+            ErrorKind::RpcError(RpcErrorCode::NotFound, msg) => {
+                assert!(msg.contains(&unknown.to_hex()))
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_merkle_proof_height() {
+        let txid = Txid::from_hex(&"cd".repeat(32)).unwrap();
+        assert_eq!(merkle_proof_height(&txid, Some(100)).unwrap(), 100);
+
+        for mempool in [0, -1] {
+            match merkle_proof_height(&txid, Some(mempool))
+                .unwrap_err()
+                .kind()
+            {
+                ErrorKind::RpcError(RpcErrorCode::InvalidParams, msg) => {
+                    assert!(msg.contains("in mempool"))
+                }
+                e => panic!("unexpected error {:?}", e),
+            }
+        }
+
+        match merkle_proof_height(&txid, None).unwrap_err().kind() {
+            ErrorKind::RpcError(RpcErrorCode::NotFound, msg) => {
+                assert!(msg.contains(&txid.to_hex()))
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_merkle_proof_error_codes() {
+        // get_merkle adds context, keeping the code of the cause
+        for code in [
+            RpcErrorCode::NotFound,
+            RpcErrorCode::Pruned,
+            RpcErrorCode::Syncing,
+        ] {
+            let e: Error = ErrorKind::RpcError(code, "cause".to_string()).into();
+            let error = batch_error_json(rpc_context(e, "cannot create merkle proof"));
+            assert_eq!(error["code"], json!(code as i32));
+            let msg = error["message"].as_str().unwrap();
+            assert!(
+                msg.ends_with("cannot create merkle proof: cause"),
+                "{}",
+                msg
+            );
+        }
+        // other errors are still internal errors
+        let e = rpc_context("failed".into(), "cannot create merkle proof");
+        assert_eq!(
+            batch_error_json(e)["code"],
+            json!(RpcErrorCode::InternalError as i32)
+        );
+    }
+
+    #[test]
+    fn test_header_json() {
+        let entry = genesis_entry();
+
+        assert_eq!(
+            header_json(&entry),
+            json!({
+                "hash": "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+                "height": 0,
+                "version": 1,
+                "prev_blockhash": "0000000000000000000000000000000000000000000000000000000000000000",
+                "merkle_root": "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+                "time": 1231006505,
+                "bits": "1d00ffff",
+                "nonce": 2083236893,
+            })
+        );
+    }
+
+    #[test]
+    fn test_subscription_memory_boundary() {
+        let alias_bytes = 54 * 2;
+        let max_bytes = subscription_bytes(2, alias_bytes);
+        let limits = ConnectionLimits {
+            max_subscription_bytes: max_bytes as u32,
+            ..ConnectionLimits::default()
+        };
+
+        // Two address subscriptions fit exactly
+        assert!(limits
+            .check_subscription_memory(subscription_bytes(2, alias_bytes))
+            .is_ok());
+        // A third (scripthash) subscription does not
+        assert!(limits
+            .check_subscription_memory(subscription_bytes(3, alias_bytes))
+            .is_err());
+        // Replacing an address subscription with a scripthash one frees the alias
+        assert!(limits
+            .check_subscription_memory(subscription_bytes(2, 54))
+            .is_ok());
+    }
+
+    fn subscription() -> Subscription {
+        Subscription {
+            statushash: None,
+            alias: None,
+        }
+    }
+
+    #[test]
+    fn test_merge_subscriptions() {
+        let stats = RpcStats::dummy();
+        let cashaddr = "bitcoincash:qp3wjpa3tjlj042z2wv7hahsldgwhwy0rq9sywjpyy".to_string();
+        let legacy = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa".to_string();
+        let scripthash = addr_to_scripthash(&cashaddr).unwrap();
+        assert_eq!(addr_to_scripthash(&legacy).unwrap(), scripthash);
+        let mut subscriptions = HashMap::new();
+        let by_address = |alias: &String| Subscription {
+            alias: Some(alias.clone()),
+            ..subscription()
+        };
+
+        // by scripthash, then by address
+        assert_eq!(
+            merge_subscription(&mut subscriptions, scripthash, subscription(), &stats),
+            0
+        );
+        assert_eq!(
+            merge_subscription(
+                &mut subscriptions,
+                scripthash,
+                by_address(&cashaddr),
+                &stats
+            ),
+            cashaddr.len() as isize
+        );
+        assert_eq!(subscriptions.len(), 1);
+        assert_eq!(subscriptions[&scripthash].alias, Some(cashaddr.clone()));
+        assert_eq!(stats.subscriptions.get(), 1);
+        assert_eq!(
+            stats.subscription_bytes.get(),
+            subscription_bytes(1, cashaddr.len()) as i64
+        );
+
+        // by scripthash again keeps notifying by address
+        assert_eq!(
+            merge_subscription(&mut subscriptions, scripthash, subscription(), &stats),
+            0
+        );
+        assert_eq!(subscriptions[&scripthash].alias, Some(cashaddr.clone()));
+
+        // another address form of the same scripthash replaces the alias
+        assert_eq!(
+            merge_subscription(&mut subscriptions, scripthash, by_address(&legacy), &stats),
+            legacy.len() as isize - cashaddr.len() as isize
+        );
+        assert_eq!(subscriptions[&scripthash].alias, Some(legacy.clone()));
+        assert_eq!(stats.subscriptions.get(), 1);
+        assert_eq!(
+            stats.subscription_bytes.get(),
+            subscription_bytes(1, legacy.len()) as i64
+        );
+    }
+
+    #[test]
+    fn test_count_new_subscriptions() {
+        let mut subscriptions = HashMap::new();
+        subscriptions.insert([1; 32], subscription());
+        subscriptions.insert([2; 32], subscription());
+
+        assert_eq!(count_new_subscriptions(&subscriptions, &[]), 0);
+        // already subscribed
+        assert_eq!(
+            count_new_subscriptions(&subscriptions, &[[1; 32], [2; 32]]),
+            0
+        );
+        // duplicates within the batch count once
+        assert_eq!(
+            count_new_subscriptions(&subscriptions, &[[3; 32], [1; 32], [3; 32], [4; 32]]),
+            2
+        );
+    }
+
+    #[test]
+    fn test_clear_subscriptions() {
+        let stats = RpcStats::dummy();
+        let alias = "bitcoincash:qrh8dxwk55pedxnxg7ez8t8h5l0g7pk6wsqjnwgcq6".to_string();
+        let mut subscriptions = HashMap::new();
+        subscriptions.insert([1; 32], subscription());
+        subscriptions.insert([2; 32], subscription());
+        subscriptions.insert(
+            [3; 32],
+            Subscription {
+                alias: Some(alias.clone()),
+                ..subscription()
+            },
+        );
+        // as if subscribed to by another connection
+        stats.subscriptions.set(4);
+        stats
+            .subscription_bytes
+            .set(subscription_bytes(4, alias.len()) as i64);
+
+        assert_eq!(
+            clear_subscriptions(&mut subscriptions, &stats),
+            (3, alias.len())
+        );
+        assert!(subscriptions.is_empty());
+        assert_eq!(stats.subscriptions.get(), 1);
+        assert_eq!(
+            stats.subscription_bytes.get(),
+            SUBSCRIPTION_ENTRY_BYTES as i64
+        );
+
+        // nothing left to remove
+        assert_eq!(clear_subscriptions(&mut subscriptions, &stats), (0, 0));
+        assert_eq!(stats.subscriptions.get(), 1);
+    }
+
+    #[test]
+    fn test_tx_status_confirm() {
+        let txid = |i: u8| Txid::from_hex(&format!("{:02x}", i).repeat(32)).unwrap();
+        let mut subscriptions: HashMap<Txid, TxStatus> = HashMap::new();
+        subscriptions.insert(txid(1), None);
+        subscriptions.insert(txid(2), Some(0));
+
+        // tx 1 enters mempool, tx 3 is not subscribed to
+        let heights: HashMap<Txid, i64> = vec![(txid(1), 0), (txid(2), 0), (txid(3), 0)]
+            .into_iter()
+            .collect();
+        let all = [txid(1), txid(2), txid(3)];
+        let changed = update_tx_statuses(&mut subscriptions, all.iter(), |txid| {
+            Ok(heights.get(txid).cloned())
+        });
+        assert_eq!(changed, vec![(txid(1), Some(0))]);
+
+        // both confirm
+        let heights: HashMap<Txid, i64> =
+            vec![(txid(1), 100), (txid(2), 100)].into_iter().collect();
+        let mut changed = update_tx_statuses(&mut subscriptions, all.iter(), |txid| {
+            Ok(heights.get(txid).cloned())
+        });
+        changed.sort();
+        assert_eq!(changed, vec![(txid(1), Some(100)), (txid(2), Some(100))]);
+
+        // no change, no notification
+        let changed = update_tx_statuses(&mut subscriptions, all.iter(), |txid| {
+            Ok(heights.get(txid).cloned())
+        });
+        assert!(changed.is_empty());
+    }
+
+    #[test]
+    fn test_tx_status_reorg() {
+        let txid = Txid::from_hex(&"aa".repeat(32)).unwrap();
+        let mut subscriptions: HashMap<Txid, TxStatus> = HashMap::new();
+        subscriptions.insert(txid, Some(100));
+
+        // block 100 is reorged, tx back to mempool
+        let changed = update_tx_statuses(&mut subscriptions, [txid].iter(), |_| Ok(Some(0)));
+        assert_eq!(changed, vec![(txid, Some(0))]);
+
+        // confirmed in a block at a different height
+        let changed = update_tx_statuses(&mut subscriptions, [txid].iter(), |_| Ok(Some(101)));
+        assert_eq!(changed, vec![(txid, Some(101))]);
+
+        // failed lookup, status is kept until the next update
+        let changed =
+            update_tx_statuses(&mut subscriptions, [txid].iter(), |_| bail!("get failed"));
+        assert!(changed.is_empty());
+        assert_eq!(subscriptions[&txid], Some(101));
+
+        // double spent in the reorg
+        let changed = update_tx_statuses(&mut subscriptions, [txid].iter(), |_| Ok(None));
+        assert_eq!(changed, vec![(txid, None)]);
+        assert_eq!(subscriptions[&txid], None);
+    }
+
+    #[test]
+    fn test_total_subscription_limit() {
+        let limits = GlobalLimits::new(100, 100, 16, 16, 0, 3, false, &Metrics::dummy());
+        let stats = RpcStats::dummy();
+        // two connections, sharing the stats
+        let mut first = HashMap::new();
+        let mut second = HashMap::new();
+
+        merge_subscription(&mut first, [1; 32], subscription(), &stats);
+        merge_subscription(&mut first, [2; 32], subscription(), &stats);
+        assert!(check_total_subscriptions(&limits, &stats, 1).is_ok());
+        merge_subscription(&mut second, [1; 32], subscription(), &stats);
+
+        // the limit is reached over both connections
+        assert!(check_total_subscriptions(&limits, &stats, 1).is_err());
+        assert!(check_total_subscriptions(&limits, &stats, 0).is_ok());
+        // also for a connection below its own limit
+        let connection_limits = ConnectionLimits {
+            max_subscriptions: 100,
+            max_subscription_bytes: u32::MAX,
+            ..ConnectionLimits::default()
+        };
+        assert!(connection_limits
+            .check_subscriptions(second.len() as u32 + 1)
+            .is_ok());
+
+        // subscriptions released by one connection are available to the other
+        clear_subscriptions(&mut first, &stats);
+        assert!(check_total_subscriptions(&limits, &stats, 2).is_ok());
+        assert!(check_total_subscriptions(&limits, &stats, 3).is_err());
+
+        // 0 is unlimited
+        let limits = GlobalLimits::new(100, 100, 16, 16, 0, 0, false, &Metrics::dummy());
+        assert!(check_total_subscriptions(&limits, &stats, 1_000_000).is_ok());
+    }
+
+    #[test]
+    fn test_bulk_subscription_limit_boundary() {
+        let limits = ConnectionLimits {
+            max_subscriptions: 4,
+            max_subscription_bytes: u32::MAX,
+            ..ConnectionLimits::default()
+        };
+        let mut subscriptions = HashMap::new();
+        subscriptions.insert([1; 32], subscription());
+        subscriptions.insert([2; 32], subscription());
+
+        // 2 existing + 2 new hits the limit exactly
+        let batch = [[1; 32], [3; 32], [4; 32], [4; 32]];
+        let total = subscriptions.len() + count_new_subscriptions(&subscriptions, &batch);
+        assert!(limits.check_subscriptions(total as u32).is_ok());
+
+        // one more exceeds it
+        let batch = [[3; 32], [4; 32], [5; 32]];
+        let total = subscriptions.len() + count_new_subscriptions(&subscriptions, &batch);
+        assert!(limits.check_subscriptions(total as u32).is_err());
+    }
+}
@@ -1,36 +1,53 @@
 use bitcoincash::blockdata::transaction::Transaction;
 use bitcoincash::hash_types::{BlockHash, Txid};
 use error_chain::ChainedError;
+use prometheus::{IntCounter, IntCounterVec, IntGauge};
 use serde_json::{from_str, Value};
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
 use std::collections::{HashMap, HashSet};
-use std::io::{BufRead, BufReader, Write};
+use std::io::{self, BufRead, BufReader, Write};
 use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
-use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
 
 use crate::def::PROTOCOL_VERSION_MAX;
 use crate::doslimit::{ConnectionLimits, GlobalLimits};
 use crate::errors::*;
 use crate::metrics::Metrics;
+use crate::opreturn::MIN_OPRETURN_PREFIX_LEN;
 use crate::query::Query;
 use crate::rpc::blockchain::BlockchainRpc;
-use crate::rpc::parseutil::usize_from_value;
+use crate::rpc::parseutil::{rpc_arg_error, str_from_value, usize_from_value, usize_from_value_or};
+#[cfg(unix)]
+use crate::rpc::peerstream::unix_peer_addr;
+use crate::rpc::peerstream::PeerStream;
+use crate::rpc::relayfee::RelayFee;
 use crate::rpc::rpcstats::RpcStats;
 use crate::rpc::server::{
     server_add_peer, server_banner, server_donation_address, server_features,
-    server_peers_subscribe, server_version,
+    server_peers_subscribe, server_resource_usage, server_version, server_warnings,
 };
+use crate::rpc::session::{MissedChanges, SessionStore};
+use crate::rpc::warnings::DaemonWarnings;
 use crate::scripthash::{compute_script_hash, FullHash};
 use crate::timeout::TimeoutTrigger;
 use crate::util::{spawn_thread, Channel, HeaderEntry};
 
 pub mod blockchain;
 pub mod parseutil;
+pub mod peerstream;
+pub mod relayfee;
 pub mod rpcstats;
 pub mod scripthash;
 pub mod server;
+pub mod session;
+pub mod warnings;
 
 fn get_output_scripthash(txn: &Transaction, n: Option<usize>) -> Vec<FullHash> {
     if let Some(out) = n {
@@ -43,37 +60,308 @@ fn get_output_scripthash(txn: &Transaction, n: Option<usize>) -> Vec<FullHash> {
     }
 }
 
+/// Compress a response line (without the trailing newline), if it is at
+/// least `threshold` bytes long. A compressed line is the base64 encoding of
+/// the raw deflate stream, which can be told apart from JSON by its first
+/// character.
+fn maybe_compress(line: String, threshold: usize) -> String {
+    if line.len() < threshold {
+        return line;
+    }
+    base64::encode(&miniz_oxide::deflate::compress_to_vec(line.as_bytes(), 6))
+}
+
+/// Methods served by `Connection::dispatch` (keep in sync), listed in
+/// unknown method errors when method hints are enabled.
+const METHODS: &[&str] = &[
+    "blockchain.address.get_balance",
+    "blockchain.address.get_first_use",
+    "blockchain.address.get_history",
+    "blockchain.address.get_mempool",
+    "blockchain.address.get_scripthash",
+    "blockchain.address.subscribe",
+    "blockchain.address.listunspent",
+    "blockchain.address.unsubscribe",
+    "blockchain.block.header",
+    "blockchain.block.headers",
+    "blockchain.block.headers_from_hash",
+    "blockchain.block.fee_stats",
+    "blockchain.block.stats",
+    "blockchain.block.tx_count",
+    "blockchain.estimatefee",
+    "blockchain.estimatefees",
+    "blockchain.headers.subscribe",
+    "blockchain.relayfee",
+    "blockchain.mempool.get_txids",
+    "blockchain.opreturn.get_history",
+    "blockchain.outpoint.get_spender",
+    "blockchain.scripthash.get_balance",
+    "blockchain.scripthash.get_first_use",
+    "blockchain.scripthash.get_history",
+    "blockchain.scripthash.get_mempool",
+    "blockchain.scripthash.get_utxos_with_proofs",
+    "blockchain.scripthash.listunspent",
+    "blockchain.scripthash.subscribe",
+    "blockchain.scripthash.subscribe_bulk",
+    "blockchain.scripthash.unsubscribe",
+    "blockchain.scripthash.unsubscribe_all",
+    "blockchain.transaction.broadcast",
+    "blockchain.transaction.get",
+    "blockchain.transaction.get_batch",
+    "blockchain.transaction.get_confirmed_blockhash",
+    "blockchain.transaction.get_confirmed_blockhash_batch",
+    "blockchain.transaction.get_merkle",
+    "blockchain.transaction.id_from_pos",
+    "blockchain.transaction.subscribe",
+    "blockchain.transaction.unsubscribe",
+    "blockchain.utxo.get",
+    "mempool.get_fee_histogram",
+    "server.add_peer",
+    "server.banner",
+    "server.donation_address",
+    "server.features",
+    "server.peers.subscribe",
+    "server.ping",
+    "server.set_compression",
+    "server.resource_usage",
+    "server.session_resume",
+    "server.session_token",
+    "server.version",
+    "server.warnings",
+    "cashaccount.query.name",
+    "cashaccount.query.name_range",
+];
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Error data of an unknown method: the closest known method (if it is a
+/// near miss) and all methods served.
+fn unknown_method_data(method: &str) -> Value {
+    let max_distance = (method.len() / 4).max(2);
+    let suggestion = METHODS
+        .iter()
+        .map(|known| (edit_distance(method, known), known))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, known)| known);
+    json!({
+        "suggestion": suggestion,
+        "methods": METHODS,
+    })
+}
+
+/// Identifies connections in the per-connection metrics, as peer addresses
+/// can be reused by later connections.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
 struct Connection {
     query: Arc<Query>,
-    stream: TcpStream,
+    stream: PeerStream,
     addr: SocketAddr,
     sender: SyncSender<Message>,
     stats: Arc<RpcStats>,
+    warnings: Arc<DaemonWarnings>,
     doslimits: ConnectionLimits,
+    global_limits: Arc<GlobalLimits>,
+    detect_ssl: bool,
+    read_buffer_size: usize,
+    /// Hint at known methods in unknown method errors
+    method_hints: bool,
+    /// Minimal size of compressed responses (0 to disable compression)
+    compression_threshold: usize,
+    /// Compression requested by the client, enabled after the reply
+    compression_requested: bool,
+    compression_enabled: bool,
     blockchainrpc: BlockchainRpc,
+    /// Protocol version negotiated with server.version, if any
+    protocol_version: Option<String>,
+    /// Set when the connection should be closed after the current reply
+    disconnect: bool,
+    /// Labels of this connection in the per-connection metrics
+    metric_labels: [String; 2],
+    /// Subscription memory last reported for this connection
+    subscription_bytes_reported: usize,
+    sessions: Arc<SessionStore>,
+    /// Token for resuming the subscriptions of this connection, once
+    /// requested
+    session_token: Option<String>,
+    /// Changes missed by a resumed session, notified after the reply
+    missed: Option<MissedChanges>,
 }
 
 impl Connection {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         query: Arc<Query>,
-        stream: TcpStream,
+        stream: PeerStream,
         addr: SocketAddr,
         stats: Arc<RpcStats>,
-        relayfee: f64,
+        relayfee: Arc<RelayFee>,
+        warnings: Arc<DaemonWarnings>,
         doslimits: ConnectionLimits,
+        global_limits: Arc<GlobalLimits>,
+        detect_ssl: bool,
+        read_buffer_size: usize,
+        method_hints: bool,
+        compression_threshold: usize,
+        sessions: Arc<SessionStore>,
         sender: SyncSender<Message>,
     ) -> Connection {
+        let blockchainrpc = BlockchainRpc::new(
+            query.clone(),
+            stats.clone(),
+            relayfee,
+            doslimits,
+            Arc::clone(&global_limits),
+        );
         Connection {
-            query: query.clone(),
+            query,
             stream,
             addr,
             sender,
-            stats: stats.clone(),
+            stats,
+            warnings,
             doslimits,
-            blockchainrpc: BlockchainRpc::new(query, stats, relayfee, doslimits),
+            global_limits,
+            detect_ssl,
+            read_buffer_size,
+            method_hints,
+            compression_threshold,
+            compression_requested: false,
+            compression_enabled: false,
+            blockchainrpc,
+            protocol_version: None,
+            disconnect: false,
+            metric_labels: [
+                NEXT_CONNECTION_ID
+                    .fetch_add(1, Ordering::Relaxed)
+                    .to_string(),
+                addr.to_string(),
+            ],
+            subscription_bytes_reported: 0,
+            sessions,
+            session_token: None,
+            missed: None,
+        }
+    }
+
+    /// Update the subscription memory metric of this connection. Connections
+    /// without subscriptions are left out of it.
+    fn report_subscription_bytes(&mut self) {
+        let bytes = self.blockchainrpc.get_subscription_bytes();
+        if bytes == self.subscription_bytes_reported {
+            return;
+        }
+        self.subscription_bytes_reported = bytes;
+        let labels = [&self.metric_labels[0][..], &self.metric_labels[1][..]];
+        if bytes == 0 {
+            let _ = self
+                .stats
+                .connection_subscription_bytes
+                .remove_label_values(&labels);
+        } else {
+            self.stats
+                .connection_subscription_bytes
+                .with_label_values(&labels)
+                .set(bytes as i64);
         }
     }
 
+    fn server_version(&mut self, params: &[Value]) -> Result<Value> {
+        match server_version(params) {
+            Ok(response) => {
+                self.protocol_version = response[1].as_str().map(|v| v.to_string());
+                debug!(
+                    "[{}] negotiated protocol version {:?}",
+                    self.addr, self.protocol_version
+                );
+                Ok(response)
+            }
+            Err(e) => {
+                // We cannot serve a client we don't share a protocol version with.
+                self.disconnect = true;
+                Err(e)
+            }
+        }
+    }
+
+    /// Enable ("deflate") or disable ("none") compression of the responses
+    /// and notifications following this reply.
+    fn server_set_compression(&mut self, params: &[Value]) -> Result<Value> {
+        let method = str_from_value(params.first(), "method")?;
+        self.compression_requested = match method.as_str() {
+            "deflate" if self.compression_threshold > 0 => true,
+            "deflate" => bail!(ErrorKind::RpcError(
+                RpcErrorCode::Other,
+                "compression is disabled on this server".to_string()
+            )),
+            "none" => false,
+            _ => bail!(rpc_invalid_params(format!(
+                "unsupported compression method '{}'",
+                method
+            ))),
+        };
+        Ok(json!(method))
+    }
+
+    /// Token for resuming the subscriptions of this connection with
+    /// server.session_resume, after reconnecting.
+    fn server_session_token(&mut self) -> Result<Value> {
+        self.sessions.check_enabled()?;
+        let token = self
+            .session_token
+            .get_or_insert_with(SessionStore::new_token);
+        Ok(json!(token))
+    }
+
+    /// Restore the subscriptions of a disconnected session, which this
+    /// connection takes over. Changes missed meanwhile are notified after
+    /// the reply.
+    fn server_session_resume(&mut self, params: &[Value]) -> Result<Value> {
+        let token = str_from_value(params.first(), "token")?;
+        let (subscriptions, missed) = self.sessions.take(&token)?;
+        let count = self.blockchainrpc.resume_subscriptions(subscriptions)?;
+        debug!("[{}] resumed {} subscriptions", self.addr, count);
+        self.session_token = Some(token);
+        self.missed = Some(missed);
+        Ok(json!(count))
+    }
+
+    fn notify_missed(&mut self, missed: MissedChanges) -> Result<()> {
+        for scripthash in missed.scripthashes {
+            if let Some(n) = self.blockchainrpc.on_scripthash_change(scripthash)? {
+                self.send_values(&[n])?;
+            }
+        }
+        let notifications = match missed.chaintip {
+            Some(tip) => {
+                if let Some(n) = self.blockchainrpc.on_chaintip_change(tip)? {
+                    self.send_values(&[n])?;
+                }
+                self.blockchainrpc.on_txs_change(None)
+            }
+            None if !missed.txids.is_empty() => {
+                self.blockchainrpc.on_txs_change(Some(&missed.txids))
+            }
+            None => vec![],
+        };
+        self.send_values(&notifications)
+    }
+
     fn mempool_get_fee_histogram(&self) -> Value {
         json!(self.query.get_fee_histogram())
     }
@@ -86,88 +374,178 @@ impl Connection {
         self.query.get_cashaccount_txs(name, height as u32)
     }
 
-    fn handle_command(&mut self, method: &str, params: &[Value], id: &Value) -> Value {
-        let timer = self
-            .stats
-            .latency
-            .with_label_values(&[method])
-            .start_timer();
-        let timeout = TimeoutTrigger::new(Duration::from_secs(self.doslimits.rpc_timeout as u64));
-        let result = match method {
+    fn cashaccount_query_name_range(&self, params: &[Value]) -> Result<Value> {
+        let name = str_from_value(params.first(), "name")?;
+        let min_height = usize_from_value_or(params.get(1), "min_height", 0)?;
+        let max_height = usize_from_value_or(params.get(2), "max_height", u32::MAX as usize)?;
+        let heights =
+            (min_height.min(u32::MAX as usize) as u32)..=(max_height.min(u32::MAX as usize) as u32);
+
+        self.query.get_cashaccount_txs_range(
+            &name,
+            heights,
+            self.doslimits.max_cashaccount_registrations as usize,
+        )
+    }
+
+    fn opreturn_get_history(&self, params: &[Value], timeout: &TimeoutTrigger) -> Result<Value> {
+        let prefix = str_from_value(params.first(), "prefix")?;
+        let prefix =
+            hex::decode(prefix).chain_err(|| rpc_arg_error("prefix is not a hex string"))?;
+        if prefix.len() < MIN_OPRETURN_PREFIX_LEN {
+            bail!(rpc_arg_error(&format!(
+                "prefix must be at least {} bytes",
+                MIN_OPRETURN_PREFIX_LEN
+            )));
+        }
+        self.query
+            .get_opreturn_history(&prefix, self.doslimits.max_opreturn_txs as usize, timeout)
+    }
+
+    fn block_stats(&self, params: &[Value]) -> Result<Value> {
+        let height = usize_from_value(params.first(), "height")?;
+        self.query.get_block_stats(height)
+    }
+
+    fn block_fee_stats(&self, params: &[Value]) -> Result<Value> {
+        let height = usize_from_value(params.first(), "height")?;
+        self.query.get_block_fee_stats(height)
+    }
+
+    fn dispatch(
+        &mut self,
+        method: &str,
+        params: &[Value],
+        timeout: &TimeoutTrigger,
+    ) -> Result<Value> {
+        match method {
             "blockchain.address.get_balance" => {
-                self.blockchainrpc.address_get_balance(params, &timeout)
+                self.blockchainrpc.address_get_balance(params, timeout)
             }
             "blockchain.address.get_first_use" => self.blockchainrpc.address_get_first_use(params),
             "blockchain.address.get_history" => {
-                self.blockchainrpc.address_get_history(params, &timeout)
+                self.blockchainrpc.address_get_history(params, timeout)
             }
             "blockchain.address.get_mempool" => {
-                self.blockchainrpc.address_get_mempool(params, &timeout)
+                self.blockchainrpc.address_get_mempool(params, timeout)
             }
             "blockchain.address.get_scripthash" => {
                 self.blockchainrpc.address_get_scripthash(params)
             }
-            "blockchain.address.subscribe" => {
-                self.blockchainrpc.address_subscribe(params, &timeout)
-            }
+            "blockchain.address.subscribe" => self.blockchainrpc.address_subscribe(params, timeout),
             "blockchain.address.listunspent" => {
-                self.blockchainrpc.address_listunspent(params, &timeout)
+                self.blockchainrpc.address_listunspent(params, timeout)
             }
             "blockchain.address.unsubscribe" => self.blockchainrpc.address_unsubscribe(params),
             "blockchain.block.header" => self.blockchainrpc.block_header(params),
             "blockchain.block.headers" => self.blockchainrpc.block_headers(params),
+            "blockchain.block.headers_from_hash" => {
+                self.blockchainrpc.block_headers_from_hash(params)
+            }
+            "blockchain.block.fee_stats" => self.block_fee_stats(params),
+            "blockchain.block.stats" => self.block_stats(params),
+            "blockchain.block.tx_count" => self.blockchainrpc.block_tx_count(params),
             "blockchain.estimatefee" => self.blockchainrpc.estimatefee(params),
-            "blockchain.headers.subscribe" => self.blockchainrpc.headers_subscribe(),
+            "blockchain.estimatefees" => self.blockchainrpc.estimatefees(params),
+            "blockchain.headers.subscribe" => self.blockchainrpc.headers_subscribe(params),
             "blockchain.relayfee" => self.blockchainrpc.relayfee(),
+            "blockchain.mempool.get_txids" => self.blockchainrpc.mempool_get_txids(params),
+            "blockchain.opreturn.get_history" => self.opreturn_get_history(params, timeout),
+            "blockchain.outpoint.get_spender" => {
+                self.blockchainrpc.outpoint_get_spender(params, timeout)
+            }
             "blockchain.scripthash.get_balance" => {
-                self.blockchainrpc.scripthash_get_balance(params, &timeout)
+                self.blockchainrpc.scripthash_get_balance(params, timeout)
             }
             "blockchain.scripthash.get_first_use" => {
                 self.blockchainrpc.scripthash_get_first_use(params)
             }
             "blockchain.scripthash.get_history" => {
-                self.blockchainrpc.scripthash_get_history(params, &timeout)
+                self.blockchainrpc.scripthash_get_history(params, timeout)
             }
             "blockchain.scripthash.get_mempool" => {
-                self.blockchainrpc.scripthash_get_mempool(params, &timeout)
+                self.blockchainrpc.scripthash_get_mempool(params, timeout)
             }
+            "blockchain.scripthash.get_utxos_with_proofs" => self
+                .blockchainrpc
+                .scripthash_get_utxos_with_proofs(params, timeout),
             "blockchain.scripthash.listunspent" => {
-                self.blockchainrpc.scripthash_listunspent(params, &timeout)
+                self.blockchainrpc.scripthash_listunspent(params, timeout)
             }
             "blockchain.scripthash.subscribe" => {
-                self.blockchainrpc.scripthash_subscribe(params, &timeout)
+                self.blockchainrpc.scripthash_subscribe(params, timeout)
             }
+            "blockchain.scripthash.subscribe_bulk" => self
+                .blockchainrpc
+                .scripthash_subscribe_bulk(params, timeout),
             "blockchain.scripthash.unsubscribe" => {
                 self.blockchainrpc.scripthash_unsubscribe(params)
             }
+            "blockchain.scripthash.unsubscribe_all" => {
+                self.blockchainrpc.scripthash_unsubscribe_all()
+            }
             "blockchain.transaction.broadcast" => self.blockchainrpc.transaction_broadcast(params),
-            "blockchain.transaction.get" => self.blockchainrpc.transaction_get(params),
+            "blockchain.transaction.get" => self.blockchainrpc.transaction_get(params, timeout),
+            "blockchain.transaction.get_batch" => {
+                self.blockchainrpc.transaction_get_batch(params, timeout)
+            }
             "blockchain.transaction.get_confirmed_blockhash" => self
                 .blockchainrpc
                 .transaction_get_confirmed_blockhash(params),
+            "blockchain.transaction.get_confirmed_blockhash_batch" => self
+                .blockchainrpc
+                .transaction_get_confirmed_blockhash_batch(params),
             "blockchain.transaction.get_merkle" => {
                 self.blockchainrpc.transaction_get_merkle(params)
             }
             "blockchain.transaction.id_from_pos" => {
                 self.blockchainrpc.transaction_id_from_pos(params)
             }
-            "blockchain.utxo.get" => self.blockchainrpc.utxo_get(params, &timeout),
+            "blockchain.transaction.subscribe" => self.blockchainrpc.transaction_subscribe(params),
+            "blockchain.transaction.unsubscribe" => {
+                self.blockchainrpc.transaction_unsubscribe(params)
+            }
+            "blockchain.utxo.get" => self.blockchainrpc.utxo_get(params, timeout),
             "mempool.get_fee_histogram" => Ok(self.mempool_get_fee_histogram()),
             "server.add_peer" => server_add_peer(),
-            "server.banner" => server_banner(&self.query),
+            "server.banner" => server_banner(&self.query, &self.global_limits),
             "server.donation_address" => server_donation_address(),
             "server.features" => server_features(&self.query),
             "server.peers.subscribe" => server_peers_subscribe(),
             "server.ping" => Ok(Value::Null),
-            "server.version" => server_version(params),
+            "server.set_compression" => self.server_set_compression(params),
+            "server.resource_usage" => {
+                server_resource_usage(&self.query, &self.stats, &self.global_limits)
+            }
+            "server.session_resume" => self.server_session_resume(params),
+            "server.session_token" => self.server_session_token(),
+            "server.version" => self.server_version(params),
+            "server.warnings" => server_warnings(&self.warnings),
             "cashaccount.query.name" => self.cashaccount_query_name(params),
+            "cashaccount.query.name_range" => self.cashaccount_query_name_range(params),
             &_ => Err(ErrorKind::RpcError(
                 RpcErrorCode::MethodNotFound,
                 format!("unknown method {}", method),
             )
             .into()),
-        };
+        }
+    }
+
+    fn handle_command(&mut self, method: &str, params: &[Value], id: &Value) -> Value {
+        let timer = self
+            .stats
+            .latency
+            .with_label_values(&[method])
+            .start_timer();
+        let timeout = TimeoutTrigger::new(Duration::from_secs(self.doslimits.rpc_timeout as u64));
+        // The request slot is held until the method returns
+        let global_limits = Arc::clone(&self.global_limits);
+        let result = global_limits
+            .start_request(&self.addr.ip())
+            .and_then(|_request| self.dispatch(method, params, &timeout))
+            .map_err(rpc_daemon_timeout);
         timer.observe_duration();
+        self.report_subscription_bytes();
         // TODO: return application errors should be sent to the client
         if let Err(e) = result {
             match *e.kind() {
@@ -176,12 +554,16 @@ impl Connection {
                     // an error descrption.
                     let errmsgs: Vec<String> = e.iter().take(2).map(|x| x.to_string()).collect();
                     let errmsgs = errmsgs.join("; ");
-                    json!({"jsonrpc": "2.0",
+                    let mut reply = json!({"jsonrpc": "2.0",
                     "id": id,
                     "error": {
                         "code": *code as i32,
                         "message": errmsgs,
-                    }})
+                    }});
+                    if self.method_hints && matches!(code, RpcErrorCode::MethodNotFound) {
+                        reply["error"]["data"] = unknown_method_data(method);
+                    }
+                    reply
                 }
                 _ => {
                     warn!(
@@ -207,7 +589,11 @@ impl Connection {
 
     pub fn send_values(&mut self, values: &[Value]) -> Result<()> {
         for value in values {
-            let line = value.to_string() + "\n";
+            let line = if self.compression_enabled {
+                maybe_compress(value.to_string(), self.compression_threshold)
+            } else {
+                value.to_string()
+            } + "\n";
             if let Err(e) = self.stream.write_all(line.as_bytes()) {
                 let truncated: String = line.chars().take(80).collect();
                 return Err(e).chain_err(|| format!("failed to send {}", truncated));
@@ -216,12 +602,20 @@ impl Connection {
         Ok(())
     }
 
-    fn handle_replies(&mut self, receiver: Receiver<Message>) -> Result<()> {
+    fn handle_replies(&mut self, receiver: &Receiver<Message>) -> Result<()> {
         let empty_params = json!([]);
+        let mut last_request = Instant::now();
         loop {
-            let msg = receiver.recv().chain_err(|| "channel closed")?;
+            let msg = match recv_until_idle(receiver, self.doslimits.idle_timeout, last_request)? {
+                Some(msg) => msg,
+                None => {
+                    debug!("[{}] disconnecting idle peer", self.addr);
+                    return Ok(());
+                }
+            };
             match msg {
                 Message::Request(line) => {
+                    last_request = Instant::now();
                     trace!("RPC {:?}", line);
                     let cmd: Value = from_str(&line).chain_err(|| "invalid JSON format")?;
                     let reply = match (
@@ -234,7 +628,18 @@ impl Connection {
                         }
                         _ => bail!("invalid command: {}", cmd),
                     };
-                    self.send_values(&[reply])?
+                    self.send_values(&[reply])?;
+                    self.compression_enabled = self.compression_requested;
+                    if let Some(missed) = self.missed.take() {
+                        self.notify_missed(missed)?;
+                    }
+                    if self.disconnect {
+                        info!(
+                            "[{}] incompatible protocol version, disconnecting",
+                            self.addr
+                        );
+                        return Ok(());
+                    }
                 }
                 Message::ScriptHashChange(hash) => {
                     let notification = self.blockchainrpc.on_scripthash_change(hash)?;
@@ -247,25 +652,52 @@ impl Connection {
                     if let Some(n) = notification {
                         self.send_values(&[n])?;
                     }
+                    // confirmations and reorgs
+                    let notifications = self.blockchainrpc.on_txs_change(None);
+                    self.send_values(&notifications)?;
+                }
+                Message::TxsChange(txids) => {
+                    let notifications = self.blockchainrpc.on_txs_change(Some(&txids));
+                    self.send_values(&notifications)?;
+                }
+                Message::Abort(reason) => {
+                    let error = json!({"jsonrpc": "2.0",
+                    "id": Value::Null,
+                    "error": {
+                        "code": RpcErrorCode::InvalidRequest as i32,
+                        "message": reason,
+                    }});
+                    self.send_values(&[error])?;
+                    return Ok(());
                 }
                 Message::Done => return Ok(()),
             }
         }
     }
 
-    fn parse_requests(mut reader: BufReader<TcpStream>, tx: SyncSender<Message>) -> Result<()> {
+    fn parse_requests(
+        mut reader: impl BufRead,
+        tx: SyncSender<Message>,
+        detect_ssl: bool,
+    ) -> Result<()> {
         loop {
             let mut line = Vec::<u8>::new();
-            reader
-                .read_until(b'\n', &mut line)
-                .chain_err(|| "failed to read a request")?;
+            if let Err(e) = reader.read_until(b'\n', &mut line) {
+                // e.g. read timeout or connection reset, close the connection.
+                let _ = tx.send(Message::Done);
+                return Err(e).chain_err(|| "failed to read a request");
+            }
             if line.is_empty() {
                 tx.send(Message::Done).chain_err(|| "channel closed")?;
                 return Ok(());
             } else {
-                if line.starts_with(&[22, 3, 1]) {
+                if detect_ssl && line.starts_with(&[22, 3, 1]) {
                     // (very) naive SSL handshake detection
-                    let _ = tx.send(Message::Done);
+                    let _ = tx.send(Message::Abort(
+                        "SSL/TLS is not supported on this port. \
+                        Connect without SSL, or use the WebSocket endpoint."
+                            .to_string(),
+                    ));
                     bail!("invalid request - maybe SSL-encrypted data?: {:?}", line)
                 }
                 match String::from_utf8(line) {
@@ -281,20 +713,62 @@ impl Connection {
         }
     }
 
+    /// Keep the subscriptions of the disconnecting peer for resuming them,
+    /// along with the changes it was not notified of.
+    fn save_session(&self, token: String, receiver: &Receiver<Message>) {
+        let subscriptions = self.blockchainrpc.save_subscriptions();
+        debug!(
+            "[{}] keeping {} subscriptions for session resume",
+            self.addr,
+            subscriptions.len()
+        );
+        let prefix = self.global_limits.get_prefix(&self.addr.ip());
+        self.sessions.save(token, prefix, subscriptions);
+        // the store tracks changes from now on, the ones still queued are
+        // added to them
+        for msg in receiver.try_iter() {
+            match msg {
+                Message::ScriptHashChange(hash) => self.sessions.on_scripthash_change(&hash),
+                Message::ChainTipChange(tip) => self.sessions.on_chaintip_change(&tip),
+                Message::TxsChange(txids) => self.sessions.on_txs_change(&txids),
+                _ => {}
+            }
+        }
+    }
+
     pub fn run(mut self, receiver: Receiver<Message>) {
-        let reader = BufReader::new(self.stream.try_clone().expect("failed to clone TcpStream"));
+        let reader = BufReader::with_capacity(
+            self.read_buffer_size,
+            self.stream.try_clone().expect("failed to clone stream"),
+        );
         let sender = self.sender.clone();
-        let child = spawn_thread("reader", || Connection::parse_requests(reader, sender));
-        if let Err(e) = self.handle_replies(receiver) {
+        let detect_ssl = self.detect_ssl;
+        let child = spawn_thread("reader", move || {
+            Connection::parse_requests(reader, sender, detect_ssl)
+        });
+        if let Err(e) = self.handle_replies(&receiver) {
             error!(
                 "[{}] connection handling failed: {}",
                 self.addr,
                 e.display_chain().to_string()
             );
         }
+        if let Some(token) = self.session_token.take() {
+            if self.sessions.is_enabled() {
+                self.save_session(token, &receiver);
+            }
+        }
         self.stats
             .subscriptions
             .sub(self.blockchainrpc.get_num_subscriptions());
+        self.stats
+            .subscription_bytes
+            .sub(self.blockchainrpc.get_subscription_bytes() as i64);
+        let labels = [&self.metric_labels[0][..], &self.metric_labels[1][..]];
+        let _ = self
+            .stats
+            .connection_subscription_bytes
+            .remove_label_values(&labels);
         debug!("[{}] shutting down connection", self.addr);
         let _ = self.stream.shutdown(Shutdown::Both);
         if let Err(err) = child.join().expect("receiver panicked") {
@@ -303,56 +777,313 @@ impl Connection {
     }
 }
 
+/// Wait for the next message. Returns None when no request was received
+/// within `idle_timeout` (if any) since `last_request`.
+fn recv_until_idle(
+    receiver: &Receiver<Message>,
+    idle_timeout: Option<Duration>,
+    last_request: Instant,
+) -> Result<Option<Message>> {
+    let idle_timeout = match idle_timeout {
+        Some(timeout) => timeout,
+        None => return Ok(Some(receiver.recv().chain_err(|| "channel closed")?)),
+    };
+    match receiver.recv_timeout(idle_timeout.saturating_sub(last_request.elapsed())) {
+        Ok(msg) => Ok(Some(msg)),
+        Err(RecvTimeoutError::Timeout) => Ok(None),
+        Err(RecvTimeoutError::Disconnected) => bail!("channel closed"),
+    }
+}
+
 #[derive(Debug)]
 pub enum Message {
     Request(String),
     ScriptHashChange(FullHash),
     ChainTipChange(HeaderEntry),
+    TxsChange(Arc<HashSet<Txid>>),
+    /// Send error to client and close the connection
+    Abort(String),
     Done,
 }
 
+/// Notification channel to a connected peer
+struct PeerSender {
+    sender: SyncSender<Message>,
+    /// Used for disconnecting the peer if it does not keep up
+    stream: PeerStream,
+    addr: SocketAddr,
+    /// Notifications dropped in a row due to a full message queue
+    dropped: usize,
+}
+
+impl PeerSender {
+    /// Queue a notification to the peer. Returns false if the peer has
+    /// disconnected, or was disconnected for dropping `max_dropped`
+    /// notifications in a row (0 = never).
+    fn notify(&mut self, msg: Message, max_dropped: usize, dropped: &IntCounterVec) -> bool {
+        match self.sender.try_send(msg) {
+            Ok(()) => {
+                self.dropped = 0;
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                dropped.with_label_values(&["queue_full"]).inc();
+                self.dropped += 1;
+                if max_dropped == 0 || self.dropped < max_dropped {
+                    return true;
+                }
+                warn!(
+                    "[{}] disconnecting slow peer ({} notifications dropped)",
+                    self.addr, self.dropped
+                );
+                let _ = self.stream.shutdown(Shutdown::Both);
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                dropped.with_label_values(&["disconnected"]).inc();
+                debug!("[{}] peer disconnected", self.addr);
+                false
+            }
+        }
+    }
+}
+
 pub enum Notification {
     ScriptHashChange(FullHash),
     ChainTipChange(HeaderEntry),
+    /// Transactions added to or removed from mempool
+    TxsChange(Arc<HashSet<Txid>>),
     Exit,
 }
 
+/// Socket options for the RPC listener and its accepted connections.
+#[derive(Clone, Copy, Debug)]
+pub struct SocketOptions {
+    pub listen_backlog: i32,
+    /// Idle time before sending TCP keepalive probes (None to disable).
+    pub keepalive: Option<Duration>,
+    /// Disconnect peers that send nothing for this long (None to disable).
+    pub read_timeout: Option<Duration>,
+    /// Capacity of the buffer reading requests from a peer.
+    pub read_buffer_size: usize,
+}
+
+impl SocketOptions {
+    fn bind(&self, addr: SocketAddr) -> std::io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(self.listen_backlog)?;
+        Ok(socket.into())
+    }
+
+    fn configure(&self, stream: &TcpStream) -> std::io::Result<()> {
+        if let Some(keepalive) = self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))?;
+        }
+        stream.set_read_timeout(self.read_timeout)
+    }
+}
+
+/// Threads serving peers, at most `max_threads` at a time (0 for no limit).
+/// Each thread is named after the id of the connection it serves.
+struct PeerThreads {
+    threads: HashMap<ThreadId, thread::JoinHandle<()>>,
+    connections: u64,
+    finished_sender: crossbeam_channel::Sender<ThreadId>,
+    finished: crossbeam_channel::Receiver<ThreadId>,
+    max_threads: usize,
+    active: IntGauge,
+}
+
+impl PeerThreads {
+    fn new(max_threads: usize, active: IntGauge) -> PeerThreads {
+        let (finished_sender, finished) = crossbeam_channel::unbounded();
+        active.set(0);
+        PeerThreads {
+            threads: HashMap::new(),
+            connections: 0,
+            finished_sender,
+            finished,
+            max_threads,
+            active,
+        }
+    }
+
+    /// Join the threads of disconnected peers
+    fn join_finished(&mut self) {
+        while let Ok(id) = self.finished.try_recv() {
+            if let Some(thread) = self.threads.remove(&id) {
+                trace!("joining {:?}", id);
+                if let Err(error) = thread.join() {
+                    error!("failed to join {:?}: {:?}", id, error);
+                }
+            }
+        }
+        self.active.set(self.threads.len() as i64);
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.join_finished();
+        self.max_threads > 0 && self.threads.len() >= self.max_threads
+    }
+
+    fn spawn<F>(&mut self, f: F) -> ThreadId
+    where
+        F: 'static + Send + FnOnce(),
+    {
+        let finished_sender = self.finished_sender.clone();
+        self.connections += 1;
+        let name = format!("peer-{}", self.connections);
+        let spawned = spawn_thread(&name, move || {
+            f();
+            let _ = finished_sender.send(thread::current().id());
+        });
+        let id = spawned.thread().id();
+        self.threads.insert(id, spawned);
+        self.join_finished();
+        id
+    }
+
+    fn len(&self) -> usize {
+        self.threads.len()
+    }
+
+    fn join_all(self) {
+        for (id, thread) in self.threads {
+            trace!("joining {:?}", id);
+            if let Err(error) = thread.join() {
+                error!("failed to join {:?}: {:?}", id, error);
+            }
+        }
+        self.active.set(0);
+    }
+}
+
+/// Tell a peer that can't be served right now (because of `reason`) to retry
+/// later, and disconnect it.
+fn reject_busy(mut stream: PeerStream, addr: SocketAddr, reason: &str) {
+    let reply = json!({
+        "jsonrpc": "2.0",
+        "id": Value::Null,
+        "error": {
+            "code": RpcErrorCode::ServerBusy as i32,
+            "message": format!("server busy: {}, retry later", reason),
+        },
+    });
+    if let Err(e) = stream.write_all(format!("{}\n", reply).as_bytes()) {
+        trace!("[{}] failed to send busy error: {}", addr, e);
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// Delay before retrying a failed accept, doubling on each consecutive
+/// failure up to `ACCEPT_BACKOFF_MAX`.
+struct AcceptBackoff {
+    delay: Duration,
+}
+
+impl AcceptBackoff {
+    fn new() -> AcceptBackoff {
+        AcceptBackoff {
+            delay: Duration::ZERO,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.delay = Duration::ZERO;
+    }
+
+    /// Returns a random delay between half and all of the current backoff,
+    /// so that acceptors don't retry in lockstep.
+    fn next_delay(&mut self) -> Duration {
+        self.delay = (self.delay * 2).clamp(ACCEPT_BACKOFF_MIN, ACCEPT_BACKOFF_MAX);
+        let half = self.delay / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// Errors meaning the listening socket itself is gone, as opposed to
+/// transient ones such as running out of file descriptors.
+fn is_fatal_accept_error(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EBADF)
+            | Some(libc::EINVAL)
+            | Some(libc::ENOTSOCK)
+            | Some(libc::EOPNOTSUPP)
+            | Some(libc::EFAULT)
+    )
+}
+
+/// Handle a failed accept on `listener`. Panics if the error is fatal,
+/// otherwise waits before the next attempt.
+fn accept_failed(listener: &str, e: io::Error, backoff: &mut AcceptBackoff, errors: &IntCounter) {
+    errors.inc();
+    if is_fatal_accept_error(&e) {
+        panic!("accept on {} failed: {}", listener, e);
+    }
+    let delay = backoff.next_delay();
+    warn!(
+        "accept on {} failed: {} (retrying in {:?})",
+        listener, e, delay
+    );
+    thread::sleep(delay);
+}
+
 pub struct Rpc {
     notification: Sender<Notification>,
     server: Option<thread::JoinHandle<()>>, // so we can join the server while dropping this ojbect
     query: Arc<Query>,
 }
 
+/// Notifies each peer, dropping the ones `notify` returns false for.
+fn retain_peers(senders: &mut Vec<PeerSender>, mut notify: impl FnMut(&mut PeerSender) -> bool) {
+    *senders = senders
+        .drain(..)
+        .filter_map(|mut peer| if notify(&mut peer) { Some(peer) } else { None })
+        .collect();
+}
+
 impl Rpc {
     fn start_notifier(
         notification: Channel<Notification>,
-        senders: Arc<Mutex<Vec<SyncSender<Message>>>>,
-        acceptor: Sender<Option<(TcpStream, SocketAddr)>>,
+        senders: Arc<Mutex<Vec<PeerSender>>>,
+        acceptor: Sender<Option<(PeerStream, SocketAddr)>>,
+        max_dropped: usize,
+        dropped: IntCounterVec,
+        sessions: Arc<SessionStore>,
     ) {
         spawn_thread("notification", move || {
             for msg in notification.receiver().iter() {
+                sessions.expire();
                 let mut senders = senders.lock().unwrap();
                 match msg {
-                    Notification::ScriptHashChange(hash) => senders.retain(|sender| {
-                        if let Err(TrySendError::Disconnected(_)) =
-                            sender.try_send(Message::ScriptHashChange(hash))
-                        {
-                            debug!("peer disconnected");
-                            false
-                        } else {
-                            true
-                        }
-                    }),
-                    Notification::ChainTipChange(hash) => senders.retain(|sender| {
-                        if let Err(TrySendError::Disconnected(_)) =
-                            sender.try_send(Message::ChainTipChange(hash.clone()))
-                        {
-                            debug!("peer disconnected");
-                            false
-                        } else {
-                            true
-                        }
-                    }),
+                    Notification::ScriptHashChange(hash) => {
+                        sessions.on_scripthash_change(&hash);
+                        retain_peers(&mut senders, |peer| {
+                            peer.notify(Message::ScriptHashChange(hash), max_dropped, &dropped)
+                        })
+                    }
+                    Notification::ChainTipChange(hash) => {
+                        sessions.on_chaintip_change(&hash);
+                        retain_peers(&mut senders, |peer| {
+                            peer.notify(
+                                Message::ChainTipChange(hash.clone()),
+                                max_dropped,
+                                &dropped,
+                            )
+                        })
+                    }
+                    Notification::TxsChange(txids) => {
+                        sessions.on_txs_change(&txids);
+                        retain_peers(&mut senders, |peer| {
+                            peer.notify(Message::TxsChange(txids.clone()), max_dropped, &dropped)
+                        })
+                    }
                     // mark acceptor as done
                     Notification::Exit => acceptor.send(None).unwrap(),
                 }
@@ -360,22 +1091,38 @@ impl Rpc {
         });
     }
 
-    fn start_acceptor(addr: SocketAddr) -> Channel<Option<(TcpStream, SocketAddr)>> {
+    fn start_acceptor(
+        addr: SocketAddr,
+        socket_options: SocketOptions,
+        accept_errors: IntCounter,
+    ) -> Channel<Option<(PeerStream, SocketAddr)>> {
         let chan = Channel::unbounded();
         let acceptor = chan.sender();
         spawn_thread("acceptor", move || {
-            let listener =
-                TcpListener::bind(addr).unwrap_or_else(|e| panic!("bind({}) failed: {}", addr, e));
+            let listener = socket_options
+                .bind(addr)
+                .unwrap_or_else(|e| panic!("bind({}) failed: {}", addr, e));
             info!(
                 "Electrum RPC server running on {} (protocol {})",
                 addr, PROTOCOL_VERSION_MAX
             );
+            let mut backoff = AcceptBackoff::new();
             loop {
-                let (stream, addr) = listener.accept().expect("accept failed");
+                let (stream, addr) = match listener.accept() {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        accept_failed(&addr.to_string(), e, &mut backoff, &accept_errors);
+                        continue;
+                    }
+                };
+                backoff.reset();
                 stream
                     .set_nonblocking(false)
                     .expect("failed to set connection as blocking");
-                match acceptor.send(Some((stream, addr))) {
+                if let Err(e) = socket_options.configure(&stream) {
+                    warn!("[{}] failed to set socket options: {}", addr, e);
+                }
+                match acceptor.send(Some((PeerStream::Tcp(stream), addr))) {
                     Ok(_) => {}
                     Err(e) => trace!("Failed to send to client {:?}", e),
                 }
@@ -384,14 +1131,78 @@ impl Rpc {
         chan
     }
 
+    /// Accept peers on a Unix domain socket at `path`, passing them to the
+    /// same `acceptor` as TCP peers.
+    #[cfg(unix)]
+    fn start_unix_acceptor(
+        path: PathBuf,
+        read_timeout: Option<Duration>,
+        acceptor: Sender<Option<(PeerStream, SocketAddr)>>,
+        accept_errors: IntCounter,
+    ) -> Result<()> {
+        use std::os::unix::fs::FileTypeExt;
+        // remove the socket left behind by a previous run
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+            if metadata.file_type().is_socket() {
+                std::fs::remove_file(&path)
+                    .chain_err(|| format!("failed to remove stale socket {:?}", path))?;
+            }
+        }
+        let listener =
+            UnixListener::bind(&path).chain_err(|| format!("bind({:?}) failed", path))?;
+        info!("Electrum RPC server running on unix socket {:?}", path);
+        spawn_thread("unix-acceptor", move || {
+            let mut backoff = AcceptBackoff::new();
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => PeerStream::Unix(stream),
+                    Err(e) => {
+                        let name = path.display().to_string();
+                        accept_failed(&name, e, &mut backoff, &accept_errors);
+                        continue;
+                    }
+                };
+                backoff.reset();
+                if let Err(e) = stream.set_read_timeout(read_timeout) {
+                    warn!("[{:?}] failed to set socket options: {}", path, e);
+                }
+                if let Err(e) = acceptor.send(Some((stream, unix_peer_addr()))) {
+                    trace!("Failed to send to client {:?}", e);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn start_unix_acceptor(
+        path: PathBuf,
+        _read_timeout: Option<Duration>,
+        _acceptor: Sender<Option<(PeerStream, SocketAddr)>>,
+        _accept_errors: IntCounter,
+    ) -> Result<()> {
+        bail!("unix socket {:?} is not supported on this platform", path)
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn start(
         addr: SocketAddr,
         query: Arc<Query>,
         metrics: Arc<Metrics>,
-        relayfee: f64,
+        relayfee: Arc<RelayFee>,
+        warnings: Arc<DaemonWarnings>,
         connection_limits: ConnectionLimits,
         global_limits: Arc<GlobalLimits>,
         rpc_buffer_size: usize,
+        max_dropped_notifications: usize,
+        detect_ssl: bool,
+        method_hints: bool,
+        compression_threshold: usize,
+        max_peer_threads: usize,
+        socket_options: SocketOptions,
+        unix_socket: Option<PathBuf>,
+        session_grace: Option<Duration>,
+        max_sessions_per_prefix: usize,
     ) -> Rpc {
         let stats = Arc::new(RpcStats {
             latency: metrics.histogram_vec(
@@ -402,29 +1213,99 @@ impl Rpc {
                 "electrscash_scripthash_subscriptions",
                 "# of scripthash subscriptions for node",
             )),
+            subscription_bytes: metrics.gauge_int(prometheus::Opts::new(
+                "electrscash_scripthash_subscriptions_bytes",
+                "Estimated memory used by scripthash subscriptions (all connections)",
+            )),
+            connection_subscription_bytes: metrics.gauge_int_vec(
+                prometheus::Opts::new(
+                    "electrscash_connection_subscriptions_bytes",
+                    "Estimated memory used by scripthash subscriptions of a connection",
+                ),
+                &["id", "peer"],
+            ),
         });
 
         stats.subscriptions.set(0);
+        stats.subscription_bytes.set(0);
+        let sessions = Arc::new(SessionStore::new(
+            session_grace,
+            max_sessions_per_prefix,
+            Arc::clone(&stats),
+        ));
+        let notifications_dropped = metrics.counter_int_vec(
+            prometheus::Opts::new(
+                "electrscash_notifications_dropped_total",
+                "# of subscription notifications not delivered to peers",
+            ),
+            &["reason"],
+        );
+        let peer_threads_active = metrics.gauge_int(prometheus::Opts::new(
+            "electrscash_rpc_peer_threads",
+            "# of threads serving RPC peers",
+        ));
+        let peers_rejected_busy = metrics.counter_int(prometheus::Opts::new(
+            "electrscash_rpc_peers_rejected_busy_total",
+            "# of RPC peers rejected for exceeding rpc_max_peer_threads",
+        ));
+        let accept_errors = metrics.counter_int(prometheus::Opts::new(
+            "electrscash_rpc_accept_errors_total",
+            "# of failures to accept RPC peer connections",
+        ));
         let notification = Channel::unbounded();
         Rpc {
             notification: notification.sender(),
             query: query.clone(),
             server: Some(spawn_thread("rpc", move || {
-                let senders = Arc::new(Mutex::new(Vec::<SyncSender<Message>>::new()));
+                let senders = Arc::new(Mutex::new(Vec::<PeerSender>::new()));
 
-                let acceptor = Rpc::start_acceptor(addr);
-                Rpc::start_notifier(notification, senders.clone(), acceptor.sender());
+                let acceptor = Rpc::start_acceptor(addr, socket_options, accept_errors.clone());
+                if let Some(path) = unix_socket {
+                    Rpc::start_unix_acceptor(
+                        path,
+                        socket_options.read_timeout,
+                        acceptor.sender(),
+                        accept_errors,
+                    )
+                    .unwrap_or_else(|e| panic!("{}", e.display_chain()));
+                }
+                Rpc::start_notifier(
+                    notification,
+                    senders.clone(),
+                    acceptor.sender(),
+                    max_dropped_notifications,
+                    notifications_dropped,
+                    Arc::clone(&sessions),
+                );
 
-                let mut threads = HashMap::new();
-                let (garbage_sender, garbage_receiver) = crossbeam_channel::unbounded();
+                let mut threads = PeerThreads::new(max_peer_threads, peer_threads_active);
 
                 while let Some((stream, addr)) = acceptor.receiver().recv().unwrap() {
+                    if threads.is_full() {
+                        debug!(
+                            "[{}] rejecting peer - all {} peer threads are busy",
+                            addr, max_peer_threads
+                        );
+                        peers_rejected_busy.inc();
+                        global_limits.proxied_peers().remove(&addr);
+                        reject_busy(stream, addr, "too many peers are being served");
+                        continue;
+                    }
                     let global_limits = global_limits.clone();
+                    let notify_stream = match stream.try_clone() {
+                        Ok(s) => s,
+                        Err(e) => {
+                            warn!("[{}] dropping peer - {}", addr, e);
+                            let _ = stream.shutdown(Shutdown::Both);
+                            continue;
+                        }
+                    };
 
-                    let mut connections = match global_limits.inc_connection(&addr.ip()) {
+                    let mut connections = match global_limits.inc_connection(&addr) {
                         Err(e) => {
                             trace!("[{}] dropping peer - {}", addr, e);
-                            let _ = stream.shutdown(Shutdown::Both);
+                            global_limits.proxied_peers().remove(&addr);
+                            reject_busy(stream, addr, e.to_string().trim_end_matches('.'));
                             continue;
                         }
                         Ok(n) => n,
@@ -432,15 +1313,23 @@ impl Rpc {
                     // explicitely scope the shadowed variables for the new thread
                     let query = Arc::clone(&query);
                     let stats = Arc::clone(&stats);
-                    let garbage_sender = garbage_sender.clone();
+                    let relayfee = Arc::clone(&relayfee);
+                    let warnings = Arc::clone(&warnings);
+                    let sessions = Arc::clone(&sessions);
                     let (sender, receiver) = mpsc::sync_channel(rpc_buffer_size);
 
-                    senders.lock().unwrap().push(sender.clone());
+                    senders.lock().unwrap().push(PeerSender {
+                        sender: sender.clone(),
+                        stream: notify_stream,
+                        addr,
+                        dropped: 0,
+                    });
 
-                    let spawned = spawn_thread("peer", move || {
+                    let spawned = threads.spawn(move || {
                         info!(
-                            "[{}] connected peer ({:?} out of {:?} connection slots used)",
+                            "[{}] connected peer on thread {} ({:?} out of {:?} connection slots used)",
                             addr,
+                            thread::current().name().unwrap_or_default(),
                             connections,
                             global_limits.connection_limits(),
                         );
@@ -450,11 +1339,18 @@ impl Rpc {
                             addr,
                             stats,
                             relayfee,
+                            warnings,
                             connection_limits,
+                            Arc::clone(&global_limits),
+                            detect_ssl,
+                            socket_options.read_buffer_size,
+                            method_hints,
+                            compression_threshold,
+                            sessions,
                             sender,
                         );
                         conn.run(receiver);
-                        match global_limits.dec_connection(&addr.ip()) {
+                        match global_limits.dec_connection(&addr) {
                             Ok(n) => connections = n,
                             Err(e) => error!("{}", e),
                         };
@@ -464,33 +1360,19 @@ impl Rpc {
                             connections,
                             global_limits.connection_limits(),
                         );
-                        let _ = garbage_sender.send(std::thread::current().id());
                     });
-
-                    trace!("[{}] spawned {:?}", addr, spawned.thread().id());
-                    threads.insert(spawned.thread().id(), spawned);
-                    while let Ok(id) = garbage_receiver.try_recv() {
-                        if let Some(thread) = threads.remove(&id) {
-                            trace!("[{}] joining {:?}", addr, id);
-                            if let Err(error) = thread.join() {
-                                error!("failed to join {:?}: {:?}", id, error);
-                            }
-                        }
-                    }
+                    trace!("[{}] spawned {:?}", addr, spawned);
                 }
                 info!("closing {} RPC connections", senders.lock().unwrap().len());
-                for sender in senders.lock().unwrap().iter() {
-                    let _ = sender.send(Message::Done);
+                for peer in senders.lock().unwrap().iter() {
+                    if let Err(TrySendError::Full(_)) = peer.sender.try_send(Message::Done) {
+                        // don't wait for a stalled peer
+                        let _ = peer.stream.shutdown(Shutdown::Both);
+                    }
                 }
 
                 info!("waiting for {} RPC handling threads", threads.len());
-
-                for (id, thread) in threads {
-                    trace!("joining {:?}", id);
-                    if let Err(error) = thread.join() {
-                        error!("failed to join {:?}: {:?}", id, error);
-                    }
-                }
+                threads.join_all();
                 info!("RPC connections are closed");
             })),
         }
@@ -551,6 +1433,12 @@ impl Rpc {
                 insert_for_tx(txid, Some(blockhash));
             }
         }
+        if !txs_changed.is_empty() {
+            let txids = Arc::new(txs_changed.clone());
+            if let Err(e) = self.notification.send(Notification::TxsChange(txids)) {
+                trace!("Transactions change notification failed: {}", e);
+            }
+        }
         for txid in txs_changed {
             insert_for_tx(txid, None);
         }
@@ -584,3 +1472,609 @@ impl Drop for Rpc {
         trace!("RPC server is stopped");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    // TLS ClientHello followed by something that looks like a request
+    const SSL_LIKE: &[u8] = b"\x16\x03\x01{\"id\": 1}\n";
+
+    #[test]
+    fn test_ssl_detection() {
+        let (tx, rx) = mpsc::sync_channel(10);
+        assert!(Connection::parse_requests(Cursor::new(SSL_LIKE), tx, true).is_err());
+        match rx.recv().unwrap() {
+            Message::Abort(reason) => assert!(reason.contains("SSL")),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn test_ssl_detection_disabled() {
+        let (tx, rx) = mpsc::sync_channel(10);
+        Connection::parse_requests(Cursor::new(SSL_LIKE), tx, false).unwrap();
+        match rx.recv().unwrap() {
+            Message::Request(line) => assert_eq!(line.as_bytes(), SSL_LIKE),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        assert!(matches!(rx.recv().unwrap(), Message::Done));
+    }
+
+    /// Reader counting the read calls (syscalls, for a socket)
+    struct CountingReader<R> {
+        inner: R,
+        reads: usize,
+    }
+
+    impl<R: Read> Read for CountingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_read_buffer_size() {
+        // a client sending many small requests at once
+        let requests: Vec<String> = (0..1000)
+            .map(|id| json!({"id": id, "method": "server.ping", "params": []}).to_string())
+            .collect();
+        let data = requests
+            .iter()
+            .map(|r| format!("{}\n", r))
+            .collect::<String>();
+
+        let parse = |capacity: usize| {
+            let (tx, rx) = mpsc::sync_channel(requests.len() + 1);
+            let mut reader = BufReader::with_capacity(
+                capacity,
+                CountingReader {
+                    inner: Cursor::new(data.as_bytes()),
+                    reads: 0,
+                },
+            );
+            Connection::parse_requests(&mut reader, tx, true).unwrap();
+            let parsed: Vec<String> = rx
+                .try_iter()
+                .filter_map(|msg| match msg {
+                    Message::Request(line) => Some(line.trim_end().to_string()),
+                    Message::Done => None,
+                    msg => panic!("unexpected message {:?}", msg),
+                })
+                .collect();
+            // line-delimited requests are kept intact, whatever the buffer
+            assert_eq!(parsed, requests);
+            reader.get_ref().reads
+        };
+        let small = parse(64);
+        let large = parse(16384);
+        assert!(small > data.len() / 64, "{} reads", small);
+        assert_eq!(large, data.len() / 16384 + 2, "{} reads", large);
+    }
+
+    #[test]
+    fn test_unknown_method_data() {
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("server.ping", "server.ping"), 0);
+
+        let data = unknown_method_data("blockchain.scripthash.get_balanc");
+        assert_eq!(
+            data["suggestion"],
+            json!("blockchain.scripthash.get_balance")
+        );
+        let data = unknown_method_data("blockchain.transaction.get_merkel");
+        assert_eq!(
+            data["suggestion"],
+            json!("blockchain.transaction.get_merkle")
+        );
+        let data = unknown_method_data("server.pnig");
+        assert_eq!(data["suggestion"], json!("server.ping"));
+        assert_eq!(data["methods"].as_array().unwrap().len(), METHODS.len());
+
+        // no suggestion far from any method
+        let data = unknown_method_data("getblockchaininfo");
+        assert_eq!(data["suggestion"], Value::Null);
+        assert!(data["methods"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("server.version")));
+    }
+
+    #[test]
+    fn test_compression() {
+        let small = json!({"id": 1, "result": null}).to_string();
+        assert_eq!(maybe_compress(small.clone(), 1024), small);
+
+        let large = json!({"id": 2, "result": "00".repeat(1000)}).to_string();
+        let compressed = maybe_compress(large.clone(), 1024);
+        assert!(compressed.len() < large.len());
+        assert!(!compressed.starts_with('{') && !compressed.starts_with('['));
+        let raw = base64::decode(&compressed).unwrap();
+        let decompressed = miniz_oxide::inflate::decompress_to_vec(&raw).unwrap();
+        assert_eq!(decompressed, large.as_bytes());
+    }
+
+    #[test]
+    fn test_silent_peer_times_out() {
+        let options = SocketOptions {
+            listen_backlog: 1,
+            keepalive: Some(Duration::from_secs(1)),
+            read_timeout: Some(Duration::from_millis(50)),
+            read_buffer_size: 1024,
+        };
+        let listener = options.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let _peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        options.configure(&stream).unwrap();
+
+        // The peer never sends anything. The reader gives up and signals the
+        // connection to close, which frees its connection slot.
+        let (tx, rx) = mpsc::sync_channel(10);
+        assert!(Connection::parse_requests(BufReader::new(stream), tx, true).is_err());
+        assert!(matches!(rx.recv().unwrap(), Message::Done));
+    }
+
+    #[test]
+    fn test_idle_peer_times_out() {
+        let (tx, rx) = mpsc::sync_channel(10);
+        let idle_timeout = Some(Duration::from_millis(100));
+        let last_request = Instant::now();
+
+        // Notifications don't count as activity.
+        tx.send(Message::TxsChange(Arc::new(HashSet::new())))
+            .unwrap();
+        let msg = recv_until_idle(&rx, idle_timeout, last_request).unwrap();
+        assert!(matches!(msg, Some(Message::TxsChange(_))));
+        assert!(recv_until_idle(&rx, idle_timeout, last_request)
+            .unwrap()
+            .is_none());
+        assert!(last_request.elapsed() >= Duration::from_millis(100));
+
+        // A recent request keeps the connection open.
+        tx.send(Message::Request("{}".to_string())).unwrap();
+        let msg = recv_until_idle(&rx, idle_timeout, Instant::now()).unwrap();
+        assert!(matches!(msg, Some(Message::Request(_))));
+
+        // Without a timeout, only a closed channel ends the connection.
+        drop(tx);
+        assert!(recv_until_idle(&rx, None, last_request).is_err());
+    }
+
+    #[test]
+    fn test_peer_thread_cap() {
+        let active = Metrics::dummy().gauge_int(prometheus::Opts::new("test_peer_threads", "test"));
+        let mut threads = PeerThreads::new(2, active.clone());
+        let (release, wait) = crossbeam_channel::unbounded::<()>();
+        for _ in 0..2 {
+            assert!(!threads.is_full());
+            let wait = wait.clone();
+            threads.spawn(move || {
+                let _ = wait.recv();
+            });
+        }
+        assert!(threads.is_full());
+        assert_eq!(active.get(), 2);
+
+        // A peer disconnecting frees its slot
+        release.send(()).unwrap();
+        let mut attempts = 0;
+        while threads.is_full() {
+            attempts += 1;
+            assert!(attempts < 500, "peer thread did not finish");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(active.get(), 1);
+
+        drop(release);
+        threads.join_all();
+        assert_eq!(active.get(), 0);
+
+        // No limit
+        let mut threads = PeerThreads::new(0, active);
+        threads.spawn(|| thread::sleep(Duration::from_millis(100)));
+        assert!(!threads.is_full());
+        threads.join_all();
+    }
+
+    #[test]
+    fn test_peer_thread_names() {
+        let active = Metrics::dummy().gauge_int(prometheus::Opts::new("test_peer_threads", "test"));
+        let mut threads = PeerThreads::new(0, active);
+        let (names, named) = crossbeam_channel::unbounded();
+        for id in 1..=2 {
+            let names = names.clone();
+            threads.spawn(move || {
+                let _ = names.send(thread::current().name().map(str::to_owned));
+            });
+            // Threads are named after the connection id
+            assert_eq!(named.recv().unwrap(), Some(format!("peer-{}", id)));
+        }
+        threads.join_all();
+    }
+
+    #[test]
+    fn test_reject_busy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        reject_busy(
+            PeerStream::Tcp(stream),
+            addr,
+            "too many peers are being served",
+        );
+
+        let mut lines = BufReader::new(client).lines();
+        let reply: Value = from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(
+            reply["error"]["code"],
+            json!(RpcErrorCode::ServerBusy as i32)
+        );
+        assert!(lines.next().is_none());
+    }
+
+    #[test]
+    fn test_reject_over_connection_limit() {
+        let limits = GlobalLimits::new(1, 1, 16, 64, 0, 0, false, &Metrics::dummy());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _first = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (_, addr) = listener.accept().unwrap();
+        limits.inc_connection(&addr).unwrap();
+
+        // the second connection is told why it is rejected, then disconnected
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        let e = limits.inc_connection(&addr).unwrap_err();
+        reject_busy(
+            PeerStream::Tcp(stream),
+            addr,
+            e.to_string().trim_end_matches('.'),
+        );
+
+        let mut lines = BufReader::new(client).lines();
+        let reply: Value = from_str(&lines.next().unwrap().unwrap()).unwrap();
+        assert_eq!(reply["id"], Value::Null);
+        assert_eq!(
+            reply["error"]["code"],
+            json!(RpcErrorCode::ServerBusy as i32)
+        );
+        assert_eq!(
+            reply["error"]["message"],
+            json!("server busy: Maximum connection limit of 1 reached for IP prefix 127.0.0.0, retry later")
+        );
+        assert!(lines.next().is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        use std::os::unix::net::UnixStream;
+        let path =
+            std::env::temp_dir().join(format!("electrscash-test-{}.sock", std::process::id()));
+        // a stale socket from a previous run is replaced
+        let stale = UnixListener::bind(&path).unwrap();
+        drop(stale);
+
+        let (sender, receiver) = mpsc::channel();
+        let accept_errors = Metrics::dummy().counter_int(prometheus::Opts::new("errors", "help"));
+        Rpc::start_unix_acceptor(path.clone(), None, sender, accept_errors).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        let (mut stream, addr) = receiver.recv().unwrap().unwrap();
+        assert_eq!(addr, unix_peer_addr());
+
+        client.write_all(b"{\"id\": 1}\n").unwrap();
+        let (tx, rx) = mpsc::sync_channel(10);
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        let parser = thread::spawn(move || Connection::parse_requests(reader, tx, true));
+        match rx.recv().unwrap() {
+            Message::Request(line) => assert_eq!(line, "{\"id\": 1}\n"),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        stream
+            .write_all(b"{\"id\": 1, \"result\": null}\n")
+            .unwrap();
+        let mut reply = String::new();
+        BufReader::new(&client).read_line(&mut reply).unwrap();
+        assert_eq!(reply, "{\"id\": 1, \"result\": null}\n");
+
+        drop(client);
+        parser.join().unwrap().unwrap();
+        assert!(matches!(rx.recv().unwrap(), Message::Done));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_accept_backoff() {
+        let mut backoff = AcceptBackoff::new();
+        let delay = backoff.next_delay();
+        assert!(delay >= ACCEPT_BACKOFF_MIN / 2 && delay <= ACCEPT_BACKOFF_MIN);
+        let delay = backoff.next_delay();
+        assert!(delay >= ACCEPT_BACKOFF_MIN && delay <= ACCEPT_BACKOFF_MIN * 2);
+        for _ in 0..20 {
+            assert!(backoff.next_delay() <= ACCEPT_BACKOFF_MAX);
+        }
+        assert!(backoff.next_delay() >= ACCEPT_BACKOFF_MAX / 2);
+        backoff.reset();
+        assert!(backoff.next_delay() <= ACCEPT_BACKOFF_MIN);
+    }
+
+    #[test]
+    fn test_fatal_accept_error() {
+        let emfile = io::Error::from_raw_os_error(libc::EMFILE);
+        assert!(!is_fatal_accept_error(&emfile));
+        let aborted = io::Error::from_raw_os_error(libc::ECONNABORTED);
+        assert!(!is_fatal_accept_error(&aborted));
+        let closed = io::Error::from_raw_os_error(libc::EBADF);
+        assert!(is_fatal_accept_error(&closed));
+    }
+
+    #[test]
+    fn test_stalled_peer_notifications() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        let metrics = Metrics::dummy();
+        let dropped = metrics.counter_int_vec(
+            prometheus::Opts::new("test_notifications_dropped", "test"),
+            &["reason"],
+        );
+
+        // The connection never reads its message queue.
+        let (sender, receiver) = mpsc::sync_channel(2);
+        let mut peer = PeerSender {
+            sender,
+            stream: PeerStream::Tcp(stream),
+            addr,
+            dropped: 0,
+        };
+        let notify = |peer: &mut PeerSender| peer.notify(Message::Done, 3, &dropped);
+
+        assert!(notify(&mut peer));
+        assert!(notify(&mut peer));
+        // Queue is full, notifications are dropped
+        assert!(notify(&mut peer));
+        assert!(notify(&mut peer));
+        assert_eq!(peer.dropped, 2);
+        assert_eq!(dropped.with_label_values(&["queue_full"]).get(), 2);
+
+        // The peer catching up resets the count
+        receiver.recv().unwrap();
+        assert!(notify(&mut peer));
+        assert_eq!(peer.dropped, 0);
+
+        // Too many dropped in a row, the peer is disconnected
+        assert!(notify(&mut peer));
+        assert!(notify(&mut peer));
+        assert!(!notify(&mut peer));
+        assert_eq!(dropped.with_label_values(&["queue_full"]).get(), 5);
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).unwrap(), 0);
+
+        drop(receiver);
+        assert!(!notify(&mut peer));
+        assert_eq!(dropped.with_label_values(&["disconnected"]).get(), 1);
+    }
+
+    fn connection_limits(max_subscriptions: u32) -> ConnectionLimits {
+        ConnectionLimits {
+            max_subscriptions,
+            ..ConnectionLimits::default()
+        }
+    }
+
+    /// Serve a connection in the background, returning its client side.
+    fn start_connection(
+        query: &Arc<Query>,
+        stats: &Arc<RpcStats>,
+        sessions: &Arc<SessionStore>,
+        doslimits: ConnectionLimits,
+    ) -> (BufReader<TcpStream>, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        let metrics = Metrics::dummy();
+        let (sender, receiver) = mpsc::sync_channel(10);
+        let conn = Connection::new(
+            Arc::clone(query),
+            PeerStream::Tcp(stream),
+            addr,
+            Arc::clone(stats),
+            Arc::new(RelayFee::new(0.00001, Duration::from_secs(3600), 0.0)),
+            Arc::new(DaemonWarnings::new(vec![], Duration::from_secs(3600))),
+            doslimits,
+            Arc::new(GlobalLimits::new(100, 100, 16, 16, 100, 0, false, &metrics)),
+            false,
+            4096,
+            false,
+            0,
+            Arc::clone(sessions),
+            sender,
+        );
+        let peer = thread::spawn(move || conn.run(receiver));
+        (BufReader::new(client), peer)
+    }
+
+    fn read_value(client: &mut BufReader<TcpStream>) -> Value {
+        let mut line = String::new();
+        client.read_line(&mut line).unwrap();
+        from_str(&line).unwrap()
+    }
+
+    fn call(client: &mut BufReader<TcpStream>, method: &str, params: Value) -> Value {
+        let request = json!({"id": 1, "method": method, "params": params});
+        writeln!(client.get_mut(), "{}", request).unwrap();
+        read_value(client)
+    }
+
+    /// Disconnect the client, waiting for the connection to close.
+    fn disconnect(client: BufReader<TcpStream>, peer: thread::JoinHandle<()>) {
+        client.get_ref().shutdown(Shutdown::Write).unwrap();
+        peer.join().unwrap();
+    }
+
+    /// Subscription memory of each connection, as reported in its metric
+    fn connection_subscription_bytes(stats: &RpcStats) -> Vec<i64> {
+        use prometheus::core::Collector;
+        stats
+            .connection_subscription_bytes
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| metric.get_gauge().get_value() as i64)
+            .collect()
+    }
+
+    #[test]
+    fn test_session_resume() {
+        use crate::query::TestQuery;
+        use crate::scripthash::ToLeHex;
+        use bitcoincash::blockdata::script::Script;
+        use bitcoincash::blockdata::transaction::TxOut;
+
+        let test_query = TestQuery::new();
+        let query = test_query.query();
+        let stats = Arc::new(RpcStats::dummy());
+        let sessions = Arc::new(SessionStore::new(
+            Some(Duration::from_secs(3600)),
+            0,
+            Arc::clone(&stats),
+        ));
+        let script = Script::from(vec![0x51]);
+        let scripthash = compute_script_hash(&script[..]);
+        let scripthash_hex = scripthash.to_le_hex();
+
+        // subscribe, and keep the session on disconnecting
+        let (mut client, peer) = start_connection(query, &stats, &sessions, connection_limits(10));
+        let reply = call(
+            &mut client,
+            "blockchain.scripthash.subscribe",
+            json!([scripthash_hex]),
+        );
+        assert_eq!(reply["result"], Value::Null);
+        let token = call(&mut client, "server.session_token", json!([]))["result"].clone();
+        assert!(token.is_string());
+        assert_eq!(stats.subscriptions.get(), 1);
+        let bytes = stats.subscription_bytes.get();
+        assert!(bytes > 0);
+        assert_eq!(connection_subscription_bytes(&stats), vec![bytes]);
+        disconnect(client, peer);
+        assert_eq!(sessions.len(), 1);
+        // still counted while kept, but no longer by a connection
+        assert_eq!(stats.subscriptions.get(), 1);
+        assert_eq!(stats.subscription_bytes.get(), bytes);
+        assert!(connection_subscription_bytes(&stats).is_empty());
+
+        // the scripthash is funded while disconnected
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: script,
+            }],
+        };
+        test_query.add_mempool_tx(tx, 100);
+        sessions.on_scripthash_change(&scripthash);
+
+        // the missed change is notified after the reply
+        let (mut client, peer) = start_connection(query, &stats, &sessions, connection_limits(10));
+        let reply = call(&mut client, "server.session_resume", json!([token]));
+        assert_eq!(reply["id"], json!(1));
+        assert_eq!(reply["result"], json!(1));
+        let notification = read_value(&mut client);
+        assert_eq!(
+            notification["method"],
+            json!("blockchain.scripthash.subscribe")
+        );
+        assert_eq!(notification["params"][0], json!(scripthash_hex));
+        assert!(notification["params"][1].is_string());
+        assert_eq!(sessions.len(), 0);
+        assert_eq!(stats.subscriptions.get(), 1);
+        assert_eq!(stats.subscription_bytes.get(), bytes);
+        assert_eq!(connection_subscription_bytes(&stats), vec![bytes]);
+
+        // the resuming connection takes over the token
+        disconnect(client, peer);
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(stats.subscriptions.get(), 1);
+
+        // the subscriptions are checked against the limits of the resuming
+        // connection, and dropped if they don't fit
+        let (mut client, peer) = start_connection(query, &stats, &sessions, connection_limits(0));
+        let reply = call(&mut client, "server.session_resume", json!([token]));
+        assert!(reply["error"].is_object(), "{}", reply);
+        assert_eq!(sessions.len(), 0);
+        assert_eq!(stats.subscriptions.get(), 0);
+        assert_eq!(stats.subscription_bytes.get(), 0);
+        disconnect(client, peer);
+        assert_eq!(stats.subscriptions.get(), 0);
+        assert_eq!(stats.subscription_bytes.get(), 0);
+    }
+
+    #[test]
+    fn test_subscription_memory_limit() {
+        use crate::query::TestQuery;
+        use crate::scripthash::ToLeHex;
+
+        let test_query = TestQuery::new();
+        let query = test_query.query();
+        let stats = Arc::new(RpcStats::dummy());
+        let sessions = Arc::new(SessionStore::new(None, 0, Arc::clone(&stats)));
+        let addr = "bitcoincash:qp3wjpa3tjlj042z2wv7hahsldgwhwy0rq9sywjpyy";
+        let other_addr = "1BoatSLRHtKNngkdXEeobR76b53LETtpyT";
+        let scripthash = compute_script_hash(&[0x51]).to_le_hex();
+
+        // the memory of a subscription by address
+        let (mut client, peer) =
+            start_connection(query, &stats, &sessions, ConnectionLimits::default());
+        let reply = call(&mut client, "blockchain.address.subscribe", json!([addr]));
+        assert!(reply["error"].is_null(), "{}", reply);
+        let bytes = stats.subscription_bytes.get();
+        assert_eq!(connection_subscription_bytes(&stats), vec![bytes]);
+        disconnect(client, peer);
+        assert!(connection_subscription_bytes(&stats).is_empty());
+
+        // a subscription reaching the limit is accepted, any further one is
+        // rejected
+        let limits = ConnectionLimits {
+            max_subscription_bytes: bytes as u32,
+            ..ConnectionLimits::default()
+        };
+        let (mut client, peer) = start_connection(query, &stats, &sessions, limits);
+        let reply = call(&mut client, "blockchain.address.subscribe", json!([addr]));
+        assert!(reply["error"].is_null(), "{}", reply);
+        assert_eq!(connection_subscription_bytes(&stats), vec![bytes]);
+        for (method, param) in [
+            ("blockchain.scripthash.subscribe", &scripthash[..]),
+            ("blockchain.address.subscribe", other_addr),
+        ] {
+            let reply = call(&mut client, method, json!([param]));
+            let error = reply["error"]["message"].as_str().unwrap();
+            assert!(error.contains("memory limit"), "{}", reply);
+            assert_eq!(stats.subscriptions.get(), 1);
+            assert_eq!(connection_subscription_bytes(&stats), vec![bytes]);
+        }
+
+        // unsubscribing makes room, and the metric follows
+        let reply = call(&mut client, "blockchain.address.unsubscribe", json!([addr]));
+        assert_eq!(reply["result"], json!(true));
+        assert!(connection_subscription_bytes(&stats).is_empty());
+        let reply = call(
+            &mut client,
+            "blockchain.scripthash.subscribe",
+            json!([scripthash]),
+        );
+        assert!(reply["error"].is_null(), "{}", reply);
+        let scripthash_bytes = bytes - addr.len() as i64;
+        assert_eq!(
+            connection_subscription_bytes(&stats),
+            vec![scripthash_bytes]
+        );
+        disconnect(client, peer);
+        assert_eq!(stats.subscription_bytes.get(), 0);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::errors::*;
+use crate::query::{HistoryCursor, HistoryOrder};
 use crate::scripthash::decode_scripthash;
 use crate::scripthash::FullHash;
 use bitcoincash::hashes::hex::FromHex;
@@ -40,6 +41,29 @@ pub fn scripthash_from_value(val: Option<&Value>) -> Result<FullHash> {
     Ok(script_hash)
 }
 
+/// Parse "asc" or "desc", defaulting to ascending order.
+pub fn history_order_from_value(val: Option<&Value>) -> Result<HistoryOrder> {
+    if val.is_none() {
+        return Ok(HistoryOrder::Ascending);
+    }
+    match str_from_value(val, "order")?.as_str() {
+        "asc" => Ok(HistoryOrder::Ascending),
+        "desc" => Ok(HistoryOrder::Descending),
+        _ => bail!(rpc_arg_error("order must be \"asc\" or \"desc\"")),
+    }
+}
+
+/// Cursor for paging history, None if history is not paged
+pub fn history_cursor_from_value(val: Option<&Value>) -> Result<Option<HistoryCursor>> {
+    match val {
+        None | Some(Value::Null) => Ok(None),
+        val => Ok(Some(
+            HistoryCursor::from_hex(&str_from_value(val, "cursor")?)
+                .map_err(|e| rpc_arg_error(&format!("invalid cursor: {}", e)))?,
+        )),
+    }
+}
+
 pub fn rpc_arg_error(what: &str) -> ErrorKind {
     ErrorKind::RpcError(RpcErrorCode::InvalidParams, what.to_string())
 }
@@ -0,0 +1,73 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// Address given to peers connected over the Unix domain socket. They share
+/// the connection and request limits of a single (trusted) source.
+pub fn unix_peer_addr() -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
+}
+
+/// Stream of a connected RPC peer
+#[derive(Debug)]
+pub enum PeerStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl PeerStream {
+    pub fn try_clone(&self) -> io::Result<PeerStream> {
+        match self {
+            PeerStream::Tcp(stream) => stream.try_clone().map(PeerStream::Tcp),
+            #[cfg(unix)]
+            PeerStream::Unix(stream) => stream.try_clone().map(PeerStream::Unix),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            PeerStream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            PeerStream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            PeerStream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            PeerStream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for PeerStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            PeerStream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            PeerStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for PeerStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            PeerStream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            PeerStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            PeerStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            PeerStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use crate::errors::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The minimum relay fee of the daemon (in BCH/kB).
+///
+/// The daemon may change its relay fee while we're running, so it's
+/// periodically refreshed. Reading it is lock free.
+///
+/// The fee advertised to clients can be raised above the daemon's with
+/// `floor`, which doesn't change what the daemon accepts.
+pub struct RelayFee {
+    fee: AtomicU64, // f64 bits
+    floor: f64,
+    refresh_interval: Duration,
+    last_refresh: Mutex<Instant>,
+}
+
+impl RelayFee {
+    pub fn new(fee: f64, refresh_interval: Duration, floor: f64) -> RelayFee {
+        RelayFee {
+            fee: AtomicU64::new(fee.to_bits()),
+            floor,
+            refresh_interval,
+            last_refresh: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Relay fee advertised to clients, at least `floor`.
+    pub fn get(&self) -> f64 {
+        self.daemon_fee().max(self.floor)
+    }
+
+    /// Relay fee of the daemon, which is what it accepts.
+    pub fn daemon_fee(&self) -> f64 {
+        f64::from_bits(self.fee.load(Ordering::Relaxed))
+    }
+
+    /// Fetches the relay fee again, if refresh interval has passed since the
+    /// last refresh.
+    pub fn maybe_refresh(&self, fetch: impl FnOnce() -> Result<f64>) -> Result<()> {
+        let mut last_refresh = self.last_refresh.lock().unwrap();
+        if last_refresh.elapsed() < self.refresh_interval {
+            return Ok(());
+        }
+        *last_refresh = Instant::now();
+        let fee = fetch().chain_err(|| "failed to refresh relayfee")?;
+        let old_fee = f64::from_bits(self.fee.swap(fee.to_bits(), Ordering::Relaxed));
+        if (old_fee - fee).abs() > f64::EPSILON {
+            info!("relayfee changed from {} to {}", old_fee, fee);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_relayfee_refresh() {
+        let relayfee = RelayFee::new(0.00001, Duration::from_secs(0), 0.0);
+        assert_eq!(relayfee.get(), 0.00001);
+
+        // daemon changed its relay fee
+        relayfee.maybe_refresh(|| Ok(0.00002)).unwrap();
+        assert_eq!(relayfee.get(), 0.00002);
+
+        // failure keeps the previous value
+        assert!(relayfee.maybe_refresh(|| bail!("daemon down")).is_err());
+        assert_eq!(relayfee.get(), 0.00002);
+    }
+
+    #[test]
+    fn test_relayfee_refresh_interval() {
+        let relayfee = RelayFee::new(0.00001, Duration::from_secs(3600), 0.0);
+        relayfee
+            .maybe_refresh(|| panic!("refreshed before interval passed"))
+            .unwrap();
+        assert_eq!(relayfee.get(), 0.00001);
+    }
+
+    #[test]
+    fn test_relayfee_floor() {
+        let relayfee = RelayFee::new(0.00001, Duration::from_secs(0), 0.00005);
+        assert_eq!(relayfee.get(), 0.00005);
+        // broadcasts are checked against what the daemon accepts
+        assert_eq!(relayfee.daemon_fee(), 0.00001);
+
+        // daemon raised its relay fee above the floor
+        relayfee.maybe_refresh(|| Ok(0.0001)).unwrap();
+        assert_eq!(relayfee.get(), 0.0001);
+        assert_eq!(relayfee.daemon_fee(), 0.0001);
+    }
+}
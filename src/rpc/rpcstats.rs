@@ -1,6 +1,29 @@
-use prometheus::{HistogramVec, IntGauge};
+use prometheus::{HistogramVec, IntGauge, IntGaugeVec};
 
 pub struct RpcStats {
     pub latency: HistogramVec,
     pub subscriptions: IntGauge,
+    pub subscription_bytes: IntGauge,
+    /// Estimated subscription memory of each connection, by connection id
+    /// and peer address
+    pub connection_subscription_bytes: IntGaugeVec,
+}
+
+impl RpcStats {
+    #[cfg(test)]
+    pub fn dummy() -> RpcStats {
+        let metrics = crate::metrics::Metrics::dummy();
+        RpcStats {
+            latency: metrics.histogram_vec(
+                prometheus::HistogramOpts::new("test_latency", "test"),
+                &["method"],
+            ),
+            subscriptions: metrics.gauge_int(prometheus::Opts::new("test_subs", "test")),
+            subscription_bytes: metrics.gauge_int(prometheus::Opts::new("test_subs_bytes", "test")),
+            connection_subscription_bytes: metrics.gauge_int_vec(
+                prometheus::Opts::new("test_conn_subs_bytes", "test"),
+                &["id", "peer"],
+            ),
+        }
+    }
 }
@@ -1,10 +1,12 @@
 use crate::errors::*;
 use crate::mempool::MEMPOOL_HEIGHT;
 use crate::query::primitives::FundingOutput;
-use crate::query::{Query, Status};
-use crate::scripthash::{FullHash, ToLeHex};
+use crate::query::{HistoryCursor, HistoryOrder, Query, Status};
+use crate::scripthash::{compute_script_hash, FullHash, ToLeHex};
 use crate::timeout::TimeoutTrigger;
-use bitcoincash::hash_types::BlockHash;
+use bitcoincash::blockdata::script::Script;
+use bitcoincash::blockdata::transaction::Transaction;
+use bitcoincash::hash_types::{BlockHash, TxMerkleNode, Txid};
 use bitcoincash::hashes::hex::ToHex;
 use serde_json::Value;
 
@@ -17,6 +19,55 @@ fn unspent_to_json(out: &FundingOutput) -> Value {
     })
 }
 
+/// Same as `unspent_to_json`, with the merkle proof (branch and position) of
+/// the funding transaction. The proof is null for mempool outputs.
+fn unspent_with_proof_to_json(
+    out: &FundingOutput,
+    proof: Option<(Vec<TxMerkleNode>, usize)>,
+) -> Value {
+    let mut result = unspent_to_json(out);
+    result["merkle"] = match proof {
+        Some((branch, pos)) => json!({
+            "branch": branch.iter().map(|node| node.to_hex()).collect::<Vec<String>>(),
+            "pos": pos,
+        }),
+        None => Value::Null,
+    };
+    result
+}
+
+/// Same as `unspent_to_json`, with the scriptPubKey (as hex) of the output
+/// and the addresses it pays to.
+fn unspent_with_script_to_json(
+    out: &FundingOutput,
+    script: &Script,
+    addresses: &[String],
+) -> Value {
+    let mut result = unspent_to_json(out);
+    result["script_pubkey"] = json!(script.to_hex());
+    result["addresses"] = json!(addresses);
+    result
+}
+
+/// scriptPubKey of the output `out` of `tx`, checked to hash to `scripthash`.
+fn unspent_script(tx: &Transaction, out: &FundingOutput, scripthash: &FullHash) -> Result<Script> {
+    let outpoint = &out.funding_output;
+    let script = &tx
+        .output
+        .get(outpoint.vout as usize)
+        .chain_err(|| format!("missing output {}:{}", outpoint.txid, outpoint.vout))?
+        .script_pubkey;
+    if compute_script_hash(script.as_bytes()) != *scripthash {
+        bail!(
+            "output {}:{} does not pay to scripthash {}",
+            outpoint.txid,
+            outpoint.vout,
+            scripthash.to_le_hex()
+        );
+    }
+    Ok(script.clone())
+}
+
 fn unspent_from_status(status: &Status) -> Value {
     json!(Value::Array(
         status.unspent().into_iter().map(unspent_to_json).collect()
@@ -26,63 +77,141 @@ fn unspent_from_status(status: &Status) -> Value {
 pub fn get_balance(
     query: &Query,
     scripthash: &FullHash,
+    exclude_tokens: bool,
     timeout: &TimeoutTrigger,
 ) -> Result<Value> {
-    let status = query.status(scripthash, timeout)?;
+    let mut status = query.status(scripthash, timeout)?;
+    if exclude_tokens {
+        query.mark_token_outputs(&mut status, timeout)?;
+    }
     Ok(json!({
-        "confirmed": status.confirmed_balance(),
-        "unconfirmed": status.mempool_balance()
+        "confirmed": status.confirmed_balance(exclude_tokens),
+        "unconfirmed": status.mempool_balance(exclude_tokens)
     }))
 }
 
-pub fn get_first_use(query: &Query, scripthash: &FullHash) -> Result<Value> {
-    let firstuse = query.scripthash_first_use(scripthash)?;
-    if firstuse.0 == 0 {
-        return Err(ErrorKind::RpcError(
-            RpcErrorCode::NotFound,
-            format!("scripthash '{}' not found", scripthash.to_le_hex()),
-        )
-        .into());
-    }
-    let blockhash = if firstuse.0 == MEMPOOL_HEIGHT {
-        BlockHash::default()
-    } else {
-        let h = query.get_headers(&[firstuse.0 as usize]);
-        if h.is_empty() {
-            warn!("expected to find header for height {}", firstuse.0);
-            BlockHash::default()
-        } else {
-            *h[0].hash()
+fn first_use_to_json(
+    scripthash: &FullHash,
+    firstuse: Option<(u32, Txid)>,
+    blockhash: &BlockHash,
+) -> Result<Value> {
+    let (height, txid) = match firstuse {
+        Some(f) => f,
+        None => {
+            return Err(ErrorKind::RpcError(
+                RpcErrorCode::NotFound,
+                format!("scripthash '{}' not found", scripthash.to_le_hex()),
+            )
+            .into())
         }
     };
-
-    let height = if firstuse.0 == MEMPOOL_HEIGHT {
-        0
-    } else {
-        firstuse.0
-    };
+    // Mempool height is 0 in the json API
+    let height = if height == MEMPOOL_HEIGHT { 0 } else { height };
 
     Ok(json!({
         "block_hash": blockhash.to_hex(),
         "height": height,
         "block_height": height, // deprecated
-        "tx_hash": firstuse.1.to_hex()
+        "tx_hash": txid.to_hex()
     }))
 }
 
+pub fn get_first_use(query: &Query, scripthash: &FullHash) -> Result<Value> {
+    let firstuse = query.scripthash_first_use(scripthash)?;
+    let blockhash = match firstuse {
+        Some((height, _)) if height != MEMPOOL_HEIGHT => {
+            let h = query.get_headers(&[height as usize]);
+            if h.is_empty() {
+                warn!("expected to find header for height {}", height);
+                BlockHash::default()
+            } else {
+                *h[0].hash()
+            }
+        }
+        _ => BlockHash::default(),
+    };
+    first_use_to_json(scripthash, firstuse, &blockhash)
+}
+
+/// Options of `get_history`
+#[derive(Debug, PartialEq)]
+pub struct HistoryOptions {
+    /// Include size and fee of transactions
+    pub tx_info: bool,
+    /// Only return unconfirmed transactions, without looking up confirmed
+    /// spends
+    pub mempool_only: bool,
+    pub order: HistoryOrder,
+    /// Return a page of history following the cursor, with the cursor of the
+    /// next page
+    pub cursor: Option<HistoryCursor>,
+    /// Include unconfirmed transactions. Can't be unset with `mempool_only`.
+    pub include_unconfirmed: bool,
+}
+
+impl Default for HistoryOptions {
+    fn default() -> HistoryOptions {
+        HistoryOptions {
+            tx_info: false,
+            mempool_only: false,
+            order: HistoryOrder::Ascending,
+            cursor: None,
+            include_unconfirmed: true,
+        }
+    }
+}
+
+/// Get history of scripthash, see `HistoryOptions`. With `tx_info` set, at
+/// most `max_tx_info` transactions are extended (the first ones in given
+/// order), loading at most `max_tx_info_prevouts` previous transactions for
+/// the fees.
 pub fn get_history(
     query: &Query,
     scripthash: &FullHash,
+    options: &HistoryOptions,
+    max_tx_info: usize,
+    max_tx_info_prevouts: usize,
     timeout: &TimeoutTrigger,
 ) -> Result<Value> {
-    let status = query.status(scripthash, timeout)?;
-    Ok(json!(Value::Array(
-        status
-            .history()
-            .into_iter()
-            .map(|item| item.to_json())
-            .collect()
-    )))
+    let HistoryOptions {
+        tx_info,
+        mempool_only,
+        order,
+        ref cursor,
+        include_unconfirmed,
+    } = *options;
+    if mempool_only && !include_unconfirmed {
+        bail!(rpc_invalid_params(
+            "mempool_only requires include_unconfirmed".to_string()
+        ));
+    }
+    let status = if mempool_only {
+        query.status_mempool(scripthash, timeout)?
+    } else {
+        query.status(scripthash, timeout)?
+    };
+    let (mut history, next) = match cursor {
+        Some(cursor) => status.history_page(
+            order,
+            include_unconfirmed,
+            cursor,
+            query.history_page_size(),
+        ),
+        None => (status.history_ordered(order, include_unconfirmed), None),
+    };
+    if tx_info {
+        query.add_history_tx_info(&mut history, max_tx_info, max_tx_info_prevouts, timeout)?;
+    }
+    let history = json!(Value::Array(
+        history.into_iter().map(|item| item.to_json()).collect()
+    ));
+    if cursor.is_none() {
+        return Ok(history);
+    }
+    Ok(json!({
+        "history": history,
+        "cursor": next.map(|next| next.to_hex()),
+    }))
 }
 
 pub fn get_mempool(
@@ -90,22 +219,94 @@ pub fn get_mempool(
     scripthash: &FullHash,
     timeout: &TimeoutTrigger,
 ) -> Result<Value> {
-    let status = query.status_mempool(scripthash, timeout)?;
-    Ok(json!(Value::Array(
-        status
-            .history()
-            .into_iter()
-            .map(|item| item.to_json())
-            .collect()
-    )))
+    let options = HistoryOptions {
+        mempool_only: true,
+        ..HistoryOptions::default()
+    };
+    get_history(query, scripthash, &options, 0, 0, timeout)
 }
 
+/// Unspent outputs of scripthash. With `include_script` set, each output
+/// includes its scriptPubKey and addresses.
 pub fn listunspent(
     query: &Query,
     scripthash: &FullHash,
+    include_script: bool,
     timeout: &TimeoutTrigger,
 ) -> Result<Value> {
-    Ok(unspent_from_status(&query.status(scripthash, timeout)?))
+    let status = query.status(scripthash, timeout)?;
+    if !include_script {
+        return Ok(unspent_from_status(&status));
+    }
+    let unspent = status.unspent();
+    let first = match unspent.first() {
+        Some(first) => first,
+        None => return Ok(json!([])),
+    };
+    // all outputs of a scripthash pay to the same script, so it's only
+    // loaded from the first funding transaction
+    let tx = query
+        .tx()
+        .get(&first.funding_output.txid, None, Some(first.height))?;
+    let script = unspent_script(&tx, first, scripthash)?;
+    let addresses = query.tx().addresses(&script);
+    Ok(json!(unspent
+        .into_iter()
+        .map(|out| unspent_with_script_to_json(out, &script, &addresses))
+        .collect::<Vec<Value>>()))
+}
+
+/// Unspent outputs of scripthash, each with the merkle proof of its
+/// confirmed funding transaction. Fails if more than `max_proofs` outputs are
+/// confirmed.
+pub fn listunspent_with_proofs(
+    query: &Query,
+    scripthash: &FullHash,
+    max_proofs: usize,
+    timeout: &TimeoutTrigger,
+) -> Result<Value> {
+    let status = query.status(scripthash, timeout)?;
+    unspent_with_proofs(&status.unspent(), max_proofs, timeout, |txid, height| {
+        query.get_merkle_proof(txid, height)
+    })
+}
+
+/// `unspent` outputs with the merkle proof of their confirmed funding
+/// transaction, as returned by `get_merkle_proof` for a txid and height.
+fn unspent_with_proofs<F>(
+    unspent: &[&FundingOutput],
+    max_proofs: usize,
+    timeout: &TimeoutTrigger,
+    mut get_merkle_proof: F,
+) -> Result<Value>
+where
+    F: FnMut(&Txid, usize) -> Result<(Vec<TxMerkleNode>, usize)>,
+{
+    let confirmed = unspent
+        .iter()
+        .filter(|out| out.height != MEMPOOL_HEIGHT)
+        .count();
+    if confirmed > max_proofs {
+        bail!(rpc_invalid_request(format!(
+            "too many confirmed outputs for proofs ({} > {}), use listunspent instead",
+            confirmed, max_proofs
+        )));
+    }
+    let mut result = vec![];
+    for out in unspent {
+        timeout.check()?;
+        let proof = if out.height == MEMPOOL_HEIGHT {
+            None
+        } else {
+            let txid = &out.funding_output.txid;
+            Some(
+                get_merkle_proof(txid, out.height as usize)
+                    .chain_err(|| format!("cannot create merkle proof of {}", txid))?,
+            )
+        };
+        result.push(unspent_with_proof_to_json(out, proof));
+    }
+    Ok(json!(result))
 }
 
 #[cfg(test)]
@@ -113,7 +314,6 @@ mod tests {
     use super::*;
     use crate::mempool::ConfirmationState;
     use bitcoincash::blockdata::transaction::OutPoint;
-    use bitcoincash::hash_types::Txid;
     use bitcoincash::hashes::hex::FromHex;
     use serde_json::from_str;
 
@@ -131,6 +331,7 @@ mod tests {
             height,
             value: 2020,
             state: ConfirmationState::InMempool,
+            has_token: false,
         }
     }
 
@@ -147,6 +348,132 @@ mod tests {
         assert_eq!(5000, res.height);
     }
 
+    #[test]
+    fn test_output_with_proof_to_json() {
+        let out = create_out(MEMPOOL_HEIGHT, Txid::default());
+        let res = unspent_with_proof_to_json(&out, None);
+        assert_eq!(res["height"], json!(0));
+        assert_eq!(res["merkle"], Value::Null);
+
+        let out = create_out(5000, Txid::default());
+        let branch = vec![TxMerkleNode::from_hex(&"cc".repeat(32)).unwrap()];
+        let res = unspent_with_proof_to_json(&out, Some((branch, 3)));
+        assert_eq!(res["height"], json!(5000));
+        assert_eq!(
+            res["merkle"],
+            json!({"branch": ["cc".repeat(32)], "pos": 3})
+        );
+    }
+
+    #[test]
+    fn test_unspent_with_proofs() {
+        use crate::query::merkle::MerkleTree;
+        use bitcoincash::blockdata::block::{Block, BlockHeader};
+        use bitcoincash::hashes::Hash;
+        use std::time::Duration;
+
+        let txdata: Vec<Transaction> = (0..5)
+            .map(|i| Transaction {
+                version: 1,
+                lock_time: i,
+                input: vec![],
+                output: vec![],
+            })
+            .collect();
+        let mut block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash: BlockHash::default(),
+                merkle_root: TxMerkleNode::default(),
+                time: 0,
+                bits: 0,
+                nonce: 0,
+            },
+            txdata,
+        };
+        block.header.merkle_root = block.merkle_root();
+        let txids: Vec<Txid> = block.txdata.iter().map(Transaction::txid).collect();
+        let tree = MerkleTree::new(&txids);
+        let get_merkle_proof = |txid: &Txid, height: usize| {
+            assert_eq!(height, 100);
+            let pos = tree.position(txid).unwrap();
+            Ok((tree.branch(pos), pos))
+        };
+
+        let confirmed = [create_out(100, txids[1]), create_out(100, txids[4])];
+        let mempool = create_out(MEMPOOL_HEIGHT, Txid::default());
+        let unspent = vec![&confirmed[0], &mempool, &confirmed[1]];
+        let timeout = TimeoutTrigger::new(Duration::from_secs(10));
+        let result = unspent_with_proofs(&unspent, 2, &timeout, get_merkle_proof).unwrap();
+        assert_eq!(result[1]["merkle"], Value::Null);
+        for (i, pos) in [(0, 1), (2, 4)] {
+            let merkle = &result[i]["merkle"];
+            assert_eq!(merkle["pos"], json!(pos));
+            // verify as an SPV client would, from the txid up
+            let mut hash = TxMerkleNode::from_inner(txids[pos].into_inner());
+            let mut index = pos;
+            for sibling in merkle["branch"].as_array().unwrap() {
+                let sibling = TxMerkleNode::from_hex(sibling.as_str().unwrap()).unwrap();
+                let (left, right) = if index % 2 == 0 {
+                    (hash, sibling)
+                } else {
+                    (sibling, hash)
+                };
+                hash = TxMerkleNode::hash(&[&left[..], &right[..]].concat());
+                index /= 2;
+            }
+            assert_eq!(hash, block.header.merkle_root, "pos {}", pos);
+        }
+
+        // only confirmed outputs count against the limit
+        let err = unspent_with_proofs(&unspent, 1, &timeout, get_merkle_proof).unwrap_err();
+        assert!(
+            err.to_string().contains("too many confirmed outputs"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_unspent_script() {
+        use bitcoincash::blockdata::transaction::TxOut;
+
+        let script = Script::from(vec![0x76, 0xa9, 0x14, 0xaa, 0x88, 0xac]);
+        let other = Script::from(vec![0x6a]);
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![
+                TxOut {
+                    value: 1000,
+                    script_pubkey: other,
+                },
+                TxOut {
+                    value: 2020,
+                    script_pubkey: script.clone(),
+                },
+            ],
+        };
+        let scripthash = compute_script_hash(script.as_bytes());
+        let mut out = create_out(5000, tx.txid());
+        out.funding_output.vout = 1;
+        assert_eq!(unspent_script(&tx, &out, &scripthash).unwrap(), script);
+
+        let addresses = vec!["bitcoincash:qz".to_string()];
+        let res = unspent_with_script_to_json(&out, &script, &addresses);
+        assert_eq!(res["script_pubkey"], json!(script.to_hex()));
+        assert_eq!(res["addresses"], json!(addresses));
+        assert_eq!(res["tx_pos"], json!(1));
+        assert_eq!(res["value"], json!(2020));
+
+        // the output must exist and pay to the scripthash
+        out.funding_output.vout = 0;
+        assert!(unspent_script(&tx, &out, &scripthash).is_err());
+        out.funding_output.vout = 2;
+        assert!(unspent_script(&tx, &out, &scripthash).is_err());
+    }
+
     #[test]
     fn test_output_to_json_txid() {
         let hex = "eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeffffffffffffffffffffffffffffffff";
@@ -154,4 +481,75 @@ mod tests {
         let res: Unspent = from_str(&unspent_to_json(&out).to_string()).unwrap();
         assert_eq!(hex, res.tx_hash);
     }
+
+    #[test]
+    fn test_first_use_confirmed() {
+        let txid = Txid::from_hex(&"aa".repeat(32)).unwrap();
+        let blockhash = BlockHash::from_hex(&"bb".repeat(32)).unwrap();
+        let res = first_use_to_json(&[0; 32], Some((1234, txid)), &blockhash).unwrap();
+        assert_eq!(1234, res["height"].as_u64().unwrap());
+        assert_eq!(blockhash.to_hex(), res["block_hash"].as_str().unwrap());
+        assert_eq!(txid.to_hex(), res["tx_hash"].as_str().unwrap());
+
+        // Genesis block is a valid first use
+        let res = first_use_to_json(&[0; 32], Some((0, txid)), &blockhash).unwrap();
+        assert_eq!(0, res["height"].as_u64().unwrap());
+        assert_eq!(blockhash.to_hex(), res["block_hash"].as_str().unwrap());
+    }
+
+    #[test]
+    fn test_first_use_mempool() {
+        let txid = Txid::from_hex(&"aa".repeat(32)).unwrap();
+        let res = first_use_to_json(
+            &[0; 32],
+            Some((MEMPOOL_HEIGHT, txid)),
+            &BlockHash::default(),
+        )
+        .unwrap();
+        assert_eq!(0, res["height"].as_u64().unwrap());
+        assert_eq!(
+            BlockHash::default().to_hex(),
+            res["block_hash"].as_str().unwrap()
+        );
+        assert_eq!(txid.to_hex(), res["tx_hash"].as_str().unwrap());
+    }
+
+    #[test]
+    fn test_first_use_unused() {
+        let err = first_use_to_json(&[0; 32], None, &BlockHash::default()).unwrap_err();
+        match err.kind() {
+            ErrorKind::RpcError(RpcErrorCode::NotFound, _) => {}
+            _ => panic!("expected NotFound error, got {}", err),
+        }
+    }
+
+    #[test]
+    fn test_history_mempool_only() {
+        use crate::query::TestQuery;
+        use std::time::Duration;
+
+        let test_query = TestQuery::new();
+        let timeout = TimeoutTrigger::new(Duration::from_secs(10));
+        let history = |options: &HistoryOptions| {
+            get_history(test_query.query(), &[0; 32], options, 0, 0, &timeout)
+        };
+        let mempool_only = HistoryOptions {
+            mempool_only: true,
+            ..HistoryOptions::default()
+        };
+        assert_eq!(history(&mempool_only).unwrap(), json!([]));
+
+        // leaving out unconfirmed transactions would return nothing
+        let err = history(&HistoryOptions {
+            include_unconfirmed: false,
+            ..mempool_only
+        })
+        .unwrap_err();
+        match err.kind() {
+            ErrorKind::RpcError(RpcErrorCode::InvalidParams, msg) => {
+                assert!(msg.contains("include_unconfirmed"))
+            }
+            _ => panic!("expected InvalidParams error, got {}", err),
+        }
+    }
 }
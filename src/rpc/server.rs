@@ -1,9 +1,13 @@
+use crate::banner::BannerVars;
 use crate::def::{
     ELECTRSCASH_VERSION, PROTOCOL_HASH_FUNCTION, PROTOCOL_VERSION_MAX, PROTOCOL_VERSION_MIN,
 };
+use crate::doslimit::GlobalLimits;
 use crate::errors::*;
 use crate::query::Query;
 use crate::rpc::parseutil::{rpc_arg_error, str_from_value};
+use crate::rpc::rpcstats::RpcStats;
+use crate::rpc::warnings::DaemonWarnings;
 use bitcoincash::hashes::hex::ToHex;
 use serde_json::Value;
 use std::sync::Arc;
@@ -18,27 +22,40 @@ use version_compare::Version;
 // The default argument to server.version
 const SPEC_DEFAULT_VERSION: &str = "1.4";
 
-fn best_match(client_min: &Version, client_max: &Version) -> String {
+/// Pick the highest protocol version supported by both us and the client.
+///
+/// Fails if the client range does not overlap with ours. Reference servers
+/// disconnect such clients, as no further request can be answered in a
+/// protocol the client understands.
+fn best_match(client_min: &Version, client_max: &Version) -> Result<String> {
     let our_min = Version::from(PROTOCOL_VERSION_MIN).unwrap();
     let our_max = Version::from(PROTOCOL_VERSION_MAX).unwrap();
 
-    if *client_max >= our_max {
-        return our_max.as_str().into();
+    if *client_min > *client_max {
+        return Err(rpc_arg_error(&format!(
+            "invalid version range [{}, {}]",
+            client_min, client_max
+        ))
+        .into());
     }
 
-    if *client_max <= our_min {
-        return our_min.as_str().into();
+    if *client_max < our_min || *client_min > our_max {
+        return Err(rpc_invalid_request(format!(
+            "unsupported protocol version [{}, {}], server supports [{}, {}]",
+            client_min, client_max, our_min, our_max
+        ))
+        .into());
     }
 
-    if *client_min >= our_min && *client_max <= our_max {
-        return client_max.as_str().into();
+    if *client_max >= our_max {
+        return Ok(our_max.as_str().into());
     }
 
-    our_min.as_str().into()
+    Ok(client_max.as_str().into())
 }
 
-fn best_match_response(client_min: &Version, client_max: &Version) -> Value {
-    json!([versionstr(), best_match(client_min, client_max)])
+fn best_match_response(client_min: &Version, client_max: &Version) -> Result<Value> {
+    Ok(json!([versionstr(), best_match(client_min, client_max)?]))
 }
 
 fn versionstr() -> String {
@@ -57,22 +74,28 @@ pub fn server_version(params: &[Value]) -> Result<Value> {
 
     if let Ok(versionstr) = str_from_value(Some(val), "version") {
         let version = parse_version(&versionstr)?;
-        return Ok(best_match_response(&version, &version));
+        return best_match_response(&version, &version);
     }
 
     if let Some(minmax_list) = val.as_array() {
-        let min = str_from_value(Some(&minmax_list[0]), "version")?;
+        let min = str_from_value(minmax_list.first(), "version")?;
         let min = parse_version(&min)?;
-        let max = str_from_value(Some(&minmax_list[1]), "version")?;
+        let max = str_from_value(minmax_list.get(1), "version")?;
         let max = parse_version(&max)?;
-        return Ok(best_match_response(&min, &max));
+        return best_match_response(&min, &max);
     }
 
     Err(rpc_arg_error("invalid value in version argument").into())
 }
 
-pub fn server_banner(query: &Arc<Query>) -> Result<Value> {
-    Ok(json!(query.get_banner()?))
+pub fn server_banner(query: &Arc<Query>, global_limits: &GlobalLimits) -> Result<Value> {
+    let vars = BannerVars {
+        height: query.get_best_header()?.height(),
+        version: ELECTRSCASH_VERSION.to_string(),
+        mempool_count: query.mempool_tx_count(),
+        peers: global_limits.total_connections(),
+    };
+    Ok(json!(query.get_banner(&vars)?))
 }
 
 pub fn server_donation_address() -> Result<Value> {
@@ -95,14 +118,120 @@ pub fn server_features(query: &Arc<Query>) -> Result<Value> {
     }))
 }
 
+pub fn server_warnings(warnings: &DaemonWarnings) -> Result<Value> {
+    Ok(json!(warnings.get()))
+}
+
 pub fn server_add_peer() -> Result<Value> {
     Ok(json!(true))
 }
 
+/// Server health data returned by server.resource_usage
+pub struct ResourceUsage {
+    pub height: usize,
+    /// Blocks left to index, or None once synced
+    pub blocks_behind: Option<usize>,
+    pub mempool_tx_count: usize,
+    /// (bytes used, bytes capacity)
+    pub tx_cache: (u64, u64),
+    /// (bytes used, bytes capacity)
+    pub blocktxids_cache: (u64, u64),
+    /// (bytes used, bytes capacity)
+    pub address_cache: (u64, u64),
+    pub connections: u32,
+    pub max_connections: u32,
+    pub subscriptions: i64,
+    pub subscription_bytes: i64,
+}
+
+impl ResourceUsage {
+    pub fn to_json(&self) -> Value {
+        let cache_json = |(usage, capacity): (u64, u64)| {
+            json!({
+                "usage": usage,
+                "capacity": capacity,
+            })
+        };
+        json!({
+            "height": self.height,
+            "synced": self.blocks_behind.is_none(),
+            "blocks_behind": self.blocks_behind.unwrap_or(0),
+            "mempool": {
+                "tx_count": self.mempool_tx_count,
+            },
+            "cache": {
+                "tx": cache_json(self.tx_cache),
+                "blocktxids": cache_json(self.blocktxids_cache),
+                "address": cache_json(self.address_cache),
+            },
+            "connections": {
+                "active": self.connections,
+                "max": self.max_connections,
+            },
+            "subscriptions": {
+                "count": self.subscriptions,
+                "bytes": self.subscription_bytes,
+            },
+        })
+    }
+}
+
+pub fn server_resource_usage(
+    query: &Arc<Query>,
+    stats: &RpcStats,
+    global_limits: &GlobalLimits,
+) -> Result<Value> {
+    let usage = ResourceUsage {
+        height: query.get_best_header()?.height(),
+        blocks_behind: query.blocks_behind(),
+        mempool_tx_count: query.mempool_tx_count(),
+        tx_cache: query.tx().cache_usage(),
+        blocktxids_cache: query.blocktxids_cache_usage(),
+        address_cache: query.address_cache().usage(),
+        connections: global_limits.total_connections(),
+        max_connections: global_limits.connection_limits().0,
+        subscriptions: stats.subscriptions.get(),
+        subscription_bytes: stats.subscription_bytes.get(),
+    };
+    Ok(usage.to_json())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_resource_usage_json() {
+        let usage = ResourceUsage {
+            height: 700_000,
+            blocks_behind: None,
+            mempool_tx_count: 42,
+            tx_cache: (100, 1000),
+            blocktxids_cache: (200, 2000),
+            address_cache: (300, 3000),
+            connections: 3,
+            max_connections: 500,
+            subscriptions: 7,
+            subscription_bytes: 1234,
+        };
+        assert_eq!(
+            usage.to_json(),
+            json!({
+                "height": 700_000,
+                "synced": true,
+                "blocks_behind": 0,
+                "mempool": {"tx_count": 42},
+                "cache": {
+                    "tx": {"usage": 100, "capacity": 1000},
+                    "blocktxids": {"usage": 200, "capacity": 2000},
+                    "address": {"usage": 300, "capacity": 3000},
+                },
+                "connections": {"active": 3, "max": 500},
+                "subscriptions": {"count": 7, "bytes": 1234},
+            })
+        );
+    }
+
     #[test]
     fn test_server_version_noarg() {
         let resp = server_version(&[]).unwrap();
@@ -115,12 +244,26 @@ mod tests {
     #[test]
     fn test_server_version_strarg() {
         let clientver = json!("bestclient 1.0");
-        let resp = server_version(&[clientver.clone(), json!("1.3")]).unwrap();
-        assert_eq!(resp[1].as_str().unwrap(), PROTOCOL_VERSION_MIN);
-        let resp = server_version(&[clientver.clone(), json!("13.3.7")]).unwrap();
+        let resp = server_version(&[clientver.clone(), json!("1.4.1")]).unwrap();
+        assert_eq!(resp[1].as_str().unwrap(), "1.4.1");
+        let resp = server_version(&[clientver.clone(), json!(PROTOCOL_VERSION_MAX)]).unwrap();
         assert_eq!(resp[1].as_str().unwrap(), PROTOCOL_VERSION_MAX);
     }
 
+    #[test]
+    fn test_server_version_too_old() {
+        let clientver = json!("bestclient 1.0");
+        assert!(server_version(&[clientver.clone(), json!("1.3")]).is_err());
+        assert!(server_version(&[clientver, json!(["1.2", "1.3"])]).is_err());
+    }
+
+    #[test]
+    fn test_server_version_too_new() {
+        let clientver = json!("bestclient 1.0");
+        assert!(server_version(&[clientver.clone(), json!("13.3.7")]).is_err());
+        assert!(server_version(&[clientver, json!(["1.5", "13.3.7"])]).is_err());
+    }
+
     #[test]
     fn test_server_version_minmax() {
         let clientver = json!("bestclient 1.0");
@@ -128,8 +271,8 @@ mod tests {
         let resp = server_version(&[clientver.clone(), json!(["1.4", "13.3.7"])]).unwrap();
         assert_eq!(resp[1].as_str().unwrap(), PROTOCOL_VERSION_MAX);
 
-        // client max is lower than our min, we shoud return our min
-        let resp = server_version(&[clientver.clone(), json!(["1.2", "1.3"])]).unwrap();
+        // client min is lower than our min, but range overlaps
+        let resp = server_version(&[clientver.clone(), json!(["1.2", "1.4"])]).unwrap();
         assert_eq!(resp[1].as_str().unwrap(), PROTOCOL_VERSION_MIN);
 
         // client max is somewhere between our max and min, return same version
@@ -137,5 +280,9 @@ mod tests {
         let resp = server_version(&[clientver.clone(), json!([PROTOCOL_VERSION_MIN, client_max])])
             .unwrap();
         assert_eq!(resp[1].as_str().unwrap(), client_max);
+
+        // malformed ranges
+        assert!(server_version(&[clientver.clone(), json!(["1.4.3", "1.4"])]).is_err());
+        assert!(server_version(&[clientver, json!(["1.4"])]).is_err());
     }
 }
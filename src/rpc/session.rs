@@ -0,0 +1,298 @@
+use crate::errors::*;
+use crate::rpc::blockchain::SavedSubscriptions;
+use crate::rpc::rpcstats::RpcStats;
+use crate::scripthash::FullHash;
+use crate::util::HeaderEntry;
+use bitcoincash::hash_types::Txid;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Changes notified while a session was detached, checked again when it is
+/// resumed.
+#[derive(Default)]
+pub struct MissedChanges {
+    pub scripthashes: HashSet<FullHash>,
+    pub txids: HashSet<Txid>,
+    /// Latest chain tip, if it changed
+    pub chaintip: Option<HeaderEntry>,
+}
+
+struct Session {
+    subscriptions: SavedSubscriptions,
+    missed: MissedChanges,
+    expiry: Instant,
+    /// IP prefix of the peer, see `GlobalLimits::get_prefix`
+    prefix: IpAddr,
+    /// Order in which the sessions were saved
+    seq: u64,
+}
+
+/// Subscriptions of disconnected peers, kept for `grace` so that a peer
+/// reconnecting with its session token resumes them without recomputing
+/// their status.
+///
+/// Kept subscriptions stay counted in the stats (and so in the global
+/// subscription limit) until resumed or expired. Peers sharing an IP prefix
+/// can keep up to `max_per_prefix` sessions, the oldest ones are dropped
+/// beyond that.
+pub struct SessionStore {
+    grace: Option<Duration>,
+    max_per_prefix: usize,
+    sessions: Mutex<HashMap<String, Session>>,
+    next_seq: AtomicU64,
+    stats: Arc<RpcStats>,
+}
+
+impl SessionStore {
+    /// Keep sessions for `grace` after disconnecting (None to disable), up
+    /// to `max_per_prefix` per IP prefix (0 for no limit).
+    pub fn new(
+        grace: Option<Duration>,
+        max_per_prefix: usize,
+        stats: Arc<RpcStats>,
+    ) -> SessionStore {
+        SessionStore {
+            grace,
+            max_per_prefix,
+            sessions: Mutex::new(HashMap::new()),
+            next_seq: AtomicU64::new(0),
+            stats,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.grace.is_some()
+    }
+
+    pub fn new_token() -> String {
+        hex::encode(rand::random::<[u8; 16]>())
+    }
+
+    pub fn check_enabled(&self) -> Result<()> {
+        if !self.is_enabled() {
+            bail!(ErrorKind::RpcError(
+                RpcErrorCode::Other,
+                "session resume is disabled on this server".to_string()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Keep the subscriptions of a disconnected peer from `prefix` under
+    /// `token`.
+    pub fn save(&self, token: String, prefix: IpAddr, subscriptions: SavedSubscriptions) {
+        let grace = match self.grace {
+            Some(grace) if !subscriptions.is_empty() => grace,
+            _ => {
+                subscriptions.release(&self.stats);
+                return;
+            }
+        };
+        self.expire();
+        let session = Session {
+            subscriptions,
+            missed: MissedChanges::default(),
+            expiry: Instant::now() + grace,
+            prefix,
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(replaced) = sessions.remove(&token) {
+            replaced.subscriptions.release(&self.stats);
+        }
+        if self.max_per_prefix > 0 {
+            let mut same_prefix: Vec<(u64, String)> = sessions
+                .iter()
+                .filter(|(_, session)| session.prefix == prefix)
+                .map(|(token, session)| (session.seq, token.clone()))
+                .collect();
+            if same_prefix.len() >= self.max_per_prefix {
+                same_prefix.sort_unstable();
+                let excess = same_prefix.len() + 1 - self.max_per_prefix;
+                for (_, oldest) in same_prefix.into_iter().take(excess) {
+                    let dropped = sessions.remove(&oldest).unwrap();
+                    dropped.subscriptions.release(&self.stats);
+                }
+            }
+        }
+        sessions.insert(token, session);
+    }
+
+    /// Take the subscriptions kept under `token`, along with the changes
+    /// missed since.
+    pub fn take(&self, token: &str) -> Result<(SavedSubscriptions, MissedChanges)> {
+        self.check_enabled()?;
+        self.expire();
+        let session = self.sessions.lock().unwrap().remove(token);
+        match session {
+            Some(session) => Ok((session.subscriptions, session.missed)),
+            None => bail!(ErrorKind::RpcError(
+                RpcErrorCode::NotFound,
+                "unknown or expired session".to_string()
+            )),
+        }
+    }
+
+    /// Drop the sessions whose grace period has passed, releasing their
+    /// subscriptions.
+    pub fn expire(&self) {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| {
+            if session.expiry > now {
+                return true;
+            }
+            session.subscriptions.release(&self.stats);
+            false
+        });
+    }
+
+    /// Number of sessions kept.
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn on_scripthash_change(&self, scripthash: &FullHash) {
+        for session in self.sessions.lock().unwrap().values_mut() {
+            if session.subscriptions.contains_scripthash(scripthash) {
+                session.missed.scripthashes.insert(*scripthash);
+            }
+        }
+    }
+
+    pub fn on_txs_change(&self, txids: &HashSet<Txid>) {
+        for session in self.sessions.lock().unwrap().values_mut() {
+            for txid in txids {
+                if session.subscriptions.contains_tx(txid) {
+                    session.missed.txids.insert(*txid);
+                }
+            }
+        }
+    }
+
+    pub fn on_chaintip_change(&self, chaintip: &HeaderEntry) {
+        for session in self.sessions.lock().unwrap().values_mut() {
+            session.missed.chaintip = Some(chaintip.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincash::hashes::Hash;
+    use std::net::Ipv4Addr;
+
+    const PEER: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 2, 0, 0));
+
+    #[test]
+    fn test_resume_within_grace() {
+        let stats = Arc::new(RpcStats::dummy());
+        let store = SessionStore::new(Some(Duration::from_secs(3600)), 0, Arc::clone(&stats));
+        let token = SessionStore::new_token();
+        assert_eq!(token.len(), 32);
+        assert_ne!(token, SessionStore::new_token());
+
+        let scripthash = [1; 32];
+        let txid = Txid::from_inner([2; 32]);
+        let saved = SavedSubscriptions::counted(&[scripthash], &[txid], &stats);
+        let bytes = stats.subscription_bytes.get();
+        store.save(token.clone(), PEER, saved);
+        assert_eq!(store.len(), 1);
+        // still counted while kept
+        assert_eq!(stats.subscriptions.get(), 2);
+        assert_eq!(stats.subscription_bytes.get(), bytes);
+
+        // changes while disconnected are checked on resume
+        store.on_scripthash_change(&scripthash);
+        store.on_scripthash_change(&[3; 32]);
+        store.on_txs_change(&[txid, Txid::from_inner([4; 32])].iter().cloned().collect());
+        store.expire();
+
+        let (saved, missed) = store.take(&token).unwrap();
+        assert_eq!(saved.len(), 2);
+        assert!(saved.contains_scripthash(&scripthash));
+        assert!(saved.contains_tx(&txid));
+        assert_eq!(missed.scripthashes, [scripthash].iter().cloned().collect());
+        assert_eq!(missed.txids, [txid].iter().cloned().collect());
+        assert!(missed.chaintip.is_none());
+        assert_eq!(store.len(), 0);
+
+        // a session is resumed once
+        let err = store.take(&token).err().unwrap();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::RpcError(RpcErrorCode::NotFound, _)
+        ));
+    }
+
+    #[test]
+    fn test_expire_after_grace() {
+        let stats = Arc::new(RpcStats::dummy());
+        let store = SessionStore::new(Some(Duration::from_secs(0)), 0, Arc::clone(&stats));
+        let token = SessionStore::new_token();
+        let saved = SavedSubscriptions::counted(&[[1; 32], [2; 32]], &[], &stats);
+        assert_eq!(stats.subscriptions.get(), 2);
+        store.save(token.clone(), PEER, saved);
+
+        // the grace period has passed, freeing the subscription slots
+        store.expire();
+        assert_eq!(store.len(), 0);
+        assert_eq!(stats.subscriptions.get(), 0);
+        assert_eq!(stats.subscription_bytes.get(), 0);
+        assert!(store.take(&token).is_err());
+
+        // nothing is kept with sessions disabled
+        let store = SessionStore::new(None, 0, Arc::clone(&stats));
+        let saved = SavedSubscriptions::counted(&[[1; 32]], &[], &stats);
+        store.save(token.clone(), PEER, saved);
+        assert_eq!(store.len(), 0);
+        assert_eq!(stats.subscriptions.get(), 0);
+        let err = store.take(&token).err().unwrap();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::RpcError(RpcErrorCode::Other, _)
+        ));
+    }
+
+    #[test]
+    fn test_sessions_per_prefix() {
+        let stats = Arc::new(RpcStats::dummy());
+        let store = SessionStore::new(Some(Duration::from_secs(3600)), 2, Arc::clone(&stats));
+        let other = IpAddr::V4(Ipv4Addr::new(1, 3, 0, 0));
+        let tokens: Vec<String> = (0..3).map(|_| SessionStore::new_token()).collect();
+        let save = |token: &String, prefix, scripthash| {
+            let saved = SavedSubscriptions::counted(&[[scripthash; 32]], &[], &stats);
+            store.save(token.clone(), prefix, saved);
+        };
+        save(&tokens[0], PEER, 1);
+        save(&tokens[1], PEER, 2);
+        save(&tokens[2], other, 3);
+        assert_eq!(store.len(), 3);
+
+        // saving the same session again doesn't count twice
+        save(&tokens[1], PEER, 2);
+        assert_eq!(store.len(), 3);
+        assert_eq!(stats.subscriptions.get(), 3);
+
+        // over the limit, the oldest session of the prefix is dropped
+        let newest = SessionStore::new_token();
+        save(&newest, PEER, 4);
+        assert_eq!(store.len(), 3);
+        assert_eq!(stats.subscriptions.get(), 3);
+        assert!(store.take(&tokens[0]).is_err());
+        for token in [&tokens[1], &tokens[2], &newest].iter() {
+            let (saved, _) = store.take(token).unwrap();
+            saved.release(&stats);
+        }
+        assert_eq!(stats.subscriptions.get(), 0);
+        assert_eq!(stats.subscription_bytes.get(), 0);
+    }
+}
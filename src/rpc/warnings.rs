@@ -0,0 +1,85 @@
+use crate::errors::*;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Warnings reported by the daemon, such as unknown new rules activating or
+/// a large reorg.
+///
+/// They're periodically refreshed, so that clients can alert their users
+/// when the node backing this server is in a degraded state.
+pub struct DaemonWarnings {
+    warnings: RwLock<Vec<String>>,
+    refresh_interval: Duration,
+    last_refresh: Mutex<Instant>,
+}
+
+impl DaemonWarnings {
+    pub fn new(warnings: Vec<String>, refresh_interval: Duration) -> DaemonWarnings {
+        DaemonWarnings {
+            warnings: RwLock::new(warnings),
+            refresh_interval,
+            last_refresh: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Current warnings of the daemon, empty when all is well.
+    pub fn get(&self) -> Vec<String> {
+        self.warnings.read().unwrap().clone()
+    }
+
+    /// Fetches the warnings again, if refresh interval has passed since the
+    /// last refresh.
+    pub fn maybe_refresh(&self, fetch: impl FnOnce() -> Result<Vec<String>>) -> Result<()> {
+        let mut last_refresh = self.last_refresh.lock().unwrap();
+        if last_refresh.elapsed() < self.refresh_interval {
+            return Ok(());
+        }
+        *last_refresh = Instant::now();
+        let warnings = fetch().chain_err(|| "failed to refresh daemon warnings")?;
+        let mut current = self.warnings.write().unwrap();
+        if *current != warnings {
+            if warnings.is_empty() {
+                info!("bitcoind warnings cleared");
+            } else {
+                warn!("bitcoind warnings: {:?}", warnings);
+            }
+            *current = warnings;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warnings_refresh() {
+        let warnings = DaemonWarnings::new(vec![], Duration::from_secs(0));
+        assert!(warnings.get().is_empty());
+
+        // daemon started warning
+        let warning = "Warning: unknown new rules activated".to_string();
+        warnings
+            .maybe_refresh(|| Ok(vec![warning.clone()]))
+            .unwrap();
+        assert_eq!(warnings.get(), vec![warning.clone()]);
+
+        // failure keeps the previous warnings
+        assert!(warnings.maybe_refresh(|| bail!("daemon down")).is_err());
+        assert_eq!(warnings.get(), vec![warning]);
+
+        // warning went away
+        warnings.maybe_refresh(|| Ok(vec![])).unwrap();
+        assert!(warnings.get().is_empty());
+    }
+
+    #[test]
+    fn test_warnings_refresh_interval() {
+        let warnings = DaemonWarnings::new(vec![], Duration::from_secs(3600));
+        warnings
+            .maybe_refresh(|| panic!("refreshed before interval passed"))
+            .unwrap();
+        assert!(warnings.get().is_empty());
+    }
+}
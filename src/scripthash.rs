@@ -1,5 +1,6 @@
 use bitcoincash::blockdata::opcodes;
 use bitcoincash::blockdata::script::{Builder, Script};
+use bitcoincash_addr::cashaddr::DecodingError;
 use bitcoincash_addr::{Address, HashType};
 use sha2::{Digest, Sha256};
 use std::convert::TryInto;
@@ -25,23 +26,124 @@ pub fn full_hash(hash: &[u8]) -> FullHash {
     hash.try_into().expect("failed to convert into FullHash")
 }
 
+// Cashaddr version byte types, including the token-aware ones (CashTokens)
+const CASHADDR_TYPE_MASK: u8 = 0x78;
+const CASHADDR_TYPE_P2PKH: u8 = 0x00;
+const CASHADDR_TYPE_P2SH: u8 = 0x08;
+const CASHADDR_TYPE_TOKEN_P2PKH: u8 = 0x10;
+const CASHADDR_TYPE_TOKEN_P2SH: u8 = 0x18;
+
+const CASHADDR_CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const CASHADDR_CHECKSUM_LEN: usize = 8;
+
+fn cashaddr_polymod(values: impl Iterator<Item = u8>) -> u64 {
+    const GENERATORS: [u64; 5] = [
+        0x98_f2bc_8e61,
+        0x79_b76d_99e2,
+        0xf3_3e5f_b3c4,
+        0xae_2eab_e2a8,
+        0x1e_4f43_e470,
+    ];
+    let mut c: u64 = 1;
+    for d in values {
+        let c0 = c >> 35;
+        c = ((c & 0x07_ffff_ffff) << 5) ^ u64::from(d);
+        for (i, generator) in GENERATORS.iter().enumerate() {
+            if c0 & (1 << i) != 0 {
+                c ^= generator;
+            }
+        }
+    }
+    c ^ 1
+}
+
+/// Decode a cashaddr into its hash type and hash. Unlike `Address::decode`,
+/// this accepts the token-aware address types, which map to the same
+/// hash types (and locking scripts) as the standard ones.
+fn decode_cashaddr(addr: &str) -> Result<(HashType, Vec<u8>)> {
+    let (prefix, payload) = addr
+        .split_once(':')
+        .chain_err(|| "missing cashaddr prefix")?;
+    if payload.chars().any(char::is_uppercase) && payload.chars().any(char::is_lowercase) {
+        bail!("mixed case cashaddr");
+    }
+    let payload = payload.to_lowercase();
+    let values: Vec<u8> = payload
+        .bytes()
+        .map(|c| {
+            CASHADDR_CHARSET
+                .iter()
+                .position(|&x| x == c)
+                .map(|i| i as u8)
+                .chain_err(|| format!("invalid cashaddr character {:?}", c as char))
+        })
+        .collect::<Result<_>>()?;
+    if values.len() <= CASHADDR_CHECKSUM_LEN {
+        bail!("cashaddr too short");
+    }
+    let prefix_values = prefix.bytes().map(|c| c & 0x1f).chain(Some(0));
+    if cashaddr_polymod(prefix_values.chain(values.iter().cloned())) != 0 {
+        bail!("invalid cashaddr checksum");
+    }
+
+    // Convert from 5-bit groups to bytes, dropping the padding
+    let mut bytes = vec![];
+    let (mut acc, mut bits) = (0u32, 0u32);
+    for value in &values[..values.len() - CASHADDR_CHECKSUM_LEN] {
+        acc = (acc << 5) | u32::from(*value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    let (version, body) = bytes.split_first().chain_err(|| "empty cashaddr payload")?;
+    let sizes = [20, 24, 28, 32, 40, 48, 56, 64];
+    if body.len() != sizes[usize::from(version & 0x07)] {
+        bail!("invalid cashaddr hash length {}", body.len());
+    }
+    let hash_type = match version & CASHADDR_TYPE_MASK {
+        CASHADDR_TYPE_P2PKH | CASHADDR_TYPE_TOKEN_P2PKH => HashType::Key,
+        CASHADDR_TYPE_P2SH | CASHADDR_TYPE_TOKEN_P2SH => HashType::Script,
+        _ => bail!("unknown cashaddr version byte {:#04x}", version),
+    };
+    Ok((hash_type, body.to_vec()))
+}
+
+/// Scripthash of the output script paying to `addr`, a cashaddr (standard or
+/// token-aware) or a legacy address. Token-aware addresses map to the same
+/// scripthash as their standard form.
 pub fn addr_to_scripthash(addr: &str) -> Result<FullHash> {
-    let decoded = match Address::decode(addr) {
-        Ok(d) => d,
-        Err(e) => return Err(format!("{:?}", e).into()),
+    let (hash_type, body) = match Address::decode(addr) {
+        Ok(d) => (d.hash_type, d.body),
+        // the token-aware types are rejected as unknown version bytes
+        Err((DecodingError::InvalidVersion(_), _)) => decode_cashaddr(addr)
+            .chain_err(|| rpc_invalid_params(format!("invalid address {}", addr)))?,
+        Err((cashaddr_err, base58_err)) => {
+            let reason = if addr.contains(':') {
+                cashaddr_err.to_string()
+            } else {
+                base58_err.to_string()
+            };
+            bail!(rpc_invalid_params(format!(
+                "invalid address {}: {}",
+                addr, reason
+            )))
+        }
     };
 
-    let pubkey: Script = match decoded.hash_type {
+    let pubkey: Script = match hash_type {
         HashType::Key => Builder::new()
             .push_opcode(opcodes::all::OP_DUP)
             .push_opcode(opcodes::all::OP_HASH160)
-            .push_slice(&decoded.body[..])
+            .push_slice(&body[..])
             .push_opcode(opcodes::all::OP_EQUALVERIFY)
             .push_opcode(opcodes::all::OP_CHECKSIG)
             .into_script(),
         HashType::Script => Builder::new()
             .push_opcode(opcodes::all::OP_HASH160)
-            .push_slice(&decoded.body[..])
+            .push_slice(&body[..])
             .push_opcode(opcodes::all::OP_EQUAL)
             .into_script(),
     };
@@ -113,9 +215,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_addr_to_scripthash_token_aware() {
+        let p2pkh = addr_to_scripthash("bitcoincash:qp3wjpa3tjlj042z2wv7hahsldgwhwy0rq9sywjpyy");
+        assert_eq!(
+            p2pkh.unwrap(),
+            addr_to_scripthash("bitcoincash:zp3wjpa3tjlj042z2wv7hahsldgwhwy0rqz6hsu8mh").unwrap()
+        );
+        let p2sh = addr_to_scripthash("bitcoincash:pp8skudq3x5hzw8ew7vzsw8tn4k8wxsqsv0lt0mf3g");
+        assert_eq!(
+            p2sh.unwrap(),
+            addr_to_scripthash("bitcoincash:rp8skudq3x5hzw8ew7vzsw8tn4k8wxsqsvg4c340wm").unwrap()
+        );
+    }
+
     #[test]
     fn test_addr_to_scripthash_garbage() {
         assert!(addr_to_scripthash("garbage").is_err());
+        // bad checksum
+        assert!(
+            addr_to_scripthash("bitcoincash:zp3wjpa3tjlj042z2wv7hahsldgwhwy0rqz6hsu8mm").is_err()
+        );
+        // unknown address type
+        let err = addr_to_scripthash("bitcoincash:yp3wjpa3tjlj042z2wv7hahsldgwhwy0rqtytmwdnt")
+            .unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::RpcError(RpcErrorCode::InvalidParams, _)
+        ));
     }
 
     #[test]
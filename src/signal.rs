@@ -1,5 +1,7 @@
 use crossbeam_channel as channel;
 use crossbeam_channel::RecvTimeoutError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
@@ -8,36 +10,52 @@ use crate::errors::*;
 #[derive(Clone)] // so multiple threads could wait on signals
 pub struct Waiter {
     receiver: channel::Receiver<i32>,
+    sender: channel::Sender<i32>,
+    interrupted: Arc<AtomicBool>,
 }
 
-fn notify(signals: &[i32]) -> channel::Receiver<i32> {
+fn notify(signals: &[i32]) -> (channel::Sender<i32>, channel::Receiver<i32>) {
     let (s, r) = channel::bounded(1);
     let signals =
         signal_hook::iterator::Signals::new(signals).expect("failed to register signal hook");
+    let sender = s.clone();
     thread::spawn(move || {
         for signal in signals.forever() {
             s.send(signal)
                 .unwrap_or_else(|_| panic!("failed to send signal {}", signal));
         }
     });
-    r
+    (sender, r)
 }
 
 impl Waiter {
     pub fn start() -> Waiter {
+        let (sender, receiver) = notify(&[
+            signal_hook::SIGINT,
+            signal_hook::SIGTERM,
+            signal_hook::SIGUSR1, // allow external triggering (e.g. via bitcoind `blocknotify`)
+        ]);
         Waiter {
-            receiver: notify(&[
-                signal_hook::SIGINT,
-                signal_hook::SIGTERM,
-                signal_hook::SIGUSR1, // allow external triggering (e.g. via bitcoind `blocknotify`)
-            ]),
+            receiver,
+            sender,
+            interrupted: Arc::new(AtomicBool::new(false)),
         }
     }
+    /// Wake up a waiting thread, same as receiving SIGUSR1.
+    pub fn wake(&self) {
+        // If the channel is full, a wake-up (or signal) is already pending.
+        let _ = self.sender.try_send(signal_hook::SIGUSR1);
+    }
+    /// Returns true once an interruption signal has been received.
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
     pub fn wait(&self, duration: Duration) -> Result<()> {
         match self.receiver.recv_timeout(duration) {
             Ok(sig) => {
                 trace!("notified via SIG{}", sig);
                 if sig != signal_hook::SIGUSR1 {
+                    self.interrupted.store(true, Ordering::Relaxed);
                     bail!(ErrorKind::Interrupt(sig))
                 };
                 Ok(())
@@ -1,13 +1,15 @@
 use rocksdb::perf::get_memory_usage_stats;
 use std::path::{Path, PathBuf};
 use std::str::from_utf8;
-use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::def::DATABASE_VERSION;
+use crate::errors::*;
+use crate::index::read_start_height;
 use crate::metrics::Metrics;
 use crate::util::spawn_thread;
 use crate::util::Bytes;
@@ -25,13 +27,68 @@ impl Row {
 }
 
 pub trait ReadStore: Sync {
-    fn get(&self, key: &[u8]) -> Option<Bytes>;
+    fn get(&self, key: &[u8]) -> Result<Option<Bytes>>;
     fn scan(&self, prefix: &[u8]) -> Vec<Row>;
+
+    /// Rows with keys from `start` up to the ones starting with `last`
+    /// (inclusive), in key order. At most `limit` rows are returned.
+    ///
+    /// By default, the rows sharing the common prefix of `start` and `last`
+    /// are scanned; stores iterating in key order stop at `last` instead.
+    fn scan_range(&self, start: &[u8], last: &[u8], limit: usize) -> Vec<Row> {
+        let common = start.iter().zip(last).take_while(|(a, b)| a == b).count();
+        self.scan(&start[..common])
+            .into_iter()
+            .filter(|row| row.key[..] >= *start && is_up_to(&row.key, last))
+            .take(limit)
+            .collect()
+    }
+}
+
+/// Whether `key` is ordered before `last`, or starts with it.
+pub fn is_up_to(key: &[u8], last: &[u8]) -> bool {
+    key[..key.len().min(last.len())] <= *last
 }
 
 pub trait WriteStore: Sync {
-    fn write<I: IntoIterator<Item = Row>>(&self, rows: I, sync: bool);
-    fn flush(&self);
+    fn write<I: IntoIterator<Item = Row>>(&self, rows: I, sync: bool) -> Result<()>;
+    fn delete<I: IntoIterator<Item = Bytes>>(&self, keys: I) -> Result<()>;
+    fn flush(&self) -> Result<()>;
+}
+
+/// Attempts of a RocksDB operation before giving up
+const DB_RETRY_ATTEMPTS: usize = 4;
+/// Delay before the first retry, doubled after each failed attempt
+const DB_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Run `f` until it succeeds, up to `attempts` times, sleeping `backoff`
+/// (doubled each time) between attempts. RocksDB may fail transiently (e.g.
+/// when the disk is temporarily full or a background job holds a lock), so
+/// the error is only returned once all attempts failed.
+fn retry<T, E: std::fmt::Display>(
+    op: &str,
+    attempts: usize,
+    mut backoff: Duration,
+    mut f: impl FnMut() -> std::result::Result<T, E>,
+) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt >= attempts => {
+                bail!("RocksDB {} failed after {} attempts: {}", op, attempts, e)
+            }
+            Err(e) => {
+                warn!(
+                    "RocksDB {} failed (attempt {}/{}), retrying in {:?}: {}",
+                    op, attempt, attempts, backoff, e
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -39,11 +96,48 @@ struct Options {
     path: PathBuf,
     bulk_import: bool,
     low_memory: bool,
+    live_wal: bool,
+}
+
+impl Options {
+    /// The write-ahead log is skipped during bulk import, as an interrupted
+    /// import resumes from the last indexed block anyway. After bulk import,
+    /// it's used (if `live_wal` is set) so that recently indexed blocks
+    /// survive a crash.
+    fn use_wal(&self, sync: bool) -> bool {
+        sync || (self.live_wal && !self.bulk_import)
+    }
+}
+
+struct Stats {
+    get: prometheus::Histogram,
+    scan: prometheus::Histogram,
+    iter_scan: prometheus::Histogram,
+}
+
+impl Stats {
+    fn new(metrics: &Metrics, instance: usize) -> Stats {
+        let latency = metrics.histogram_vec(
+            prometheus::HistogramOpts::new(
+                format!("electrscash_rocksdb_read_latency_{}", instance),
+                "RocksDB read latency (seconds)".to_string(),
+            )
+            // RocksDB reads typically take microseconds
+            .buckets(prometheus::exponential_buckets(1e-6, 4.0, 12).unwrap()),
+            &["operation"],
+        );
+        Stats {
+            get: latency.with_label_values(&["get"]),
+            scan: latency.with_label_values(&["scan"]),
+            iter_scan: latency.with_label_values(&["iter_scan"]),
+        }
+    }
 }
 
 pub struct DbStore {
     db: Arc<rocksdb::DB>,
     opts: Options,
+    stats: Stats,
     stats_thread: Option<thread::JoinHandle<()>>,
     stats_thread_kill: Arc<(Mutex<bool>, Condvar)>,
 }
@@ -69,25 +163,28 @@ impl DbStore {
 
         let mut block_opts = rocksdb::BlockBasedOptions::default();
         block_opts.set_block_size(if opts.low_memory { 256 << 10 } else { 1 << 20 });
+        static DBINSTANCE_COUNT: AtomicUsize = AtomicUsize::new(0);
+        let instance = DBINSTANCE_COUNT.fetch_add(1, Ordering::Relaxed);
+
         #[allow(clippy::mutex_atomic)]
         let mut store = DbStore {
             db: Arc::new(rocksdb::DB::open(&db_opts, &opts.path).unwrap()),
             opts,
+            stats: Stats::new(metrics, instance),
             stats_thread: None,
             stats_thread_kill: Arc::new((Mutex::new(false), Condvar::new())),
         };
         if is_new_db {
-            store.write(vec![version_marker()], true);
-            store.flush();
+            store
+                .write(vec![version_marker()], true)
+                .and_then(|_| store.flush())
+                .expect("failed to write DB version");
         }
-        store.start_stats_thread(metrics);
+        store.start_stats_thread(metrics, instance);
         store
     }
 
-    fn start_stats_thread(&mut self, metrics: &Metrics) {
-        static DBINSTANCE_COUNT: AtomicUsize = AtomicUsize::new(0);
-        let i = DBINSTANCE_COUNT.fetch_add(1, Ordering::Relaxed);
-
+    fn start_stats_thread(&mut self, metrics: &Metrics, i: usize) {
         let mem_table_total = metrics.gauge_int(prometheus::Opts::new(
             format!("electrscash_rockdb_mem_table_total_{}", i),
             "Rockdb approximate memory usage of all the mem-tables".to_string(),
@@ -130,21 +227,21 @@ impl DbStore {
     }
 
     /// Opens a new RocksDB at the specified location.
-    pub fn open(path: &Path, low_memory: bool, metrics: &Metrics) -> Self {
+    pub fn open(path: &Path, low_memory: bool, live_wal: bool, metrics: &Metrics) -> Self {
         DbStore::open_opts(
             Options {
                 path: path.to_path_buf(),
                 bulk_import: true,
                 low_memory,
+                live_wal,
             },
             metrics,
         )
     }
 
-    pub fn enable_compaction(self) -> Self {
-        let mut opts = self.opts.clone();
-        if opts.bulk_import {
-            opts.bulk_import = false;
+    pub fn enable_compaction(mut self) -> Self {
+        if self.opts.bulk_import {
+            self.opts.bulk_import = false;
             info!("enabling auto-compactions");
             let opts = [("disable_auto_compactions", "false")];
             self.db.set_options(&opts).unwrap();
@@ -159,11 +256,23 @@ impl DbStore {
         self
     }
 
+    /// Full compaction of the DB, which can run on another thread while the
+    /// DB is in use.
+    pub fn compactor(&self) -> impl FnOnce() + Send + 'static {
+        let db = Arc::clone(&self.db);
+        move || {
+            info!("starting full compaction of the live DB");
+            db.compact_range(None::<&[u8]>, None::<&[u8]>);
+            info!("finished full compaction of the live DB");
+        }
+    }
+
     pub fn iter_scan(&self, prefix: &[u8]) -> ScanIterator {
         ScanIterator {
             prefix: prefix.to_vec(),
             iter: self.db.prefix_iterator(prefix),
             done: false,
+            _timer: self.stats.iter_scan.start_timer(),
         }
     }
 
@@ -179,6 +288,8 @@ pub struct ScanIterator<'a> {
     prefix: Vec<u8>,
     iter: rocksdb::DBIterator<'a>,
     done: bool,
+    // Observes the scan duration when the iterator is dropped
+    _timer: prometheus::HistogramTimer,
 }
 
 impl<'a> Iterator for ScanIterator<'a> {
@@ -201,12 +312,16 @@ impl<'a> Iterator for ScanIterator<'a> {
 }
 
 impl ReadStore for DbStore {
-    fn get(&self, key: &[u8]) -> Option<Bytes> {
-        self.db.get(key).unwrap().map(|v| v.to_vec())
+    fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        let _timer = self.stats.get.start_timer();
+        retry("get", DB_RETRY_ATTEMPTS, DB_RETRY_BACKOFF, || {
+            self.db.get(key)
+        })
     }
 
     // TODO: use generators
     fn scan(&self, prefix: &[u8]) -> Vec<Row> {
+        let _timer = self.stats.scan.start_timer();
         let mut rows = vec![];
         for (key, value) in self.db.iterator(rocksdb::IteratorMode::From(
             prefix,
@@ -222,26 +337,60 @@ impl ReadStore for DbStore {
         }
         rows
     }
+
+    fn scan_range(&self, start: &[u8], last: &[u8], limit: usize) -> Vec<Row> {
+        let _timer = self.stats.scan.start_timer();
+        self.db
+            .iterator(rocksdb::IteratorMode::From(
+                start,
+                rocksdb::Direction::Forward,
+            ))
+            .take_while(|(key, _)| is_up_to(key, last))
+            .take(limit)
+            .map(|(key, value)| Row {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            })
+            .collect()
+    }
 }
 
 impl WriteStore for DbStore {
-    fn write<I: IntoIterator<Item = Row>>(&self, rows: I, sync: bool) {
-        let mut batch = rocksdb::WriteBatch::default();
-        for row in rows {
-            batch.put(row.key.as_slice(), row.value.as_slice());
-        }
+    fn write<I: IntoIterator<Item = Row>>(&self, rows: I, sync: bool) -> Result<()> {
+        let rows: Vec<Row> = rows.into_iter().collect();
         let mut opts = rocksdb::WriteOptions::new();
         opts.set_sync(sync);
-        opts.disable_wal(!sync);
-        self.db.write_opt(batch, &opts).unwrap();
+        opts.disable_wal(!self.opts.use_wal(sync));
+        // a batch is consumed by a write, so it's rebuilt for each attempt
+        retry("write", DB_RETRY_ATTEMPTS, DB_RETRY_BACKOFF, || {
+            let mut batch = rocksdb::WriteBatch::default();
+            for row in &rows {
+                batch.put(row.key.as_slice(), row.value.as_slice());
+            }
+            self.db.write_opt(batch, &opts)
+        })
+    }
+
+    fn delete<I: IntoIterator<Item = Bytes>>(&self, keys: I) -> Result<()> {
+        let keys: Vec<Bytes> = keys.into_iter().collect();
+        let mut opts = rocksdb::WriteOptions::new();
+        opts.disable_wal(!self.opts.use_wal(false));
+        retry("delete", DB_RETRY_ATTEMPTS, DB_RETRY_BACKOFF, || {
+            let mut batch = rocksdb::WriteBatch::default();
+            for key in &keys {
+                batch.delete(key.as_slice());
+            }
+            self.db.write_opt(batch, &opts)
+        })
     }
 
-    fn flush(&self) {
+    fn flush(&self) -> Result<()> {
         let mut opts = rocksdb::WriteOptions::new();
         opts.set_sync(true);
         opts.disable_wal(false);
-        let empty = rocksdb::WriteBatch::default();
-        self.db.write_opt(empty, &opts).unwrap();
+        retry("flush", DB_RETRY_ATTEMPTS, DB_RETRY_BACKOFF, || {
+            self.db.write_opt(rocksdb::WriteBatch::default(), &opts)
+        })
     }
 }
 
@@ -273,25 +422,306 @@ pub fn version_marker() -> Row {
     }
 }
 
-pub fn is_compatible_version(store: &dyn ReadStore) -> bool {
-    let version = store.get(&version_marker().key);
-    match version {
+pub fn is_compatible_version(store: &dyn ReadStore) -> Result<bool> {
+    let version = store.get(&version_marker().key)?;
+    Ok(match version {
         Some(v) => match from_utf8(&v) {
             Ok(v) => v == DATABASE_VERSION,
             Err(_) => false,
         },
         None => false,
+    })
+}
+
+/// Whether the DB has to be rebuilt, as it was created by an incompatible
+/// version or indexed from another `index_start_height`. As rebuilding
+/// destroys the index (and takes as long as the initial sync), it's only
+/// allowed with `reindex_incompatible`; otherwise an incompatible DB is an
+/// error.
+pub fn needs_reindex(
+    store: &dyn ReadStore,
+    index_start_height: usize,
+    reindex_incompatible: bool,
+) -> Result<bool> {
+    let reason = if !is_compatible_version(store)? {
+        let version = match store.get(&version_marker().key)? {
+            Some(v) => String::from_utf8_lossy(&v).into_owned(),
+            None => "unknown".to_string(),
+        };
+        format!(
+            "database version {} is incompatible with version {}",
+            version, DATABASE_VERSION
+        )
+    } else {
+        match read_start_height(store)? {
+            Some(height) if height != index_start_height => format!(
+                "database was indexed from height {}, not index_start_height {}",
+                height, index_start_height
+            ),
+            _ => return Ok(false),
+        }
+    };
+    if !reindex_incompatible {
+        bail!(
+            "{}. Restart with --reindex-incompatible-db to rebuild the index",
+            reason
+        );
     }
+    warn!(
+        "{}: DESTROYING THE INDEX, it will be rebuilt from scratch",
+        reason
+    );
+    Ok(true)
 }
 
-pub fn full_compaction(store: DbStore) -> DbStore {
-    store.flush();
+pub fn full_compaction(store: DbStore) -> Result<DbStore> {
+    store.flush()?;
     let store = store.compact().enable_compaction();
-    store.write(vec![full_compaction_marker()], true);
-    store
+    store.write(vec![full_compaction_marker()], true)?;
+    Ok(store)
 }
 
-pub fn is_fully_compacted(store: &dyn ReadStore) -> bool {
-    let marker = store.get(&full_compaction_marker().key);
-    marker.is_some()
+pub fn is_fully_compacted(store: &dyn ReadStore) -> Result<bool> {
+    let marker = store.get(&full_compaction_marker().key)?;
+    Ok(marker.is_some())
+}
+
+/// Full compactions of the live DB, which accumulates tombstones from
+/// mempool churn and reorgs. When enabled, the DB is compacted once the
+/// index first reaches the daemon's tip, and then every `interval` (if set).
+/// Compactions run on a background thread, one at a time, so that RPC
+/// serving goes on meanwhile.
+pub struct TipCompaction {
+    enabled: bool,
+    interval: Option<Duration>,
+    last_start: Mutex<Option<Instant>>,
+    running: Arc<AtomicBool>,
+    duration: prometheus::Histogram,
+}
+
+impl TipCompaction {
+    pub fn new(enabled: bool, interval: Option<Duration>, metrics: &Metrics) -> TipCompaction {
+        TipCompaction {
+            enabled,
+            interval,
+            last_start: Mutex::new(None),
+            running: Arc::new(AtomicBool::new(false)),
+            duration: metrics.histogram(prometheus::HistogramOpts::new(
+                "electrscash_db_compaction_duration",
+                "Duration of full compactions of the live DB (in seconds)",
+            )),
+        }
+    }
+
+    /// Starts `compact` on a background thread if a compaction is due, to be
+    /// called whenever the index reached the daemon's tip. Returns whether
+    /// it was started.
+    pub fn maybe_start(&self, compact: impl FnOnce() + Send + 'static) -> bool {
+        if !self.enabled || self.running.load(Ordering::SeqCst) {
+            return false;
+        }
+        let mut last_start = self.last_start.lock().unwrap();
+        let due = match (*last_start, self.interval) {
+            (None, _) => true,
+            (Some(last_start), Some(interval)) => last_start.elapsed() >= interval,
+            (Some(_), None) => false,
+        };
+        if !due {
+            return false;
+        }
+        *last_start = Some(Instant::now());
+        self.running.store(true, Ordering::SeqCst);
+        let running = Arc::clone(&self.running);
+        let duration = self.duration.clone();
+        spawn_thread("compaction", move || {
+            let timer = duration.start_timer();
+            compact();
+            timer.observe_duration();
+            running.store(false, Ordering::SeqCst);
+        });
+        true
+    }
+
+    /// Whether a compaction is running
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(bulk_import: bool, live_wal: bool) -> Options {
+        Options {
+            path: PathBuf::new(),
+            bulk_import,
+            low_memory: false,
+            live_wal,
+        }
+    }
+
+    #[test]
+    fn test_wal_bulk_import() {
+        let opts = options(true, true);
+        assert!(!opts.use_wal(false));
+        assert!(opts.use_wal(true));
+    }
+
+    #[test]
+    fn test_wal_live() {
+        let opts = options(false, true);
+        assert!(opts.use_wal(false));
+        assert!(opts.use_wal(true));
+
+        // WAL explicitly disabled for live indexing
+        let opts = options(false, false);
+        assert!(!opts.use_wal(false));
+        assert!(opts.use_wal(true));
+    }
+
+    #[test]
+    fn test_retry() {
+        let backoff = Duration::from_millis(1);
+        // transient failures are retried
+        let mut calls = 0;
+        let value = retry("get", 3, backoff, || {
+            calls += 1;
+            if calls < 3 {
+                Err("busy")
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(value.unwrap(), 3);
+
+        // persistent failures give up after the last attempt
+        let mut calls = 0;
+        let result: Result<()> = retry("write", 3, backoff, || {
+            calls += 1;
+            Err("disk full")
+        });
+        assert_eq!(calls, 3);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "RocksDB write failed after 3 attempts: disk full"
+        );
+    }
+
+    #[test]
+    fn test_needs_reindex() {
+        use crate::fake::MemStore;
+
+        let store = MemStore::default();
+        store.write(vec![version_marker()], true).unwrap();
+        assert!(!needs_reindex(&store, 0, false).unwrap());
+        assert!(!needs_reindex(&store, 0, true).unwrap());
+
+        // nothing indexed yet, so any start height goes
+        assert!(!needs_reindex(&store, 100, false).unwrap());
+
+        // DB indexed from another start height
+        store
+            .write(vec![crate::index::start_height_marker(100)], true)
+            .unwrap();
+        assert!(!needs_reindex(&store, 100, false).unwrap());
+        let err = needs_reindex(&store, 0, false).unwrap_err().to_string();
+        assert!(
+            err.contains("indexed from height 100, not index_start_height 0"),
+            "{}",
+            err
+        );
+        assert!(needs_reindex(&store, 0, true).unwrap());
+
+        // DB of an older version
+        let old_version = Row {
+            key: version_marker().key,
+            value: b"0.9".to_vec(),
+        };
+        store.write(vec![old_version], true).unwrap();
+        let err = needs_reindex(&store, 100, false).unwrap_err().to_string();
+        assert!(err.contains("version 0.9 is incompatible"), "{}", err);
+        assert!(needs_reindex(&store, 100, true).unwrap());
+
+        // DB without a version
+        assert!(needs_reindex(&MemStore::default(), 0, false).is_err());
+        assert!(needs_reindex(&MemStore::default(), 0, true).unwrap());
+    }
+
+    #[test]
+    fn test_failing_store() {
+        use crate::fake::FailingStore;
+
+        assert!(is_compatible_version(&FailingStore).is_err());
+        assert!(is_fully_compacted(&FailingStore).is_err());
+    }
+
+    /// Compaction that signals on `started`, and runs until `finish` is
+    /// signalled.
+    fn blocking_compaction(
+        started: &crossbeam_channel::Sender<()>,
+        finish: &crossbeam_channel::Receiver<()>,
+    ) -> impl FnOnce() + Send + 'static {
+        let (started, finish) = (started.clone(), finish.clone());
+        move || {
+            started.send(()).unwrap();
+            let _ = finish.recv();
+        }
+    }
+
+    fn wait_until_done(compaction: &TipCompaction) {
+        let mut attempts = 0;
+        while compaction.is_running() {
+            attempts += 1;
+            assert!(attempts < 500, "compaction didn't finish");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_tip_compaction() {
+        let (started, wait_started) = crossbeam_channel::unbounded();
+        let (finish, wait_finish) = crossbeam_channel::unbounded();
+        let compaction = TipCompaction::new(true, None, &Metrics::dummy());
+
+        // compacted when the tip is first reached, in the background
+        assert!(compaction.maybe_start(blocking_compaction(&started, &wait_finish)));
+        wait_started.recv().unwrap();
+        assert!(compaction.is_running());
+        // only one compaction at a time
+        assert!(!compaction.maybe_start(|| panic!("concurrent compaction")));
+        finish.send(()).unwrap();
+        wait_until_done(&compaction);
+        assert_eq!(compaction.duration.get_sample_count(), 1);
+
+        // not again without an interval
+        assert!(!compaction.maybe_start(|| panic!("compacted twice")));
+    }
+
+    #[test]
+    fn test_tip_compaction_interval() {
+        let (started, wait_started) = crossbeam_channel::unbounded();
+        let (finish, wait_finish) = crossbeam_channel::unbounded();
+        drop(finish);
+        let compaction = TipCompaction::new(true, Some(Duration::from_secs(0)), &Metrics::dummy());
+        for _ in 0..3 {
+            assert!(compaction.maybe_start(blocking_compaction(&started, &wait_finish)));
+            wait_started.recv().unwrap();
+            wait_until_done(&compaction);
+        }
+        assert_eq!(compaction.duration.get_sample_count(), 3);
+
+        let compaction =
+            TipCompaction::new(true, Some(Duration::from_secs(3600)), &Metrics::dummy());
+        assert!(compaction.maybe_start(|| ()));
+        wait_until_done(&compaction);
+        assert!(!compaction.maybe_start(|| panic!("compacted before interval passed")));
+    }
+
+    #[test]
+    fn test_tip_compaction_disabled() {
+        let compaction = TipCompaction::new(false, None, &Metrics::dummy());
+        assert!(!compaction.maybe_start(|| panic!("compaction is disabled")));
+        assert!(!compaction.is_running());
+    }
 }
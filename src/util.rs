@@ -1,5 +1,6 @@
 use bitcoincash::blockdata::block::BlockHeader;
 use bitcoincash::hash_types::BlockHash;
+use bitcoincash::util::uint::Uint256;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt;
@@ -28,6 +29,14 @@ pub struct HeaderEntry {
 }
 
 impl HeaderEntry {
+    pub fn new(height: usize, hash: BlockHash, header: BlockHeader) -> HeaderEntry {
+        HeaderEntry {
+            height,
+            hash,
+            header,
+        }
+    }
+
     pub fn hash(&self) -> &BlockHash {
         &self.hash
     }
@@ -79,15 +88,22 @@ fn hash_headers(headers: Vec<BlockHeader>) -> Vec<HashedHeader> {
 }
 
 pub struct HeaderList {
+    // Hashes of the headers dropped by `trim`, indexed by height
+    trimmed: Vec<BlockHash>,
+    // Headers above the trimmed ones
     headers: Vec<HeaderEntry>,
     heights: HashMap<BlockHash, usize>,
+    // Cumulative chain work, indexed by height (also of trimmed headers)
+    chainwork: Vec<Uint256>,
 }
 
 impl HeaderList {
     pub fn empty() -> HeaderList {
         HeaderList {
+            trimmed: vec![],
             headers: vec![],
             heights: HashMap::new(),
+            chainwork: vec![],
         }
     }
 
@@ -102,9 +118,8 @@ impl HeaderList {
         let new_height: usize = if prev_blockhash == null_hash {
             0
         } else {
-            self.header_by_blockhash(&prev_blockhash)
+            self.height_by_blockhash(&prev_blockhash)
                 .unwrap_or_else(|| panic!("{} is not part of the blockchain", prev_blockhash))
-                .height()
                 + 1
         };
         (new_height..)
@@ -121,7 +136,9 @@ impl HeaderList {
         if tip == BlockHash::default() {
             assert!(new_headers.is_empty());
             self.heights.clear();
+            self.trimmed.clear();
             self.headers.clear();
+            self.chainwork.clear();
             return;
         }
         // new_headers[i] -> new_headers[i - 1] (i.e. new_headers.last() is the tip)
@@ -140,7 +157,8 @@ impl HeaderList {
                 // Make sure first header connects correctly to existing chain
                 let height = entry.height();
                 let expected_prev_blockhash = if height > 0 {
-                    *self.headers[height - 1].hash()
+                    self.blockhash_at(height - 1)
+                        .unwrap_or_else(|| panic!("missing header at height {}", height - 1))
                 } else {
                     BlockHash::default()
                 };
@@ -163,11 +181,22 @@ impl HeaderList {
             new_height
         );
         // keep [0..new_height) entries
-        self.headers.truncate(new_height);
-        assert_eq!(new_height, self.headers.len());
+        if new_height <= self.trimmed.len() {
+            self.trimmed.truncate(new_height);
+            self.headers.clear();
+        } else {
+            self.headers.truncate(new_height - self.trimmed.len());
+        }
+        assert_eq!(new_height, self.len());
+        self.chainwork.truncate(new_height);
         for new_header in new_headers {
-            assert_eq!(new_header.height(), self.headers.len());
+            assert_eq!(new_header.height(), self.len());
             assert_eq!(new_header.header().prev_blockhash, self.tiphash());
+            let chainwork = match self.chainwork.last() {
+                Some(prev) => *prev + new_header.header().work(),
+                None => new_header.header().work(),
+            };
+            self.chainwork.push(chainwork);
             self.heights.insert(*new_header.hash(), new_header.height());
             self.headers.push(new_header.clone())
         }
@@ -175,9 +204,10 @@ impl HeaderList {
         assert!(self.heights.contains_key(&tip));
     }
 
+    /// Returns None also for trimmed headers, see `height_by_blockhash`.
     pub fn header_by_blockhash(&self, blockhash: &BlockHash) -> Option<&HeaderEntry> {
         let height = self.heights.get(blockhash)?;
-        let header = self.headers.get(*height)?;
+        let header = self.header_by_height(*height)?;
         if *blockhash == *header.hash() {
             Some(header)
         } else {
@@ -185,19 +215,66 @@ impl HeaderList {
         }
     }
 
+    /// Returns None also for trimmed headers, see `blockhash_at`.
     pub fn header_by_height(&self, height: usize) -> Option<&HeaderEntry> {
-        self.headers.get(height).map(|entry| {
+        let index = height.checked_sub(self.trimmed.len())?;
+        self.headers.get(index).map(|entry| {
             assert_eq!(entry.height(), height);
             entry
         })
     }
 
+    /// Height of a block in the best chain, including trimmed ones.
+    pub fn height_by_blockhash(&self, blockhash: &BlockHash) -> Option<usize> {
+        let height = *self.heights.get(blockhash)?;
+        if self.blockhash_at(height)? == *blockhash {
+            Some(height)
+        } else {
+            None
+        }
+    }
+
+    /// Hash of the block at `height`, including trimmed ones.
+    pub fn blockhash_at(&self, height: usize) -> Option<BlockHash> {
+        match self.trimmed.get(height) {
+            Some(hash) => Some(*hash),
+            None => self.header_by_height(height).map(|entry| *entry.hash()),
+        }
+    }
+
+    /// Cumulative work of the best chain up to (and including) `height`,
+    /// including trimmed headers.
+    pub fn chainwork_at(&self, height: usize) -> Option<Uint256> {
+        self.chainwork.get(height).copied()
+    }
+
+    /// Drop all but the latest `window` headers from memory, keeping only
+    /// their hashes (and chain work). The tip is always kept.
+    pub fn trim(&mut self, window: usize) {
+        let window = window.max(1);
+        if self.headers.len() <= window {
+            return;
+        }
+        let count = self.headers.len() - window;
+        self.trimmed
+            .extend(self.headers.drain(..count).map(|entry| entry.hash));
+        self.headers.shrink_to_fit();
+    }
+
+    /// Number of headers dropped from memory by `trim`.
+    pub fn trimmed_len(&self) -> usize {
+        self.trimmed.len()
+    }
+
     pub fn equals(&self, other: &HeaderList) -> bool {
         self.headers.last() == other.headers.last()
     }
 
     pub fn tiphash(&self) -> BlockHash {
-        self.headers.last().map(|h| *h.hash()).unwrap_or_default()
+        match self.headers.last() {
+            Some(h) => *h.hash(),
+            None => self.trimmed.last().copied().unwrap_or_default(),
+        }
     }
 
     pub fn tip(&self) -> Option<HeaderEntry> {
@@ -205,13 +282,14 @@ impl HeaderList {
     }
 
     pub fn len(&self) -> usize {
-        self.headers.len()
+        self.trimmed.len() + self.headers.len()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.headers.is_empty()
+        self.len() == 0
     }
 
+    /// Iterate over the headers kept in memory.
     pub fn iter(&self) -> slice::Iter<HeaderEntry> {
         self.headers.iter()
     }
@@ -265,6 +343,19 @@ impl<T> Channel<T> {
     }
 }
 
+/// Longest thread name shown by the OS (Linux allows 15 bytes)
+const THREAD_NAME_MAX_LEN: usize = 15;
+
+/// Truncate `name` to fit `THREAD_NAME_MAX_LEN`, so that tools like `top -H`
+/// show the same name as the logs.
+fn thread_name(name: &str) -> String {
+    let mut end = name.len().min(THREAD_NAME_MAX_LEN);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name[..end].to_owned()
+}
+
 pub fn spawn_thread<F, T>(name: &str, f: F) -> thread::JoinHandle<T>
 where
     F: FnOnce() -> T,
@@ -272,13 +363,29 @@ where
     T: Send + 'static,
 {
     thread::Builder::new()
-        .name(name.to_owned())
+        .name(thread_name(name))
         .spawn(f)
         .unwrap()
 }
 
 #[cfg(test)]
 mod tests {
+    #[test]
+    fn test_thread_name() {
+        use super::{spawn_thread, thread_name};
+        assert_eq!(thread_name("peer-42"), "peer-42");
+        assert_eq!(thread_name("peer-12345678901234"), "peer-1234567890");
+        // never split a character
+        assert_eq!(thread_name("peer-123456789é"), "peer-123456789");
+
+        let name = spawn_thread("peer-123456789012", || {
+            std::thread::current().name().map(str::to_owned)
+        })
+        .join()
+        .unwrap();
+        assert_eq!(name.as_deref(), Some("peer-1234567890"));
+    }
+
     #[test]
     fn test_headers() {
         use super::HeaderList;
@@ -300,7 +407,7 @@ mod tests {
             prev_blockhash: BlockHash::default(),
             merkle_root,
             time: 0,
-            bits: 0,
+            bits: 0x1d00ffff,
             nonce: 0,
         }];
         for _height in 1..10 {
@@ -310,7 +417,7 @@ mod tests {
                 prev_blockhash,
                 merkle_root,
                 time: 0,
-                bits: 0,
+                bits: 0x1d00ffff,
                 nonce: 0,
             };
             headers.push(header);
@@ -402,5 +509,62 @@ mod tests {
             assert_eq!(entry.height, h);
             assert_eq!(header_list.header_by_blockhash(&entry.hash), Some(entry));
         }
+
+        // Test dropping old headers from memory
+        header_list.trim(4);
+        assert_eq!(header_list.len(), 10);
+        assert_eq!(header_list.tiphash(), headers[9].block_hash());
+        for (h, header) in headers.iter().enumerate() {
+            let hash = header.block_hash();
+            assert_eq!(header_list.blockhash_at(h), Some(hash));
+            assert_eq!(header_list.height_by_blockhash(&hash), Some(h));
+            assert_eq!(header_list.header_by_height(h).is_some(), h >= 6);
+            assert_eq!(header_list.header_by_blockhash(&hash).is_some(), h >= 6);
+        }
+
+        // Test reorging below the trimmed headers
+        header_list.apply(&[], headers[2].block_hash());
+        assert_eq!(header_list.len(), 3);
+        assert_eq!(header_list.tiphash(), headers[2].block_hash());
+        let ordered = header_list.order(headers[3..].to_vec());
+        header_list.apply(&ordered, ordered[6].hash);
+        assert_eq!(header_list.len(), 10);
+        assert_eq!(header_list.trimmed_len(), 3);
+        assert_eq!(header_list.header_by_height(3).unwrap().header, headers[3]);
+    }
+
+    #[test]
+    fn test_chainwork() {
+        use super::HeaderList;
+        use crate::fake::chain_headers;
+        use bitcoincash::util::uint::Uint256;
+
+        // work of a minimum difficulty block
+        let work = Uint256::from_u64(0x1_0001_0001).unwrap();
+        let chain = chain_headers(&[1; 20]);
+        assert_eq!(chain[0].work(), work);
+
+        let mut header_list = HeaderList::empty();
+        assert_eq!(header_list.chainwork_at(0), None);
+        let entries = header_list.order(chain[..10].to_vec());
+        header_list.apply(&entries, *entries[9].hash());
+        assert_eq!(header_list.chainwork_at(0), Some(work));
+        assert_eq!(header_list.chainwork_at(9), Some(work.mul_u32(10)));
+        assert_eq!(header_list.chainwork_at(10), None);
+
+        // kept for trimmed headers, and extended from them
+        header_list.trim(2);
+        assert_eq!(header_list.chainwork_at(3), Some(work.mul_u32(4)));
+        let entries = header_list.order(chain[10..].to_vec());
+        header_list.apply(&entries, *entries[9].hash());
+        assert_eq!(header_list.chainwork_at(19), Some(work.mul_u32(20)));
+
+        // reorg below the trimmed headers
+        let fork = chain_headers(&[1, 1, 1, 1, 1, 2, 2]);
+        let entries = header_list.order(fork[5..].to_vec());
+        header_list.apply(&entries, *entries[1].hash());
+        assert_eq!(header_list.len(), 7);
+        assert_eq!(header_list.chainwork_at(6), Some(work.mul_u32(7)));
+        assert_eq!(header_list.chainwork_at(7), None);
     }
 }
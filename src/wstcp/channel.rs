@@ -1,6 +1,7 @@
+use crate::doslimit::ProxiedPeers;
 use crate::errors::*;
 use crate::wstcp::frame::{Frame, FrameDecoder, FrameEncoder};
-use crate::wstcp::util::{self, WebSocketKey};
+use crate::wstcp::util::{self, AllowedOrigins, WebSocketKey};
 use async_std::net::TcpStream;
 use bytecodec::io::{IoDecodeExt, IoEncodeExt, ReadBuf, StreamState, WriteBuf};
 use bytecodec::{Decode, Encode, EncodeExt};
@@ -8,21 +9,43 @@ use httpcodec::{
     HeaderField, HttpVersion, NoBodyDecoder, NoBodyEncoder, ReasonPhrase, Request, RequestDecoder,
     Response, ResponseEncoder, StatusCode,
 };
+use socket2::{Domain, Socket, Type};
 use std::future::Future;
 use std::mem;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
 
 const BUF_SIZE: usize = 4096;
 
+/// Connect to the RPC server at `addr`, from a local address added to
+/// `proxied_peers` beforehand, so that the server can't mistake the
+/// connection for a local client. Connecting to a local server doesn't block.
+fn connect_proxied(addr: SocketAddr, proxied_peers: &ProxiedPeers) -> std::io::Result<TcpStream> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.bind(&SocketAddr::new(addr.ip(), 0).into())?;
+    let local_addr = socket
+        .local_addr()?
+        .as_socket()
+        .expect("bound to an IP address");
+    proxied_peers.add(&local_addr);
+    if let Err(e) = socket.connect(&addr.into()) {
+        proxied_peers.remove(&local_addr);
+        return Err(e);
+    }
+    Ok(TcpStream::from(std::net::TcpStream::from(socket)))
+}
+
 #[derive(Debug)]
 pub struct ProxyChannel {
     ws_stream: TcpStream,
     ws_rbuf: ReadBuf<Vec<u8>>,
     ws_wbuf: WriteBuf<Vec<u8>>,
     real_server_addr: SocketAddr,
+    allowed_origins: Arc<AllowedOrigins>,
+    proxied_peers: Arc<ProxiedPeers>,
     real_stream: Option<TcpStream>,
     real_stream_rstate: StreamState,
     real_stream_wstate: StreamState,
@@ -34,7 +57,15 @@ pub struct ProxyChannel {
     frame_encoder: FrameEncoder,
 }
 impl ProxyChannel {
-    pub fn new(ws_stream: TcpStream, real_server_addr: SocketAddr) -> Self {
+    /// Frames with a payload larger than `max_frame_size` bytes (0 for no
+    /// limit) close the channel with code 1009 (message too big).
+    pub fn new(
+        ws_stream: TcpStream,
+        real_server_addr: SocketAddr,
+        allowed_origins: Arc<AllowedOrigins>,
+        proxied_peers: Arc<ProxiedPeers>,
+        max_frame_size: u64,
+    ) -> Self {
         let _ = ws_stream.set_nodelay(true);
         info!("New proxy channel is created");
         ProxyChannel {
@@ -42,6 +73,8 @@ impl ProxyChannel {
             ws_rbuf: ReadBuf::new(vec![0; BUF_SIZE]),
             ws_wbuf: WriteBuf::new(vec![0; BUF_SIZE]),
             real_server_addr,
+            allowed_origins,
+            proxied_peers,
             real_stream: None,
             real_stream_rstate: StreamState::Normal,
             real_stream_wstate: StreamState::Normal,
@@ -49,7 +82,7 @@ impl ProxyChannel {
             closing: Closing::NotYet,
             pending_pong: None,
             pending_close: None,
-            frame_decoder: FrameDecoder::default(),
+            frame_decoder: FrameDecoder::new(max_frame_size),
             frame_encoder: FrameEncoder::default(),
         }
     }
@@ -73,19 +106,36 @@ impl ProxyChannel {
                                 warn!("Invalid WebSocket handshake request: {}", e);
                                 self.handshake = Handshake::response_bad_request();
                             }
-                            Ok(key) => {
-                                debug!("WebSocket connecting to RPC {}", self.real_server_addr);
-                                let future = TcpStream::connect(self.real_server_addr);
-                                self.handshake =
-                                    Handshake::ConnectToRealServer(Box::pin(future), key);
+                            Ok((key, origin)) => {
+                                match self.allowed_origins.allow(origin.as_deref()) {
+                                    Err(e) => {
+                                        warn!("Rejected WebSocket handshake request: {}", e);
+                                        self.handshake = Handshake::response_forbidden();
+                                    }
+                                    Ok(allow_origin) => {
+                                        debug!(
+                                            "WebSocket connecting to RPC {}",
+                                            self.real_server_addr
+                                        );
+                                        let addr = self.real_server_addr;
+                                        let proxied_peers = Arc::clone(&self.proxied_peers);
+                                        let future =
+                                            async move { connect_proxied(addr, &proxied_peers) };
+                                        self.handshake = Handshake::ConnectToRealServer(
+                                            Box::pin(future),
+                                            key,
+                                            allow_origin,
+                                        );
+                                    }
+                                }
                             }
                         },
                     }
                 }
-                Handshake::ConnectToRealServer(mut f, key) => {
+                Handshake::ConnectToRealServer(mut f, key, allow_origin) => {
                     match Pin::new(&mut f).poll(cx).map_err(Error::from) {
                         Poll::Pending => {
-                            self.handshake = Handshake::ConnectToRealServer(f, key);
+                            self.handshake = Handshake::ConnectToRealServer(f, key, allow_origin);
                             break;
                         }
                         Poll::Ready(Err(e)) => {
@@ -98,7 +148,8 @@ impl ProxyChannel {
                             if let Ok(addr) = stream.local_addr() {
                                 trace!("relay_addr {}", addr.to_string())
                             }
-                            self.handshake = Handshake::response_accepted(&key);
+                            self.handshake =
+                                Handshake::response_accepted(&key, allow_origin.as_deref());
                             self.real_stream = Some(stream);
                         }
                     }
@@ -129,7 +180,12 @@ impl ProxyChannel {
         true
     }
 
-    fn handle_handshake_request(&mut self, request: &Request<()>) -> Result<WebSocketKey> {
+    /// Validates the handshake request, returning the WebSocket key and the
+    /// 'Origin' header (if present).
+    fn handle_handshake_request(
+        &mut self,
+        request: &Request<()>,
+    ) -> Result<(WebSocketKey, Option<String>)> {
         if request.method().as_str() != "GET" {
             return Err(rpc_invalid_request("Not a GET request".to_string()).into());
         }
@@ -138,6 +194,7 @@ impl ProxyChannel {
         }
 
         let mut key = None;
+        let mut origin = None;
         for field in request.header().fields() {
             let name = field.name();
             let value = field.value();
@@ -157,6 +214,8 @@ impl ProxyChannel {
                 }
             } else if name.eq_ignore_ascii_case("sec-websocket-key") {
                 key = Some(value.to_owned());
+            } else if name.eq_ignore_ascii_case("origin") {
+                origin = Some(value.to_owned());
             } else if name.eq_ignore_ascii_case("sec-websocket-version") && value != "13" {
                 return Err(
                     rpc_invalid_request("Websocket verison not supported".to_string()).into(),
@@ -165,7 +224,7 @@ impl ProxyChannel {
         }
 
         if let Some(k) = key {
-            Ok(WebSocketKey(k))
+            Ok((WebSocketKey(k), origin))
         } else {
             Err(rpc_invalid_request("sec-websocket-key missing".to_string()).into())
         }
@@ -177,8 +236,18 @@ impl ProxyChannel {
             self.starts_closing(1001, false)?;
         }
         if let Err(e) = self.handle_ws_stream() {
-            warn!("{}", e);
-            self.starts_closing(1002, false)?;
+            match self.frame_decoder.payload_too_big() {
+                Some(len) => {
+                    warn!("WebSocket frame too big: {} bytes", len);
+                    // the rest of the stream can't be decoded, so the
+                    // client's Close frame isn't waited for
+                    self.starts_closing(1009, true)?;
+                }
+                None => {
+                    warn!("{}", e);
+                    self.starts_closing(1002, false)?;
+                }
+            }
         }
         Ok(())
     }
@@ -212,6 +281,10 @@ impl ProxyChannel {
         if self.frame_encoder.is_idle() && self.closing.is_client_closed() {
             self.closing = Closing::Closed;
         }
+        if self.closing.is_client_closed() || self.closing == Closing::Closed {
+            // no frames follow the client's Close frame
+            return Ok(());
+        }
 
         self.frame_decoder.decode_from_read_buf(&mut self.ws_rbuf)?;
         if self.frame_decoder.is_idle() {
@@ -338,6 +411,7 @@ enum Handshake {
     ConnectToRealServer(
         Pin<Box<(dyn Future<Output = async_std::io::Result<TcpStream>> + Send + 'static)>>,
         WebSocketKey,
+        Option<String>,
     ),
     SendResponse(ResponseEncoder<NoBodyEncoder>, bool),
     Done,
@@ -351,7 +425,7 @@ impl Handshake {
         matches!(*self, Handshake::Done)
     }
 
-    fn response_accepted(key: &WebSocketKey) -> Self {
+    fn response_accepted(key: &WebSocketKey, allow_origin: Option<&str>) -> Self {
         let hash = util::calc_accept_hash(key);
 
         unsafe {
@@ -366,6 +440,15 @@ impl Handshake {
                 .add_field(HeaderField::new_unchecked("Upgrade", "websocket"))
                 .add_field(HeaderField::new_unchecked("Connection", "Upgrade"))
                 .add_field(HeaderField::new_unchecked("Sec-WebSocket-Accept", &hash));
+            if let Some(origin) = allow_origin {
+                response
+                    .header_mut()
+                    .add_field(HeaderField::new_unchecked(
+                        "Access-Control-Allow-Origin",
+                        origin,
+                    ))
+                    .add_field(HeaderField::new_unchecked("Vary", "Origin"));
+            }
 
             let encoder = ResponseEncoder::with_item(response).expect("Never fails");
             Handshake::SendResponse(encoder, true)
@@ -388,6 +471,22 @@ impl Handshake {
         }
     }
 
+    fn response_forbidden() -> Self {
+        unsafe {
+            let mut response = Response::new(
+                HttpVersion::V1_1,
+                StatusCode::new_unchecked(403),
+                ReasonPhrase::new_unchecked("Forbidden"),
+                (),
+            );
+            response
+                .header_mut()
+                .add_field(HeaderField::new_unchecked("Content-Length", "0"));
+            let encoder = ResponseEncoder::with_item(response).expect("Never fails");
+            Handshake::SendResponse(encoder, false)
+        }
+    }
+
     fn response_unavailable() -> Self {
         unsafe {
             let mut response = Response::new(
@@ -493,3 +592,88 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    /// Connects to `addr` and completes the WebSocket handshake
+    fn connect(addr: SocketAddr) -> std::net::TcpStream {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(std::time::Duration::from_secs(10)))
+            .unwrap();
+        stream
+            .write_all(
+                b"GET / HTTP/1.1\r\n\
+                Host: localhost\r\n\
+                Upgrade: websocket\r\n\
+                Connection: Upgrade\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                Sec-WebSocket-Version: 13\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = vec![];
+        let mut byte = [0; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101"));
+        stream
+    }
+
+    #[test]
+    fn test_frame_too_big() {
+        async_std::task::block_on(async {
+            let real_server = async_std::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let listener = async_std::net::TcpListener::bind("127.0.0.1:0")
+                .await
+                .unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = std::thread::spawn(move || {
+                let mut stream = connect(addr);
+                // masked binary frame advertising a 1 TB payload
+                let mut frame = vec![0x82, 0x80 | 127];
+                frame.extend_from_slice(&(1u64 << 40).to_be_bytes());
+                frame.extend_from_slice(&[1, 2, 3, 4]);
+                stream.write_all(&frame).unwrap();
+                let mut received = vec![];
+                stream.read_to_end(&mut received).unwrap();
+                received
+            });
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let channel = ProxyChannel::new(
+                stream,
+                real_server.local_addr().unwrap(),
+                Arc::new(AllowedOrigins::Any),
+                Arc::new(ProxiedPeers::default()),
+                1000,
+            );
+            channel.await.unwrap();
+
+            // a Close frame with code 1009 (message too big), then the
+            // connection is closed
+            let received = client.join().unwrap();
+            assert_eq!(received, vec![0x88, 2, 0x03, 0xf1]);
+        });
+    }
+
+    #[test]
+    fn test_connect_proxied() {
+        let proxied_peers = ProxiedPeers::default();
+        let real_server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = real_server.local_addr().unwrap();
+        let _stream = connect_proxied(addr, &proxied_peers).unwrap();
+        let (_, peer) = real_server.accept().unwrap();
+        assert!(proxied_peers.contains(&peer));
+        assert!(proxied_peers.remove(&peer));
+
+        drop(real_server);
+        assert!(connect_proxied(addr, &proxied_peers).is_err());
+    }
+}
@@ -424,8 +424,39 @@ impl Default for FramePayloadDecoder {
 pub struct FrameDecoder {
     header: FrameHeaderDecoder,
     payload: FramePayloadDecoder,
+    max_payload_len: u64, // 0 for no limit
+    too_big: Option<u64>,
 }
 impl FrameDecoder {
+    /// Decoder rejecting frames with a payload larger than `max_payload_len`
+    /// bytes (0 for no limit). Control frames are buffered in full, so they
+    /// are limited by the buffer size.
+    pub fn new(max_payload_len: u64) -> Self {
+        FrameDecoder {
+            max_payload_len,
+            ..Default::default()
+        }
+    }
+
+    /// Advertised payload length of a rejected frame, if decoding failed as
+    /// the frame was too big. The stream can't be decoded any further then.
+    pub fn payload_too_big(&self) -> Option<u64> {
+        self.too_big
+    }
+
+    fn check_payload_len(&self, header: &FrameHeader) -> Option<u64> {
+        let too_big = if header.opcode.is_control() {
+            header.payload_len > BUF_SIZE as u64
+        } else {
+            self.max_payload_len > 0 && header.payload_len > self.max_payload_len
+        };
+        if too_big {
+            Some(header.payload_len)
+        } else {
+            None
+        }
+    }
+
     pub fn write_decoded_data<W: Write>(&mut self, mut writer: W) -> Result<StreamState> {
         if self.is_data_empty() {
             return Ok(StreamState::Normal);
@@ -462,10 +493,17 @@ impl Decode for FrameDecoder {
     type Item = Frame;
 
     fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        if self.too_big.is_some() {
+            return Err(bytecodec::ErrorKind::InvalidInput.into());
+        }
         let mut offset = 0;
         if self.payload.header.is_none() {
             bytecodec_try_decode!(self.header, offset, buf, eos);
             let header = self.header.finish_decoding()?;
+            self.too_big = self.check_payload_len(&header);
+            if self.too_big.is_some() {
+                return Err(bytecodec::ErrorKind::InvalidInput.into());
+            }
             self.payload.header = Some(header);
         }
         bytecodec_try_decode!(self.payload, offset, buf, eos);
@@ -502,3 +540,52 @@ impl AsMut<[u8]> for ExtendedHeaderBytes {
         &mut self.bytes[..self.size]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Header of a masked binary frame with a 64-bit payload length
+    fn header(opcode: Opcode, payload_len: u64) -> Vec<u8> {
+        let mut header = vec![FIN_FLAG | opcode as u8, MASK_FLAG | 127];
+        header.extend_from_slice(&payload_len.to_be_bytes());
+        header.extend_from_slice(&[0; 4]); // mask
+        header
+    }
+
+    #[test]
+    fn test_payload_too_big() {
+        let mut decoder = FrameDecoder::new(1000);
+        let mut frame = header(Opcode::BinaryFrame, 1000);
+        frame.extend_from_slice(&[0; 1000]);
+        assert_eq!(
+            decoder.decode(&frame, Eos::new(false)).unwrap(),
+            frame.len()
+        );
+        assert!(decoder.payload_too_big().is_none());
+
+        let mut decoder = FrameDecoder::new(1000);
+        let frame = header(Opcode::BinaryFrame, 1 << 62);
+        assert!(decoder.decode(&frame, Eos::new(false)).is_err());
+        assert_eq!(decoder.payload_too_big(), Some(1 << 62));
+        // the rejected frame is not decoded any further
+        assert!(decoder.decode(&[0; 10], Eos::new(false)).is_err());
+    }
+
+    #[test]
+    fn test_control_frame_too_big() {
+        // control frames are limited by the buffer size, also without limit
+        let mut decoder = FrameDecoder::new(0);
+        let frame = header(Opcode::Ping, BUF_SIZE as u64 + 1);
+        assert!(decoder.decode(&frame, Eos::new(false)).is_err());
+        assert_eq!(decoder.payload_too_big(), Some(BUF_SIZE as u64 + 1));
+
+        let mut decoder = FrameDecoder::new(0);
+        let frame = header(Opcode::BinaryFrame, 1 << 40);
+        assert_eq!(
+            decoder.decode(&frame, Eos::new(false)).unwrap(),
+            frame.len()
+        );
+        assert!(decoder.payload_too_big().is_none());
+    }
+}
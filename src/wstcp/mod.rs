@@ -1,7 +1,10 @@
+use crate::doslimit::ProxiedPeers;
 use crate::wstcp::server::ProxyServer;
+use crate::wstcp::util::AllowedOrigins;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 pub mod channel;
 pub mod frame;
@@ -9,7 +12,13 @@ pub mod opcode;
 pub mod server;
 pub mod util;
 
-pub fn start_ws_proxy(bind_addr: SocketAddr, rpc_addr: SocketAddr) {
+pub fn start_ws_proxy(
+    bind_addr: SocketAddr,
+    rpc_addr: SocketAddr,
+    allowed_origins: AllowedOrigins,
+    proxied_peers: Arc<ProxiedPeers>,
+    max_frame_size: u64,
+) {
     let forward_addr = if rpc_addr.ip().is_unspecified() {
         // RPC bind address is 0.0.0.0, so we can't forward to that.
         // Use localhost.
@@ -19,9 +28,15 @@ pub fn start_ws_proxy(bind_addr: SocketAddr, rpc_addr: SocketAddr) {
     };
 
     async_std::task::block_on(async {
-        let proxy = ProxyServer::new(bind_addr, forward_addr)
-            .await
-            .unwrap_or_else(|e| panic!("{}", e));
+        let proxy = ProxyServer::new(
+            bind_addr,
+            forward_addr,
+            allowed_origins,
+            proxied_peers,
+            max_frame_size,
+        )
+        .await
+        .unwrap_or_else(|e| panic!("{}", e));
         info!("WebSocket initalized");
         proxy.run_accept_loop().await.expect("WebSocket error");
     });
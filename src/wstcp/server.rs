@@ -1,17 +1,29 @@
+use crate::doslimit::ProxiedPeers;
 use crate::errors::*;
 use crate::wstcp::channel::ProxyChannel;
+use crate::wstcp::util::AllowedOrigins;
 use async_std::net::TcpListener;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 /// WebSocket to TCP proxy server.
 #[derive(Debug)]
 pub struct ProxyServer {
     real_server_addr: SocketAddr,
+    allowed_origins: Arc<AllowedOrigins>,
+    proxied_peers: Arc<ProxiedPeers>,
+    max_frame_size: u64,
     listener: TcpListener,
 }
 impl ProxyServer {
     /// Makes a new `ProxyServer` instance.
-    pub async fn new(proxy_addr: SocketAddr, real_server_addr: SocketAddr) -> Result<Self> {
+    pub async fn new(
+        proxy_addr: SocketAddr,
+        real_server_addr: SocketAddr,
+        allowed_origins: AllowedOrigins,
+        proxied_peers: Arc<ProxiedPeers>,
+        max_frame_size: u64,
+    ) -> Result<Self> {
         info!("Starting a WebSocket server on {}", proxy_addr.to_string());
         trace!("WebSocket proxy to {}", real_server_addr.to_string());
         let listener = TcpListener::bind(proxy_addr)
@@ -19,6 +31,9 @@ impl ProxyServer {
             .expect("failed to bind websocket server");
         Ok(ProxyServer {
             real_server_addr,
+            allowed_origins: Arc::new(allowed_origins),
+            proxied_peers,
+            max_frame_size,
             listener,
         })
     }
@@ -31,7 +46,13 @@ impl ProxyServer {
                 Ok((stream, addr)) => {
                     debug!("New connection: {}", addr);
 
-                    let channel = ProxyChannel::new(stream, self.real_server_addr);
+                    let channel = ProxyChannel::new(
+                        stream,
+                        self.real_server_addr,
+                        Arc::clone(&self.allowed_origins),
+                        Arc::clone(&self.proxied_peers),
+                        self.max_frame_size,
+                    );
                     async_std::task::spawn(async move {
                         match channel.await {
                             Err(e) => {
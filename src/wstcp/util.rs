@@ -6,6 +6,50 @@ const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 #[derive(Debug)]
 pub struct WebSocketKey(pub String);
 
+/// Origins allowed to open a WebSocket connection from a browser.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AllowedOrigins {
+    Any,
+    List(Vec<String>),
+}
+
+impl AllowedOrigins {
+    /// Parses '*' or a comma separated list of origins.
+    pub fn parse(origins: &str) -> AllowedOrigins {
+        let origins: Vec<String> = origins
+            .split(',')
+            .map(|o| o.trim().trim_end_matches('/').to_string())
+            .filter(|o| !o.is_empty())
+            .collect();
+        if origins.iter().any(|o| o == "*") {
+            AllowedOrigins::Any
+        } else {
+            AllowedOrigins::List(origins)
+        }
+    }
+
+    /// Validates the 'Origin' header of a handshake request, returning the
+    /// value for the 'Access-Control-Allow-Origin' response header, if any.
+    ///
+    /// Requests without an origin are not from a browser and always allowed.
+    pub fn allow(&self, origin: Option<&str>) -> Result<Option<String>> {
+        let origin = match origin {
+            Some(o) => o,
+            None => return Ok(None),
+        };
+        match self {
+            AllowedOrigins::Any => Ok(Some("*".to_string())),
+            AllowedOrigins::List(origins) => {
+                if origins.iter().any(|o| o.eq_ignore_ascii_case(origin)) {
+                    Ok(Some(origin.to_string()))
+                } else {
+                    Err(ErrorKind::WebSocket(format!("origin '{}' not allowed", origin)).into())
+                }
+            }
+        }
+    }
+}
+
 pub fn calc_accept_hash(key: &WebSocketKey) -> String {
     let mut hasher = Sha1::new();
 
@@ -47,4 +91,44 @@ mod test {
         let hash = calc_accept_hash(&WebSocketKey("dGhlIHNhbXBsZSBub25jZQ==".to_owned()));
         assert_eq!(hash, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
     }
+
+    #[test]
+    fn test_parse_allowed_origins() {
+        assert_eq!(AllowedOrigins::parse("*"), AllowedOrigins::Any);
+        assert_eq!(
+            AllowedOrigins::parse("https://a.example, https://b.example/"),
+            AllowedOrigins::List(vec![
+                "https://a.example".to_string(),
+                "https://b.example".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_any_origin_allowed() {
+        let origins = AllowedOrigins::Any;
+        assert_eq!(
+            origins.allow(Some("https://a.example")).unwrap(),
+            Some("*".to_string())
+        );
+        assert_eq!(origins.allow(None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_listed_origin_allowed() {
+        let origins = AllowedOrigins::parse("https://a.example,https://b.example");
+        assert_eq!(
+            origins.allow(Some("https://b.example")).unwrap(),
+            Some("https://b.example".to_string())
+        );
+        // not a browser
+        assert_eq!(origins.allow(None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_unlisted_origin_rejected() {
+        let origins = AllowedOrigins::parse("https://a.example");
+        assert!(origins.allow(Some("https://evil.example")).is_err());
+        assert!(origins.allow(Some("null")).is_err());
+    }
 }
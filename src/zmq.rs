@@ -0,0 +1,203 @@
+//! Minimal ZeroMQ subscriber (ZMTP 3.0, NULL security mechanism), sufficient
+//! for receiving bitcoind `hashblock` and `hashtx` notifications.
+use std::io::{ErrorKind as IoErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use crate::errors::*;
+use crate::util::Bytes;
+
+const FLAG_MORE: u8 = 0x01;
+const FLAG_LONG: u8 = 0x02;
+const FLAG_COMMAND: u8 = 0x04;
+
+const GREETING_LEN: usize = 64;
+const MAX_FRAME_SIZE: u64 = 1_000_000;
+
+fn greeting() -> [u8; GREETING_LEN] {
+    let mut greeting = [0u8; GREETING_LEN];
+    greeting[0] = 0xFF; // signature
+    greeting[9] = 0x7F;
+    greeting[10] = 3; // version 3.0
+    greeting[11] = 0;
+    greeting[12..16].copy_from_slice(b"NULL"); // mechanism (zero padded)
+    greeting
+}
+
+fn check_greeting(greeting: &[u8]) -> Result<()> {
+    if greeting[0] != 0xFF || greeting[9] != 0x7F {
+        bail!("invalid ZMQ greeting signature");
+    }
+    if greeting[10] < 3 {
+        bail!("unsupported ZMQ version {}.{}", greeting[10], greeting[11]);
+    }
+    if &greeting[12..16] != b"NULL" {
+        bail!("unsupported ZMQ security mechanism");
+    }
+    Ok(())
+}
+
+fn encode_frame(flags: u8, body: &[u8]) -> Bytes {
+    let mut frame = vec![];
+    if body.len() > 255 {
+        frame.push(flags | FLAG_LONG);
+        frame.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    } else {
+        frame.push(flags);
+        frame.push(body.len() as u8);
+    }
+    frame.extend_from_slice(body);
+    frame
+}
+
+fn ready_command(socket_type: &str) -> Bytes {
+    let name = b"Socket-Type";
+    let mut body = vec![5];
+    body.extend_from_slice(b"READY");
+    body.push(name.len() as u8);
+    body.extend_from_slice(name);
+    body.extend_from_slice(&(socket_type.len() as u32).to_be_bytes());
+    body.extend_from_slice(socket_type.as_bytes());
+    encode_frame(FLAG_COMMAND, &body)
+}
+
+/// Read a frame body, given its already read flags byte.
+fn read_frame_body(stream: &mut impl Read, flags: u8) -> Result<Bytes> {
+    let size = if flags & FLAG_LONG != 0 {
+        let mut size = [0u8; 8];
+        stream.read_exact(&mut size)?;
+        u64::from_be_bytes(size)
+    } else {
+        let mut size = [0u8; 1];
+        stream.read_exact(&mut size)?;
+        size[0] as u64
+    };
+    if size > MAX_FRAME_SIZE {
+        bail!("ZMQ frame too large ({} bytes)", size);
+    }
+    let mut body = vec![0u8; size as usize];
+    stream.read_exact(&mut body)?;
+    Ok(body)
+}
+
+pub struct Subscriber {
+    stream: TcpStream,
+}
+
+impl Subscriber {
+    /// Connect to a ZMQ publisher and subscribe to `topics`. `recv` returns
+    /// after at most `timeout` if no message is received.
+    pub fn connect(addr: SocketAddr, topics: &[&str], timeout: Duration) -> Result<Subscriber> {
+        let mut stream = TcpStream::connect_timeout(&addr, timeout)
+            .chain_err(|| format!("failed to connect to ZMQ publisher at {}", addr))?;
+        stream.set_read_timeout(Some(timeout))?;
+
+        stream.write_all(&greeting())?;
+        let mut peer_greeting = [0u8; GREETING_LEN];
+        stream.read_exact(&mut peer_greeting)?;
+        check_greeting(&peer_greeting)?;
+
+        stream.write_all(&ready_command("SUB"))?;
+        let mut flags = [0u8; 1];
+        stream.read_exact(&mut flags)?;
+        let ready = read_frame_body(&mut stream, flags[0])?;
+        if flags[0] & FLAG_COMMAND == 0 || !ready.starts_with(b"\x05READY") {
+            bail!("expected ZMQ READY command");
+        }
+
+        for topic in topics {
+            // ZMTP 3.0 subscriptions are messages starting with 0x01
+            let subscription = [&[1u8][..], topic.as_bytes()].concat();
+            stream.write_all(&encode_frame(0, &subscription))?;
+        }
+        Ok(Subscriber { stream })
+    }
+
+    /// Receive a multipart message, or None if timed out waiting for it.
+    pub fn recv(&mut self) -> Result<Option<Vec<Bytes>>> {
+        let mut parts = vec![];
+        loop {
+            let mut flags = [0u8; 1];
+            if parts.is_empty() {
+                match self.stream.read(&mut flags) {
+                    Ok(0) => bail!("ZMQ publisher closed the connection"),
+                    Ok(_) => (),
+                    Err(e)
+                        if e.kind() == IoErrorKind::WouldBlock
+                            || e.kind() == IoErrorKind::TimedOut =>
+                    {
+                        return Ok(None)
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            } else {
+                self.stream.read_exact(&mut flags)?;
+            }
+            let body = read_frame_body(&mut self.stream, flags[0])?;
+            if flags[0] & FLAG_COMMAND != 0 {
+                continue;
+            }
+            parts.push(body);
+            if flags[0] & FLAG_MORE == 0 {
+                return Ok(Some(parts));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_encode_frame() {
+        assert_eq!(encode_frame(FLAG_MORE, b"abc"), b"\x01\x03abc".to_vec());
+        let long = vec![0u8; 300];
+        let frame = encode_frame(0, &long);
+        assert_eq!(frame[0], FLAG_LONG);
+        assert_eq!(&frame[1..9], &300u64.to_be_bytes());
+        assert_eq!(read_frame_body(&mut &frame[1..], frame[0]).unwrap(), long);
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let publisher = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut peer_greeting = [0u8; GREETING_LEN];
+            stream.read_exact(&mut peer_greeting).unwrap();
+            check_greeting(&peer_greeting).unwrap();
+            stream.write_all(&greeting()).unwrap();
+
+            let mut flags = [0u8; 1];
+            stream.read_exact(&mut flags).unwrap();
+            let ready = read_frame_body(&mut stream, flags[0]).unwrap();
+            assert!(ready.ends_with(b"SUB"));
+            stream.write_all(&ready_command("PUB")).unwrap();
+
+            stream.read_exact(&mut flags).unwrap();
+            let subscription = read_frame_body(&mut stream, flags[0]).unwrap();
+            assert_eq!(subscription, b"\x01hashblock".to_vec());
+
+            stream
+                .write_all(&encode_frame(FLAG_MORE, b"hashblock"))
+                .unwrap();
+            stream
+                .write_all(&encode_frame(FLAG_MORE, &[0xAB; 32]))
+                .unwrap();
+            stream.write_all(&encode_frame(0, &[1, 0, 0, 0])).unwrap();
+        });
+
+        let mut subscriber =
+            Subscriber::connect(addr, &["hashblock"], Duration::from_secs(5)).unwrap();
+        let message = subscriber.recv().unwrap().unwrap();
+        assert_eq!(message.len(), 3);
+        assert_eq!(message[0], b"hashblock".to_vec());
+        assert_eq!(message[1], vec![0xAB; 32]);
+        publisher.join().unwrap();
+        assert!(subscriber.recv().is_err()); // connection closed
+    }
+}